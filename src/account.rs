@@ -0,0 +1,101 @@
+use matrix_sdk::ruma::api::client::uiaa;
+use matrix_sdk::ruma::thirdparty::Medium;
+use matrix_sdk::ruma::{ClientSecret, UInt};
+use matrix_sdk::Client;
+use std::future::Future;
+
+use crate::AccountAction;
+
+type Error = Box<dyn std::error::Error>;
+
+/// Run `request` and repeat it with password authentication if the homeserver requires it.
+async fn with_password_auth<T, F, Fut>(
+    client: &Client,
+    prompt: &str,
+    request: F,
+) -> Result<T, Error>
+where
+    F: Fn(Option<uiaa::AuthData>) -> Fut,
+    Fut: Future<Output = matrix_sdk::Result<T>>,
+{
+    let session = match request(None).await {
+        Ok(response) => return Ok(response),
+        Err(e) => match e.as_uiaa_response() {
+            Some(info) => info.session.clone(),
+            None => return Err(e.into()),
+        },
+    };
+    let password = rpassword::read_password_from_tty(Some(prompt))?;
+    let mut auth_data = uiaa::Password::new(
+        uiaa::UserIdentifier::UserIdOrLocalpart(client.user_id().unwrap().to_string()),
+        password,
+    );
+    auth_data.session = session;
+    Ok(request(Some(uiaa::AuthData::Password(auth_data))).await?)
+}
+
+async fn change_password(client: &Client) -> Result<(), Error> {
+    let new_password = rpassword::read_password_from_tty(Some("New password: "))?;
+    if new_password.is_empty() {
+        return Err("The password must not be empty".into());
+    }
+    let repeated = rpassword::read_password_from_tty(Some("Repeat new password: "))?;
+    if new_password != repeated {
+        return Err("The passwords do not match".into());
+    }
+    let account = client.account();
+    with_password_auth(client, "Current password: ", |auth| {
+        account.change_password(&new_password, auth)
+    })
+    .await?;
+    println!("Password changed");
+    Ok(())
+}
+
+async fn list_emails(client: &Client) -> Result<(), Error> {
+    let response = client.account().get_3pids().await?;
+    for threepid in response.threepids {
+        if threepid.medium == Medium::Email {
+            println!("{}", threepid.address);
+        }
+    }
+    Ok(())
+}
+
+async fn add_email(client: &Client, email: String) -> Result<(), Error> {
+    let account = client.account();
+    let secret = ClientSecret::new();
+    let token = account
+        .request_3pid_email_token(&secret, &email, UInt::new(1).unwrap())
+        .await?;
+    println!(
+        "A confirmation mail was sent to {}. Press enter after following the link in it.",
+        email
+    );
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    with_password_auth(client, "Password: ", |auth| {
+        account.add_3pid(&secret, &token.sid, auth)
+    })
+    .await?;
+    println!("Added {}", email);
+    Ok(())
+}
+
+async fn remove_email(client: &Client, email: String) -> Result<(), Error> {
+    client
+        .account()
+        .delete_3pid(&email, Medium::Email, None)
+        .await?;
+    println!("Removed {}", email);
+    Ok(())
+}
+
+pub async fn run(client: Client, action: AccountAction) -> Result<(), Error> {
+    match action {
+        AccountAction::Password => change_password(&client).await,
+        AccountAction::Emails => list_emails(&client).await,
+        AccountAction::AddEmail { email } => add_email(&client, email).await,
+        AccountAction::RemoveEmail { email } => remove_email(&client, email).await,
+    }
+}
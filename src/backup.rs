@@ -0,0 +1,46 @@
+use matrix_sdk::encryption::recovery::RecoveryState;
+use matrix_sdk::{config::SyncSettings, Client};
+
+use crate::BackupAction;
+
+pub async fn run(client: Client, action: BackupAction) -> Result<(), Box<dyn std::error::Error>> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let encryption = client.encryption();
+
+    match action {
+        BackupAction::Enable => {
+            if encryption.backups().fetch_exists_on_server().await? {
+                return Err(
+                    "A backup already exists on the server. Use `backup restore` to connect to it."
+                        .into(),
+                );
+            }
+            let recovery_key = encryption.recovery().enable().await?;
+            println!("Backup enabled. New room keys will be uploaded automatically.");
+            println!("Your recovery key is:\n\n    {}\n", recovery_key);
+            println!(
+                "Store it in a safe place. It is required to restore the backup on new devices."
+            );
+        }
+        BackupAction::Restore => {
+            let key = rpassword::read_password_from_tty(Some("Recovery key or passphrase: "))?;
+            encryption.recovery().recover(key.trim()).await?;
+            println!("Backup restored. Keys for old messages are downloaded as they are needed.");
+        }
+        BackupAction::Status => {
+            let recovery = match encryption.recovery().state() {
+                RecoveryState::Enabled => "enabled",
+                RecoveryState::Disabled => "disabled",
+                RecoveryState::Incomplete => "incomplete (run `backup restore`)",
+                RecoveryState::Unknown => "unknown",
+            };
+            println!("Recovery: {}", recovery);
+            println!("Backup state: {:?}", encryption.backups().state());
+            println!(
+                "Backup exists on server: {}",
+                encryption.backups().fetch_exists_on_server().await?
+            );
+        }
+    }
+    Ok(())
+}
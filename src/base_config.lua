@@ -45,6 +45,35 @@ run_all = function(...)
     end;
 end
 
+-- Account profiles, e.g.:
+-- profile "work" { host = "example.org", user = "alice", session_storage = "keyring" }
+-- Each key names a config function that is called with the value (or the unpacked values if the
+-- value is a table) when the profile is selected with --profile.
+__profiles = {}
+function profile(name)
+    return function(settings)
+        __profiles[name] = settings
+    end
+end
+
+function __apply_profile(name)
+    local settings = __profiles[name]
+    if settings == nil then
+        error("No profile named '" .. name .. "' is defined")
+    end
+    for key, value in pairs(settings) do
+        local setter = _G[key]
+        if type(setter) ~= "function" then
+            error("Invalid setting '" .. key .. "' in profile '" .. name .. "'")
+        end
+        if type(value) == "table" then
+            setter(table.unpack(value))
+        else
+            setter(value)
+        end
+    end
+end
+
 function finish_auxline(run_on_content, run_on_empty)
     return function(c)
         content = c:get_auxline_content()
@@ -111,13 +140,17 @@ bind('O', 'normal', push_mode("roomfilterunread"))
 bind(':', 'normal', push_mode("command"))
 bind('v', 'normal', run_all(push_mode("visual"), select_prev_message))
 bind('L', 'normal', push_mode("limit"))
-bind('<Esc>', 'normal', run_first(clear_error_message, deselect_message, cancel_special_message))
+bind('M', 'normal', toggle_members)
+bind('<Esc>', 'normal', run_first(close_popup, clear_error_message, deselect_message, cancel_special_message))
 bind('<C-n>', 'normal', select_next_room)
 bind('<C-p>', 'normal', select_prev_room)
 bind('<C-i>', 'normal', select_room_history_next)
 bind('<C-o>', 'normal', select_room_history_prev)
 bind('<C-c>', 'normal', clear_message)
 bind('<Return>', 'normal', send_message)
+bind('<C-y>', 'normal', accept_verification)
+bind('<C-x>', 'normal', cancel_verification)
+bind('gi', 'normal', list_room_invitations)
 
 -- vim-like bindings
 bind('k', 'normal', cursor_move_up)
@@ -174,6 +207,42 @@ bind('<Left>', 'insert', cursor_move_backward('cell'))
 bind('<Right>', 'insert', cursor_move_forward('cell'))
 bind('<Home>', 'insert', cursor_move_backward('line_separator'))
 bind('<End>', 'insert', cursor_move_forward('line_separator'))
+bind('<A-Return>', 'insert', send_message)
+-- Complete room aliases and emoji shortcodes: #partial<Tab> cycles through the aliases of joined
+-- rooms and the ones mentioned in messages that start with it, :partial<Tab> through the emoji
+-- whose shortcode or name matches it.
+__word_completion = {}
+function complete_word(c)
+    local s = __word_completion
+    local word = c:cursor_yank('WORD_begin', 'cursor')
+    if s.completed == nil or word ~= s.completed then
+        local kind = word:sub(1, 1)
+        if (kind ~= '#' and kind ~= ':') or #word < 2 then
+            return c:type('\t')
+        end
+        s.index = 0
+        s.matches = {}
+        if kind == '#' then
+            for _, alias in ipairs(c:get_room_aliases()) do
+                if alias:sub(1, #word) == word then
+                    table.insert(s.matches, alias)
+                end
+            end
+        else
+            for _, e in ipairs(c:find_emoji(word)) do
+                table.insert(s.matches, e.emoji)
+            end
+        end
+    end
+    if #s.matches == 0 then
+        return res_noop()
+    end
+    s.index = s.index % #s.matches + 1
+    s.completed = s.matches[s.index]
+    c:cursor_delete('WORD_begin', 'cursor')
+    return c:type(s.completed)
+end
+bind('<Tab>', 'insert', complete_word)
 
 -- insert-line mode
 define_mode('insert-line', 'insert')
@@ -185,7 +254,21 @@ bind('<Left>', 'insert-line', cursor_move_backward('cell'))
 bind('<Right>', 'insert-line', cursor_move_forward('cell'))
 bind('<Home>', 'insert-line', cursor_move_backward('line_separator'))
 bind('<End>', 'insert-line', cursor_move_forward('line_separator'))
-bind('<Return>', 'insert-line', send_message)
+
+-- Whether <Return> sends the message in insert-line mode and <A-Return> (which many terminals can
+-- be configured to send for <S-Return>) inserts a line break, or the other way around.
+function enter_sends(send)
+    pcall(unbind, '<Return>', 'insert-line')
+    pcall(unbind, '<A-Return>', 'insert-line')
+    if send then
+        bind('<Return>', 'insert-line', send_message)
+        bind('<A-Return>', 'insert-line', insert_newline)
+    else
+        bind('<Return>', 'insert-line', insert_newline)
+        bind('<A-Return>', 'insert-line', send_message)
+    end
+end
+enter_sends(true)
 
 -- roomfilter mode
 bind('<C-n>', 'roomfilter', select_next_room)
@@ -209,6 +292,35 @@ define_mode('limit', 'command')
 on_enter('limit', run_all(switch_auxline('limit'), set_auxline_prompt('Limit: ')))
 bind('<Esc>', 'limit', run_first(clear_error_message, run_all(clear_auxline, pop_mode)))
 bind('<C-c>', 'limit', clear_auxline)
+-- Complete the sender after ~f with the ids of the room members whose id or name contains the
+-- text typed so far. Repeated presses cycle through the matches.
+__sender_completion = {}
+bind('<Tab>', 'limit', function(c)
+    local s = __sender_completion
+    local content = c:get_auxline_content()
+    if s.completed == nil or content ~= s.completed then
+        local head, partial = content:match('^(.*~f%s*)([^~|()]*)$')
+        if head == nil then
+            return res_noop()
+        end
+        partial = partial:lower()
+        s.head = head
+        s.index = 0
+        s.matches = {}
+        for _, m in ipairs(c:get_room_members()) do
+            local name = (m.name or ''):lower()
+            if m.user_id:lower():find(partial, 1, true) or name:find(partial, 1, true) then
+                table.insert(s.matches, m.user_id)
+            end
+        end
+    end
+    if #s.matches == 0 then
+        return res_noop()
+    end
+    s.index = s.index % #s.matches + 1
+    s.completed = s.head .. s.matches[s.index]
+    return c:set_auxline_content(s.completed)
+end)
 bind('<Return>', 'limit', function(c)
     content = c:get_auxline_content()
     res = res_ok()
@@ -228,6 +340,7 @@ on_enter('send-file', run_all(switch_auxline('send-file'), set_auxline_prompt('S
 bind('<Esc>', 'send-file', run_first(clear_error_message, run_all(clear_auxline, pop_mode)))
 bind('<C-c>', 'send-file', clear_auxline)
 bind('<Return>', 'send-file', finish_auxline(function(c, content) return c:send_file(content) end))
+bind('<C-o>', 'send-file', finish_auxline(function(c, content) return c:send_file_original(content) end))
 
 -- save-file mode
 define_mode('save-file', 'command')
@@ -236,12 +349,176 @@ bind('<Esc>', 'save-file', run_first(clear_error_message, run_all(clear_auxline,
 bind('<C-c>', 'save-file', clear_auxline)
 bind('<Return>', 'save-file', finish_auxline(function(c, content) return c:save_file(content) end))
 
--- react mode
-define_mode('react', 'command')
-on_enter('react', run_all(switch_auxline('react'), set_auxline_prompt('React with: ')))
-bind('<Esc>', 'react', run_first(clear_error_message, run_all(clear_auxline, pop_mode)))
-bind('<C-c>', 'react', clear_auxline)
-bind('<Return>', 'react', finish_auxline(function(c, content) return c:react(content) end))
+-- react mode: The aux line searches for an emoji by its name or shortcode. <Return> reacts with
+-- the selected match or, with <A-Return> (or if nothing matches), the text as typed.
+function emoji_picker_mode(mode, prompt, pick)
+    define_mode(mode, 'command')
+    on_enter(mode, run_all(switch_auxline('react'), set_auxline_prompt(prompt), open_emoji_picker))
+    on_leave(mode, close_emoji_picker)
+    bind('<Esc>', mode, run_first(clear_error_message, run_all(clear_auxline, pop_mode)))
+    bind('<C-c>', mode, clear_auxline)
+    bind('<Tab>', mode, select_next_emoji)
+    bind('<C-n>', mode, select_next_emoji)
+    bind('<C-p>', mode, select_prev_emoji)
+    local function finish(literal)
+        return function(c)
+            local content = c:get_auxline_content()
+            -- Before leaving the mode, which closes the picker
+            local emoji = not literal and c:get_picked_emoji() or content
+            if content ~= "" then
+                c:accept_auxline()
+            end
+            c:pop_mode()
+            if content ~= "" then
+                return pick(c, emoji)
+            end
+            return res_ok()
+        end
+    end
+    bind('<Return>', mode, finish(false))
+    bind('<A-Return>', mode, finish(true))
+end
+emoji_picker_mode('react', 'React with: ', function(c, emoji) return c:react(emoji) end)
+
+-- insert-emoji mode: Like react, but the emoji is inserted at the cursor of the composer. Both
+-- share the history, so recently used searches can be repeated with <Up>/<Down>.
+emoji_picker_mode('insert-emoji', 'Insert emoji: ', function(c, emoji) return c:type(emoji) end)
+insert_emoji = push_mode('insert-emoji')
+bind('<C-e>', 'insert', insert_emoji)
+
+-- relogin mode
+define_mode('relogin', 'command')
+on_enter('relogin', run_all(switch_auxline('relogin'), set_auxline_prompt('Password: '), hide_auxline_content))
+bind('<Esc>', 'relogin', run_first(clear_error_message, run_all(clear_auxline, pop_mode)))
+bind('<C-c>', 'relogin', clear_auxline)
+bind('<Return>', 'relogin', function(c)
+    local password = c:get_auxline_content()
+    -- Do not use accept_auxline: The password must not end up in the history.
+    c:clear_auxline()
+    c:pop_mode()
+    return c:login_with_password(password)
+end)
+relogin = push_mode('relogin')
+-- Ask for the password as soon as the server invalidates the session, and again if logging in
+-- failed, e.g., because of a wrong password.
+on_session_expired(function(c, data)
+    if data.soft_logout == 'true' then
+        local result = relogin(c)
+        if data.error then
+            c:set_auxline_prompt(data.error .. '. Password: ')
+        end
+        return result
+    end
+end)
+
+-- unlock-secrets mode
+define_mode('unlock-secrets', 'command')
+on_enter('unlock-secrets', run_all(switch_auxline('unlock-secrets'), set_auxline_prompt('Recovery key: '), hide_auxline_content))
+bind('<Esc>', 'unlock-secrets', run_first(clear_error_message, run_all(clear_auxline, pop_mode)))
+bind('<C-c>', 'unlock-secrets', clear_auxline)
+bind('<Return>', 'unlock-secrets', function(c)
+    local key = c:get_auxline_content()
+    c:clear_auxline()
+    c:pop_mode()
+    return c:recover_secrets(key)
+end)
+unlock_secrets = push_mode('unlock-secrets')
+
+-- prompt mode: Ask for input in custom actions, e.g.
+-- bind('D', 'visual', prompt('Reason: ', function(c, reason) ... end, {'spam', 'off-topic'}))
+-- The optional hints are completed (and cycled through) with <Tab>.
+define_mode('prompt', 'command')
+__prompt = {}
+bind('<Esc>', 'prompt', run_first(clear_error_message, run_all(clear_auxline, pop_mode)))
+bind('<C-c>', 'prompt', clear_auxline)
+bind('<Tab>', 'prompt', function(c)
+    local p = __prompt
+    local content = c:get_auxline_content()
+    if p.completed == nil or content ~= p.completed then
+        p.prefix = content
+        p.index = 0
+    end
+    local matches = {}
+    for _, hint in ipairs(p.hints) do
+        if hint:sub(1, #p.prefix) == p.prefix then
+            table.insert(matches, hint)
+        end
+    end
+    if #matches == 0 then
+        return res_noop()
+    end
+    p.index = p.index % #matches + 1
+    p.completed = matches[p.index]
+    return c:set_auxline_content(p.completed)
+end)
+bind('<Return>', 'prompt', function(c)
+    local answer = c:get_auxline_content()
+    local p = __prompt
+    __prompt = {}
+    if p.secret then
+        -- Secrets must not end up in the history.
+        c:clear_auxline()
+    elseif answer ~= "" then
+        c:accept_auxline()
+    end
+    c:pop_mode()
+    return p.callback(c, answer) or res_ok()
+end)
+
+function prompt(text, callback, hints)
+    return function(c)
+        __prompt = { callback = callback, hints = hints or {} }
+        local res = c:push_mode('prompt')
+        if res:is_error() then
+            return res
+        end
+        -- Each prompt has its own history
+        c:switch_auxline('prompt:' .. text)
+        return c:set_auxline_prompt(text)
+    end
+end
+
+-- Like prompt, but the input is hidden and not kept in the history, e.g., for passwords.
+function secret_prompt(text, callback)
+    return function(c)
+        __prompt = { callback = callback, hints = {}, secret = true }
+        local res = c:push_mode('prompt')
+        if res:is_error() then
+            return res
+        end
+        c:switch_auxline('secret-prompt:' .. text)
+        c:hide_auxline_content()
+        return c:set_auxline_prompt(text)
+    end
+end
+
+-- devices mode
+define_mode('devices', 'normal')
+bind('j', 'devices', select_next_device)
+bind('k', 'devices', select_prev_device)
+bind('r', 'devices', prompt('New device name: ', function(c, name)
+    if name ~= "" then
+        return c:rename_device(name)
+    end
+end))
+bind('d', 'devices', secret_prompt('Password (empty if not required): ', function(c, password)
+    return c:delete_device(password)
+end))
+bind('v', 'devices', function(c)
+    return c:verify_device(c:get_selected_device())
+end)
+bind('<Esc>', 'devices', run_all(close_device_manager, pop_mode))
+manage_devices = run_all(open_device_manager, push_mode('devices'))
+
+-- mentions mode
+define_mode('mentions', 'normal')
+bind('j', 'mentions', select_next_mention)
+bind('k', 'mentions', select_prev_mention)
+bind('<Return>', 'mentions', run_all(jump_to_mention, close_mentions, switch_mode('visual')))
+bind('d', 'mentions', mark_mention_handled)
+bind('D', 'mentions', clear_mentions)
+bind('<Esc>', 'mentions', run_all(close_mentions, pop_mode))
+show_mentions = run_all(open_mentions, push_mode('mentions'))
 
 -- visual mode
 define_mode('visual', 'normal')
@@ -250,18 +527,23 @@ bind('j', 'visual', select_next_message)
 bind('f', 'visual', follow_reply)
 bind('r', 'visual', run_all(start_reply, deselect_message, switch_mode("insert-line")))
 bind('c', 'visual', run_all(start_edit, deselect_message, switch_mode("insert-line")))
+bind('>', 'visual', run_all(quote_reply, deselect_message, switch_mode("insert-line")))
 bind('R', 'visual', push_mode('react'))
 bind('L', 'visual', push_mode("limit"))
 bind('s', 'visual', push_mode('save-file'))
 bind(':', 'visual', push_mode('command'))
-bind('<Esc>', 'visual', run_all(deselect_message, pop_mode))
+bind('<Esc>', 'visual', run_first(close_popup, run_all(deselect_message, pop_mode)))
 bind('<Return>', 'visual', open_selected_message)
+bind('V', 'visual', preview_selected_message)
+bind('+', 'visual', show_reactions)
+bind('G', 'visual', run_all(scroll_to_end, pop_mode))
 bind('y', 'visual', function(c)
     content = c:get_message_content()
     c:set_clipboard(content)
     c:deselect_message()
     return c:pop_mode()
 end)
+bind('Y', 'visual', run_all(yank_code_block(), deselect_message, pop_mode))
 
 e = clear_timeline_cache
 q = quit
@@ -0,0 +1,137 @@
+/// The contents of all code blocks in a message, in order. Blocks are taken from the formatted
+/// (HTML) body if there is one, and from markdown fences in the plain body otherwise.
+pub fn code_blocks(body: &str, formatted: Option<&str>) -> Vec<String> {
+    match formatted {
+        Some(html) => html_code_blocks(html),
+        None => fenced_code_blocks(body),
+    }
+}
+
+fn fenced_code_blocks(body: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<(&str, Vec<&str>)> = None;
+    for line in body.lines() {
+        let trimmed = line.trim_start();
+        match &mut current {
+            Some((fence, lines)) => {
+                if trimmed.trim_end() == *fence {
+                    blocks.push(lines.join("\n"));
+                    current = None;
+                } else {
+                    lines.push(line);
+                }
+            }
+            None => {
+                for fence in ["```", "~~~"] {
+                    if trimmed.starts_with(fence) {
+                        current = Some((fence, Vec::new()));
+                    }
+                }
+            }
+        }
+    }
+    // An unterminated block extends to the end of the message.
+    if let Some((_, lines)) = current {
+        blocks.push(lines.join("\n"));
+    }
+    blocks
+}
+
+fn html_code_blocks(html: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("<pre") {
+        let Some(content_start) = rest[start..].find('>').map(|i| start + i + 1) else {
+            break;
+        };
+        let content_end = rest[content_start..]
+            .find("</pre>")
+            .map(|i| content_start + i)
+            .unwrap_or(rest.len());
+        let block = unescape_html(&strip_tags(&rest[content_start..content_end]));
+        blocks.push(block.strip_suffix('\n').unwrap_or(&block).to_owned());
+        rest = &rest[content_end..];
+    }
+    blocks
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+fn unescape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let c = entity.and_then(|(name, _)| match name {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => {
+                let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => name.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+            }
+        });
+        match (c, entity) {
+            (Some(c), Some((_, end))) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fenced_code_blocks() {
+        let body =
+            "Try this:\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\nor\n~~~\nls -l\n~~~";
+        assert_eq!(
+            code_blocks(body, None),
+            vec!["fn main() {\n    println!(\"hi\");\n}", "ls -l"]
+        );
+        assert_eq!(
+            code_blocks("```\nunterminated\n", None),
+            vec!["unterminated"]
+        );
+        assert!(code_blocks("no code here", None).is_empty());
+    }
+
+    #[test]
+    fn test_html_code_blocks() {
+        let html = "<p>Try this:</p>\n<pre><code class=\"language-rust\">if a &lt; b &amp;&amp; c {\n}\n</code></pre>\n<pre><code>x &#x3E; 1</code></pre>";
+        assert_eq!(
+            code_blocks("ignored", Some(html)),
+            vec!["if a < b && c {\n}", "x > 1"]
+        );
+        assert_eq!(unescape_html("a & b &unknown; &#65;"), "a & b &unknown; A");
+    }
+}
@@ -1,7 +1,11 @@
+use matrix_sdk::ruma::api::client::filter::{FilterDefinition, LazyLoadOptions};
+use matrix_sdk::ruma::api::client::receipt::create_receipt::v3::ReceiptType;
+use matrix_sdk::ruma::events::receipt::ReceiptThread;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId, UInt};
 use matrix_sdk::OwnedServerName;
 use rlua::{Lua, RegistryKey, Value};
 use sequence_trie::SequenceTrie;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -62,7 +66,7 @@ impl ModeSet {
         f: rlua::Function<'lua>,
     ) -> rlua::Result<()> {
         let k = lua.create_registry_value(f)?;
-        self.on_enter.insert(mode.to_string(), k);
+        self.on_leave.insert(mode.to_string(), k);
         Ok(())
     }
     pub fn get_on_leave(&self, mode: &Mode) -> Option<Action> {
@@ -70,7 +74,46 @@ impl ModeSet {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Hook {
+    Startup,
+    Sync,
+    Message,
+    RoomChange,
+    SessionExpired,
+    Notification,
+}
+
+/// Lua functions registered by the config to be called on events of the client.
+pub struct Hooks(HashMap<Hook, Vec<RegistryKey>>);
+
+impl Hooks {
+    fn add<'lua>(
+        &mut self,
+        hook: Hook,
+        lua: &rlua::Context<'lua>,
+        f: rlua::Function<'lua>,
+    ) -> rlua::Result<()> {
+        let k = lua.create_registry_value(f)?;
+        self.0.entry(hook).or_default().push(k);
+        Ok(())
+    }
+    pub fn get(&self, hook: Hook) -> impl Iterator<Item = Action<'_>> + '_ {
+        self.0.get(&hook).into_iter().flatten().map(Action)
+    }
+    pub fn is_registered(&self, hook: Hook) -> bool {
+        self.0.get(&hook).map(|h| !h.is_empty()).unwrap_or(false)
+    }
+}
+
 const DEFAULT_OPEN_PROG: &str = "xdg-open";
+const DEFAULT_TIMELINE_CACHE_ROOM_LIMIT: usize = 2000;
+const DEFAULT_TIMELINE_CACHE_TOTAL_LIMIT: usize = 20000;
+const DEFAULT_MEDIA_CACHE_SIZE: u64 = 500 * 1024 * 1024;
+const DEFAULT_UPLOAD_IMAGE_QUALITY: u8 = 85;
+const DEFAULT_COMPOSER_MAX_ROWS: usize = 5;
+const DEFAULT_ROOM_PANE_FRACTION: f64 = 0.25;
+const DEFAULT_MARK_READ_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
 
 use unsegen::input::Key;
 
@@ -218,6 +261,14 @@ fn parse_keys(s: &str) -> rlua::Result<Vec<Key>> {
                 chars = rest;
                 Key::Delete
             }
+            &['<', 'A' | 'M', '-', 'R', 'e', 't', 'u', 'r', 'n', '>', ref rest @ ..] => {
+                chars = rest;
+                Key::Alt('\r')
+            }
+            &['<', 'A' | 'M', '-', c @ '!'..='~', '>', ref rest @ ..] => {
+                chars = rest;
+                Key::Alt(c)
+            }
             &['<', 'C' | 'c', '-', c @ '!'..='~', '>', ref rest @ ..] => {
                 chars = rest;
                 Key::Ctrl(c)
@@ -263,6 +314,7 @@ impl std::fmt::Display for Keys {
                 Key::Char('\n') => write!(f, "<Return>"),
                 Key::Char('\t') => write!(f, "<Tab>"),
                 Key::Char(c) => write!(f, "{}", c),
+                Key::Alt('\r') => write!(f, "<A-Return>"),
                 Key::Alt(c) => write!(f, "<A-{}>", c),
                 Key::Ctrl(c) => write!(f, "<C-{}>", c),
                 Key::Null => write!(f, "<Null>"),
@@ -338,6 +390,367 @@ impl rlua::FromLua<'_> for NotificationStyle {
     }
 }
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum NotificationLevel {
+    All,
+    MentionsOnly,
+    Muted,
+}
+
+impl std::default::Default for NotificationLevel {
+    fn default() -> Self {
+        NotificationLevel::All
+    }
+}
+
+impl FromStr for NotificationLevel {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "all" => NotificationLevel::All,
+            "mentions" => NotificationLevel::MentionsOnly,
+            "muted" => NotificationLevel::Muted,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl NotificationLevel {
+    /// The name as used in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::MentionsOnly => "mentions",
+            NotificationLevel::Muted => "muted",
+        }
+    }
+}
+
+impl rlua::FromLua<'_> for NotificationLevel {
+    fn from_lua(lua_value: rlua::Value<'_>, _lua: rlua::Context<'_>) -> rlua::Result<Self> {
+        if let rlua::Value::String(s) = lua_value {
+            let s = s.to_str()?;
+            NotificationLevel::from_str(s).map_err(|_| {
+                rlua::Error::RuntimeError(format!("'{}' is not a valid notification level", s))
+            })
+        } else {
+            Err(rlua::Error::RuntimeError(format!(
+                "'{:?}' is not a valid notification level",
+                lua_value
+            )))
+        }
+    }
+}
+
+/// The kind of read receipts that are sent when reading messages.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ReadReceipts {
+    Private,
+    Public,
+    Both,
+    Disabled,
+}
+
+impl std::default::Default for ReadReceipts {
+    fn default() -> Self {
+        ReadReceipts::Private
+    }
+}
+
+impl ReadReceipts {
+    pub fn receipt_types(self) -> Vec<ReceiptType> {
+        match self {
+            ReadReceipts::Private => vec![ReceiptType::ReadPrivate],
+            ReadReceipts::Public => vec![ReceiptType::Read],
+            ReadReceipts::Both => vec![ReceiptType::ReadPrivate, ReceiptType::Read],
+            ReadReceipts::Disabled => Vec::new(),
+        }
+    }
+}
+
+impl rlua::FromLua<'_> for ReadReceipts {
+    fn from_lua(lua_value: rlua::Value<'_>, _lua: rlua::Context<'_>) -> rlua::Result<Self> {
+        if let rlua::Value::String(s) = lua_value {
+            match s.to_str()? {
+                "private" => Ok(ReadReceipts::Private),
+                "public" => Ok(ReadReceipts::Public),
+                "both" => Ok(ReadReceipts::Both),
+                "none" => Ok(ReadReceipts::Disabled),
+                s => Err(rlua::Error::RuntimeError(format!(
+                    "'{}' is not a valid read receipt setting (private, public, both or none)",
+                    s
+                ))),
+            }
+        } else {
+            Err(rlua::Error::RuntimeError(format!(
+                "'{:?}' is not a valid read receipt setting",
+                lua_value
+            )))
+        }
+    }
+}
+
+/// The thread that read receipts apply to. Since threads are not displayed separately, receipts
+/// are either sent for the main timeline or unthreaded, i.e., for the room as a whole.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ReadReceiptThread {
+    Main,
+    Unthreaded,
+}
+
+impl std::default::Default for ReadReceiptThread {
+    fn default() -> Self {
+        ReadReceiptThread::Main
+    }
+}
+
+impl ReadReceiptThread {
+    pub fn receipt_thread(self) -> ReceiptThread {
+        match self {
+            ReadReceiptThread::Main => ReceiptThread::Main,
+            ReadReceiptThread::Unthreaded => ReceiptThread::Unthreaded,
+        }
+    }
+}
+
+impl rlua::FromLua<'_> for ReadReceiptThread {
+    fn from_lua(lua_value: rlua::Value<'_>, _lua: rlua::Context<'_>) -> rlua::Result<Self> {
+        if let rlua::Value::String(s) = lua_value {
+            match s.to_str()? {
+                "main" => Ok(ReadReceiptThread::Main),
+                "unthreaded" => Ok(ReadReceiptThread::Unthreaded),
+                s => Err(rlua::Error::RuntimeError(format!(
+                    "'{}' is not a valid read receipt thread (main or unthreaded)",
+                    s
+                ))),
+            }
+        } else {
+            Err(rlua::Error::RuntimeError(format!(
+                "'{:?}' is not a valid read receipt thread",
+                lua_value
+            )))
+        }
+    }
+}
+
+/// Which notifications ring the terminal bell.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Bell {
+    All,
+    Highlights,
+    Disabled,
+}
+
+impl std::default::Default for Bell {
+    fn default() -> Self {
+        Bell::All
+    }
+}
+
+impl Bell {
+    pub fn rings_for(self, is_highlight: bool) -> bool {
+        match self {
+            Bell::All => true,
+            Bell::Highlights => is_highlight,
+            Bell::Disabled => false,
+        }
+    }
+}
+
+impl rlua::FromLua<'_> for Bell {
+    fn from_lua(lua_value: rlua::Value<'_>, _lua: rlua::Context<'_>) -> rlua::Result<Self> {
+        if let rlua::Value::String(s) = lua_value {
+            match s.to_str()? {
+                "all" => Ok(Bell::All),
+                "highlights" => Ok(Bell::Highlights),
+                "none" => Ok(Bell::Disabled),
+                s => Err(rlua::Error::RuntimeError(format!(
+                    "'{}' is not a valid bell setting (all, highlights or none)",
+                    s
+                ))),
+            }
+        } else {
+            Err(rlua::Error::RuntimeError(format!(
+                "'{:?}' is not a valid bell setting",
+                lua_value
+            )))
+        }
+    }
+}
+
+/// Events that are left out of the timeline.
+#[derive(Clone, Default)]
+pub struct HiddenEvents {
+    event_types: HashSet<String>,
+    membership_changes: HashSet<String>,
+}
+
+impl HiddenEvents {
+    pub fn hides(&self, event: &crate::timeline::Event) -> bool {
+        self.event_types.contains(&event.event_type().to_string())
+            || crate::timeline::membership_change(event)
+                .map_or(false, |c| self.membership_changes.contains(c))
+    }
+}
+
+/// Which rooms can be found by the display names of their members in the room filter.
+#[derive(Copy, Clone, PartialEq)]
+pub enum RoomFilterMembers {
+    All,
+    Direct,
+    Disabled,
+}
+
+impl std::default::Default for RoomFilterMembers {
+    fn default() -> Self {
+        RoomFilterMembers::Disabled
+    }
+}
+
+impl RoomFilterMembers {
+    pub fn applies_to(self, is_direct: bool) -> bool {
+        match self {
+            RoomFilterMembers::All => true,
+            RoomFilterMembers::Direct => is_direct,
+            RoomFilterMembers::Disabled => false,
+        }
+    }
+}
+
+impl rlua::FromLua<'_> for RoomFilterMembers {
+    fn from_lua(lua_value: rlua::Value<'_>, _lua: rlua::Context<'_>) -> rlua::Result<Self> {
+        if let rlua::Value::String(s) = lua_value {
+            match s.to_str()? {
+                "all" => Ok(RoomFilterMembers::All),
+                "direct" => Ok(RoomFilterMembers::Direct),
+                "none" => Ok(RoomFilterMembers::Disabled),
+                s => Err(rlua::Error::RuntimeError(format!(
+                    "'{}' is not a valid room filter member setting (all, direct or none)",
+                    s
+                ))),
+            }
+        } else {
+            Err(rlua::Error::RuntimeError(format!(
+                "'{:?}' is not a valid room filter member setting",
+                lua_value
+            )))
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SessionStorage {
+    Plain,
+    Passphrase,
+    Keyring,
+}
+
+impl std::default::Default for SessionStorage {
+    fn default() -> Self {
+        SessionStorage::Plain
+    }
+}
+
+impl FromStr for SessionStorage {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "plain" => SessionStorage::Plain,
+            "passphrase" => SessionStorage::Passphrase,
+            "keyring" => SessionStorage::Keyring,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl rlua::FromLua<'_> for SessionStorage {
+    fn from_lua(lua_value: rlua::Value<'_>, _lua: rlua::Context<'_>) -> rlua::Result<Self> {
+        if let rlua::Value::String(s) = lua_value {
+            let s = s.to_str()?;
+            SessionStorage::from_str(s).map_err(|_| {
+                rlua::Error::RuntimeError(format!("'{}' is not a valid session storage", s))
+            })
+        } else {
+            Err(rlua::Error::RuntimeError(format!(
+                "'{:?}' is not a valid session storage",
+                lua_value
+            )))
+        }
+    }
+}
+
+/// The room that is selected when the tui starts.
+#[derive(Clone, PartialEq)]
+pub enum InitialRoom {
+    First, // In the order of the room list
+    Last,  // The room that was selected when sparse was last closed
+    Recent,
+    Id(OwnedRoomId),
+}
+
+impl std::default::Default for InitialRoom {
+    fn default() -> Self {
+        InitialRoom::First
+    }
+}
+
+impl FromStr for InitialRoom {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "first" => InitialRoom::First,
+            "last" => InitialRoom::Last,
+            "recent" => InitialRoom::Recent,
+            o => InitialRoom::Id(RoomId::parse(o).map_err(|_| ())?),
+        })
+    }
+}
+
+impl rlua::FromLua<'_> for InitialRoom {
+    fn from_lua(lua_value: rlua::Value<'_>, _lua: rlua::Context<'_>) -> rlua::Result<Self> {
+        if let rlua::Value::String(s) = lua_value {
+            let s = s.to_str()?;
+            InitialRoom::from_str(s).map_err(|_| {
+                rlua::Error::RuntimeError(format!(
+                    "'{}' is not a valid initial room (first, last, recent or a room id)",
+                    s
+                ))
+            })
+        } else {
+            Err(rlua::Error::RuntimeError(format!(
+                "'{:?}' is not a valid initial room",
+                lua_value
+            )))
+        }
+    }
+}
+
+/// Check that `url` can be used as the base url of the client api, i.e., includes the scheme.
+pub fn parse_homeserver_url(url: &str) -> Result<String, String> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        Ok(url.trim_end_matches('/').to_owned())
+    } else {
+        Err(format!(
+            "Invalid homeserver url '{}', expected e.g. https://matrix.example.org:8448",
+            url
+        ))
+    }
+}
+
+/// Check that `level` is one of error, warn, info, debug, trace or off.
+fn parse_log_level(level: &str) -> rlua::Result<String> {
+    match level.parse::<tracing_subscriber::filter::LevelFilter>() {
+        Ok(_) => Ok(level.to_lowercase()),
+        Err(_) => Err(rlua::Error::RuntimeError(format!(
+            "'{}' is not a valid log level",
+            level
+        ))),
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub host: OwnedServerName,
@@ -345,8 +758,60 @@ pub struct Config {
     pub notification_style: NotificationStyle,
     pub file_open_program: String,
     pub url_open_program: String,
+    pub timeline_cache_room_limit: usize,
+    pub timeline_cache_total_limit: usize,
+    pub notification_click_focus: bool,
+    pub notification_raise_command: Option<String>,
+    pub room_notification_levels: HashMap<String, NotificationLevel>,
+    pub notification_command: Option<String>,
+    pub notification_body: String,
+    pub auto_away_timeout: Option<std::time::Duration>,
+    pub sync_lazy_load_members: bool,
+    pub sync_timeline_limit: Option<u32>,
+    pub sync_excluded_event_types: Vec<String>,
+    pub session_storage: SessionStorage,
+    pub store_encryption: SessionStorage, // Where the passphrase of the sqlite store comes from
+    pub profile: Option<String>,
+    pub profile_names: Vec<String>, // All profiles defined in the config
+    pub homeserver_url: Option<String>,
+    pub data_dir_override: Option<PathBuf>,
+    pub proxy: Option<String>,
+    pub tls_ca_certificates: Vec<PathBuf>,
+    pub status_file: Option<PathBuf>,
+    pub tls_verify: bool,
+    pub auto_accept_own_verifications: bool,
+    pub initial_room: InitialRoom,
+    pub start_mode: String,
+    pub restore_room_history: bool,
+    pub media_cache_size: u64, // In bytes
+    pub upload_image_max_dimension: Option<u32>,
+    pub upload_image_quality: u8,
+    pub strip_exif: bool,
+    pub room_strip_exif: HashMap<String, bool>,
+    pub image_placeholders: bool,
+    pub auto_follow: bool,
+    pub read_receipts: ReadReceipts,
+    pub read_receipt_thread: ReadReceiptThread,
+    pub bell: Bell,
+    pub visual_bell: bool,
+    pub urgency_hint: bool,
+    pub urgency_hint_command: Option<String>,
+    pub mark_read_delay: std::time::Duration,
+    pub composer_max_rows: usize,
+    pub room_filter_topic: bool,
+    pub room_filter_members: RoomFilterMembers,
+    pub hidden_events: Arc<HiddenEvents>,
+    pub collapse_membership_events: bool,
+    pub open_all_links: bool,
+    pub room_pane_fraction: f64, // Share of the width that the room list takes up
+    pub log_filter: Option<String>, // In the syntax of RUST_LOG
+    pub log_retention: crate::log::RetentionPolicy,
+    pub message_formatter: Option<Arc<RegistryKey>>,
+    pub room_list_filter: Option<Arc<RegistryKey>>,
+    pub room_list_sort: Option<Arc<RegistryKey>>,
     pub keymaps: Arc<KeyMaps>,
     pub modes: Arc<ModeSet>,
+    pub hooks: Arc<Hooks>,
 }
 
 impl Config {
@@ -354,16 +819,70 @@ impl Config {
         format!("@{}:{}", self.user, self.host.host())
     }
 
+    /// The directory with the data dirs of all accounts and profiles.
+    pub fn data_root(&self) -> PathBuf {
+        dirs::data_local_dir().unwrap().join(crate::APP_NAME)
+    }
+
     pub fn data_dir(&self) -> PathBuf {
-        dirs::data_local_dir()
-            .unwrap()
-            .join(crate::APP_NAME)
-            .join(self.user_id())
+        if let Some(dir) = &self.data_dir_override {
+            dir.clone()
+        } else if let Some(profile) = &self.profile {
+            self.data_root().join("profiles").join(profile)
+        } else {
+            self.data_root().join(self.user_id())
+        }
     }
 
     pub fn session_file_path(&self) -> PathBuf {
         self.data_dir().join("session")
     }
+
+    pub fn encrypted_session_file_path(&self) -> PathBuf {
+        self.data_dir().join("session.enc")
+    }
+
+    pub fn control_socket_path(&self) -> PathBuf {
+        let name = self.profile.clone().unwrap_or_else(|| self.user_id());
+        // The temporary directory is shared by all users, so each one gets their own directory.
+        let dir = match dirs::runtime_dir() {
+            Some(dir) => dir.join(crate::APP_NAME),
+            None => {
+                std::env::temp_dir().join(format!("{}-{}", crate::APP_NAME, nix::unistd::getuid()))
+            }
+        };
+        dir.join(format!("{}.sock", name))
+    }
+
+    pub fn sync_filter(&self) -> FilterDefinition {
+        let mut filter = FilterDefinition::default();
+        if self.sync_lazy_load_members {
+            filter.room.state.lazy_load_options = LazyLoadOptions::Enabled {
+                include_redundant_members: false,
+            };
+            filter.room.timeline.lazy_load_options = LazyLoadOptions::Enabled {
+                include_redundant_members: false,
+            };
+        }
+        filter.room.timeline.limit = self.sync_timeline_limit.map(UInt::from);
+        filter.room.timeline.not_types = self.sync_excluded_event_types.clone();
+        filter
+    }
+
+    pub fn room_notification_level(&self, room: &matrix_sdk::ruma::RoomId) -> NotificationLevel {
+        self.room_notification_levels
+            .get(room.as_str())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether metadata should be removed from images sent to the room.
+    pub fn strip_exif_for(&self, room: &matrix_sdk::ruma::RoomId) -> bool {
+        self.room_strip_exif
+            .get(room.as_str())
+            .cloned()
+            .unwrap_or(self.strip_exif)
+    }
 }
 pub struct KeyMaps(HashMap<Mode, KeyMap>);
 
@@ -384,7 +903,59 @@ pub struct ConfigBuilder {
     notification_style: NotificationStyle,
     file_open_program: String,
     url_open_program: String,
+    timeline_cache_room_limit: usize,
+    timeline_cache_total_limit: usize,
+    notification_click_focus: bool,
+    notification_raise_command: Option<String>,
+    room_notification_levels: HashMap<String, NotificationLevel>,
+    notification_command: Option<String>,
+    notification_body: String,
+    auto_away_timeout: Option<std::time::Duration>,
+    sync_lazy_load_members: bool,
+    sync_timeline_limit: Option<u32>,
+    sync_excluded_event_types: Vec<String>,
+    session_storage: SessionStorage,
+    store_encryption: SessionStorage,
+    profile: Option<String>,
+    homeserver_url: Option<String>,
+    data_dir_override: Option<PathBuf>,
+    proxy: Option<String>,
+    tls_ca_certificates: Vec<PathBuf>,
+    status_file: Option<PathBuf>,
+    tls_verify: bool,
+    auto_accept_own_verifications: bool,
+    initial_room: InitialRoom,
+    start_mode: String,
+    restore_room_history: bool,
+    media_cache_size: u64,
+    upload_image_max_dimension: Option<u32>,
+    upload_image_quality: u8,
+    strip_exif: bool,
+    room_strip_exif: HashMap<String, bool>,
+    image_placeholders: bool,
+    auto_follow: bool,
+    read_receipts: ReadReceipts,
+    read_receipt_thread: ReadReceiptThread,
+    bell: Bell,
+    visual_bell: bool,
+    urgency_hint: bool,
+    urgency_hint_command: Option<String>,
+    mark_read_delay: std::time::Duration,
+    composer_max_rows: usize,
+    room_filter_topic: bool,
+    room_filter_members: RoomFilterMembers,
+    hidden_events: HiddenEvents,
+    collapse_membership_events: bool,
+    open_all_links: bool,
+    room_pane_fraction: f64,
+    log_level: Option<String>,
+    log_module_levels: Vec<String>,
+    log_retention: crate::log::RetentionPolicy,
+    message_formatter: Option<RegistryKey>,
+    room_list_filter: Option<RegistryKey>,
+    room_list_sort: Option<RegistryKey>,
     modes: ModeSet,
+    hooks: Hooks,
 }
 
 fn add_global_fun(context: &rlua::Context, name: &str, nargs: usize) -> rlua::Result<()> {
@@ -413,10 +984,88 @@ impl ConfigBuilder {
             notification_style: NotificationStyle::default(),
             file_open_program: DEFAULT_OPEN_PROG.to_owned(),
             url_open_program: DEFAULT_OPEN_PROG.to_owned(),
+            timeline_cache_room_limit: DEFAULT_TIMELINE_CACHE_ROOM_LIMIT,
+            timeline_cache_total_limit: DEFAULT_TIMELINE_CACHE_TOTAL_LIMIT,
+            notification_click_focus: false,
+            notification_raise_command: None,
+            room_notification_levels: HashMap::new(),
+            notification_command: None,
+            notification_body: "{body}".to_owned(),
+            auto_away_timeout: None,
+            sync_lazy_load_members: false, // Member lists and display names need all members
+            sync_timeline_limit: None,
+            sync_excluded_event_types: Vec::new(),
+            session_storage: SessionStorage::default(),
+            store_encryption: SessionStorage::default(),
+            profile: None,
+            homeserver_url: None,
+            data_dir_override: None,
+            proxy: None,
+            tls_ca_certificates: Vec::new(),
+            status_file: None,
+            tls_verify: true,
+            auto_accept_own_verifications: false,
+            initial_room: InitialRoom::default(),
+            start_mode: "normal".to_owned(),
+            restore_room_history: false,
+            media_cache_size: DEFAULT_MEDIA_CACHE_SIZE,
+            upload_image_max_dimension: None,
+            upload_image_quality: DEFAULT_UPLOAD_IMAGE_QUALITY,
+            strip_exif: false,
+            room_strip_exif: HashMap::new(),
+            image_placeholders: false,
+            auto_follow: true,
+            read_receipts: ReadReceipts::default(),
+            read_receipt_thread: ReadReceiptThread::default(),
+            bell: Bell::default(),
+            visual_bell: false,
+            urgency_hint: false,
+            urgency_hint_command: None,
+            mark_read_delay: DEFAULT_MARK_READ_DELAY,
+            composer_max_rows: DEFAULT_COMPOSER_MAX_ROWS,
+            room_filter_topic: false,
+            room_filter_members: RoomFilterMembers::default(),
+            hidden_events: HiddenEvents::default(),
+            collapse_membership_events: false,
+            open_all_links: true,
+            room_pane_fraction: DEFAULT_ROOM_PANE_FRACTION,
+            log_level: None,
+            log_module_levels: Vec::new(),
+            log_retention: crate::log::RetentionPolicy::default(),
+            message_formatter: None,
+            room_list_filter: None,
+            room_list_sort: None,
             modes: ModeSet::new(),
+            hooks: Hooks(HashMap::new()),
         }
     }
     pub fn finalize(self) -> Result<(Config, CommandEnvironment), String> {
+        if self.modes.get(&self.start_mode).is_none() {
+            return Err(format!("Start mode '{}' is not defined.", self.start_mode));
+        }
+        // The default level comes first so that the levels of single modules override it.
+        let log_filter = if self.log_level.is_none() && self.log_module_levels.is_empty() {
+            None
+        } else {
+            let directives: Vec<_> = self
+                .log_level
+                .iter()
+                .chain(&self.log_module_levels)
+                .cloned()
+                .collect();
+            Some(directives.join(","))
+        };
+        let profile_names = self
+            .lua
+            .context(|lua_ctx| {
+                lua_ctx
+                    .globals()
+                    .get::<_, rlua::Table>("__profiles")?
+                    .pairs::<String, rlua::Value>()
+                    .map(|pair| pair.map(|(name, _)| name))
+                    .collect::<rlua::Result<Vec<_>>>()
+            })
+            .map_err(|e| format!("Invalid profiles: {}", e))?;
         Ok((
             Config {
                 host: self
@@ -427,8 +1076,60 @@ impl ConfigBuilder {
                 notification_style: self.notification_style,
                 file_open_program: self.file_open_program,
                 url_open_program: self.url_open_program,
+                timeline_cache_room_limit: self.timeline_cache_room_limit,
+                timeline_cache_total_limit: self.timeline_cache_total_limit,
+                notification_click_focus: self.notification_click_focus,
+                notification_raise_command: self.notification_raise_command,
+                room_notification_levels: self.room_notification_levels,
+                notification_command: self.notification_command,
+                notification_body: self.notification_body,
+                auto_away_timeout: self.auto_away_timeout,
+                sync_lazy_load_members: self.sync_lazy_load_members,
+                sync_timeline_limit: self.sync_timeline_limit,
+                sync_excluded_event_types: self.sync_excluded_event_types,
+                session_storage: self.session_storage,
+                store_encryption: self.store_encryption,
+                profile: self.profile,
+                profile_names,
+                homeserver_url: self.homeserver_url,
+                data_dir_override: self.data_dir_override,
+                proxy: self.proxy,
+                tls_ca_certificates: self.tls_ca_certificates,
+                status_file: self.status_file,
+                tls_verify: self.tls_verify,
+                auto_accept_own_verifications: self.auto_accept_own_verifications,
+                initial_room: self.initial_room,
+                start_mode: self.start_mode,
+                restore_room_history: self.restore_room_history,
+                media_cache_size: self.media_cache_size,
+                upload_image_max_dimension: self.upload_image_max_dimension,
+                upload_image_quality: self.upload_image_quality,
+                strip_exif: self.strip_exif,
+                room_strip_exif: self.room_strip_exif,
+                image_placeholders: self.image_placeholders,
+                auto_follow: self.auto_follow,
+                read_receipts: self.read_receipts,
+                read_receipt_thread: self.read_receipt_thread,
+                bell: self.bell,
+                visual_bell: self.visual_bell,
+                urgency_hint: self.urgency_hint,
+                urgency_hint_command: self.urgency_hint_command,
+                mark_read_delay: self.mark_read_delay,
+                composer_max_rows: self.composer_max_rows,
+                room_filter_topic: self.room_filter_topic,
+                room_filter_members: self.room_filter_members,
+                hidden_events: Arc::new(self.hidden_events),
+                collapse_membership_events: self.collapse_membership_events,
+                open_all_links: self.open_all_links,
+                room_pane_fraction: self.room_pane_fraction,
+                log_filter,
+                log_retention: self.log_retention,
+                message_formatter: self.message_formatter.map(Arc::new),
+                room_list_filter: self.room_list_filter.map(Arc::new),
+                room_list_sort: self.room_list_sort.map(Arc::new),
                 keymaps: Arc::new(KeyMaps(self.keymaps)),
                 modes: Arc::new(self.modes),
+                hooks: Arc::new(self.hooks),
             },
             CommandEnvironment::new(self.lua),
         ))
@@ -439,15 +1140,113 @@ impl ConfigBuilder {
     pub fn set_user(&mut self, user: String) {
         self.user = Some(user);
     }
+    pub fn set_homeserver_url(&mut self, url: String) {
+        self.homeserver_url = Some(url);
+    }
+    pub fn set_data_dir(&mut self, dir: PathBuf) {
+        self.data_dir_override = Some(dir);
+    }
+    /// Make `require` find modules in `dir` and its `modules` subdirectory and allow reading files
+    /// relative to it with `read_config_file`.
+    pub fn set_config_dir(&mut self, dir: &std::path::Path) -> rlua::Result<()> {
+        let dir = dir.to_owned();
+        self.lua.context(|lua_ctx| {
+            let globals = lua_ctx.globals();
+            let package: rlua::Table = globals.get("package")?;
+            let path: String = package.get("path")?;
+            let d = dir.to_string_lossy();
+            package.set(
+                "path",
+                format!("{d}/?.lua;{d}/modules/?.lua;{}", path, d = d),
+            )?;
+            globals.set("config_dir", d.as_ref())?;
+            globals.set(
+                "read_config_file",
+                lua_ctx.create_function(move |_lua_ctx, name: String| {
+                    std::fs::read_to_string(dir.join(&name)).map_err(|e| {
+                        rlua::Error::RuntimeError(format!("Cannot read {}: {}", name, e))
+                    })
+                })?,
+            )?;
+            Ok(())
+        })
+    }
+    pub fn select_profile(&mut self, name: String) -> rlua::Result<()> {
+        self.configure_with(|lua_ctx| {
+            lua_ctx
+                .globals()
+                .get::<_, rlua::Function>("__apply_profile")?
+                .call::<_, ()>(name.as_str())
+        })?;
+        self.profile = Some(name);
+        Ok(())
+    }
     pub fn configure(&mut self, source: &str) -> rlua::Result<()> {
+        self.configure_with(|lua_ctx| lua_ctx.load(source).eval::<()>())
+    }
+    /// Runs `run` with the config setters and actions available in the lua context.
+    fn configure_with(
+        &mut self,
+        run: impl for<'lua> FnOnce(rlua::Context<'lua>) -> rlua::Result<()>,
+    ) -> rlua::Result<()> {
         //TODO maybe we can avoid these bindings with disjoint struct capturing in 2021 edition?
         let keymaps = std::cell::RefCell::new(&mut self.keymaps);
         let modes = std::cell::RefCell::new(&mut self.modes);
+        let hooks = std::cell::RefCell::new(&mut self.hooks);
         let host = &mut self.host;
         let user = &mut self.user;
         let notification_style = &mut self.notification_style;
         let file_open_program = &mut self.file_open_program;
         let url_open_program = &mut self.url_open_program;
+        let timeline_cache_room_limit = &mut self.timeline_cache_room_limit;
+        let timeline_cache_total_limit = &mut self.timeline_cache_total_limit;
+        let notification_click_focus = &mut self.notification_click_focus;
+        let notification_raise_command = &mut self.notification_raise_command;
+        let room_notification_levels = &mut self.room_notification_levels;
+        let notification_command = &mut self.notification_command;
+        let notification_body = &mut self.notification_body;
+        let auto_away_timeout = &mut self.auto_away_timeout;
+        let sync_lazy_load_members = &mut self.sync_lazy_load_members;
+        let sync_timeline_limit = &mut self.sync_timeline_limit;
+        let sync_excluded_event_types = &mut self.sync_excluded_event_types;
+        let session_storage = &mut self.session_storage;
+        let store_encryption = &mut self.store_encryption;
+        let homeserver_url = &mut self.homeserver_url;
+        let proxy = &mut self.proxy;
+        let tls_ca_certificates = &mut self.tls_ca_certificates;
+        let status_file = &mut self.status_file;
+        let tls_verify = &mut self.tls_verify;
+        let auto_accept_own_verifications = &mut self.auto_accept_own_verifications;
+        let initial_room = &mut self.initial_room;
+        let start_mode = &mut self.start_mode;
+        let restore_room_history = &mut self.restore_room_history;
+        let media_cache_size = &mut self.media_cache_size;
+        let upload_image_max_dimension = &mut self.upload_image_max_dimension;
+        let upload_image_quality = &mut self.upload_image_quality;
+        let strip_exif = &mut self.strip_exif;
+        let room_strip_exif = &mut self.room_strip_exif;
+        let image_placeholders = &mut self.image_placeholders;
+        let auto_follow = &mut self.auto_follow;
+        let read_receipts = &mut self.read_receipts;
+        let read_receipt_thread = &mut self.read_receipt_thread;
+        let bell = &mut self.bell;
+        let visual_bell = &mut self.visual_bell;
+        let urgency_hint = &mut self.urgency_hint;
+        let urgency_hint_command = &mut self.urgency_hint_command;
+        let mark_read_delay = &mut self.mark_read_delay;
+        let composer_max_rows = &mut self.composer_max_rows;
+        let room_filter_topic = &mut self.room_filter_topic;
+        let room_filter_members = &mut self.room_filter_members;
+        let hidden_events = &mut self.hidden_events;
+        let collapse_membership_events = &mut self.collapse_membership_events;
+        let open_all_links = &mut self.open_all_links;
+        let room_pane_fraction = &mut self.room_pane_fraction;
+        let log_level = &mut self.log_level;
+        let log_module_levels = &mut self.log_module_levels;
+        let log_retention = &mut self.log_retention;
+        let message_formatter = &mut self.message_formatter;
+        let room_list_filter = &mut self.room_list_filter;
+        let room_list_sort = &mut self.room_list_sort;
 
         self.lua.context(|lua_ctx| {
             let globals = lua_ctx.globals();
@@ -504,6 +1303,26 @@ impl ConfigBuilder {
                     )?,
                 )?;
 
+                for (name, hook) in [
+                    ("on_startup", Hook::Startup),
+                    ("on_sync", Hook::Sync),
+                    ("on_message", Hook::Message),
+                    ("on_room_change", Hook::RoomChange),
+                    ("on_session_expired", Hook::SessionExpired),
+                    ("on_notification", Hook::Notification),
+                ]
+                .iter()
+                .copied()
+                {
+                    let hooks = &hooks;
+                    globals.set(
+                        name,
+                        scope.create_function_mut(move |lua_ctx, fun: rlua::Function| {
+                            hooks.borrow_mut().add(hook, &lua_ctx, fun)
+                        })?,
+                    )?;
+                }
+
                 globals.set(
                     "bind",
                     scope.create_function_mut(
@@ -595,22 +1414,475 @@ impl ConfigBuilder {
                     })?,
                 )?;
 
-                // Define a shortcut binding for all methods of CommandContext
-                for (n, _) in ACTIONS_ARGS_NONE {
-                    lua_ctx
-                        .load(&format!("{f} = function(c) return c:{f}() end", f = n))
-                        .eval::<()>()?;
-                }
-
-                for (n, _) in ACTIONS_ARGS_STRING {
+                globals.set(
+                    "timeline_cache_room_limit",
+                    scope.create_function_mut(|_lua_ctx, limit: usize| {
+                        *timeline_cache_room_limit = limit;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "timeline_cache_total_limit",
+                    scope.create_function_mut(|_lua_ctx, limit: usize| {
+                        *timeline_cache_total_limit = limit;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "notification_click_focus",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *notification_click_focus = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "notification_raise_command",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        *notification_raise_command = Some(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "room_notify",
+                    scope.create_function_mut(
+                        |_lua_ctx, (room, level): (String, NotificationLevel)| {
+                            room_notification_levels.insert(room, level);
+                            Ok(())
+                        },
+                    )?,
+                )?;
+
+                globals.set(
+                    "notification_command",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        *notification_command = Some(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "notification_body",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        *notification_body = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "auto_away_after",
+                    scope.create_function_mut(|_lua_ctx, v: u64| {
+                        *auto_away_timeout = Some(std::time::Duration::from_secs(v));
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "sync_lazy_load_members",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *sync_lazy_load_members = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "sync_timeline_limit",
+                    scope.create_function_mut(|_lua_ctx, v: u32| {
+                        *sync_timeline_limit = Some(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "sync_exclude_event_type",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        sync_excluded_event_types.push(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "session_storage",
+                    scope.create_function_mut(|_lua_ctx, v: SessionStorage| {
+                        *session_storage = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "store_encryption",
+                    scope.create_function_mut(|_lua_ctx, v: SessionStorage| {
+                        *store_encryption = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "homeserver_url",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        *homeserver_url =
+                            Some(parse_homeserver_url(&v).map_err(rlua::Error::RuntimeError)?);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "proxy",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        let schemes = ["http://", "https://", "socks5://", "socks5h://"];
+                        if !schemes.iter().any(|s| v.starts_with(s)) {
+                            return Err(rlua::Error::RuntimeError(format!(
+                                "Invalid proxy '{}', expected e.g. socks5h://localhost:9050",
+                                v
+                            )));
+                        }
+                        *proxy = Some(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "add_ca_certificate",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        let path = shellexpand::full(&v)
+                            .map_err(|e| rlua::Error::RuntimeError(format!("{}", e)))?;
+                        tls_ca_certificates.push(PathBuf::from(path.as_ref()));
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "status_file",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        let path = shellexpand::full(&v)
+                            .map_err(|e| rlua::Error::RuntimeError(format!("{}", e)))?;
+                        *status_file = Some(PathBuf::from(path.as_ref()));
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "tls_verify",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *tls_verify = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "auto_accept_own_verifications",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *auto_accept_own_verifications = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "initial_room",
+                    scope.create_function_mut(|_lua_ctx, v: InitialRoom| {
+                        *initial_room = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "start_mode",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        *start_mode = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "restore_room_history",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *restore_room_history = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "media_cache_size",
+                    scope.create_function_mut(|_lua_ctx, v: u64| {
+                        *media_cache_size = v * 1024 * 1024;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "upload_image_max_dimension",
+                    scope.create_function_mut(|_lua_ctx, v: u32| {
+                        *upload_image_max_dimension = Some(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "upload_image_quality",
+                    scope.create_function_mut(|_lua_ctx, v: u8| {
+                        if !(1..=100).contains(&v) {
+                            return Err(rlua::Error::RuntimeError(format!(
+                                "Invalid image quality {}, expected a value from 1 to 100",
+                                v
+                            )));
+                        }
+                        *upload_image_quality = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "strip_exif",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *strip_exif = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "room_strip_exif",
+                    scope.create_function_mut(|_lua_ctx, (room, v): (String, bool)| {
+                        room_strip_exif.insert(room, v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "image_placeholders",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *image_placeholders = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "auto_follow",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *auto_follow = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "read_receipts",
+                    scope.create_function_mut(|_lua_ctx, v: ReadReceipts| {
+                        *read_receipts = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "read_receipt_thread",
+                    scope.create_function_mut(|_lua_ctx, v: ReadReceiptThread| {
+                        *read_receipt_thread = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "bell",
+                    scope.create_function_mut(|_lua_ctx, v: Bell| {
+                        *bell = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "visual_bell",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *visual_bell = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "urgency_hint",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *urgency_hint = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "urgency_hint_command",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        *urgency_hint_command = Some(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "mark_read_delay_ms",
+                    scope.create_function_mut(|_lua_ctx, v: u64| {
+                        *mark_read_delay = std::time::Duration::from_millis(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "composer_max_rows",
+                    scope.create_function_mut(|_lua_ctx, v: usize| {
+                        *composer_max_rows = v.max(1);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "room_filter_topic",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *room_filter_topic = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "room_filter_members",
+                    scope.create_function_mut(|_lua_ctx, v: RoomFilterMembers| {
+                        *room_filter_members = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "hide_event_type",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        hidden_events.event_types.insert(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "hide_membership_change",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        if !crate::timeline::MEMBERSHIP_CHANGES.contains(&v.as_str()) {
+                            return Err(rlua::Error::RuntimeError(format!(
+                                "'{}' is not a valid membership change ({})",
+                                v,
+                                crate::timeline::MEMBERSHIP_CHANGES.join(", ")
+                            )));
+                        }
+                        hidden_events.membership_changes.insert(v);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "collapse_membership_events",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *collapse_membership_events = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "open_all_links",
+                    scope.create_function_mut(|_lua_ctx, v: bool| {
+                        *open_all_links = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "pane_weights",
+                    scope.create_function_mut(|_lua_ctx, (rooms, timeline): (f64, f64)| {
+                        if !(rooms > 0.0 && timeline > 0.0) {
+                            return Err(rlua::Error::RuntimeError(
+                                "Pane weights must be positive".to_owned(),
+                            ));
+                        }
+                        *room_pane_fraction = rooms / (rooms + timeline);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "log_level",
+                    scope.create_function_mut(|_lua_ctx, v: String| {
+                        *log_level = Some(parse_log_level(&v)?);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "log_module_level",
+                    scope.create_function_mut(|_lua_ctx, (module, v): (String, String)| {
+                        let level = parse_log_level(&v)?;
+                        log_module_levels.push(format!("{}={}", module, level));
+                        Ok(())
+                    })?,
+                )?;
+
+                // nil removes the limit in all of the following.
+                globals.set(
+                    "log_keep_files",
+                    scope.create_function_mut(|_lua_ctx, v: Option<usize>| {
+                        log_retention.max_files = v;
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "log_keep_days",
+                    scope.create_function_mut(|_lua_ctx, v: Option<u64>| {
+                        log_retention.max_age =
+                            v.map(|days| std::time::Duration::from_secs(days * 24 * 60 * 60));
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "log_max_size",
+                    scope.create_function_mut(|_lua_ctx, v: Option<u64>| {
+                        log_retention.max_total_size = v.map(|mb| mb * 1024 * 1024);
+                        Ok(())
+                    })?,
+                )?;
+
+                globals.set(
+                    "message_formatter",
+                    scope.create_function_mut(|lua_ctx, f: rlua::Function| {
+                        *message_formatter = Some(lua_ctx.create_registry_value(f)?);
+                        Ok(())
+                    })?,
+                )?;
+
+                // A predicate deciding whether a room is shown in the room list.
+                globals.set(
+                    "room_list_filter",
+                    scope.create_function_mut(|lua_ctx, f: rlua::Function| {
+                        *room_list_filter = Some(lua_ctx.create_registry_value(f)?);
+                        Ok(())
+                    })?,
+                )?;
+
+                // A comparator returning whether the first room comes before the second one.
+                globals.set(
+                    "room_list_sort",
+                    scope.create_function_mut(|lua_ctx, f: rlua::Function| {
+                        *room_list_sort = Some(lua_ctx.create_registry_value(f)?);
+                        Ok(())
+                    })?,
+                )?;
+
+                // Define a shortcut binding for all methods of CommandContext
+                for (n, _) in ACTIONS_ARGS_NONE {
+                    lua_ctx
+                        .load(&format!("{f} = function(c) return c:{f}() end", f = n))
+                        .eval::<()>()?;
+                }
+
+                for (n, _) in ACTIONS_ARGS_STRING {
                     add_global_fun(&lua_ctx, n, 1)?;
                 }
                 add_global_fun(&lua_ctx, "cursor_move_forward", 1)?;
                 add_global_fun(&lua_ctx, "cursor_move_backward", 1)?;
                 add_global_fun(&lua_ctx, "cursor_delete", 2)?;
+                add_global_fun(&lua_ctx, "yank_code_block", 1)?;
 
-                lua_ctx.load(source).eval::<()>()?;
-                Ok(())
+                run(lua_ctx)
             })
         })
     }
@@ -0,0 +1,29 @@
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+/// Send `command` to the tui listening on `socket`. Nothing is printed if it succeeds, an error
+/// response of the tui is returned as the error.
+pub async fn run(socket: &Path, command: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let stream = UnixStream::connect(socket).await.map_err(|e| {
+        format!(
+            "Cannot connect to {} (is sparse running?): {}",
+            socket.display(),
+            e
+        )
+    })?;
+    let (read, mut write) = stream.into_split();
+    let mut line = command.join(" ");
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let response = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or("Connection closed without response")?;
+    match response.strip_prefix("error: ") {
+        Some(e) => Err(e.to_owned().into()),
+        None => Ok(()),
+    }
+}
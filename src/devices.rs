@@ -1,6 +1,16 @@
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedDeviceId};
 use matrix_sdk::{config::SyncSettings, Client};
 
-pub async fn run(client: Client) -> Result<(), matrix_sdk::Error> {
+fn format_last_seen(ts: Option<MilliSecondsSinceUnixEpoch>) -> String {
+    ts.and_then(|ts| chrono::DateTime::from_timestamp_millis(i64::from(ts.0)))
+        .map(|t| {
+            let t: chrono::DateTime<chrono::Local> = t.into();
+            t.format("%Y-%m-%d %H:%M").to_string()
+        })
+        .unwrap_or_default()
+}
+
+pub async fn run(client: Client, json: bool) -> Result<(), matrix_sdk::Error> {
     let settings = SyncSettings::new().full_state(true);
 
     let _ = client.sync_once(settings).await?;
@@ -10,7 +20,9 @@ pub async fn run(client: Client) -> Result<(), matrix_sdk::Error> {
         .await
         .expect("Can't get devices from server");
 
-    println!("ID\tDevice name\tverified\tcurrent",);
+    if !json {
+        println!("ID\tDevice name\tverified\tlast seen ip\tlast seen\tcurrent",);
+    }
 
     let current_device = client.device_id().unwrap();
 
@@ -21,15 +33,31 @@ pub async fn run(client: Client) -> Result<(), matrix_sdk::Error> {
             .get_device(&user_id, &*device.device_id)
             .await
             .unwrap();
+        let verified = crypt_device.map(|d| d.is_verified());
+        let current = device.device_id == current_device;
+        if json {
+            let line = serde_json::json!({
+                "id": device.device_id,
+                "name": device.display_name,
+                "verified": verified,
+                "last_seen_ip": device.last_seen_ip,
+                "last_seen_ts": device.last_seen_ts,
+                "current": current,
+            });
+            println!("{}", line);
+            continue;
+        }
         print!(
-            "{}\t{}\t{}",
+            "{}\t{}\t{}\t{}\t{}",
             device.device_id,
             device.display_name.as_deref().unwrap_or(""),
-            crypt_device
-                .map(|d| d.is_verified().to_string())
-                .unwrap_or("unknown".to_owned())
+            verified
+                .map(|v| v.to_string())
+                .unwrap_or("unknown".to_owned()),
+            device.last_seen_ip.as_deref().unwrap_or(""),
+            format_last_seen(device.last_seen_ts),
         );
-        if device.device_id == current_device {
+        if current {
             println!("\t*");
         } else {
             println!();
@@ -38,3 +66,13 @@ pub async fn run(client: Client) -> Result<(), matrix_sdk::Error> {
 
     Ok(())
 }
+
+pub async fn rename(
+    client: Client,
+    device_id: OwnedDeviceId,
+    name: String,
+) -> Result<(), matrix_sdk::Error> {
+    client.rename_device(&device_id, &name).await?;
+    println!("Renamed device {} to '{}'", device_id, name);
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use std::path::PathBuf;
+
+use crate::config::{Config, ConfigBuilder};
+
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok(&self, msg: impl std::fmt::Display) {
+        println!("✓ {}", msg);
+    }
+    fn problem(&mut self, msg: impl std::fmt::Display, hint: impl std::fmt::Display) {
+        self.problems += 1;
+        println!("✗ {}", msg);
+        println!("  → {}", hint);
+    }
+}
+
+fn config_hint(e: &rlua::Error) -> String {
+    let msg = e.to_string();
+    if msg.contains("attempt to call a nil value") {
+        "A function is called that does not exist. Check for typos in its name.".to_owned()
+    } else if msg.contains("is not a valid key sequence") {
+        "Key sequences look like \"<C-x>\", \"<Enter>\" or \"gg\".".to_owned()
+    } else {
+        "Fix the error above. Evaluation of the config stops at the first error.".to_owned()
+    }
+}
+
+/// Evaluate the config like on a regular start, but report all problems instead of aborting.
+fn check_config(
+    report: &mut Report,
+    config_file: &Option<PathBuf>,
+    profile: Option<String>,
+    overrides: impl FnOnce(&mut ConfigBuilder),
+) -> Option<Config> {
+    let mut builder = ConfigBuilder::new();
+    if let Err(e) = builder.configure(include_str!("base_config.lua")) {
+        report.problem(
+            format!("Builtin config is broken: {}", e),
+            "This is a bug in sparse, please report it.",
+        );
+        return None;
+    }
+
+    match config_file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(content) => match path
+                .parent()
+                .map(|dir| builder.set_config_dir(dir))
+                .unwrap_or(Ok(()))
+                .and_then(|_| builder.configure(&content))
+            {
+                Ok(()) => report.ok(format!("Evaluated {}", path.display())),
+                Err(e) => report.problem(
+                    format!("Error in {}: {}", path.display(), e),
+                    config_hint(&e),
+                ),
+            },
+            Err(e) => report.problem(
+                format!("Cannot read {}: {}", path.display(), e),
+                "Check that the file exists and is readable.",
+            ),
+        },
+        None => report.ok("No config file found, using the defaults"),
+    }
+
+    if let Some(profile) = profile {
+        match builder.select_profile(profile.clone()) {
+            Ok(()) => report.ok(format!("Selected profile '{}'", profile)),
+            Err(e) => report.problem(
+                format!("Cannot select profile '{}': {}", profile, e),
+                "Define it in the config with profile \"name\" { host = ..., user = ... }.",
+            ),
+        }
+    }
+
+    overrides(&mut builder);
+
+    match builder.finalize() {
+        Ok((config, _)) => {
+            report.ok(format!("Configured user {}", config.user_id()));
+            Some(config)
+        }
+        Err(e) => {
+            report.problem(
+                e,
+                "Set host and user with host(\"example.org\") and user(\"name\") in the config \
+                 or with --host and --user.",
+            );
+            None
+        }
+    }
+}
+
+async fn check_connection(report: &mut Report, config: &Config) -> Option<matrix_sdk::Client> {
+    // No store and no automatic token refresh: The check must not interfere with the session of a
+    // running instance.
+    let client = match crate::client_builder(config) {
+        Ok(builder) => crate::build_client(builder, config).await,
+        Err(e) => Err(e),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            report.problem(
+                e,
+                "Check host(...), homeserver_url(...) and the network settings in the config.",
+            );
+            return None;
+        }
+    };
+    match client.server_versions().await {
+        Ok(versions) => {
+            report.ok(format!(
+                "Reached homeserver {} (supports {})",
+                client.homeserver(),
+                versions
+                    .iter()
+                    .map(|v| v.as_str().unwrap_or("?"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+            Some(client)
+        }
+        Err(e) => {
+            report.problem(
+                format!("Cannot reach homeserver {}: {}", client.homeserver(), e),
+                "Check your network connection, proxy and TLS settings.",
+            );
+            None
+        }
+    }
+}
+
+async fn check_session(report: &mut Report, config: &Config, client: &matrix_sdk::Client) {
+    let session = match crate::session::try_load_session(config) {
+        Ok(session) => session,
+        Err(e) => {
+            report.problem(
+                format!("No usable stored session: {}", e),
+                "Start sparse to log in with your password.",
+            );
+            return;
+        }
+    };
+    let has_refresh_token = session.tokens.refresh_token.is_some();
+    if let Err(e) = client.restore_session(session).await {
+        report.problem(
+            format!("Cannot restore the stored session: {}", e),
+            "Start sparse to log in again.",
+        );
+        return;
+    }
+    match client.whoami().await {
+        Ok(response) => report.ok(format!(
+            "Session is valid for {} on device {}",
+            response.user_id,
+            response
+                .device_id
+                .as_ref()
+                .map(|d| d.as_str())
+                .unwrap_or("?")
+        )),
+        Err(e) => match e.client_api_error_kind() {
+            Some(ErrorKind::UnknownToken { soft_logout }) => {
+                if has_refresh_token {
+                    report.ok("Access token expired, it will be refreshed on the next start")
+                } else if *soft_logout {
+                    report.problem(
+                        "Session expired",
+                        "Start sparse and log in again, the device and its keys are kept.",
+                    )
+                } else {
+                    report.problem(
+                        "Session was logged out",
+                        "Start sparse to log in again as a new device.",
+                    )
+                }
+            }
+            _ => report.problem(
+                format!("Cannot check the session: {}", e),
+                "Try again later, the homeserver may be having problems.",
+            ),
+        },
+    }
+}
+
+/// `overrides` applies the settings given on the command line.
+pub async fn run(
+    config_file: Option<PathBuf>,
+    profile: Option<String>,
+    overrides: impl FnOnce(&mut ConfigBuilder),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut report = Report { problems: 0 };
+
+    if let Some(config) = check_config(&mut report, &config_file, profile, overrides) {
+        if let Some(client) = check_connection(&mut report, &config).await {
+            check_session(&mut report, &config, &client).await;
+        }
+    }
+
+    match report.problems {
+        0 => println!("\nNo problems found."),
+        1 => println!("\nFound 1 problem."),
+        n => println!("\nFound {} problems.", n),
+    }
+    Ok(())
+}
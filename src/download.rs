@@ -0,0 +1,79 @@
+use matrix_sdk::deserialized_responses::SyncTimelineEvent;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+};
+use matrix_sdk::ruma::{MatrixId, MatrixToUri, MatrixUri, OwnedMxcUri};
+use matrix_sdk::{config::SyncSettings, Client};
+use std::path::PathBuf;
+
+/// Find the media source, a suggested file name and the event id for a message event given by its
+/// permalink.
+async fn event_media(
+    client: &Client,
+    link: &str,
+) -> Result<(MediaSource, String, String), Box<dyn std::error::Error>> {
+    let id = if let Ok(uri) = MatrixToUri::parse(link) {
+        uri.id().clone()
+    } else {
+        MatrixUri::parse(link)
+            .map_err(|_| format!("'{}' is neither an mxc uri nor an event permalink", link))?
+            .id()
+            .clone()
+    };
+    let (room, event_id) = match id {
+        MatrixId::Event(room, event_id) => (room, event_id),
+        _ => return Err(format!("'{}' does not refer to an event", link).into()),
+    };
+
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let room = crate::resolve::find_room(client, room.as_str()).await?;
+    let event = SyncTimelineEvent::from(room.event(&event_id, None).await?);
+    let msg = match event.raw().deserialize()? {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(msg),
+        )) => msg,
+        _ => return Err(format!("Event {} is not a message", event_id).into()),
+    };
+    let source = match msg.content.msgtype {
+        MessageType::Image(content) => content.source,
+        MessageType::File(content) => content.source,
+        MessageType::Video(content) => content.source,
+        MessageType::Audio(content) => content.source,
+        _ => return Err(format!("Event {} does not contain media", event_id).into()),
+    };
+    Ok((source, msg.content.body().to_owned(), event_id.to_string()))
+}
+
+pub async fn run(
+    client: Client,
+    target: String,
+    dest: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (source, name, fallback) = if target.starts_with("mxc://") {
+        let uri = OwnedMxcUri::from(target.as_str());
+        let name = uri.media_id()?.to_owned();
+        (MediaSource::Plain(uri), name.clone(), name)
+    } else {
+        event_media(&client, &target).await?
+    };
+
+    // Do not allow the (remote) file name to escape the destination directory. `.` and `..` would
+    // refer to the directory itself or its parent.
+    let name = match crate::media::sanitize_file_name(&name) {
+        name if name.is_empty() || name == "." || name == ".." => {
+            crate::media::sanitize_file_name(&fallback)
+        }
+        name => name,
+    };
+    let path = match dest {
+        Some(dest) if dest.is_dir() => dest.join(name),
+        Some(dest) => dest,
+        None => PathBuf::from(name),
+    };
+    let mut file = std::fs::File::create(&path)?;
+    crate::media::write_source(&client, source, &mut file).await?;
+    eprintln!("Saved to {}", path.display());
+    Ok(())
+}
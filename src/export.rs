@@ -0,0 +1,207 @@
+use matrix_sdk::deserialized_responses::SyncTimelineEvent;
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::events::room::message::{MessageType, OriginalSyncRoomMessageEvent};
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+};
+use matrix_sdk::{config::SyncSettings, Client};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Json,
+    Html,
+    Text,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "json" => Format::Json,
+            "html" => Format::Html,
+            "text" => Format::Text,
+            o => return Err(format!("Invalid format '{}'", o)),
+        })
+    }
+}
+
+/// Parse a date (YYYY-MM-DD) as the start of that day in local time in ms since the epoch.
+pub fn parse_date(s: &str) -> Result<u64, String> {
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|e| format!("Invalid date '{}': {}", s, e))?;
+    let time = date
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_local_timezone(chrono::Local)
+        .earliest()
+        .ok_or_else(|| format!("Invalid date '{}'", s))?;
+    Ok(time.timestamp_millis() as u64)
+}
+
+fn format_time(ts: u64) -> String {
+    let time = chrono::DateTime::from_timestamp_millis(ts as i64).unwrap();
+    let time: chrono::DateTime<chrono::Local> = time.into();
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\n' => out.push_str("<br>"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+async fn download_media(
+    client: &Client,
+    cache: &crate::media::MediaCache,
+    msg: &OriginalSyncRoomMessageEvent,
+    dir: &PathBuf,
+) -> Result<Option<PathBuf>, String> {
+    let cached = match &msg.content.msgtype {
+        MessageType::Image(content) => cache.get_file(client, content.clone()).await,
+        MessageType::File(content) => cache.get_file(client, content.clone()).await,
+        MessageType::Video(content) => cache.get_file(client, content.clone()).await,
+        MessageType::Audio(content) => cache.get_file(client, content.clone()).await,
+        _ => return Ok(None),
+    }?;
+    // Prefix the file name with the event id to avoid collisions.
+    let file_name =
+        crate::media::sanitize_file_name(&format!("{}_{}", msg.event_id, msg.content.body()));
+    let path = dir.join(file_name);
+    std::fs::copy(&*cached, &path).map_err(|e| format!("{}", e))?;
+    Ok(Some(path))
+}
+
+pub struct ExportOptions {
+    pub format: Format,
+    pub output: PathBuf,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub media: Option<PathBuf>,
+    pub cache: crate::media::MediaCache,
+}
+
+pub async fn run(
+    client: Client,
+    room: String,
+    options: ExportOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let room = crate::resolve::find_room(&client, &room).await?;
+
+    // Paginate backwards from the newest message until we reach `since` or the room creation.
+    let mut messages = Vec::new();
+    let mut from = None;
+    'outer: loop {
+        let mut request = MessagesOptions::backward();
+        request.from = from;
+        let response = room.messages(request).await?;
+        for event in response.chunk {
+            let event = SyncTimelineEvent::from(event);
+            if let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(msg),
+            ))) = event.raw().deserialize()
+            {
+                let ts = u64::from(msg.origin_server_ts.0);
+                if options.since.map(|since| ts < since).unwrap_or(false) {
+                    break 'outer;
+                }
+                if options.until.map(|until| ts >= until).unwrap_or(false) {
+                    continue;
+                }
+                messages.push(msg);
+            }
+        }
+        match response.end {
+            Some(end) => from = Some(end),
+            None => break,
+        }
+        eprint!("\rFetched {} messages", messages.len());
+    }
+    eprintln!();
+    messages.reverse();
+
+    if let Some(dir) = &options.media {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(&options.output)?);
+    if let Format::Html = options.format {
+        writeln!(
+            out,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head><body>",
+            escape_html(room.room_id().as_str())
+        )?;
+    }
+    let mut json = Vec::new();
+    for msg in &messages {
+        let ts = u64::from(msg.origin_server_ts.0);
+        let media = if let Some(dir) = &options.media {
+            match download_media(&client, &options.cache, msg, dir).await {
+                Ok(path) => path,
+                Err(e) => {
+                    eprintln!("Failed to download media of {}: {}", msg.event_id, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let body = msg.content.body();
+        match options.format {
+            Format::Text => {
+                write!(out, "[{}] {}: {}", format_time(ts), msg.sender, body)?;
+                if let Some(path) = &media {
+                    write!(out, " ({})", path.display())?;
+                }
+                writeln!(out)?;
+            }
+            Format::Html => {
+                write!(
+                    out,
+                    "<p><small>{}</small> <b>{}</b>: {}",
+                    format_time(ts),
+                    escape_html(msg.sender.as_str()),
+                    escape_html(body)
+                )?;
+                if let Some(path) = &media {
+                    let path = escape_html(&path.display().to_string());
+                    write!(out, " <a href=\"{}\">[media]</a>", path)?;
+                }
+                writeln!(out, "</p>")?;
+            }
+            Format::Json => json.push(serde_json::json!({
+                "event_id": msg.event_id,
+                "sender": msg.sender,
+                "timestamp": msg.origin_server_ts,
+                "type": msg.content.msgtype(),
+                "body": body,
+                "media": media.map(|p| p.display().to_string()),
+            })),
+        }
+    }
+    match options.format {
+        Format::Json => serde_json::to_writer_pretty(&mut out, &json)?,
+        Format::Html => writeln!(out, "</body></html>")?,
+        Format::Text => {}
+    }
+    out.flush()?;
+    eprintln!(
+        "Exported {} messages to {}",
+        messages.len(),
+        options.output.display()
+    );
+    Ok(())
+}
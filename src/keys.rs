@@ -0,0 +1,38 @@
+use matrix_sdk::{config::SyncSettings, Client};
+
+use crate::KeysAction;
+
+fn read_passphrase(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let passphrase = rpassword::read_password_from_tty(Some(prompt))?;
+    if passphrase.is_empty() {
+        return Err("Passphrase must not be empty".into());
+    }
+    Ok(passphrase)
+}
+
+pub async fn run(client: Client, action: KeysAction) -> Result<(), Box<dyn std::error::Error>> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let encryption = client.encryption();
+
+    match action {
+        KeysAction::Export { file } => {
+            let passphrase = read_passphrase("Passphrase: ")?;
+            if passphrase != read_passphrase("Repeat passphrase: ")? {
+                return Err("Passphrases do not match".into());
+            }
+            encryption
+                .export_room_keys(file.clone(), &passphrase, |_| true)
+                .await?;
+            println!("Exported room keys to {}", file.display());
+        }
+        KeysAction::Import { file } => {
+            let passphrase = read_passphrase("Passphrase: ")?;
+            let result = encryption.import_room_keys(file, &passphrase).await?;
+            println!(
+                "Imported {} of {} room keys",
+                result.imported_count, result.total_count
+            );
+        }
+    }
+    Ok(())
+}
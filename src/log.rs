@@ -1,22 +1,42 @@
-use std::path::PathBuf;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
-#[allow(dead_code)]
-pub enum RetentionPolicy {
-    All,
-    Keep(usize),
+/// Which old log files are deleted at startup. Files exceeding any of the limits are deleted.
+#[derive(Clone)]
+pub struct RetentionPolicy {
+    pub max_files: Option<usize>, // Including the file of the current run
+    pub max_age: Option<Duration>,
+    pub max_total_size: Option<u64>, // In bytes
 }
 
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_files: Some(3),
+            max_age: None,
+            max_total_size: None,
+        }
+    }
+}
+
+/// `filter` uses the syntax of `RUST_LOG` (e.g. `info,matrix_sdk=warn`), which is read if it is not
+/// given.
 pub fn init(
-    rotation: RetentionPolicy,
+    retention: &RetentionPolicy,
+    filter: Option<&str>,
 ) -> Result<tracing_appender::non_blocking::WorkerGuard, Box<dyn std::error::Error>> {
+    let env_filter = match filter {
+        Some(f) => tracing_subscriber::EnvFilter::try_new(f)
+            .map_err(|e| format!("Invalid log filter '{}': {}", f, e))?,
+        None => tracing_subscriber::EnvFilter::from_default_env(),
+    };
+
     let cache_dir = dirs::cache_dir()
         .ok_or("Could not get cache dir")?
         .join(crate::APP_NAME);
     std::fs::create_dir_all(&cache_dir)?;
 
-    if let RetentionPolicy::Keep(num) = rotation {
-        clean_up(&cache_dir, num)?;
-    }
+    clean_up(&cache_dir, retention)?;
 
     let dt = chrono::Local::now().naive_local();
     let log_file = dt
@@ -27,13 +47,13 @@ pub fn init(
 
     tracing_subscriber::fmt()
         .with_writer(non_blocking)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_env_filter(env_filter)
         .init();
 
     Ok(guard)
 }
 
-fn clean_up(dir: &PathBuf, num: usize) -> Result<(), Box<dyn std::error::Error>> {
+fn clean_up(dir: &Path, retention: &RetentionPolicy) -> Result<(), Box<dyn std::error::Error>> {
     let mut files: Vec<_> = std::fs::read_dir(dir)?
         .filter_map(|f| f.ok())
         .filter(|f| match f.path().file_name() {
@@ -44,19 +64,33 @@ fn clean_up(dir: &PathBuf, num: usize) -> Result<(), Box<dyn std::error::Error>>
         })
         .map(|f| f.path())
         .collect::<Vec<_>>();
-    // sort files so removing the first few will remove the oldest first
+    // sort files so that the newest come first
     files.sort();
-    let num_to_remove: usize = if num <= files.len() {
-        // remove one more because we will write a new file after cleanup
-        files.len() - num + 1
-    } else {
-        0
-    };
-    for to_remove in files.iter().take(num_to_remove) {
-        if let Err(e) = std::fs::remove_file(to_remove) {
+    files.reverse();
+    let now = SystemTime::now();
+    let mut total_size = 0;
+    for (i, file) in files.iter().enumerate() {
+        let metadata = std::fs::metadata(file)?;
+        total_size += metadata.len();
+        // Leave room for the file that we will write after cleanup
+        let too_many = retention.max_files.map_or(false, |max| i + 1 >= max);
+        let too_old = retention.max_age.map_or(false, |max| {
+            metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .map_or(false, |age| age > max)
+        });
+        let too_large = retention
+            .max_total_size
+            .map_or(false, |max| total_size > max);
+        if !(too_many || too_old || too_large) {
+            continue;
+        }
+        if let Err(e) = std::fs::remove_file(file) {
             Err(format!(
                 "Error deleting old log file '{}':{}",
-                to_remove.to_string_lossy(),
+                file.to_string_lossy(),
                 e
             ))?;
         }
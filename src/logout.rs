@@ -1,30 +1,47 @@
 use matrix_sdk::ruma::api::client::uiaa;
+use matrix_sdk::ruma::OwnedDeviceId;
 use matrix_sdk::{self, config::SyncSettings, Client};
 use std::collections::HashMap;
+use std::io::Write;
 
-pub async fn run(client: Client, ids: Vec<String>) -> Result<(), matrix_sdk::Error> {
-    let _res = client.sync_once(SyncSettings::new()).await?;
+use crate::config::Config;
 
-    let response = client.devices().await?;
-    let available_ids = response
-        .devices
-        .iter()
-        .map(|d| (d.device_id.as_str(), &d.device_id))
-        .collect::<HashMap<_, _>>();
+type Error = Box<dyn std::error::Error>;
 
-    let mut device_ids = Vec::new();
-    for id in &ids {
-        if let Some(i) = available_ids.get(id.as_str()) {
-            device_ids.push((*i).to_owned());
-        } else {
-            panic!("'{}' is not the id one of your devices.", id);
+/// Let the user pick devices from a numbered list.
+fn select_devices(devices: &[(OwnedDeviceId, String)]) -> Result<Vec<OwnedDeviceId>, Error> {
+    if devices.is_empty() {
+        println!("There are no other devices.");
+        return Ok(Vec::new());
+    }
+    for (i, (id, name)) in devices.iter().enumerate() {
+        println!("{:>3}) {}\t{}", i + 1, id, name);
+    }
+    loop {
+        print!("Devices to log out (e.g. \"1 3 4\", empty to abort): ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        let selection = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| match s.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= devices.len() => Ok(devices[n - 1].0.clone()),
+                _ => Err(s),
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match selection {
+            Ok(selection) => return Ok(selection),
+            Err(s) => println!("'{}' is not a number between 1 and {}", s, devices.len()),
         }
     }
+}
 
+async fn delete_devices(client: &Client, device_ids: &[OwnedDeviceId]) -> Result<(), Error> {
     let session = client.session().unwrap();
     let user_id = session.meta().user_id.as_str();
 
-    if let Err(e) = client.delete_devices(&device_ids, None).await {
+    if let Err(e) = client.delete_devices(device_ids, None).await {
         if let Some(info) = e.as_uiaa_response() {
             println!("Logging out other devices requires additional password authentication.");
             match rpassword::read_password_from_tty(Some("Password: ")) {
@@ -36,14 +53,76 @@ pub async fn run(client: Client, ids: Vec<String>) -> Result<(), matrix_sdk::Err
                     );
                     auth_data.session = info.session.clone();
                     let auth_data = uiaa::AuthData::Password(auth_data);
-                    client.delete_devices(&device_ids, Some(auth_data)).await?;
+                    client.delete_devices(device_ids, Some(auth_data)).await?;
                     println!("Done");
                 }
                 Err(e) => panic!("{}", e),
             }
+        } else {
+            return Err(e.into());
         }
     } else {
-        panic!("Delete succeeded without authentication!");
+        println!("Done");
+    }
+    Ok(())
+}
+
+pub async fn run(
+    client: Client,
+    config: &Config,
+    ids: Vec<String>,
+    all_others: bool,
+    current: bool,
+) -> Result<(), Error> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+
+    let current_device = client.device_id().unwrap().to_owned();
+    let response = client.devices().await?;
+    let available_ids = response
+        .devices
+        .iter()
+        .map(|d| (d.device_id.as_str(), &d.device_id))
+        .collect::<HashMap<_, _>>();
+
+    let mut device_ids = Vec::new();
+    for id in &ids {
+        if let Some(i) = available_ids.get(id.as_str()) {
+            device_ids.push((*i).to_owned());
+        } else {
+            return Err(format!("'{}' is not the id one of your devices.", id).into());
+        }
+    }
+    if device_ids.contains(&current_device) {
+        return Err("Use --current to log out the current device.".into());
+    }
+
+    let others = response
+        .devices
+        .iter()
+        .filter(|d| d.device_id != current_device)
+        .map(|d| {
+            let name = d.display_name.clone().unwrap_or_default();
+            (d.device_id.clone(), name)
+        })
+        .collect::<Vec<_>>();
+    if all_others {
+        device_ids.extend(others.into_iter().map(|(id, _)| id));
+    } else if ids.is_empty() && !current {
+        device_ids = select_devices(&others)?;
+    }
+
+    if !device_ids.is_empty() {
+        delete_devices(&client, &device_ids).await?;
+    }
+
+    if current {
+        client.matrix_auth().logout().await?;
+        drop(client);
+        println!("Logged out device {}", current_device);
+        // The store holds the keys of the device, so it cannot be used by the next one. It is
+        // kept, since it may contain keys of messages that are not in the key backup.
+        let backup = crate::session::move_store_aside(config)?;
+        println!("Moved the store to {}", backup.display());
     }
 
     Ok(())
@@ -1,14 +1,32 @@
+mod account;
+mod backup;
+mod code_blocks;
+mod ctl;
 mod devices;
+mod doctor;
+mod download;
+mod export;
+mod keys;
 mod log;
 mod logout;
+mod manage;
+mod media;
+mod resolve;
+mod rooms;
 mod search;
+mod send;
+mod session;
 mod timeline;
 mod tui_app;
+mod upload;
 mod verification_common;
 mod verification_initiate;
 mod verification_wait;
+mod watch;
+mod whoami;
 
 use matrix_sdk::{self, matrix_auth::MatrixSession, Client};
+use session::{try_load_session, try_store_session};
 
 use structopt::StructOpt;
 
@@ -18,27 +36,6 @@ mod config;
 use config::{Config, ConfigBuilder};
 
 const APP_NAME: &str = env!("CARGO_PKG_NAME");
-const LOG_RETENTION_POLICY: log::RetentionPolicy = log::RetentionPolicy::Keep(3);
-
-fn try_load_session(config: &Config) -> Result<MatrixSession, Box<dyn std::error::Error>> {
-    let session_file = std::fs::File::open(config.session_file_path())?; //TODO: encrypt?
-    Ok(serde_json::from_reader(session_file)?)
-}
-
-fn try_store_session(
-    config: &Config,
-    session: &MatrixSession,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let session_file_path = config.session_file_path();
-    std::fs::create_dir_all(session_file_path.parent().unwrap())?;
-    let session_file = std::fs::OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(session_file_path)?;
-    serde_json::to_writer(session_file, session)?;
-    Ok(())
-}
 
 async fn try_restore_session(
     client: &Client,
@@ -49,23 +46,147 @@ async fn try_restore_session(
 
     // Test the token which may have been invalidated: We don't actually care about the result, but
     // it will fail if we are not logged in with the old token.
-    let _ = client.devices().await?;
+    match client.devices().await {
+        Ok(_) => {}
+        Err(e @ matrix_sdk::HttpError::Api(_)) => return Err(e.into()),
+        Err(e) => {
+            // We cannot reach the server, but the session may still be valid. Continue with what
+            // we have in the local store and let the sync loop reconnect once possible.
+            tracing::warn!("Failed to reach server: {}", e);
+            eprintln!("Could not reach the server. Starting in offline mode.");
+        }
+    }
+    Ok(())
+}
+
+/// Set up the connection to the homeserver, but not the store or login.
+fn client_builder(config: &Config) -> Result<matrix_sdk::ClientBuilder, String> {
+    let client = Client::builder().user_agent(APP_NAME);
+    let mut client = if let Some(url) = &config.homeserver_url {
+        client.homeserver_url(url)
+    } else {
+        // Discover the url of the client api via .well-known
+        client.server_name(&config.host)
+    };
+    if let Some(proxy) = &config.proxy {
+        client = client.proxy(proxy);
+    }
+    let mut certificates = Vec::new();
+    for path in &config.tls_ca_certificates {
+        let pem = std::fs::read(path)
+            .map_err(|e| format!("Failed to read certificate {}: {}", path.display(), e))?;
+        let certificate = matrix_sdk::reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| format!("Invalid certificate {}: {}", path.display(), e))?;
+        certificates.push(certificate);
+    }
+    if !certificates.is_empty() {
+        client = client.add_root_certificates(certificates);
+    }
+    if !config.tls_verify {
+        eprintln!(
+            "WARNING: TLS certificate verification is disabled! Anyone on the network path can \
+             read and modify the traffic to {}.",
+            config.host
+        );
+        tracing::warn!("TLS certificate verification is disabled");
+        client = client.disable_ssl_verification();
+    }
+    Ok(client)
+}
+
+fn build_error(e: matrix_sdk::ClientBuildError, config: &Config) -> String {
+    match e {
+        matrix_sdk::ClientBuildError::AutoDiscovery(e) => format!(
+            "Could not discover the homeserver of {} via .well-known: {}\n\
+             Specify the url of the homeserver with homeserver_url(...) in the config or \
+             --homeserver-url instead.",
+            config.host, e
+        ),
+        e => format!("Failed to set up the client: {}", e),
+    }
+}
+
+async fn build_client(
+    client: matrix_sdk::ClientBuilder,
+    config: &Config,
+) -> Result<Client, String> {
+    client.build().await.map_err(|e| build_error(e, config))
+}
+
+fn confirm_store_reset() -> std::io::Result<bool> {
+    use std::io::Write;
+    eprint!("Move the store aside and log in again as a new device? Keys of encrypted messages that are not in the key backup are lost. [y/N] ");
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(matches!(line.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Move the store that could not be opened aside, if `--reset-store` was given or the user agrees.
+fn reset_store(config: &Config, error: impl std::fmt::Display, forced: bool) -> Result<(), String> {
+    let error = format!(
+        "Failed to open the store in {}: {}",
+        config.data_dir().display(),
+        error
+    );
+    if !forced {
+        eprintln!("{}", error);
+        eprintln!("The store may be corrupted or its format changed with an update. (If the store passphrase was mistyped, answer no and try again.)");
+        let confirmed =
+            confirm_store_reset().map_err(|e| format!("Unable to read user input: {}", e))?;
+        if !confirmed {
+            return Err(error);
+        }
+    }
+    let backup = session::move_store_aside(config)
+        .map_err(|e| format!("Failed to move the store aside: {}", e))?;
+    eprintln!("Moved the store to {}", backup.display());
+    eprintln!("The previous device is still listed by `devices` until it is logged out.");
     Ok(())
 }
 
-async fn login(config: &Config) -> Result<Client, String> {
-    // the location for `JsonStore` to save files to
-    let data_dir = config.data_dir();
+fn store_client_builder(
+    config: &Config,
+    store_passphrase: Option<&str>,
+) -> Result<matrix_sdk::ClientBuilder, String> {
+    Ok(client_builder(config)?
+        .handle_refresh_tokens()
+        .with_encryption_settings(matrix_sdk::encryption::EncryptionSettings {
+            // Enable backups if we know the backup key, which also enables automatic upload of new
+            // room keys. Keys are restored from the backup for messages that fail to decrypt.
+            auto_enable_backups: true,
+            backup_download_strategy:
+                matrix_sdk::encryption::BackupDownloadStrategy::AfterDecryptionFailure,
+            ..Default::default()
+        })
+        .sqlite_store(config.data_dir(), store_passphrase))
+}
 
-    let client = Client::builder()
-        .user_agent(APP_NAME)
-        .server_name(&config.host)
-        .sqlite_store(data_dir, None);
+/// Log in and return the client together with the passphrase of its store.
+async fn login(
+    config: &Config,
+    force_store_reset: bool,
+) -> Result<(Client, Option<String>), String> {
+    session::offer_store_migration(config)
+        .map_err(|e| format!("Failed to migrate the store: {}", e))?;
+    let store_passphrase = session::store_passphrase(config)
+        .map_err(|e| format!("Failed to get the store passphrase: {}", e))?;
 
     // create a new Client with the given homeserver url and config
-    let client = match client.build().await {
+    let client = match store_client_builder(config, store_passphrase.as_deref())?
+        .build()
+        .await
+    {
         Ok(client) => client,
-        Err(e) => return Err(format!("{:?}", e)),
+        Err(matrix_sdk::ClientBuildError::SqliteStore(e)) => {
+            reset_store(config, e, force_store_reset)?;
+            build_client(
+                store_client_builder(config, store_passphrase.as_deref())?,
+                config,
+            )
+            .await?
+        }
+        Err(e) => return Err(build_error(e, config)),
     };
 
     if try_restore_session(&client, &config).await.is_err() {
@@ -85,20 +206,11 @@ async fn login(config: &Config) -> Result<Client, String> {
                     let login = client
                         .matrix_auth()
                         .login_username(&config.user, &pw)
-                        .initial_device_display_name(&device_name);
+                        .initial_device_display_name(&device_name)
+                        .request_refresh_token();
                     match login.send().await {
                         Ok(response) => {
-                            let session = MatrixSession {
-                                meta: matrix_sdk::SessionMeta {
-                                    user_id: response.user_id,
-                                    device_id: response.device_id,
-                                },
-                                tokens: matrix_sdk::matrix_auth::MatrixSessionTokens {
-                                    access_token: response.access_token,
-                                    refresh_token: None,
-                                },
-                            };
-
+                            let session = MatrixSession::from(&response);
                             try_store_session(&config, &session).unwrap();
                             break;
                         }
@@ -119,7 +231,41 @@ async fn login(config: &Config) -> Result<Client, String> {
         }
     }
     eprintln!("Logged in as {}", config.user);
-    Ok(client)
+    Ok((client, store_passphrase))
+}
+
+/// Ask for the recovery key if secret storage is set up, but its secrets (cross-signing keys and
+/// the backup key) are not available on this device yet.
+async fn try_unlock_secret_storage(client: &Client) {
+    use matrix_sdk::encryption::recovery::RecoveryState;
+
+    client
+        .encryption()
+        .wait_for_e2ee_initialization_tasks()
+        .await;
+    let recovery = client.encryption().recovery();
+    if recovery.state() != RecoveryState::Incomplete {
+        return;
+    }
+    eprintln!("Secret storage is locked. Provide the recovery key or passphrase to unlock it (leave empty to skip):");
+    loop {
+        match rpassword::read_password_from_tty(Some("Recovery key: ")) {
+            Ok(key) if key.is_empty() => break,
+            Ok(key) => match recovery.recover(key.trim()).await {
+                Ok(()) => {
+                    eprintln!("Unlocked secret storage");
+                    break;
+                }
+                Err(e) => eprintln!("Failed to unlock secret storage: {}", e),
+            },
+            Err(e) => {
+                // E.g. when started without a terminal. It can be unlocked from the tui later on.
+                tracing::warn!("Not unlocking secret storage, no recovery key: {}", e);
+                eprintln!("Skipping the recovery key: {}", e);
+                break;
+            }
+        }
+    }
 }
 
 #[derive(StructOpt, Clone)]
@@ -128,10 +274,211 @@ struct VerifyInitiate {
     device_id: String,
 }
 
+#[derive(StructOpt, Clone)]
+struct Devices {
+    #[structopt(long, help = "Print one JSON object per device")]
+    json: bool,
+}
+
+#[derive(StructOpt, Clone)]
+enum DeviceAction {
+    #[structopt(about = "Change the display name of a device")]
+    Rename {
+        #[structopt()]
+        device_id: String,
+        #[structopt()]
+        name: String,
+    },
+}
+
+#[derive(StructOpt, Clone)]
+struct Device {
+    #[structopt(subcommand)]
+    action: DeviceAction,
+}
+
 #[derive(StructOpt, Clone)]
 struct Logout {
-    #[structopt(required = true)]
+    #[structopt(help = "Devices to log out. Select them interactively if omitted")]
     device_ids: Vec<String>,
+    #[structopt(long, help = "Log out all devices except the current one")]
+    all_others: bool,
+    #[structopt(
+        long,
+        help = "Log out the current device, remove its stored session and move its store aside"
+    )]
+    current: bool,
+}
+
+#[derive(StructOpt, Clone)]
+enum AccountAction {
+    #[structopt(about = "Change the password of the account")]
+    Password,
+    #[structopt(about = "List the email addresses bound to the account")]
+    Emails,
+    #[structopt(about = "Bind an email address to the account")]
+    AddEmail {
+        #[structopt()]
+        email: String,
+    },
+    #[structopt(about = "Remove an email address from the account")]
+    RemoveEmail {
+        #[structopt()]
+        email: String,
+    },
+}
+
+#[derive(StructOpt, Clone)]
+struct Account {
+    #[structopt(subcommand)]
+    action: AccountAction,
+}
+
+#[derive(StructOpt, Clone)]
+enum BackupAction {
+    #[structopt(about = "Create a new server-side key backup and print the recovery key")]
+    Enable,
+    #[structopt(about = "Connect to an existing backup using the recovery key")]
+    Restore,
+    #[structopt(about = "Show the state of the key backup")]
+    Status,
+}
+
+#[derive(StructOpt, Clone)]
+struct Backup {
+    #[structopt(subcommand)]
+    action: BackupAction,
+}
+
+#[derive(StructOpt, Clone)]
+enum KeysAction {
+    #[structopt(about = "Export all room keys to a passphrase-encrypted file")]
+    Export {
+        #[structopt()]
+        file: PathBuf,
+    },
+    #[structopt(about = "Import room keys from a passphrase-encrypted file (e.g., from Element)")]
+    Import {
+        #[structopt()]
+        file: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Clone)]
+struct Keys {
+    #[structopt(subcommand)]
+    action: KeysAction,
+}
+
+#[derive(StructOpt, Clone)]
+struct SendMessage {
+    #[structopt(help = "Room id, alias or name")]
+    room: String,
+    #[structopt(help = "The message to send. Read from stdin if omitted")]
+    message: Option<String>,
+    #[structopt(long, help = "Send as notice (e.g., for bots)")]
+    notice: bool,
+    #[structopt(long, help = "Interpret the message as markdown")]
+    markdown: bool,
+}
+
+#[derive(StructOpt, Clone)]
+struct Watch {
+    #[structopt(help = "Room ids, aliases or names to watch. All rooms if omitted")]
+    rooms: Vec<String>,
+}
+
+#[derive(StructOpt, Clone)]
+struct Rooms {
+    #[structopt(long, help = "Print one JSON object per room")]
+    json: bool,
+}
+
+#[derive(StructOpt, Clone)]
+struct Export {
+    #[structopt(help = "Room id, alias or name")]
+    room: String,
+    #[structopt(
+        long,
+        default_value = "text",
+        help = "Output format: json, html or text"
+    )]
+    format: export::Format,
+    #[structopt(short, long, help = "File to write the history to")]
+    output: PathBuf,
+    #[structopt(long, parse(try_from_str = export::parse_date), help = "Only export messages from this day (YYYY-MM-DD) on")]
+    since: Option<u64>,
+    #[structopt(long, parse(try_from_str = export::parse_date), help = "Only export messages up to and including this day (YYYY-MM-DD)")]
+    until: Option<u64>,
+    #[structopt(long, help = "Download attached media into this directory")]
+    media: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Clone)]
+struct Upload {
+    #[structopt(help = "The file to upload")]
+    file: PathBuf,
+}
+
+#[derive(StructOpt, Clone)]
+struct Download {
+    #[structopt(help = "An mxc uri or a permalink to a message with an attachment")]
+    source: String,
+    #[structopt(help = "Target file or directory. Defaults to the current directory")]
+    dest: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Clone)]
+struct Completions {
+    #[structopt(possible_values = &structopt::clap::Shell::variants(), case_insensitive = true)]
+    shell: structopt::clap::Shell,
+}
+
+#[derive(StructOpt, Clone)]
+struct Join {
+    #[structopt(help = "Room id or alias")]
+    room: String,
+}
+
+#[derive(StructOpt, Clone)]
+struct Knock {
+    #[structopt(help = "Room id or alias")]
+    room: String,
+    #[structopt(long)]
+    reason: Option<String>,
+}
+
+#[derive(StructOpt, Clone)]
+struct Leave {
+    #[structopt(help = "Room id, alias or name")]
+    room: String,
+}
+
+#[derive(StructOpt, Clone)]
+struct Invite {
+    #[structopt(help = "Room id, alias or name")]
+    room: String,
+    #[structopt(help = "User id, e.g. @alice:example.org")]
+    user: String,
+}
+
+#[derive(StructOpt, Clone)]
+struct Redact {
+    #[structopt(help = "Room id, alias or name")]
+    room: String,
+    #[structopt()]
+    event_id: String,
+    #[structopt(long)]
+    reason: Option<String>,
+}
+
+#[derive(StructOpt, Clone)]
+struct Ctl {
+    #[structopt(
+        required = true,
+        help = "One of: send <room> <message>, focus-room <room>, set-dnd on|off|toggle"
+    )]
+    command: Vec<String>,
 }
 
 #[derive(StructOpt, Clone)]
@@ -139,13 +486,51 @@ enum Command {
     #[structopt(about = "Start the interactive tui client (the default action)")]
     Tui,
     #[structopt(about = "List registered devices")]
-    Devices,
+    Devices(Devices),
+    #[structopt(about = "Manage a single device")]
+    Device(Device),
     #[structopt(about = "Log out (delete) devices from the server")]
     Logout(Logout),
+    #[structopt(about = "Change the password or manage email addresses of the account")]
+    Account(Account),
     #[structopt(about = "Start verification of a specific device")]
     VerifyInitiate(VerifyInitiate),
     #[structopt(about = "Wait for incoming device verifications")]
     VerifyWait,
+    #[structopt(about = "Manage the server-side backup of room keys")]
+    Backup(Backup),
+    #[structopt(about = "Export or import room keys")]
+    Keys(Keys),
+    #[structopt(about = "Send a message to a room and exit")]
+    Send(SendMessage),
+    #[structopt(about = "Print incoming messages as JSON lines")]
+    Watch(Watch),
+    #[structopt(about = "List joined rooms")]
+    Rooms(Rooms),
+    #[structopt(about = "Export the history of a room to a file")]
+    Export(Export),
+    #[structopt(about = "Upload a file to the media repository and print its mxc uri")]
+    Upload(Upload),
+    #[structopt(about = "Download media by mxc uri or message permalink")]
+    Download(Download),
+    #[structopt(about = "Show the account and device of the current session")]
+    Whoami,
+    #[structopt(about = "Join a room")]
+    Join(Join),
+    #[structopt(about = "Ask to be let into a room that can be joined by knocking")]
+    Knock(Knock),
+    #[structopt(about = "Leave a room")]
+    Leave(Leave),
+    #[structopt(about = "Invite a user to a room")]
+    Invite(Invite),
+    #[structopt(about = "Redact (delete) an event")]
+    Redact(Redact),
+    #[structopt(about = "Control a running tui instance")]
+    Ctl(Ctl),
+    #[structopt(about = "Check the configuration, the connection and the stored session")]
+    Doctor,
+    #[structopt(about = "Print a completion script for the given shell to stdout")]
+    Completions(Completions),
 }
 
 #[derive(StructOpt)]
@@ -155,8 +540,35 @@ struct Options {
     host: Option<matrix_sdk::OwnedServerName>,
     #[structopt(short = "u", long = "user")]
     user: Option<String>,
+    #[structopt(
+        long = "homeserver-url",
+        parse(try_from_str = config::parse_homeserver_url),
+        help = "Url of the client api, if it cannot be discovered via .well-known of the host"
+    )]
+    homeserver_url: Option<String>,
+    #[structopt(
+        long = "data-dir",
+        help = "Directory of the store and the session, instead of one derived from the account"
+    )]
+    data_dir: Option<PathBuf>,
     #[structopt(short = "c", long = "config")]
     config_file: Option<PathBuf>,
+    #[structopt(
+        short = "p",
+        long = "profile",
+        help = "Account profile defined in the config. Repeat to use multiple accounts in the tui"
+    )]
+    profiles: Vec<String>,
+    #[structopt(
+        long = "log-level",
+        help = "Log filter in the syntax of RUST_LOG, e.g. debug or info,matrix_sdk=warn"
+    )]
+    log_level: Option<String>,
+    #[structopt(
+        long = "reset-store",
+        help = "Move the local store aside without asking if it cannot be opened and log in as a new device"
+    )]
+    reset_store: bool,
     #[structopt(subcommand)]
     command: Option<Command>,
 }
@@ -174,6 +586,11 @@ fn main() {
     // handling.
     match options.command() {
         Command::Tui => tui_app::init(),
+        Command::Completions(c) => {
+            // Neither a config nor a login is required for this.
+            Options::clap().gen_completions_to(APP_NAME, c.shell, &mut std::io::stdout());
+            return;
+        }
         _ => {}
     }
 
@@ -182,17 +599,68 @@ fn main() {
     let rt = Runtime::new().unwrap();
     if let Err(e) = rt.block_on(tokio_main(options)) {
         eprintln!("{}", e);
+        // Lets scripts detect failures, e.g. of a verification.
+        std::process::exit(1);
     }
 }
 
-async fn tokio_main(options: Options) -> Result<(), Box<dyn std::error::Error>> {
-    let _log_guard = log::init(LOG_RETENTION_POLICY)?;
-    let command = options.command();
+fn load_config(
+    config_file: &Option<PathBuf>,
+    profile: Option<String>,
+    options: &Options,
+) -> Result<(Config, tui_app::tui::actions::CommandEnvironment), Box<dyn std::error::Error>> {
     let mut config = ConfigBuilder::new();
 
     config.configure(include_str!("base_config.lua"))?;
 
-    let config_file = options.config_file.or({
+    if let Some(config_file) = config_file {
+        let content = std::fs::read_to_string(config_file)?;
+        if let Some(dir) = config_file.parent() {
+            config.set_config_dir(dir)?;
+        }
+        config.configure(&content)?;
+    }
+
+    if let Some(profile) = profile {
+        config.select_profile(profile)?;
+    }
+
+    apply_overrides(&mut config, options);
+
+    Ok(config.finalize()?)
+}
+
+fn apply_overrides(config: &mut ConfigBuilder, options: &Options) {
+    if let Some(user) = &options.user {
+        config.set_user(user.clone());
+    }
+    if let Some(host) = &options.host {
+        config.set_host(host.clone());
+    }
+    if let Some(url) = &options.homeserver_url {
+        config.set_homeserver_url(url.clone());
+    }
+    if let Some(dir) = &options.data_dir {
+        config.set_data_dir(dir.clone());
+    }
+}
+
+/// The log filter given on the command line takes precedence over RUST_LOG, which takes precedence
+/// over the one in the config.
+fn log_filter(options: &Options, config: Option<&Config>) -> Option<String> {
+    options.log_level.clone().or_else(|| {
+        if std::env::var_os("RUST_LOG").is_some() {
+            None
+        } else {
+            config.and_then(|c| c.log_filter.clone())
+        }
+    })
+}
+
+async fn tokio_main(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    let command = options.command();
+
+    let config_file = options.config_file.clone().or({
         let f = PathBuf::from(
             dirs::config_dir()
                 .unwrap()
@@ -205,30 +673,112 @@ async fn tokio_main(options: Options) -> Result<(), Box<dyn std::error::Error>>
             None
         }
     });
-    if let Some(config_file) = config_file {
-        let content = std::fs::read_to_string(config_file)?;
-        config.configure(&content)?;
-    }
 
-    if let Some(user) = options.user {
-        config.set_user(user);
+    if let Command::Doctor = command {
+        // Runs before the login below, since that is one of the things to check.
+        let _log_guard = log::init(
+            &log::RetentionPolicy::default(),
+            log_filter(&options, None).as_deref(),
+        )?;
+        let profile = options.profiles.first().cloned();
+        return doctor::run(config_file, profile, |c| apply_overrides(c, &options)).await;
     }
-    if let Some(host) = options.host {
-        config.set_host(host);
+
+    if options.profiles.len() > 1 {
+        if options.user.is_some()
+            || options.host.is_some()
+            || options.homeserver_url.is_some()
+            || options.data_dir.is_some()
+        {
+            return Err(
+                "--user, --host, --homeserver-url and --data-dir cannot be combined with multiple profiles"
+                    .into(),
+            );
+        }
+        let Command::Tui = command else {
+            return Err("Multiple profiles are only supported by the tui".into());
+        };
+        let mut configs = Vec::new();
+        for profile in &options.profiles {
+            configs.push(load_config(&config_file, Some(profile.clone()), &options)?);
+        }
+        // Like key bindings and other tui settings, logging is configured by the first profile.
+        let filter = log_filter(&options, Some(&configs[0].0));
+        let _log_guard = log::init(&configs[0].0.log_retention, filter.as_deref())?;
+        let mut accounts = Vec::new();
+        let mut key_mapping = None;
+        for ((config, mapping), profile) in configs.into_iter().zip(&options.profiles) {
+            eprintln!("Logging in to profile '{}'", profile);
+            let (client, store_passphrase) = login(&config, options.reset_store).await?;
+            try_unlock_secret_storage(&client).await;
+            accounts.push((client, config, store_passphrase));
+            key_mapping.get_or_insert(mapping);
+        }
+        tui_app::run(accounts, key_mapping.unwrap()).await?;
+        return Ok(());
     }
 
-    let (config, key_mapping) = config.finalize()?;
+    let (config, key_mapping) =
+        load_config(&config_file, options.profiles.first().cloned(), &options)?;
+    let _log_guard = log::init(
+        &config.log_retention,
+        log_filter(&options, Some(&config)).as_deref(),
+    )?;
+
+    if let Command::Ctl(ctl) = &command {
+        // Talks to the running instance, which is already logged in.
+        return ctl::run(&config.control_socket_path(), ctl.command.clone()).await;
+    }
 
-    let client = login(&config).await?;
+    let (client, store_passphrase) = login(&config, options.reset_store).await?;
 
     match command {
-        Command::Tui => tui_app::run(client, config, key_mapping).await?,
-        Command::Devices => devices::run(client).await?,
-        Command::Logout(l) => logout::run(client, l.device_ids).await?,
+        Command::Tui => {
+            try_unlock_secret_storage(&client).await;
+            tui_app::run(vec![(client, config, store_passphrase)], key_mapping).await?
+        }
+        Command::Devices(d) => devices::run(client, d.json).await?,
+        Command::Device(d) => match d.action {
+            DeviceAction::Rename { device_id, name } => {
+                devices::rename(client, device_id.into(), name).await?
+            }
+        },
+        Command::Logout(l) => {
+            logout::run(client, &config, l.device_ids, l.all_others, l.current).await?
+        }
         Command::VerifyInitiate(v) => {
             verification_initiate::run(client, v.device_id.clone()).await?
         }
         Command::VerifyWait => verification_wait::run(client).await?,
+        Command::Account(a) => account::run(client, a.action).await?,
+        Command::Backup(b) => backup::run(client, b.action).await?,
+        Command::Keys(k) => keys::run(client, k.action).await?,
+        Command::Send(s) => send::run(client, s.room, s.message, s.notice, s.markdown).await?,
+        Command::Watch(w) => watch::run(client, w.rooms).await?,
+        Command::Rooms(r) => rooms::run(client, r.json).await?,
+        Command::Export(e) => {
+            let options = export::ExportOptions {
+                format: e.format,
+                output: e.output,
+                since: e.since,
+                // Include the whole day given by the user
+                until: e.until.map(|t| t + 24 * 60 * 60 * 1000),
+                media: e.media,
+                cache: media::MediaCache::new(&config),
+            };
+            export::run(client, e.room, options).await?
+        }
+        Command::Upload(u) => upload::run(client, u.file).await?,
+        Command::Download(d) => download::run(client, d.source, d.dest).await?,
+        Command::Whoami => whoami::run(client).await?,
+        Command::Join(j) => manage::join(client, j.room).await?,
+        Command::Knock(k) => manage::knock(client, k.room, k.reason).await?,
+        Command::Leave(l) => manage::leave(client, l.room).await?,
+        Command::Invite(i) => manage::invite(client, i.room, i.user).await?,
+        Command::Redact(r) => manage::redact(client, r.room, r.event_id, r.reason).await?,
+        Command::Doctor | Command::Completions(_) | Command::Ctl(_) => {
+            unreachable!("handled before the login")
+        }
     }
     Ok(())
 }
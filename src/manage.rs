@@ -0,0 +1,68 @@
+use matrix_sdk::ruma::api::client::knock::knock_room;
+use matrix_sdk::ruma::{EventId, OwnedRoomId, RoomOrAliasId, UserId};
+use matrix_sdk::{config::SyncSettings, Client};
+
+type Error = Box<dyn std::error::Error>;
+
+pub async fn join(client: Client, room: String) -> Result<(), Error> {
+    let id = RoomOrAliasId::parse(&room)
+        .map_err(|e| format!("'{}' is not a room id or alias: {}", room, e))?;
+    let room = client.join_room_by_id_or_alias(&id, &[]).await?;
+    println!("Joined {}", room.room_id());
+    Ok(())
+}
+
+/// Ask the members of a room with join rule `knock` to let us in. Returns the id of the room.
+pub async fn send_knock(
+    client: &Client,
+    room: &RoomOrAliasId,
+    reason: Option<String>,
+) -> matrix_sdk::HttpResult<OwnedRoomId> {
+    let mut request = knock_room::v3::Request::new(room.to_owned());
+    request.reason = reason;
+    Ok(client.send(request, None).await?.room_id)
+}
+
+pub async fn knock(client: Client, room: String, reason: Option<String>) -> Result<(), Error> {
+    let id = RoomOrAliasId::parse(&room)
+        .map_err(|e| format!("'{}' is not a room id or alias: {}", room, e))?;
+    let room_id = send_knock(&client, &id, reason).await?;
+    println!(
+        "Knocked on {}, you can join once a member has invited you",
+        room_id
+    );
+    Ok(())
+}
+
+pub async fn leave(client: Client, room: String) -> Result<(), Error> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let room = crate::resolve::find_room(&client, &room).await?;
+    room.leave().await?;
+    println!("Left {}", room.room_id());
+    Ok(())
+}
+
+pub async fn invite(client: Client, room: String, user: String) -> Result<(), Error> {
+    let user_id =
+        UserId::parse(&user).map_err(|e| format!("'{}' is not a user id: {}", user, e))?;
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let room = crate::resolve::find_room(&client, &room).await?;
+    room.invite_user_by_id(&user_id).await?;
+    println!("Invited {} to {}", user_id, room.room_id());
+    Ok(())
+}
+
+pub async fn redact(
+    client: Client,
+    room: String,
+    event_id: String,
+    reason: Option<String>,
+) -> Result<(), Error> {
+    let event_id = EventId::parse(&event_id)
+        .map_err(|e| format!("'{}' is not an event id: {}", event_id, e))?;
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let room = crate::resolve::find_room(&client, &room).await?;
+    room.redact(&event_id, reason.as_deref(), None).await?;
+    println!("Redacted {}", event_id);
+    Ok(())
+}
@@ -0,0 +1,371 @@
+use matrix_sdk::media::{MediaEventContent, MediaFormat, MediaThumbnailSettings};
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Client;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::Config;
+
+pub async fn write_source(
+    c: &Client,
+    source: MediaSource,
+    target: &mut (dyn std::io::Write + Send),
+) -> Result<(), String> {
+    download(c, source, MediaFormat::File, target).await
+}
+
+async fn download(
+    c: &Client,
+    source: MediaSource,
+    format: MediaFormat,
+    target: &mut (dyn std::io::Write + Send),
+) -> Result<(), String> {
+    match c
+        .media()
+        .get_media_content(
+            &matrix_sdk::media::MediaRequestParameters { source, format },
+            false, // Files are cached by MediaCache instead of in the store
+        )
+        .await
+    {
+        Ok(bytes) => target
+            .write_all(&bytes[..])
+            .and_then(|_| target.flush())
+            .map_err(|e| format!("can't write file: {}", e)),
+        Err(e) => Err(format!("can't open file: {:?}", e)),
+    }
+}
+
+/// Downloaded media files under the data dir, evicting the least recently used files once the
+/// configured size is exceeded.
+#[derive(Clone)]
+pub struct MediaCache {
+    dir: PathBuf,
+    size_limit: u64,
+}
+
+/// A downloaded media file: Encrypted media is not cached, since the decrypted content would be
+/// kept on disk without the protection of the store. Those files are deleted on drop unless kept.
+pub enum MediaFile {
+    Cached(PathBuf),
+    Decrypted(tempfile::TempPath),
+}
+
+impl MediaFile {
+    /// Keep the file even after this is dropped, e.g., because a program may still open it later.
+    pub fn keep(self) -> Result<PathBuf, String> {
+        match self {
+            MediaFile::Cached(path) => Ok(path),
+            MediaFile::Decrypted(path) => {
+                path.keep().map_err(|e| format!("can't keep file: {}", e))
+            }
+        }
+    }
+}
+
+impl std::ops::Deref for MediaFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        match self {
+            MediaFile::Cached(path) => path,
+            MediaFile::Decrypted(path) => path,
+        }
+    }
+}
+
+/// Media ids are immutable, so the mxc uri identifies the content. The uri is hashed, since server
+/// names and media ids may contain anything and names that differ only in case may collide.
+fn cache_file_name(uri: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(uri.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Most file systems limit file names to 255 bytes.
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// A file name for media named by the sender (e.g., the body of a file message) that stays in the
+/// target directory: Path separators and control characters are replaced and long names are
+/// shortened, keeping the extension.
+pub fn sanitize_file_name(name: &str) -> String {
+    let mut name = name
+        .chars()
+        .map(|c| if c == '/' || c.is_control() { '_' } else { c })
+        .collect::<String>();
+    if name.len() > MAX_FILE_NAME_LEN {
+        let extension = Path::new(&name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .filter(|e| e.len() < 16)
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+        let mut end = MAX_FILE_NAME_LEN - extension.len();
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name = format!("{}{}", &name[..end], extension);
+    }
+    name
+}
+
+impl MediaCache {
+    pub fn new(config: &Config) -> Self {
+        MediaCache {
+            dir: config.data_dir().join("media"),
+            size_limit: config.media_cache_size,
+        }
+    }
+
+    /// The file with the content of `source`, downloading it if it is not cached.
+    pub async fn get(&self, c: &Client, source: MediaSource) -> Result<MediaFile, String> {
+        match source {
+            MediaSource::Plain(ref uri) => {
+                let file_name = cache_file_name(uri.as_str());
+                self.fetch(c, source, MediaFormat::File, file_name)
+                    .await
+                    .map(MediaFile::Cached)
+            }
+            MediaSource::Encrypted(_) => {
+                let mut file = tempfile::NamedTempFile::new()
+                    .map_err(|e| format!("can't create file: {}", e))?;
+                download(c, source, MediaFormat::File, &mut file).await?;
+                Ok(MediaFile::Decrypted(file.into_temp_path()))
+            }
+        }
+    }
+
+    /// A server-side thumbnail that fits into `width`x`height`. Servers cannot create thumbnails
+    /// of encrypted media, so the thumbnail attached by the sender (or the original file) is used
+    /// for those.
+    pub async fn get_thumbnail(
+        &self,
+        c: &Client,
+        content: impl MediaEventContent,
+        width: u32,
+        height: u32,
+    ) -> Result<MediaFile, String> {
+        match content.source() {
+            Some(MediaSource::Plain(uri)) => {
+                let file_name = format!(
+                    "{}_thumb_{}x{}",
+                    cache_file_name(uri.as_str()),
+                    width,
+                    height
+                );
+                let settings = MediaThumbnailSettings::new(UInt::from(width), UInt::from(height));
+                self.fetch(
+                    c,
+                    MediaSource::Plain(uri),
+                    MediaFormat::Thumbnail(settings),
+                    file_name,
+                )
+                .await
+                .map(MediaFile::Cached)
+            }
+            Some(source) => match content.thumbnail_source() {
+                Some(thumbnail) => self.get(c, thumbnail).await,
+                None => self.get(c, source).await,
+            },
+            None => Err("can't open file: No content".to_owned()),
+        }
+    }
+
+    async fn fetch(
+        &self,
+        c: &Client,
+        source: MediaSource,
+        format: MediaFormat,
+        file_name: String,
+    ) -> Result<PathBuf, String> {
+        let path = self.dir.join(file_name);
+        if path.exists() {
+            // Mark as recently used
+            if let Err(e) = std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|f| f.set_modified(SystemTime::now()))
+            {
+                tracing::warn!("Failed to update {}: {}", path.display(), e);
+            }
+            return Ok(path);
+        }
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("can't create {}: {}", self.dir.display(), e))?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)
+            .map_err(|e| format!("can't create file: {}", e))?;
+        download(c, source, format, &mut file).await?;
+        file.persist(&path)
+            .map_err(|e| format!("can't write file: {}", e))?;
+        self.evict(&path);
+        Ok(path)
+    }
+
+    fn evict(&self, keep: &Path) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => return tracing::warn!("Failed to read {}: {}", self.dir.display(), e),
+        };
+        let mut files = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let metadata = e.metadata().ok()?;
+                Some((e.path(), metadata.len(), metadata.modified().ok()?))
+            })
+            .collect::<Vec<_>>();
+        let mut total = files.iter().map(|(_, len, _)| len).sum::<u64>();
+        files.sort_by_key(|(_, _, modified)| *modified);
+        for (path, len, _) in files {
+            if total <= self.size_limit {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => total -= len,
+                Err(e) => tracing::warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    pub async fn get_file(
+        &self,
+        c: &Client,
+        content: impl MediaEventContent,
+    ) -> Result<MediaFile, String> {
+        if let Some(source) = content.source() {
+            self.get(c, source).await
+        } else {
+            Err("can't open file: No content".to_owned())
+        }
+    }
+}
+
+/// Decoder for still images. GIFs are left alone, since animations would be reduced to their first
+/// frame.
+fn image_decoder(data: &[u8]) -> Result<Option<impl image::ImageDecoder + '_>, String> {
+    use image::ImageFormat;
+
+    let reader = image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| format!("can't read image: {}", e))?;
+    if !matches!(
+        reader.format(),
+        Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+    ) {
+        return Ok(None);
+    }
+    reader
+        .into_decoder()
+        .map(Some)
+        .map_err(|e| format!("can't decode image: {}", e))
+}
+
+fn decode_oriented(mut decoder: impl image::ImageDecoder) -> Result<image::DynamicImage, String> {
+    let orientation = decoder
+        .orientation()
+        .map_err(|e| format!("can't decode image: {}", e))?;
+    let mut img = image::DynamicImage::from_decoder(decoder)
+        .map_err(|e| format!("can't decode image: {}", e))?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Decode and encode the image again without any metadata, optionally scaling it down to fit into
+/// a square of `max_dimension`.
+fn reencode(
+    decoder: impl image::ImageDecoder,
+    max_dimension: Option<u32>,
+    quality: u8,
+) -> Result<(Vec<u8>, mime_guess::Mime), String> {
+    use image::codecs::{jpeg::JpegEncoder, png::PngEncoder};
+
+    // The metadata is not carried over, so the orientation has to be applied to the pixels.
+    let mut img = decode_oriented(decoder)?;
+    if let Some(max) = max_dimension {
+        img = img.resize(max, max, image::imageops::FilterType::Lanczos3);
+    }
+
+    let mut buf = Vec::new();
+    let mime = if img.color().has_alpha() {
+        img.write_with_encoder(PngEncoder::new(&mut buf))
+            .map_err(|e| format!("can't encode image: {}", e))?;
+        mime_guess::mime::IMAGE_PNG
+    } else {
+        img.to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buf, quality))
+            .map_err(|e| format!("can't encode image: {}", e))?;
+        mime_guess::mime::IMAGE_JPEG
+    };
+    Ok((buf, mime))
+}
+
+/// Re-encode an image whose width or height exceeds `max_dimension` to fit into a square of that
+/// size. Returns `None` if the image is small enough or not a (still) image we can decode.
+pub fn downscale_image(
+    data: &[u8],
+    max_dimension: u32,
+    quality: u8,
+) -> Result<Option<(Vec<u8>, mime_guess::Mime)>, String> {
+    use image::ImageDecoder;
+
+    let Some(decoder) = image_decoder(data)? else {
+        return Ok(None);
+    };
+    let (width, height) = decoder.dimensions();
+    if width <= max_dimension && height <= max_dimension {
+        return Ok(None);
+    }
+    reencode(decoder, Some(max_dimension), quality).map(Some)
+}
+
+/// Remove the EXIF metadata (location, camera model, ...) from an image. Returns `None` if there
+/// is none.
+pub fn strip_exif(data: &[u8], quality: u8) -> Result<Option<(Vec<u8>, mime_guess::Mime)>, String> {
+    use image::{metadata::Orientation, ImageDecoder};
+    use img_parts::{DynImage, ImageEXIF};
+
+    let image = DynImage::from_bytes(img_parts::Bytes::copy_from_slice(data))
+        .map_err(|e| format!("can't read image: {}", e))?;
+    let Some(mut image) = image.filter(|i| i.exif().is_some()) else {
+        return Ok(None);
+    };
+    if let Some(mut decoder) = image_decoder(data)? {
+        // Viewers would no longer rotate the image without the metadata, so it is rotated here.
+        if decoder.orientation().ok() != Some(Orientation::NoTransforms) {
+            return reencode(decoder, None, quality).map(Some);
+        }
+    }
+    let mime = match &image {
+        DynImage::Jpeg(_) => mime_guess::mime::IMAGE_JPEG,
+        DynImage::Png(_) => mime_guess::mime::IMAGE_PNG,
+        DynImage::WebP(_) => mime_guess::from_ext("webp").first_or_octet_stream(),
+    };
+    image.set_exif(None);
+    Ok(Some((image.encoder().bytes().to_vec(), mime)))
+}
+
+const BLURHASH_COMPONENTS_X: u32 = 4;
+const BLURHASH_COMPONENTS_Y: u32 = 3;
+const BLURHASH_INPUT_SIZE: u32 = 64;
+
+/// Width, height and blurhash of an image, which other clients show as a placeholder until the
+/// image is loaded.
+pub fn blurhash(data: &[u8]) -> Result<Option<(u32, u32, String)>, String> {
+    let Some(decoder) = image_decoder(data)? else {
+        return Ok(None);
+    };
+    let img = decode_oriented(decoder)?;
+    // The hash only captures a few color components, so a small version of the image suffices.
+    let small = img
+        .thumbnail(BLURHASH_INPUT_SIZE, BLURHASH_INPUT_SIZE)
+        .to_rgba8();
+    let hash = blurhash::encode_image(BLURHASH_COMPONENTS_X, BLURHASH_COMPONENTS_Y, &small)
+        .map_err(|e| format!("can't compute blurhash: {}", e))?;
+    Ok(Some((img.width(), img.height(), hash)))
+}
@@ -0,0 +1,42 @@
+use matrix_sdk::ruma::{RoomAliasId, RoomId};
+use matrix_sdk::{Client, Room, RoomState};
+
+/// Find a joined room by its id, one of its aliases or its (display) name.
+pub async fn find_room(client: &Client, name: &str) -> Result<Room, String> {
+    if let Ok(id) = RoomId::parse(name) {
+        return joined_room(client, &id).ok_or_else(|| format!("Room {} is not joined", id));
+    }
+    if let Ok(alias) = RoomAliasId::parse(name) {
+        let response = client
+            .resolve_room_alias(&alias)
+            .await
+            .map_err(|e| format!("Failed to resolve alias {}: {}", alias, e))?;
+        return joined_room(client, &response.room_id)
+            .ok_or_else(|| format!("Room {} is not joined", alias));
+    }
+    let mut candidates = Vec::new();
+    for room in client.joined_rooms() {
+        let display_name = room
+            .compute_display_name()
+            .await
+            .ok()
+            .map(|n| n.to_string());
+        if room.name().as_deref() == Some(name) || display_name.as_deref() == Some(name) {
+            candidates.push(room);
+        }
+    }
+    match candidates.len() {
+        0 => Err(format!("No joined room named '{}'", name)),
+        1 => Ok(candidates.pop().unwrap()),
+        _ => Err(format!(
+            "Room name '{}' is ambiguous, use the room id instead",
+            name
+        )),
+    }
+}
+
+fn joined_room(client: &Client, id: &RoomId) -> Option<Room> {
+    client
+        .get_room(id)
+        .filter(|room| room.state() == RoomState::Joined)
+}
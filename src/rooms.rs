@@ -0,0 +1,37 @@
+use matrix_sdk::{config::SyncSettings, Client};
+
+pub async fn run(client: Client, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+
+    if !json {
+        println!("ID\tAlias\tName\tMembers\tUnread\tHighlights");
+    }
+    for room in client.joined_rooms() {
+        let name = room.compute_display_name().await?.to_string();
+        let alias = room.canonical_alias();
+        let members = room.joined_members_count();
+        let unread = room.unread_notification_counts();
+        if json {
+            let line = serde_json::json!({
+                "id": room.room_id(),
+                "alias": alias,
+                "name": name,
+                "members": members,
+                "unread": unread.notification_count,
+                "highlights": unread.highlight_count,
+            });
+            println!("{}", line);
+        } else {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                room.room_id(),
+                alias.as_ref().map(|a| a.as_str()).unwrap_or(""),
+                name,
+                members,
+                unread.notification_count,
+                unread.highlight_count
+            );
+        }
+    }
+    Ok(())
+}
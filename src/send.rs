@@ -0,0 +1,35 @@
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::{config::SyncSettings, Client};
+
+pub async fn run(
+    client: Client,
+    room: String,
+    message: Option<String>,
+    notice: bool,
+    markdown: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+    let room = crate::resolve::find_room(&client, &room).await?;
+
+    let body = match message {
+        Some(m) => m,
+        None => {
+            use std::io::Read;
+            let mut s = String::new();
+            std::io::stdin().read_to_string(&mut s)?;
+            s
+        }
+    };
+    if body.trim().is_empty() {
+        return Err("Refusing to send an empty message".into());
+    }
+
+    let content = match (notice, markdown) {
+        (false, false) => RoomMessageEventContent::text_plain(body),
+        (false, true) => RoomMessageEventContent::text_markdown(body),
+        (true, false) => RoomMessageEventContent::notice_plain(body),
+        (true, true) => RoomMessageEventContent::notice_markdown(body),
+    };
+    room.send(content).await?;
+    Ok(())
+}
@@ -0,0 +1,381 @@
+use matrix_sdk::matrix_auth::MatrixSession;
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use matrix_sdk_store_encryption::StoreCipher;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::config::{Config, SessionStorage};
+
+type Error = Box<dyn std::error::Error>;
+
+// Created by the sqlite store in the data dir, used to detect whether the store already exists.
+const CRYPTO_STORE_FILE: &str = "matrix-sdk-crypto.sqlite3";
+// All files of the sqlite store (including the journals) start with this.
+const STORE_FILE_PREFIX: &str = "matrix-sdk-";
+
+// The cipher protecting the session file is unlocked once per run, so that we can store
+// refreshed tokens later on without asking for the passphrase again.
+static SESSION_CIPHER: OnceLock<(StoreCipher, Vec<u8>)> = OnceLock::new();
+
+fn keyring_entry(config: &Config) -> Result<keyring::Entry, Error> {
+    Ok(keyring::Entry::new(crate::APP_NAME, &config.user_id())?)
+}
+
+fn read_passphrase(prompt: &str) -> Result<String, Error> {
+    Ok(rpassword::read_password_from_tty(Some(prompt))?)
+}
+
+fn choose_passphrase(prompt: &str) -> Result<String, Error> {
+    loop {
+        let passphrase = read_passphrase(prompt)?;
+        if passphrase.is_empty() {
+            continue;
+        }
+        if passphrase == read_passphrase("Repeat passphrase: ")? {
+            return Ok(passphrase);
+        }
+        eprintln!("Passphrases do not match");
+    }
+}
+
+fn unlock_cipher(exported: &[u8]) -> Result<&'static StoreCipher, Error> {
+    if let Some((cipher, _)) = SESSION_CIPHER.get() {
+        return Ok(cipher);
+    }
+    loop {
+        let passphrase = read_passphrase("Session passphrase: ")?;
+        match StoreCipher::import(&passphrase, exported) {
+            Ok(cipher) => {
+                let _ = SESSION_CIPHER.set((cipher, exported.to_owned()));
+                return Ok(&SESSION_CIPHER.get().unwrap().0);
+            }
+            Err(_) => eprintln!("Wrong passphrase"),
+        }
+    }
+}
+
+fn create_cipher() -> Result<&'static (StoreCipher, Vec<u8>), Error> {
+    if let Some(c) = SESSION_CIPHER.get() {
+        return Ok(c);
+    }
+    eprintln!("Choose a passphrase to encrypt the session file with:");
+    let passphrase = choose_passphrase("Session passphrase: ")?;
+    let cipher = StoreCipher::new()?;
+    let exported = cipher.export(&passphrase)?;
+    let _ = SESSION_CIPHER.set((cipher, exported));
+    Ok(SESSION_CIPHER.get().unwrap())
+}
+
+fn load_plain(config: &Config) -> Result<MatrixSession, Error> {
+    let session_file = std::fs::File::open(config.session_file_path())?;
+    Ok(serde_json::from_reader(session_file)?)
+}
+
+fn load_encrypted(config: &Config) -> Result<MatrixSession, Error> {
+    let content = std::fs::read(config.encrypted_session_file_path())?;
+    let mut content: serde_json::Value = serde_json::from_slice(&content)?;
+    let key: Vec<u8> = serde_json::from_value(content["key"].take())?;
+    let session: Vec<u8> = serde_json::from_value(content["session"].take())?;
+    let cipher = unlock_cipher(&key)?;
+    Ok(cipher.decrypt_value(&session)?)
+}
+
+fn load_keyring(config: &Config) -> Result<MatrixSession, Error> {
+    let secret = keyring_entry(config)?.get_password()?;
+    Ok(serde_json::from_str(&secret)?)
+}
+
+fn write_file(path: std::path::PathBuf, content: &[u8]) -> Result<(), Error> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(content)?;
+    Ok(())
+}
+
+pub fn try_load_session(config: &Config) -> Result<MatrixSession, Error> {
+    let plain_path = config.session_file_path();
+    if config.session_storage != SessionStorage::Plain && plain_path.exists() {
+        // Migrate from the plaintext session file used previously.
+        let session = load_plain(config)?;
+        try_store_session(config, &session)?;
+        // Only remove the plaintext file once the session can be read back from the new storage.
+        let stored = match config.session_storage {
+            SessionStorage::Keyring => load_keyring(config)?,
+            _ => load_encrypted(config)?,
+        };
+        if serde_json::to_value(&stored)? != serde_json::to_value(&session)? {
+            return Err("The migrated session differs from the plaintext session file".into());
+        }
+        std::fs::remove_file(plain_path)?;
+        eprintln!("Migrated the plaintext session file.");
+        return Ok(session);
+    }
+    match config.session_storage {
+        SessionStorage::Plain => load_plain(config),
+        SessionStorage::Passphrase => load_encrypted(config),
+        SessionStorage::Keyring => load_keyring(config),
+    }
+}
+
+pub fn try_store_session(config: &Config, session: &MatrixSession) -> Result<(), Error> {
+    match config.session_storage {
+        SessionStorage::Plain => {
+            write_file(config.session_file_path(), &serde_json::to_vec(session)?)?;
+        }
+        SessionStorage::Passphrase => {
+            let (cipher, key) = create_cipher()?;
+            let content = serde_json::json!({
+                "key": key,
+                "session": cipher.encrypt_value(session)?,
+            });
+            write_file(
+                config.encrypted_session_file_path(),
+                &serde_json::to_vec(&content)?,
+            )?;
+        }
+        SessionStorage::Keyring => {
+            keyring_entry(config)?.set_password(&serde_json::to_string(session)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the stored session from all storage locations, e.g., after logging out.
+pub fn remove_session(config: &Config) -> Result<(), Error> {
+    for path in [
+        config.session_file_path(),
+        config.encrypted_session_file_path(),
+    ] {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    if config.session_storage == SessionStorage::Keyring {
+        match keyring_entry(config)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Move the files of the sqlite store into a new directory in the data dir, so that a fresh store
+/// is created, and discard the session. Returns the new directory.
+pub fn move_store_aside(config: &Config) -> Result<PathBuf, Error> {
+    let data_dir = config.data_dir();
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let backup = data_dir.join(format!("store-backup-{}", now.as_secs()));
+    std::fs::create_dir(&backup)?;
+    for entry in std::fs::read_dir(&data_dir)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(STORE_FILE_PREFIX)
+        {
+            std::fs::rename(entry.path(), backup.join(entry.file_name()))?;
+        }
+    }
+    // The keys of the device are in the crypto store, so its session cannot be used with a fresh
+    // store: Other devices would see new keys for a known device and stop trusting it.
+    remove_session(config)?;
+    Ok(backup)
+}
+
+/// The account a data dir belongs to, if it is known without unlocking anything: The user id of
+/// the session if it is stored in plain text, otherwise the account the data dir is named after.
+fn data_dir_account(dir: &Path) -> Option<OwnedUserId> {
+    let session = std::fs::File::open(dir.join("session"))
+        .ok()
+        .and_then(|f| serde_json::from_reader::<_, MatrixSession>(f).ok());
+    if let Some(session) = session {
+        return Some(session.meta.user_id);
+    }
+    UserId::parse(dir.file_name()?.to_str()?).ok()
+}
+
+/// Data dirs with a store of the account of `config`, which were created when its host was
+/// configured differently or for a profile that no longer exists. The data dirs of the other
+/// profiles in the config are never offered, even if they are logged in to the same account.
+fn other_stores(config: &Config) -> Vec<(PathBuf, OwnedUserId)> {
+    let root = config.data_root();
+    let profiles = root.join("profiles");
+    let configured = config
+        .profile_names
+        .iter()
+        .map(|name| profiles.join(name))
+        .collect::<Vec<_>>();
+    [root.clone(), profiles.clone()]
+        .iter()
+        .filter_map(|d| std::fs::read_dir(d).ok())
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|d| *d != config.data_dir() && !configured.contains(d))
+        .filter(|d| d.join(CRYPTO_STORE_FILE).exists())
+        .filter_map(|d| {
+            let account = data_dir_account(&d)?;
+            if account.as_str() == config.user_id() {
+                Some((d, account))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn select_store(stores: &[(PathBuf, OwnedUserId)]) -> Result<Option<usize>, Error> {
+    use std::io::Write;
+    for (i, (dir, account)) in stores.iter().enumerate() {
+        eprintln!("{:>3}) {} ({})", i + 1, dir.display(), account);
+    }
+    loop {
+        eprint!("Store to migrate (empty to start with a fresh store): ");
+        std::io::stderr().flush()?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        match line.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= stores.len() => return Ok(Some(n - 1)),
+            _ => eprintln!("'{}' is not a number between 1 and {}", line, stores.len()),
+        }
+    }
+}
+
+/// If there is no store in the data dir yet, offer to move the store (and the session) of an older
+/// data dir of the account there. Otherwise changing the user or host in the config would start
+/// with a fresh store and lose the keys of encrypted messages.
+pub fn offer_store_migration(config: &Config) -> Result<(), Error> {
+    let data_dir = config.data_dir();
+    if data_dir.join(CRYPTO_STORE_FILE).exists() {
+        return Ok(());
+    }
+    let stores = other_stores(config);
+    if stores.is_empty() {
+        return Ok(());
+    }
+    eprintln!(
+        "There is no store in {} yet, but stores of {} exist:",
+        data_dir.display(),
+        config.user_id()
+    );
+    let Some(i) = select_store(&stores)? else {
+        return Ok(());
+    };
+    let (old_dir, _) = &stores[i];
+    // Keyring entries are named after the user id that was configured for the old data dir.
+    let old_keyring_account = old_dir
+        .file_name()
+        .and_then(|name| UserId::parse(name.to_str()?).ok());
+    std::fs::create_dir_all(&data_dir)?;
+    for entry in std::fs::read_dir(old_dir)? {
+        let entry = entry?;
+        std::fs::rename(entry.path(), data_dir.join(entry.file_name()))?;
+    }
+    std::fs::remove_dir(old_dir)?;
+    let uses_keyring = config.session_storage == SessionStorage::Keyring
+        || config.store_encryption == SessionStorage::Keyring;
+    match &old_keyring_account {
+        Some(account) if uses_keyring && account.as_str() != config.user_id() => {
+            move_keyring_entries(config, account)?;
+        }
+        _ => {}
+    }
+    eprintln!("Moved {} to {}", old_dir.display(), data_dir.display());
+    Ok(())
+}
+
+/// Keyring entries are named after the account, so they have to follow its store.
+fn move_keyring_entries(config: &Config, old_account: &UserId) -> Result<(), Error> {
+    for service in [
+        crate::APP_NAME.to_owned(),
+        format!("{} store", crate::APP_NAME),
+    ] {
+        let old = keyring::Entry::new(&service, old_account.as_str())?;
+        match old.get_password() {
+            Ok(secret) => {
+                keyring::Entry::new(&service, &config.user_id())?.set_password(&secret)?;
+                old.delete_credential()?;
+            }
+            Err(keyring::Error::NoEntry) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn store_keyring_entry(config: &Config) -> Result<keyring::Entry, Error> {
+    let service = format!("{} store", crate::APP_NAME);
+    Ok(keyring::Entry::new(&service, &config.user_id())?)
+}
+
+fn prompt_store_passphrase(config: &Config) -> Result<String, Error> {
+    if config.data_dir().join(CRYPTO_STORE_FILE).exists() {
+        read_passphrase("Store passphrase: ")
+    } else {
+        eprintln!("Choose a passphrase to encrypt the store with:");
+        choose_passphrase("Store passphrase: ")
+    }
+}
+
+/// The passphrase the sqlite store is encrypted with, if any. Note that the store is only
+/// encrypted if it is created with a passphrase, changing the setting later on requires removing
+/// the existing store.
+pub fn store_passphrase(config: &Config) -> Result<Option<String>, Error> {
+    match config.store_encryption {
+        SessionStorage::Plain => Ok(None),
+        SessionStorage::Passphrase => prompt_store_passphrase(config).map(Some),
+        SessionStorage::Keyring => {
+            let entry = store_keyring_entry(config)?;
+            match entry.get_password() {
+                Ok(passphrase) => Ok(Some(passphrase)),
+                Err(keyring::Error::NoEntry) => {
+                    let passphrase = prompt_store_passphrase(config)?;
+                    // The store would be lost with the passphrase, so it is only used once it is
+                    // known to be in the keyring.
+                    entry.set_password(&passphrase).map_err(|e| {
+                        format!("Failed to save the store passphrase in the keyring: {}", e)
+                    })?;
+                    if store_keyring_entry(config)?.get_password()? != passphrase {
+                        return Err("The keyring did not keep the store passphrase".into());
+                    }
+                    Ok(Some(passphrase))
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Failed to read the store passphrase from the keyring: {}",
+                        e
+                    );
+                    prompt_store_passphrase(config).map(Some)
+                }
+            }
+        }
+    }
+}
+
+/// A second handle to the crypto store of the client, to read what matrix-sdk does not expose,
+/// like the outbound group sessions. `None` if it cannot be opened. Drop it right after use, the
+/// client keeps writing to the store.
+pub async fn open_crypto_store(
+    config: &Config,
+    passphrase: Option<&str>,
+) -> Option<matrix_sdk_sqlite::SqliteCryptoStore> {
+    match matrix_sdk_sqlite::SqliteCryptoStore::open(config.data_dir(), passphrase).await {
+        Ok(store) => Some(store),
+        Err(e) => {
+            tracing::warn!("Failed to open the crypto store: {}", e);
+            None
+        }
+    }
+}
@@ -1,17 +1,20 @@
 use crate::search::Filter;
 use matrix_sdk::deserialized_responses::{SyncTimelineEvent, TimelineEventKind};
 use matrix_sdk::ruma::api::Direction;
+use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
+use matrix_sdk::ruma::events::room::member::MembershipChange;
 use matrix_sdk::ruma::events::room::message::Relation;
 use matrix_sdk::ruma::events::room::redaction::OriginalSyncRoomRedactionEvent;
 use matrix_sdk::ruma::events::{
-    AnySyncMessageLikeEvent, AnySyncTimelineEvent, OriginalSyncMessageLikeEvent,
+    AnySyncMessageLikeEvent, AnySyncStateEvent, AnySyncTimelineEvent, OriginalSyncMessageLikeEvent,
 };
 use matrix_sdk::{
     room::{Messages, Room},
     ruma::events::reaction::ReactionEventContent,
-    ruma::{EventId, OwnedEventId},
+    ruma::{serde::Raw, EventId, OwnedEventId},
 };
 use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
 struct EventSequence {
     index_offset: isize,
@@ -19,6 +22,12 @@ struct EventSequence {
     index: HashMap<OwnedEventId, EventSequenceId>,
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Side {
+    Before,
+    After,
+}
+
 #[derive(Copy, Clone)]
 pub struct EventSequenceId {
     pos: isize,
@@ -45,11 +54,17 @@ impl EventSequence {
     }
 
     fn append(&mut self, item: OwnedEventId) {
+        if self.index.contains_key(&item) {
+            return;
+        }
         let id = self.sequence_index_to_id(self.sequence.len());
         self.index.insert(item.clone(), id);
         self.sequence.push_back(item);
     }
     fn prepend(&mut self, item: OwnedEventId) {
+        if self.index.contains_key(&item) {
+            return;
+        }
         self.index_offset += 1;
 
         let id = self.sequence_index_to_id(0);
@@ -57,6 +72,32 @@ impl EventSequence {
         self.sequence.push_front(item)
     }
 
+    /// Insert `item` next to `anchor`, or at the respective end of the sequence if there is no
+    /// anchor (or it is unknown). Items that are already part of the sequence stay where they are.
+    fn insert(&mut self, item: OwnedEventId, anchor: Option<&EventId>, side: Side) {
+        if self.index.contains_key(&item) {
+            return;
+        }
+        let pos = match (anchor.and_then(|a| self.position(a)), side) {
+            (Some(pos), Side::Before) => pos,
+            (Some(pos), Side::After) => pos + 1,
+            (None, Side::Before) => 0,
+            (None, Side::After) => self.sequence.len(),
+        };
+        if pos == self.sequence.len() {
+            self.append(item);
+        } else if pos == 0 {
+            self.prepend(item);
+        } else {
+            self.sequence.insert(pos, item);
+            // All following items have moved by one
+            for i in pos..self.sequence.len() {
+                let id = self.sequence_index_to_id(i);
+                self.index.insert(self.sequence[i].clone(), id);
+            }
+        }
+    }
+
     fn event(&self, id: EventSequenceId) -> &EventId {
         let i = self.id_to_sequence_index(id);
         &self.sequence[i]
@@ -90,6 +131,28 @@ impl EventSequence {
     fn last(&self) -> Option<&EventId> {
         self.sequence.back().map(|e| &**e)
     }
+
+    fn position(&self, e: &EventId) -> Option<usize> {
+        self.id(e).map(|id| self.id_to_sequence_index(id))
+    }
+
+    fn remove_front(&mut self, n: usize) -> Vec<OwnedEventId> {
+        let removed = self.sequence.drain(..n).collect::<Vec<_>>();
+        for e in &removed {
+            self.index.remove(e);
+        }
+        self.index_offset -= n as isize;
+        removed
+    }
+
+    fn remove_back(&mut self, n: usize) -> Vec<OwnedEventId> {
+        let begin = self.sequence.len() - n;
+        let removed = self.sequence.drain(begin..).collect::<Vec<_>>();
+        for e in &removed {
+            self.index.remove(e);
+        }
+        removed
+    }
 }
 
 struct FilteredTimeline {
@@ -114,6 +177,46 @@ pub type Event = AnySyncTimelineEvent;
 pub type Reaction = OriginalSyncMessageLikeEvent<ReactionEventContent>;
 pub type Reactions = HashMap<String, Vec<Reaction>>;
 
+/// The kinds of membership changes that can be told apart in the configuration.
+pub const MEMBERSHIP_CHANGES: &[&str] = &[
+    "join",
+    "leave",
+    "invite",
+    "reject",
+    "revoke",
+    "kick",
+    "ban",
+    "unban",
+    "displayname",
+    "avatar",
+];
+
+/// The kind of membership change (one of `MEMBERSHIP_CHANGES`) if `event` is a member event.
+pub fn membership_change(event: &Event) -> Option<&'static str> {
+    let Event::State(AnySyncStateEvent::RoomMember(e)) = event else {
+        return None;
+    };
+    Some(match e.as_original()?.membership_change() {
+        MembershipChange::Joined | MembershipChange::InvitationAccepted => "join",
+        MembershipChange::Left => "leave",
+        MembershipChange::Invited => "invite",
+        MembershipChange::InvitationRejected => "reject",
+        MembershipChange::InvitationRevoked => "revoke",
+        MembershipChange::Kicked => "kick",
+        MembershipChange::Banned | MembershipChange::KickedAndBanned => "ban",
+        MembershipChange::Unbanned => "unban",
+        MembershipChange::ProfileChanged {
+            displayname_change: Some(_),
+            ..
+        } => "displayname",
+        MembershipChange::ProfileChanged {
+            avatar_url_change: Some(_),
+            ..
+        } => "avatar",
+        _ => return None,
+    })
+}
+
 pub enum CacheEndState {
     Open,
     Reached,
@@ -132,7 +235,15 @@ pub struct RoomTimelineCache {
     msg_to_edits: HashMap<OwnedEventId, Vec<Event>>,
     edits_to_original: HashMap<OwnedEventId, OwnedEventId>,
     redactions: HashMap<OwnedEventId, Box<OriginalSyncRoomRedactionEvent>>,
+    // Pagination tokens that point directly before the respective event. These are used to
+    // refetch ranges of the timeline that have been evicted from the cache.
+    tokens_before: HashMap<OwnedEventId, String>,
     has_undecrypted_messages: bool,
+    // Events that failed to decrypt, and when we requested their keys.
+    undecrypted: HashMap<OwnedEventId, Raw<OriginalSyncRoomEncryptedEvent>>,
+    key_requested: HashMap<OwnedEventId, Instant>,
+    // Changes whenever events are added, removed or filtered, so that views can cache results.
+    generation: u64,
 }
 
 impl std::default::Default for RoomTimelineCache {
@@ -150,7 +261,11 @@ impl std::default::Default for RoomTimelineCache {
             msg_to_edits: HashMap::new(),
             edits_to_original: HashMap::new(),
             redactions: HashMap::new(),
+            tokens_before: HashMap::new(),
             has_undecrypted_messages: false,
+            undecrypted: HashMap::new(),
+            key_requested: HashMap::new(),
+            generation: 0,
         }
     }
 }
@@ -224,6 +339,10 @@ impl<'a> TimelineEntry<'a> {
 
 const QUERY_BATCH_SIZE_LIMIT: u32 = 10;
 
+// How long an event is marked as waiting for its key. The key may still arrive later on, but most
+// likely none of our devices has it.
+const KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Clone, Copy, Debug)]
 pub struct RoomTimelineIndex<'a> {
     pos: &'a EventId,
@@ -251,6 +370,9 @@ impl RoomTimelineCache {
         self.edits_to_original.clear();
         self.reactions.clear();
         self.reactions_to_target.clear();
+        self.tokens_before.clear();
+        self.undecrypted.clear();
+        self.key_requested.clear();
         let f = self.filtered_timeline.as_ref().map(|ft| ft.filter.clone());
         self.set_filter(f);
     }
@@ -265,6 +387,7 @@ impl RoomTimelineCache {
     }
 
     pub fn set_filter(&mut self, filter: Option<Filter>) {
+        self.generation += 1;
         if let Some(filter) = filter {
             let mut ft = FilteredTimeline {
                 filtered_messages: EventSequence::empty(),
@@ -284,6 +407,26 @@ impl RoomTimelineCache {
         self.has_undecrypted_messages
     }
 
+    /// Undecryptable events for which we have not requested the keys yet. They are considered
+    /// requested from now on.
+    pub fn take_missing_key_events(&mut self) -> Vec<Raw<OriginalSyncRoomEncryptedEvent>> {
+        let mut events = Vec::new();
+        for (eid, raw) in &self.undecrypted {
+            if !self.key_requested.contains_key(eid) {
+                self.key_requested.insert(eid.clone(), Instant::now());
+                events.push(raw.clone());
+            }
+        }
+        events
+    }
+
+    /// Whether the key of the event was requested recently and has not arrived yet.
+    pub fn is_key_requested(&self, eid: &EventId) -> bool {
+        self.key_requested
+            .get(eid)
+            .map_or(false, |t| t.elapsed() < KEY_REQUEST_TIMEOUT)
+    }
+
     fn pre_process_message(&mut self, event: Event) -> Option<Event> {
         if let Event::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(_)) = &event {
             self.has_undecrypted_messages = true;
@@ -356,34 +499,81 @@ impl RoomTimelineCache {
         }
     }
 
-    fn append(&mut self, msg: Event) {
-        // Somewhat annoying: There is a race condition between fetching messages manually
-        // and receiving messages via a sync. An example of this would be one where we
-        // first notice that we are missing messages from the store (e.g. because there are
-        // any) and request the latest messages BEFORE receiving the first sync, which then
-        // contains the (duplicate) messages that have already been fetched. This would
-        // typically happen on startup of the application.
-        if !self.events.contains_key(msg.event_id()) {
-            if let Some(msg) = self.pre_process_message(msg) {
-                let event_id = msg.event_id().to_owned();
-                self.full_timeline.append(event_id.clone());
-                if let Some(f) = &mut self.filtered_timeline {
-                    f.try_append(&msg);
+    /// Whether the event is part of the timeline or attached to one of its events (e.g. as a
+    /// reaction or edit).
+    fn contains(&self, eid: &EventId) -> bool {
+        self.events.contains_key(eid)
+            || self.reactions_to_target.contains_key(eid)
+            || self.edits_to_original.contains_key(eid)
+    }
+
+    /// Add a batch of events that extends the timeline in direction `dir`, i.e., `Forward` batches
+    /// are in chronological order and `Backward` batches start with the newest event.
+    ///
+    /// Somewhat annoying: There is a race condition between fetching messages manually and
+    /// receiving messages via a sync. An example of this would be one where we first notice that
+    /// we are missing messages from the store (e.g. because there are any) and request the latest
+    /// messages BEFORE receiving the first sync, which then contains the (duplicate) messages that
+    /// have already been fetched. This would typically happen on startup of the application.
+    /// Known events are therefore skipped and new events are placed relative to them, so that the
+    /// order is preserved where the batch overlaps with the cache.
+    fn insert_batch(&mut self, msgs: Vec<Event>, dir: Direction) {
+        self.generation += 1;
+        let (towards_known, onwards) = match dir {
+            Direction::Forward => (Side::Before, Side::After),
+            Direction::Backward => (Side::After, Side::Before),
+        };
+        // New events that precede the first known event in the batch belong on its other side.
+        let first_known = msgs
+            .iter()
+            .position(|m| self.full_timeline.id(m.event_id()).is_some());
+        let known_anchor = first_known.map(|k| msgs[k].event_id().to_owned());
+
+        let mut anchor: Option<OwnedEventId> = None;
+        let mut filter_outdated = false;
+        for (i, msg) in msgs.into_iter().enumerate() {
+            let event_id = msg.event_id().to_owned();
+            if self.full_timeline.id(&event_id).is_some() {
+                anchor = Some(event_id);
+                continue;
+            }
+            if self.contains(&event_id) {
+                continue;
+            }
+            let Some(msg) = self.pre_process_message(msg) else {
+                continue;
+            };
+            if first_known.map_or(false, |k| i < k) {
+                self.full_timeline
+                    .insert(event_id.clone(), known_anchor.as_deref(), towards_known);
+            } else {
+                self.full_timeline
+                    .insert(event_id.clone(), anchor.as_deref(), onwards);
+            }
+            if let Some(f) = &mut self.filtered_timeline {
+                let len = self.full_timeline.sequence.len();
+                match self.full_timeline.position(&event_id) {
+                    Some(0) => f.try_prepend(&msg),
+                    Some(pos) if pos + 1 == len => f.try_append(&msg),
+                    _ => filter_outdated = true,
                 }
-                self.events.insert(event_id, msg);
             }
+            self.events.insert(event_id.clone(), msg);
+            anchor = Some(event_id);
+        }
+        if filter_outdated {
+            let f = self.filtered_timeline.as_ref().map(|ft| ft.filter.clone());
+            self.set_filter(f);
         }
     }
-    fn prepend(&mut self, msg: Event) {
-        // Avoid race condition. See `append`.
-        if !self.events.contains_key(msg.event_id()) {
-            if let Some(msg) = self.pre_process_message(msg) {
-                let event_id = msg.event_id().to_owned();
-                self.full_timeline.prepend(event_id.clone());
-                if let Some(f) = &mut self.filtered_timeline {
-                    f.try_prepend(&msg);
-                }
-                self.events.insert(event_id, msg);
+
+    fn remember_token_before(&mut self, event: Option<&Event>, token: Option<String>) {
+        if let (Some(event), Some(token)) = (event, token) {
+            // Events that are not part of the timeline itself (e.g. reactions) cannot be used as
+            // eviction boundaries.
+            if self.events.contains_key(event.event_id()) {
+                self.tokens_before
+                    .insert(event.event_id().to_owned(), token);
             }
         }
     }
@@ -394,9 +584,11 @@ impl RoomTimelineCache {
         let num_events = msgs.len() + batch.state.len();
         match query_result.query {
             MessageQuery::AfterCache => {
-                for msg in transform_events(msgs.into_iter().map(|e| e.into())) {
-                    self.append(msg);
-                }
+                let msgs =
+                    transform_events(msgs.into_iter().map(|e| e.into()), &mut self.undecrypted);
+                let first = msgs.first().cloned();
+                self.insert_batch(msgs, Direction::Forward);
+                self.remember_token_before(first.as_ref(), Some(batch.start.clone()));
 
                 self.end = if num_events < QUERY_BATCH_SIZE_LIMIT as usize {
                     // For some reason the /messages endpoint returns no end token when we reach
@@ -409,9 +601,11 @@ impl RoomTimelineCache {
                 };
             }
             MessageQuery::BeforeCache => {
-                for msg in transform_events(msgs.into_iter().map(|e| e.into())) {
-                    self.prepend(msg);
-                }
+                let msgs =
+                    transform_events(msgs.into_iter().map(|e| e.into()), &mut self.undecrypted);
+                let oldest = msgs.last().cloned();
+                self.insert_batch(msgs, Direction::Backward);
+                self.remember_token_before(oldest.as_ref(), batch.end.clone());
 
                 self.begin = if num_events < QUERY_BATCH_SIZE_LIMIT as usize {
                     self.begin_token = None;
@@ -424,13 +618,24 @@ impl RoomTimelineCache {
         }
     }
 
+    /// Show the latest events that the event cache kept in the store, e.g., while the server cannot
+    /// be reached on startup. They are only used if nothing was received yet. Fetches and syncs
+    /// that overlap with them are merged as usual.
+    pub fn insert_cached(&mut self, events: Vec<SyncTimelineEvent>) {
+        if self.num_events() > 0 {
+            return;
+        }
+        let msgs = transform_events(events.into_iter(), &mut self.undecrypted);
+        self.insert_batch(msgs, Direction::Forward);
+    }
+
     pub fn handle_sync_batch(&mut self, batch: matrix_sdk::sync::Timeline, end_token: &str) {
         if matches!(self.end, CacheEndState::Reached) {
             let events = batch.events.into_iter();
 
             if batch.limited {
                 self.clear_timeline();
-                if let Some(token) = batch.prev_batch {
+                if let Some(token) = batch.prev_batch.clone() {
                     self.begin_token = Some(token);
                     self.begin = CacheEndState::Open;
                 } else {
@@ -441,12 +646,117 @@ impl RoomTimelineCache {
             self.end_token = Some(end_token.to_owned());
             self.end = CacheEndState::Reached;
 
-            for msg in transform_events(events.into_iter()) {
-                self.append(msg);
+            let msgs = transform_events(events.into_iter(), &mut self.undecrypted);
+            let first = msgs.first().cloned();
+            self.insert_batch(msgs, Direction::Forward);
+            self.remember_token_before(first.as_ref(), batch.prev_batch);
+        }
+    }
+
+    /// Changes whenever the events of the timeline change.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn num_events(&self) -> usize {
+        self.full_timeline.sequence.len()
+    }
+
+    fn forget_events(&mut self, removed: Vec<OwnedEventId>) {
+        self.generation += 1;
+        for eid in removed {
+            self.events.remove(&eid);
+            self.tokens_before.remove(&eid);
+            self.undecrypted.remove(&eid);
+            self.key_requested.remove(&eid);
+            self.redactions.remove(&eid);
+            if let Some(edits) = self.msg_to_edits.remove(&eid) {
+                for e in edits {
+                    self.edits_to_original.remove(e.event_id());
+                }
+            }
+            if let Some(reactions) = self.reactions.remove(&eid) {
+                for r in reactions.values().flatten() {
+                    self.reactions_to_target.remove(&r.event_id);
+                }
             }
         }
     }
 
+    /// Evict events from the cache until at most `max_events` are left (or no further eviction
+    /// is possible). Events are removed from the end that is farther away from `keep`, which is
+    /// never evicted itself. If `keep` is `None`, the newest events are kept.
+    pub fn evict(&mut self, max_events: usize, keep: Option<&EventId>) {
+        let mut evicted = false;
+        while self.num_events() > max_events {
+            let len = self.num_events();
+            let excess = len - max_events;
+            let keep_pos = keep
+                .and_then(|k| self.full_timeline.position(k))
+                .unwrap_or(len - 1);
+
+            let boundaries = self
+                .full_timeline
+                .sequence
+                .iter()
+                .enumerate()
+                .filter(|(i, e)| *i > 0 && self.tokens_before.contains_key(*e))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+
+            let evict_front = keep_pos >= len - 1 - keep_pos;
+            let removed = if evict_front {
+                // Remove everything before the boundary, i.e., the boundary event is kept.
+                let boundary = boundaries
+                    .iter()
+                    .find(|i| **i >= excess && **i <= keep_pos)
+                    .or_else(|| boundaries.iter().filter(|i| **i <= keep_pos).last());
+                let num_removed = match boundary {
+                    Some(&boundary) => {
+                        self.begin_token = self
+                            .tokens_before
+                            .get(&self.full_timeline.sequence[boundary])
+                            .cloned();
+                        boundary
+                    }
+                    None => {
+                        // Without a token, the evicted events are fetched again from the latest
+                        // sync backwards (see `events_query`), skipping those that are cached.
+                        self.begin_token = None;
+                        excess.min(keep_pos)
+                    }
+                };
+                if num_removed == 0 {
+                    break;
+                }
+                self.begin = CacheEndState::Open;
+                self.full_timeline.remove_front(num_removed)
+            } else {
+                // Remove the boundary event and everything after it.
+                let Some(&boundary) = boundaries
+                    .iter()
+                    .rev()
+                    .find(|i| len - **i >= excess && **i > keep_pos)
+                    .or_else(|| boundaries.iter().find(|i| **i > keep_pos))
+                else {
+                    break;
+                };
+                self.end_token = self
+                    .tokens_before
+                    .get(&self.full_timeline.sequence[boundary])
+                    .cloned();
+                self.end = CacheEndState::Open;
+                self.full_timeline.remove_back(len - boundary)
+            };
+            self.forget_events(removed);
+            evicted = true;
+        }
+        if evicted {
+            let f = self.filtered_timeline.as_ref().map(|ft| ft.filter.clone());
+            self.set_filter(f);
+        }
+    }
+
     fn entry_from_event<'a>(&'a self, original: &'a Event) -> TimelineEntry<'a> {
         if let Some(_e) = self.redactions.get(original.event_id()) {
             TimelineEntry::Deleted(original)
@@ -473,6 +783,13 @@ impl RoomTimelineCache {
         }
     }
 
+    /// The position of `id` if it is cached.
+    pub fn index_of(&self, id: &EventId) -> Option<RoomTimelineIndex> {
+        self.events
+            .get_key_value(id)
+            .map(|(id, _)| RoomTimelineIndex::new(id))
+    }
+
     pub fn message_from_id(&self, id: &EventId) -> Option<TimelineEntry> {
         let original_id = self.edits_to_original.get(id).map(|v| &**v).unwrap_or(id);
         self.events
@@ -480,6 +797,21 @@ impl RoomTimelineCache {
             .map(|i| self.entry_from_event(i))
     }
 
+    /// Whether `event` is `other` or precedes it in the timeline, or `None` if either of them is
+    /// not cached. Reactions and edits are placed at the event that they refer to.
+    pub fn is_at_or_before(&self, event: &EventId, other: &EventId) -> Option<bool> {
+        let position = |id: &EventId| {
+            let id = self
+                .reactions_to_target
+                .get(id)
+                .or_else(|| self.edits_to_original.get(id))
+                .map(|v| &**v)
+                .unwrap_or(id);
+            self.full_timeline.position(id)
+        };
+        Some(position(event)? <= position(other)?)
+    }
+
     pub fn walk_from_known<'a>(&'a self, id: &'a EventId) -> EventWalkResult<'a> {
         if let Some(i) = self.find(id) {
             EventWalkResult::Message(i)
@@ -582,7 +914,10 @@ impl RoomTimelineCache {
     }
 }
 
-fn transform_events(i: impl Iterator<Item = SyncTimelineEvent>) -> impl Iterator<Item = Event> {
+fn transform_events(
+    i: impl Iterator<Item = SyncTimelineEvent>,
+    undecrypted: &mut HashMap<OwnedEventId, Raw<OriginalSyncRoomEncryptedEvent>>,
+) -> Vec<Event> {
     i.filter_map(|msg| match msg.kind {
         TimelineEventKind::Decrypted(decrypted_room_event) => {
             match decrypted_room_event.event.deserialize() {
@@ -594,7 +929,11 @@ fn transform_events(i: impl Iterator<Item = SyncTimelineEvent>) -> impl Iterator
             }
         }
         TimelineEventKind::UnableToDecrypt { event, utd_info: _ } => match event.deserialize() {
-            Ok(e) => Some(e),
+            Ok(e) => {
+                // Keep the raw event around so that we can try to decrypt it again later.
+                undecrypted.insert(e.event_id().to_owned(), event.cast());
+                Some(e)
+            }
             Err(e) => {
                 tracing::warn!("Failed to deserialize message {:?}", e);
                 None
@@ -608,6 +947,7 @@ fn transform_events(i: impl Iterator<Item = SyncTimelineEvent>) -> impl Iterator
             }
         },
     })
+    .collect()
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -620,3 +960,170 @@ pub struct MessageQueryResult {
     query: MessageQuery,
     events: Messages,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eid(n: u64) -> OwnedEventId {
+        EventId::parse(format!("${}:example.org", n)).unwrap()
+    }
+
+    fn event(n: u64) -> Event {
+        serde_json::from_value(serde_json::json!({
+            "type": "m.room.topic",
+            "state_key": "",
+            "content": { "topic": format!("topic {}", n) },
+            "event_id": format!("${}:example.org", n),
+            "sender": "@user:example.org",
+            "origin_server_ts": n,
+        }))
+        .unwrap()
+    }
+
+    fn events(ns: &[u64]) -> Vec<Event> {
+        ns.iter().map(|n| event(*n)).collect()
+    }
+
+    fn ids(ns: &[u64]) -> Vec<OwnedEventId> {
+        ns.iter().map(|n| eid(*n)).collect()
+    }
+
+    fn contents(s: &EventSequence) -> Vec<OwnedEventId> {
+        // The index has to agree with the order of the sequence.
+        assert_eq!(s.index.len(), s.sequence.len());
+        for (i, e) in s.sequence.iter().enumerate() {
+            assert_eq!(s.position(e), Some(i));
+        }
+        s.sequence.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_sequence_append_prepend_idempotent() {
+        let mut s = EventSequence::empty();
+        s.append(eid(1));
+        s.append(eid(2));
+        s.prepend(eid(0));
+        s.append(eid(0));
+        s.prepend(eid(2));
+        s.append(eid(1));
+        assert_eq!(contents(&s), ids(&[0, 1, 2]));
+    }
+
+    #[test]
+    fn test_sequence_insert() {
+        let mut s = EventSequence::empty();
+        s.insert(eid(2), None, Side::After);
+        s.insert(eid(5), None, Side::After);
+        s.insert(eid(1), None, Side::Before);
+        s.insert(eid(3), Some(&eid(5)), Side::Before);
+        s.insert(eid(4), Some(&eid(3)), Side::After);
+        s.insert(eid(6), Some(&eid(5)), Side::After);
+        s.insert(eid(0), Some(&eid(1)), Side::Before);
+        s.insert(eid(3), Some(&eid(0)), Side::Before);
+        assert_eq!(contents(&s), ids(&[0, 1, 2, 3, 4, 5, 6]));
+
+        assert_eq!(s.next(&eid(3)), Some(&*eid(4)));
+        assert_eq!(s.prev(&eid(3)), Some(&*eid(2)));
+
+        assert_eq!(s.remove_front(2), ids(&[0, 1]));
+        assert_eq!(s.remove_back(2), ids(&[5, 6]));
+        assert_eq!(contents(&s), ids(&[2, 3, 4]));
+        s.insert(eid(1), Some(&eid(2)), Side::Before);
+        assert_eq!(contents(&s), ids(&[1, 2, 3, 4]));
+    }
+
+    fn timeline(c: &RoomTimelineCache) -> Vec<OwnedEventId> {
+        let full = contents(&c.full_timeline);
+        assert_eq!(full.len(), c.events.len());
+        full
+    }
+
+    #[test]
+    fn test_batch_duplicates() {
+        let mut c = RoomTimelineCache::default();
+        c.insert_batch(events(&[1, 2, 3]), Direction::Forward);
+        c.insert_batch(events(&[1, 2, 3]), Direction::Forward);
+        c.insert_batch(events(&[3, 2, 1]), Direction::Backward);
+        c.insert_batch(events(&[2, 2]), Direction::Forward);
+        assert_eq!(timeline(&c), ids(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_batch_overlap_forward() {
+        let mut c = RoomTimelineCache::default();
+        c.insert_batch(events(&[1, 2, 5, 6]), Direction::Forward);
+        // E.g. a query that started before a sync delivered 5 and 6
+        c.insert_batch(events(&[3, 4, 5, 6, 7]), Direction::Forward);
+        assert_eq!(timeline(&c), ids(&[1, 2, 3, 4, 5, 6, 7]));
+        c.insert_batch(events(&[7, 8]), Direction::Forward);
+        assert_eq!(timeline(&c), ids(&[1, 2, 3, 4, 5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn test_batch_overlap_backward() {
+        let mut c = RoomTimelineCache::default();
+        c.insert_batch(events(&[1, 2, 5]), Direction::Forward);
+        c.insert_batch(events(&[4, 3, 2, 1, 0]), Direction::Backward);
+        assert_eq!(timeline(&c), ids(&[0, 1, 2, 3, 4, 5]));
+
+        let mut c = RoomTimelineCache::default();
+        c.insert_batch(events(&[3, 4]), Direction::Forward);
+        c.insert_batch(events(&[2, 1]), Direction::Backward);
+        assert_eq!(timeline(&c), ids(&[1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_is_at_or_before() {
+        let mut c = RoomTimelineCache::default();
+        c.insert_batch(events(&[3, 4]), Direction::Forward);
+        c.insert_batch(events(&[2, 1]), Direction::Backward);
+        assert_eq!(c.is_at_or_before(&eid(1), &eid(4)), Some(true));
+        assert_eq!(c.is_at_or_before(&eid(3), &eid(3)), Some(true));
+        assert_eq!(c.is_at_or_before(&eid(4), &eid(2)), Some(false));
+        assert_eq!(c.is_at_or_before(&eid(4), &eid(5)), None);
+    }
+
+    fn cache_with_boundaries(ns: &[u64], boundaries: &[u64]) -> RoomTimelineCache {
+        let mut c = RoomTimelineCache::default();
+        c.insert_batch(events(ns), Direction::Forward);
+        c.end = CacheEndState::Reached;
+        for n in boundaries {
+            c.tokens_before.insert(eid(*n), format!("t{}", n));
+        }
+        c
+    }
+
+    #[test]
+    fn test_evict_front() {
+        let mut c = cache_with_boundaries(&[1, 2, 3, 4, 5, 6, 7, 8], &[3, 6]);
+        c.evict(4, None);
+        assert_eq!(timeline(&c), ids(&[6, 7, 8]));
+        assert!(matches!(c.begin, CacheEndState::Open));
+        assert_eq!(c.begin_token.as_deref(), Some("t6"));
+        assert!(matches!(c.end, CacheEndState::Reached));
+    }
+
+    #[test]
+    fn test_evict_back() {
+        let mut c = cache_with_boundaries(&[1, 2, 3, 4, 5, 6, 7, 8], &[3, 6]);
+        c.evict(4, Some(&eid(2)));
+        // Evicting from 6 would not suffice, so everything from the previous boundary is evicted.
+        assert_eq!(timeline(&c), ids(&[1, 2]));
+        assert!(matches!(c.end, CacheEndState::Open));
+        assert_eq!(c.end_token.as_deref(), Some("t3"));
+    }
+
+    #[test]
+    fn test_evict_front_without_boundary() {
+        let mut c = cache_with_boundaries(&[1, 2, 3, 4, 5, 6], &[]);
+        c.begin = CacheEndState::Reached;
+        c.evict(2, None);
+        assert_eq!(timeline(&c), ids(&[5, 6]));
+        assert!(matches!(c.begin, CacheEndState::Open));
+        assert_eq!(c.begin_token, None);
+        // The newest event is kept.
+        c.evict(0, None);
+        assert_eq!(timeline(&c), ids(&[6]));
+    }
+}
@@ -0,0 +1,171 @@
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use std::future::IntoFuture;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use super::retry::with_retry;
+use super::tui::Event;
+use super::{Connection, RoomKey};
+
+/// Find a room of any of the logged in accounts, together with the index of that account.
+pub async fn find_room(c: &Connection, name: &str) -> Result<(usize, Room), String> {
+    let clients = {
+        let state = c.state.lock().await;
+        state
+            .accounts
+            .iter()
+            .map(|a| a.client.clone())
+            .collect::<Vec<_>>()
+    };
+    let mut error = None;
+    for (account, client) in clients.into_iter().enumerate() {
+        match crate::resolve::find_room(&client, name).await {
+            Ok(room) => return Ok((account, room)),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| format!("No room '{}'", name)))
+}
+
+pub async fn send_message(c: &Connection, room: &str, msg: &str) -> Result<(), String> {
+    let (_, room) = find_room(c, room).await?;
+    let content = RoomMessageEventContent::text_plain(msg);
+    with_retry(|| room.send(content.clone()).into_future())
+        .await
+        .map_err(|e| format!("Failed to send message: {}", e))?;
+    Ok(())
+}
+
+pub async fn focus_room(c: &Connection, room: &str) -> Result<(), String> {
+    let (account, room) = find_room(c, room).await?;
+    let key = RoomKey::new(account, room.room_id().to_owned());
+    // Rooms that are not shown in the tui (e.g. invites) cannot be focused.
+    if !c.state.lock().await.rooms.contains_key(&key) {
+        return Err(format!("Room {} is not shown in the tui", room.room_id()));
+    }
+    let events = c.events.lock().await.clone();
+    events
+        .send(Event::FocusRoom(key))
+        .await
+        .map_err(|_| "The tui is shutting down".to_owned())
+}
+
+async fn handle_command(c: &Connection, line: &str) -> Result<(), String> {
+    let mut parts = line.trim().splitn(2, ' ');
+    let command = parts.next().unwrap_or("");
+    let args = parts.next().unwrap_or("").trim();
+    match command {
+        "send" => {
+            let (room, msg) = args
+                .split_once(' ')
+                .ok_or_else(|| "Usage: send <room> <message>".to_owned())?;
+            send_message(c, room, msg.trim()).await
+        }
+        "focus-room" => focus_room(c, args).await,
+        "set-dnd" => {
+            {
+                let mut state = c.state.lock().await;
+                state.do_not_disturb = match args {
+                    "on" => true,
+                    "off" => false,
+                    "toggle" => !state.do_not_disturb,
+                    _ => return Err("Usage: set-dnd on|off|toggle".to_owned()),
+                };
+            }
+            c.update().await;
+            Ok(())
+        }
+        "" => Err("No command given".to_owned()),
+        o => Err(format!("Unknown command '{}'", o)),
+    }
+}
+
+async fn handle_client(c: Connection, stream: UnixStream) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match handle_command(&c, &line).await {
+            Ok(()) => "ok\n".to_owned(),
+            Err(e) => format!("error: {}\n", e.replace('\n', " ")),
+        };
+        if write.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Returns false if another instance is already listening on the socket.
+async fn remove_stale_socket(path: &Path) -> bool {
+    if !path.exists() {
+        return true;
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        return false;
+    }
+    let _ = std::fs::remove_file(path);
+    true
+}
+
+fn create_socket_dir(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    let dir = path.parent().unwrap();
+    // Only the user may control the client.
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    // The directory may already exist, e.g., created by someone else in the temporary directory.
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != nix::unistd::getuid().as_raw()
+        || metadata.mode() & 0o077 != 0
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!(
+                "{} has to be a directory that only the user can access",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn bind_control_socket(path: &Path) -> Option<UnixListener> {
+    if !remove_stale_socket(path).await {
+        tracing::warn!(
+            "Another instance is already listening on {}, remote control is disabled",
+            path.display()
+        );
+        return None;
+    }
+    if let Err(e) = create_socket_dir(path) {
+        tracing::error!("Failed to create directory for {}: {}", path.display(), e);
+        return None;
+    }
+    match UnixListener::bind(path) {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            tracing::error!("Failed to bind control socket {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Accept control commands (see `sparse ctl`) until the tui exits.
+pub async fn run_control_socket(c: Connection, listener: UnixListener) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = c.quit() => break,
+        };
+        match accepted {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_client(c.clone(), stream));
+            }
+            Err(e) => tracing::error!("Failed to accept control connection: {}", e),
+        }
+    }
+}
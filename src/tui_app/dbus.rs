@@ -0,0 +1,85 @@
+use zbus::{fdo, interface};
+
+use super::{control, Connection};
+
+const BUS_NAME: &str = "de.tamepointer.Sparse";
+const OBJECT_PATH: &str = "/de/tamepointer/Sparse";
+
+struct Sparse {
+    c: Connection,
+}
+
+// Properties do not emit change signals, since they change with every sync. Clients are expected
+// to poll them instead.
+#[interface(name = "de.tamepointer.Sparse1")]
+impl Sparse {
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn unread_count(&self) -> u64 {
+        let state = self.c.state.lock().await;
+        state
+            .rooms
+            .values()
+            .map(|r| r.num_unread_notifications())
+            .sum()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn current_room(&self) -> String {
+        let state = self.c.state.lock().await;
+        state
+            .current_room_state()
+            .map(|r| r.id.to_string())
+            .unwrap_or_default()
+    }
+
+    /// (id, name, unread notifications, account) of all rooms. Rooms shared by multiple accounts
+    /// are listed once per account.
+    async fn rooms(&self) -> Vec<(String, String, u64, String)> {
+        let state = self.c.state.lock().await;
+        state
+            .rooms
+            .values()
+            .map(|r| {
+                (
+                    r.id.to_string(),
+                    r.name().to_owned(),
+                    r.num_unread_notifications(),
+                    state.accounts[r.account()].user_id().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    async fn send_message(&self, room: &str, message: &str) -> fdo::Result<()> {
+        control::send_message(&self.c, room, message)
+            .await
+            .map_err(fdo::Error::Failed)
+    }
+
+    async fn focus_room(&self, room: &str) -> fdo::Result<()> {
+        control::focus_room(&self.c, room)
+            .await
+            .map_err(fdo::Error::Failed)
+    }
+}
+
+/// Export the state of the client on the session bus. The returned connection has to be kept
+/// alive for the service to be available.
+pub async fn start_service(c: Connection) -> Option<zbus::Connection> {
+    let service = zbus::connection::Builder::session()
+        .and_then(|b| b.name(BUS_NAME))
+        .and_then(|b| b.serve_at(OBJECT_PATH, Sparse { c }));
+    match service {
+        Ok(builder) => match builder.build().await {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                tracing::error!("Failed to start D-Bus service: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            tracing::error!("Failed to set up D-Bus service: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use matrix_sdk::room::{Room, RoomMember};
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use matrix_sdk::RoomMemberships;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+
+struct Name {
+    display_name: String,
+    ambiguous: bool, // Another member of the room uses the same display name
+}
+
+// The names of a user in all rooms. Most users go by the same name everywhere, which is stored
+// once. Rooms in which they use another name, or in which the name is ambiguous, override it.
+#[derive(Default)]
+struct UserNames {
+    shared: Option<String>,
+    shared_rooms: BTreeSet<OwnedRoomId>,
+    overrides: BTreeMap<OwnedRoomId, Name>,
+}
+
+impl UserNames {
+    fn remove(&mut self, room_id: &RoomId) {
+        self.overrides.remove(room_id);
+        if self.shared_rooms.remove(room_id) && self.shared_rooms.is_empty() {
+            self.shared = None;
+        }
+    }
+
+    fn insert(&mut self, room_id: &RoomId, name: Name) {
+        self.remove(room_id);
+        let is_shared = !name.ambiguous
+            && self
+                .shared
+                .as_ref()
+                .map_or(true, |shared| *shared == name.display_name);
+        if is_shared {
+            self.shared = Some(name.display_name);
+            self.shared_rooms.insert(room_id.to_owned());
+        } else {
+            self.overrides.insert(room_id.to_owned(), name);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.shared_rooms.is_empty() && self.overrides.is_empty()
+    }
+}
+
+/// Display names of the joined members of all rooms, keyed by user id. The state and its rooms
+/// share them, so cloning only clones the handle. Drawing messages only reads from here, the store
+/// is queried when member events arrive.
+#[derive(Clone, Default)]
+pub struct DisplayNames(Arc<RwLock<BTreeMap<OwnedUserId, UserNames>>>);
+
+impl DisplayNames {
+    /// The display name of `user_id` in `room_id`, followed by the user id if it is ambiguous.
+    pub fn get(&self, room_id: &RoomId, user_id: &UserId) -> Option<String> {
+        let names = self.0.read().unwrap();
+        let user = names.get(user_id)?;
+        if let Some(name) = user.overrides.get(room_id) {
+            return Some(if name.ambiguous {
+                format!("{} ({})", name.display_name, user_id)
+            } else {
+                name.display_name.clone()
+            });
+        }
+        if user.shared_rooms.contains(room_id) {
+            user.shared.clone()
+        } else {
+            None
+        }
+    }
+
+    fn is_ambiguous(&self, room_id: &RoomId, user_id: &UserId) -> bool {
+        let names = self.0.read().unwrap();
+        names
+            .get(user_id)
+            .and_then(|user| user.overrides.get(room_id))
+            .map_or(false, |name| name.ambiguous)
+    }
+
+    fn set(
+        names: &mut BTreeMap<OwnedUserId, UserNames>,
+        room_id: &RoomId,
+        user_id: &UserId,
+        name: Option<Name>,
+    ) {
+        match name {
+            Some(name) => names
+                .entry(user_id.to_owned())
+                .or_default()
+                .insert(room_id, name),
+            None => {
+                if let Some(user) = names.get_mut(user_id) {
+                    user.remove(room_id);
+                    if user.is_empty() {
+                        names.remove(user_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace the names in `room` with the ones of the joined members that are currently in the
+    /// store.
+    pub async fn load_room(&self, room: &Room) {
+        let members = match room.members_no_sync(RoomMemberships::JOIN).await {
+            Ok(members) => members,
+            Err(e) => {
+                return tracing::error!("Failed to load members of {}: {}", room.room_id(), e);
+            }
+        };
+        let mut names = self.0.write().unwrap();
+        for user in names.values_mut() {
+            user.remove(room.room_id());
+        }
+        names.retain(|_, user| !user.is_empty());
+        for member in &members {
+            Self::set(
+                &mut names,
+                room.room_id(),
+                member.user_id(),
+                name_of(member),
+            );
+        }
+    }
+
+    /// Update the name after a member event for `user_id` in `room`.
+    pub async fn update_member(&self, room: &Room, user_id: &UserId) {
+        let member = match room.get_member_no_sync(user_id).await {
+            Ok(Some(member)) => member,
+            Ok(None) => {
+                return Self::set(&mut self.0.write().unwrap(), room.room_id(), user_id, None)
+            }
+            Err(e) => {
+                return tracing::error!(
+                    "Failed to load member {} of {}: {}",
+                    user_id,
+                    room.room_id(),
+                    e
+                );
+            }
+        };
+        // Changing a name that is (or was) shared with others changes whether their names are
+        // ambiguous, too.
+        if member.name_ambiguous() || self.is_ambiguous(room.room_id(), user_id) {
+            self.load_room(room).await;
+        } else {
+            let mut names = self.0.write().unwrap();
+            Self::set(&mut names, room.room_id(), user_id, name_of(&member));
+        }
+    }
+}
+
+fn name_of(member: &RoomMember) -> Option<Name> {
+    if *member.membership() != MembershipState::Join {
+        return None;
+    }
+    Some(Name {
+        display_name: member.display_name()?.to_owned(),
+        ambiguous: member.name_ambiguous(),
+    })
+}
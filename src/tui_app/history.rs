@@ -0,0 +1,133 @@
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use crate::config::{Config, InitialRoom};
+
+use super::{RoomKey, RoomState, State};
+
+/// Room selection and activity that is kept across restarts to restore the previous state on
+/// startup. Only the room ids are saved, so a room that is shared by multiple accounts is restored
+/// for the first of them.
+#[derive(Default)]
+pub struct SavedRooms {
+    pub current: Option<OwnedRoomId>,
+    pub history: Vec<OwnedRoomId>, // Ordered from least to most recent selection
+    pub activity: HashMap<OwnedRoomId, u64>, // Timestamp (ms) of the latest event in the room
+}
+
+fn path(config: &Config) -> PathBuf {
+    config.data_dir().join("rooms.json")
+}
+
+fn parse_room_id(value: &serde_json::Value) -> Option<OwnedRoomId> {
+    RoomId::parse(value.as_str()?).ok()
+}
+
+fn find_key(rooms: &BTreeMap<RoomKey, RoomState>, id: &RoomId) -> Option<RoomKey> {
+    rooms.keys().find(|key| &*key.id == id).cloned()
+}
+
+impl SavedRooms {
+    pub fn load(config: &Config) -> Self {
+        let content = match std::fs::read(path(config)) {
+            Ok(content) => content,
+            Err(_) => return SavedRooms::default(),
+        };
+        let value: serde_json::Value = match serde_json::from_slice(&content) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::warn!("Failed to parse {}: {}", path(config).display(), e);
+                return SavedRooms::default();
+            }
+        };
+        SavedRooms {
+            current: parse_room_id(&value["current"]),
+            history: value["history"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(parse_room_id)
+                .collect(),
+            activity: value["activity"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(id, ts)| Some((RoomId::parse(id).ok()?, ts.as_u64()?)))
+                .collect(),
+        }
+    }
+
+    pub fn from_state(state: &State) -> Self {
+        let mut activity = HashMap::new();
+        for r in state.rooms.values().filter(|r| r.last_activity > 0) {
+            let ts = activity.entry(r.id.clone()).or_insert(0);
+            *ts = r.last_activity.max(*ts);
+        }
+        SavedRooms {
+            current: state.tui.room_selection.current().map(|key| key.id.clone()),
+            history: state
+                .tui
+                .room_selection
+                .history()
+                .iter()
+                .map(|key| key.id.clone())
+                .collect(),
+            activity,
+        }
+    }
+
+    pub fn save(&self, config: &Config) {
+        let content = serde_json::json!({
+            "current": self.current,
+            "history": self.history,
+            "activity": self.activity,
+        });
+        if let Err(e) = std::fs::write(path(config), content.to_string()) {
+            tracing::warn!("Failed to save {}: {}", path(config).display(), e);
+        }
+    }
+
+    /// The room to select on startup according to the config. Falls back to the first room.
+    pub fn initial_room(
+        &self,
+        config: &Config,
+        rooms: &BTreeMap<RoomKey, RoomState>,
+    ) -> Option<RoomKey> {
+        match &config.initial_room {
+            InitialRoom::First => None,
+            InitialRoom::Last => self.current.as_deref().and_then(|id| find_key(rooms, id)),
+            InitialRoom::Recent => rooms
+                .values()
+                .filter(|r| r.last_activity > 0)
+                .max_by_key(|r| r.last_activity)
+                .map(|r| r.key()),
+            InitialRoom::Id(id) => find_key(rooms, id),
+        }
+        .or_else(|| {
+            rooms
+                .values()
+                .find(|r| !r.read_only)
+                .or_else(|| rooms.values().next())
+                .map(|r| r.key())
+        })
+    }
+
+    /// The saved selection history of the rooms that are still known, from least to most recent.
+    pub fn history(&self, rooms: &BTreeMap<RoomKey, RoomState>) -> Vec<RoomKey> {
+        let mut history = Vec::new();
+        // A room may occur more than once if it was shared by multiple accounts.
+        for key in self
+            .history
+            .iter()
+            .rev()
+            .filter_map(|id| find_key(rooms, id))
+        {
+            if !history.contains(&key) {
+                history.push(key);
+            }
+        }
+        history.reverse();
+        history
+    }
+}
@@ -1,63 +1,91 @@
 use matrix_sdk::{
     self,
     config::SyncSettings,
-    deserialized_responses::RawAnySyncOrStrippedTimelineEvent,
+    deserialized_responses::{RawAnySyncOrStrippedTimelineEvent, SyncTimelineEvent},
     room::Room,
     ruma::{
+        api::client::presence::set_presence,
         events::{
-            receipt::{ReceiptThread, ReceiptType},
-            room::message::MessageType,
-            AnyMessageLikeEventContent, AnySyncTimelineEvent, AnyToDeviceEvent,
+            receipt::{ReceiptEventContent, ReceiptThread, ReceiptType},
+            room::{
+                member::MembershipState,
+                message::{MessageType, Relation},
+                power_levels::RoomPowerLevels,
+            },
+            tag::Tags,
+            AnyGlobalAccountDataEvent, AnyMessageLikeEventContent, AnyRoomAccountDataEvent,
+            AnySyncEphemeralRoomEvent, AnySyncMessageLikeEvent, AnySyncStateEvent,
+            AnySyncTimelineEvent, AnyToDeviceEvent, SyncMessageLikeEvent, TimelineEventType,
         },
-        OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+        presence::PresenceState,
+        EventId, Int, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomAliasId, OwnedRoomId,
+        OwnedTransactionId, OwnedUserId, RoomAliasId, RoomId, TransactionId, UserId,
     },
     sync::Notification,
-    Client, LoopCtrl,
+    Client, LoopCtrl, RoomStateFilter, SessionChange,
 };
 
+use crate::config::{Hook, NotificationLevel};
 use crate::timeline::{self};
 
 use nix::sys::signal::{SigSet, Signal};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 use tokio::sync::{mpsc, watch, Mutex};
+use tui::mentions::Mention;
 use tui::Event;
 use unsegen::base::Color;
 
+mod control;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod display_names;
+mod history;
+mod notification_clicks;
+mod retry;
+mod shutdown;
+mod status_file;
 pub mod tui;
+mod verification;
 
 type UserColors = BTreeMap<OwnedUserId, Color>;
 
+const USER_COLORS: [Color; 6] = [
+    Color::Red,
+    Color::Blue,
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Magenta,
+];
+const OWN_USER_COLOR: Color = Color::White;
+
+fn preferred_color_index(user_id: &UserId) -> usize {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    user_id.as_str().hash(&mut hasher);
+    hasher.finish() as usize % USER_COLORS.len()
+}
+
 async fn calculate_user_colors(room: &Room) -> UserColors {
-    let available_colors = [
-        Color::Red,
-        Color::Blue,
-        Color::Cyan,
-        Color::Green,
-        Color::Yellow,
-        Color::Magenta,
-    ];
-    let num_colors = available_colors.len();
-    let own_color = Color::White;
+    let num_colors = USER_COLORS.len();
 
     let own_user_id = room.own_user_id();
     let users = room.joined_user_ids().await.unwrap();
 
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-
     let mut raw_colors = users
         .into_iter()
         .filter(|i| i != own_user_id)
         .map(|i| {
-            let mut hasher = DefaultHasher::new();
-            i.as_str().hash(&mut hasher);
-            (i.into(), hasher.finish() as usize % num_colors)
+            let pos = preferred_color_index(&i);
+            (i, pos)
         })
         .peekable();
 
     let mut user_colors = UserColors::new();
-    user_colors.insert(own_user_id.into(), own_color);
+    user_colors.insert(own_user_id.into(), OWN_USER_COLOR);
 
     let mut table = vec![None; num_colors];
     'outer: while let Some((_id, pos)) = raw_colors.peek() {
@@ -73,57 +101,280 @@ async fn calculate_user_colors(room: &Room) -> UserColors {
 
         for (i, e) in table.iter_mut().enumerate() {
             if let Some(e) = e.take() {
-                user_colors.insert(e, available_colors[i]);
+                user_colors.insert(e, USER_COLORS[i]);
             }
         }
     }
     for (i, e) in table.into_iter().enumerate() {
         if let Some(e) = e {
-            user_colors.insert(e, available_colors[i]);
+            user_colors.insert(e, USER_COLORS[i]);
         }
     }
     user_colors
 }
 
+/// Update the colors for a single membership change without fetching the member list. Other
+/// users keep their color, so the result may differ from a full `calculate_user_colors`.
+fn update_user_color(user_colors: &mut UserColors, user_id: &UserId, membership: &MembershipState) {
+    if *membership != MembershipState::Join {
+        if user_colors.get(user_id) != Some(&OWN_USER_COLOR) {
+            user_colors.remove(user_id);
+        }
+        return;
+    }
+    if user_colors.contains_key(user_id) {
+        return;
+    }
+    // Pick the least used color, starting from the one the user would get in an empty room.
+    let preferred = preferred_color_index(user_id);
+    let color = (0..USER_COLORS.len())
+        .map(|o| USER_COLORS[(preferred + o) % USER_COLORS.len()])
+        .min_by_key(|color| user_colors.values().filter(|c| *c == color).count())
+        .unwrap();
+    user_colors.insert(user_id.to_owned(), color);
+}
+
+/// Identifies a room in the room list. Rooms that are shared by multiple accounts are listed once
+/// for each of them.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RoomKey {
+    pub account: usize, // Index into `State::accounts`
+    pub id: OwnedRoomId,
+}
+
+impl RoomKey {
+    pub fn new(account: usize, id: OwnedRoomId) -> Self {
+        RoomKey { account, id }
+    }
+}
+
+/// A message that we are sending, shown below the timeline until it arrives in a sync.
+pub struct LocalEcho {
+    pub txn_id: OwnedTransactionId,
+    pub body: String,
+    pub state: EchoState,
+}
+
+pub enum EchoState {
+    Sending,
+    Failed(String),
+    Sent(OwnedEventId), // But not synced yet
+}
+
 pub struct RoomState {
     id: OwnedRoomId,
+    account: usize, // Index into `State::accounts`
     pub messages: timeline::RoomTimelineCache,
     name: String,
     latest_read_message: Option<OwnedEventId>,
     num_unread_notifications: u64,
+    num_unread_highlights: u64,
     last_notification_handle: Option<notify_rust::NotificationHandle>,
+    num_notified: usize, // Notifications shown since the room was last read
+    direct_target: Option<OwnedUserId>,
+    notification_level: Option<crate::config::NotificationLevel>, // Overrides the configured level
     user_colors: UserColors,
+    display_names: display_names::DisplayNames,
+    last_activity: u64, // Timestamp (ms) of the latest event, 0 if unknown
+    successor: Option<OwnedRoomId>, // Set once the room has been upgraded
+    topic: Option<String>,
+    power_levels: Option<RoomPowerLevels>, // None until loaded from the store
+    newest_read_by_others: Option<OwnedEventId>, // According to the receipts received since startup
+    local_echoes: Vec<LocalEcho>,          // Oldest first
+    tags: Vec<String>,
+    aliases: Vec<OwnedRoomAliasId>, // The canonical alias first
+    read_only: bool, // We left the room, so it is archived: Its history is shown but nothing can be sent
 
     pub tui: tui::RoomTuiState,
 }
 
+/// Operations in a room that require a minimum power level.
+#[derive(Clone, Copy)]
+pub enum Privilege {
+    RedactOwn,
+    RedactOther,
+    Invite,
+    ChangeAliases,
+    ChangeJoinRule,
+    ChangeHistoryVisibility,
+}
+
+/// The level required to send state events of type `event_type`.
+fn state_level(levels: &RoomPowerLevels, event_type: TimelineEventType) -> Int {
+    levels
+        .events
+        .get(&event_type)
+        .copied()
+        .unwrap_or(levels.state_default)
+}
+
+impl Privilege {
+    fn required_level(self, levels: &RoomPowerLevels) -> Int {
+        let redaction = levels
+            .events
+            .get(&TimelineEventType::RoomRedaction)
+            .copied()
+            .unwrap_or(levels.events_default);
+        match self {
+            Privilege::RedactOwn => redaction,
+            Privilege::RedactOther => redaction.max(levels.redact),
+            Privilege::Invite => levels.invite,
+            Privilege::ChangeAliases => state_level(levels, TimelineEventType::RoomCanonicalAlias),
+            Privilege::ChangeJoinRule => state_level(levels, TimelineEventType::RoomJoinRules),
+            Privilege::ChangeHistoryVisibility => {
+                state_level(levels, TimelineEventType::RoomHistoryVisibility)
+            }
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Privilege::RedactOwn => "Deleting messages",
+            Privilege::RedactOther => "Deleting messages of others",
+            Privilege::Invite => "Inviting users",
+            Privilege::ChangeAliases => "Changing the published aliases",
+            Privilege::ChangeJoinRule => "Changing who can join",
+            Privilege::ChangeHistoryVisibility => "Changing who can read the history",
+        }
+    }
+}
+
+async fn load_power_levels(room: &Room) -> Option<RoomPowerLevels> {
+    match room.power_levels().await {
+        Ok(levels) => Some(levels),
+        Err(e) => {
+            tracing::warn!("Failed to load power levels of {}: {}", room.room_id(), e);
+            None
+        }
+    }
+}
+
+/// The most recent of our own receipts, regardless of the kind of receipts we are configured to
+/// send (other clients or earlier configurations may have sent others).
+async fn load_latest_read_message(room: &Room) -> Option<OwnedEventId> {
+    let mut latest = None;
+    for receipt_type in [ReceiptType::ReadPrivate, ReceiptType::Read] {
+        for thread in [ReceiptThread::Main, ReceiptThread::Unthreaded] {
+            if let Some((id, receipt)) = room
+                .load_user_receipt(receipt_type.clone(), thread, room.own_user_id())
+                .await
+                .unwrap()
+            {
+                if latest.as_ref().map_or(true, |(_, ts)| receipt.ts > *ts) {
+                    latest = Some((id, receipt.ts));
+                }
+            }
+        }
+    }
+    latest.map(|(id, _)| id)
+}
+
+/// The names of the tags of `room`, like `m.favourite` or `u.work`.
+async fn load_tags(room: &Room) -> Vec<String> {
+    match room.tags().await {
+        Ok(tags) => tags.as_ref().map(tag_names).unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!("Failed to load tags of {}: {}", room.room_id(), e);
+            Vec::new()
+        }
+    }
+}
+
+fn tag_names(tags: &Tags) -> Vec<String> {
+    tags.keys().map(|t| t.as_ref().to_owned()).collect()
+}
+
+fn room_aliases(room: &Room) -> Vec<OwnedRoomAliasId> {
+    room.canonical_alias()
+        .into_iter()
+        .chain(room.alt_aliases())
+        .collect()
+}
+
+/// The room alias like `#room:example.org` that `word` starts with (ignoring trailing
+/// punctuation) and the rest of the word.
+fn alias_prefix(word: &str) -> Option<(OwnedRoomAliasId, &str)> {
+    let alias = word.trim_end_matches(|c: char| ".,;:!?)]}'\"".contains(c));
+    if !alias.starts_with('#') {
+        return None;
+    }
+    let alias = RoomAliasId::parse(alias).ok()?;
+    let rest = &word[alias.as_str().len()..];
+    Some((alias, rest))
+}
+
+/// Room aliases mentioned in a message body.
+fn aliases_in(body: &str) -> impl Iterator<Item = OwnedRoomAliasId> + '_ {
+    body.split_whitespace()
+        .filter_map(|word| alias_prefix(word).map(|(alias, _)| alias))
+}
+
+/// The other user of a direct chat according to `m.direct`.
+fn direct_target(room: &Room) -> Option<OwnedUserId> {
+    room.direct_targets()
+        .iter()
+        .find_map(|t| t.as_user_id().map(|u| u.to_owned()))
+}
+
+/// The name shown for `room`. Unnamed direct chats are named after the other user, which the name
+/// computed from the room heroes does not always get right.
+async fn room_name(room: &Room) -> String {
+    if room.name().is_none() {
+        if let Some(target) = direct_target(room) {
+            if let Ok(Some(member)) = room.get_member_no_sync(&target).await {
+                return member.name().to_owned();
+            }
+        }
+    }
+    room.compute_display_name().await.unwrap().to_string()
+}
+
 impl RoomState {
-    async fn from_room(room: &Room) -> Self {
-        let name = room.compute_display_name().await.unwrap().to_string();
-        let latest_read_message = room
-            .load_user_receipt(
-                ReceiptType::ReadPrivate,
-                ReceiptThread::Main,
-                room.own_user_id(),
-            )
-            .await
-            .unwrap()
-            .map(|(id, _)| id);
+    async fn from_room(room: &Room, account: usize, names: &display_names::DisplayNames) -> Self {
+        let mut s = Self::placeholder(room, account, names);
+        s.name = room_name(room).await;
+        s.latest_read_message = load_latest_read_message(room).await;
+        s.user_colors = calculate_user_colors(room).await;
+        s.power_levels = load_power_levels(room).await;
+        s.tags = load_tags(room).await;
+        names.load_room(room).await;
+        s
+    }
 
+    /// Create a room state from information that is available without querying the store. The
+    /// remaining fields are filled in later by `populate_room_state`.
+    fn placeholder(room: &Room, account: usize, names: &display_names::DisplayNames) -> Self {
         RoomState {
             id: room.room_id().into(),
+            account,
             messages: timeline::RoomTimelineCache::default(),
-            name,
-            latest_read_message,
+            name: room.name().unwrap_or_else(|| room.room_id().to_string()),
+            latest_read_message: None,
             num_unread_notifications: room.unread_notification_counts().notification_count,
+            num_unread_highlights: room.unread_notification_counts().highlight_count,
             last_notification_handle: None,
-            user_colors: calculate_user_colors(room).await,
+            num_notified: 0,
+            direct_target: direct_target(room),
+            notification_level: None,
+            user_colors: UserColors::new(),
+            display_names: names.clone(),
+            last_activity: 0,
+            successor: room.tombstone().map(|t| t.replacement_room),
+            topic: room.topic(),
+            power_levels: None,
+            newest_read_by_others: None,
+            local_echoes: Vec::new(),
+            tags: Vec::new(),
+            aliases: room_aliases(room),
+            read_only: room.state() != matrix_sdk::RoomState::Joined,
             tui: tui::RoomTuiState::at_last_message(),
         }
     }
 
     pub fn mark_newest_event_as_read(&mut self) -> Option<OwnedEventId> {
         self.num_unread_notifications = 0;
+        self.num_unread_highlights = 0;
+        self.num_notified = 0;
         self.last_notification_handle
             .take()
             .map(|handle| handle.close());
@@ -135,6 +386,33 @@ impl RoomState {
             None
         }
     }
+    /// Show `n` grouped with the other notifications since the room was last read: The summary
+    /// counts them and the notification that is still open is updated instead of replaced.
+    pub fn show_notification(
+        &mut self,
+        mut n: DesktopNotification,
+        config: &crate::config::Config,
+        clicks: &notification_clicks::NotificationClicks,
+    ) {
+        self.num_notified += 1;
+        if self.num_notified > 1 {
+            n.summary = match config.notification_style {
+                crate::config::NotificationStyle::NameOnly => {
+                    format!("{} ({} new messages)", n.sender, self.num_notified)
+                }
+                _ => format!("{} new messages in {}", self.num_notified, n.room_name),
+            };
+        }
+        match &mut self.last_notification_handle {
+            Some(handle) => {
+                handle.summary(&n.summary);
+                handle.body(n.body.as_deref().unwrap_or(""));
+                handle.urgency(n.urgency);
+                handle.update();
+            }
+            None => self.last_notification_handle = show_notification(&n, config, clicks),
+        }
+    }
     pub fn num_unread_notifications(&self) -> u64 {
         self.num_unread_notifications
     }
@@ -144,13 +422,204 @@ impl RoomState {
     pub fn name(&self) -> &str {
         &self.name
     }
+    /// The display name of `user_id` in the room, followed by the user id if it is ambiguous.
+    pub fn display_name(&self, user_id: &UserId) -> Option<String> {
+        self.display_names.get(&self.id, user_id)
+    }
+    pub fn direct_target(&self) -> Option<&UserId> {
+        self.direct_target.as_deref()
+    }
+    pub fn account(&self) -> usize {
+        self.account
+    }
+    pub fn key(&self) -> RoomKey {
+        RoomKey::new(self.account, self.id.clone())
+    }
+    pub fn successor(&self) -> Option<&RoomId> {
+        self.successor.as_deref()
+    }
+    pub fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    pub fn aliases(&self) -> &[OwnedRoomAliasId] {
+        &self.aliases
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    pub fn last_activity(&self) -> u64 {
+        self.last_activity
+    }
+    /// The notification level of the room, taking overrides into account.
+    pub fn notification_level(&self, config: &crate::config::Config) -> NotificationLevel {
+        self.notification_level
+            .unwrap_or_else(|| config.room_notification_level(&self.id))
+    }
+    /// Remember the newest event that has been read by a member other than `own_user_id`.
+    fn handle_receipts(&mut self, receipts: &ReceiptEventContent, own_user_id: &UserId) {
+        for (event_id, receipts) in receipts.iter() {
+            let read_by_others = receipts
+                .get(&ReceiptType::Read)
+                .map_or(false, |users| users.keys().any(|u| u != own_user_id));
+            // Receipts arrive in order, so if we cannot tell, the new one is the better guess.
+            let newer = self.newest_read_by_others.as_ref().map_or(true, |newest| {
+                self.messages
+                    .is_at_or_before(newest, event_id)
+                    .unwrap_or(true)
+            });
+            if read_by_others && newer {
+                self.newest_read_by_others = Some(event_id.clone());
+            }
+        }
+    }
+    /// Whether another member has read `event_id` (or a later event).
+    pub fn is_read_by_others(&self, event_id: &EventId) -> bool {
+        self.newest_read_by_others.as_ref().map_or(false, |newest| {
+            self.messages
+                .is_at_or_before(event_id, newest)
+                .unwrap_or(false)
+        })
+    }
+    pub fn local_echoes(&self) -> &[LocalEcho] {
+        &self.local_echoes
+    }
+    /// Show `body` as being sent until the result for the returned transaction id is known. Failed
+    /// messages are kept until the next message is sent in the room.
+    pub fn add_local_echo(&mut self, body: String) -> OwnedTransactionId {
+        self.local_echoes
+            .retain(|e| !matches!(e.state, EchoState::Failed(_)));
+        let txn_id = TransactionId::new();
+        self.local_echoes.push(LocalEcho {
+            txn_id: txn_id.clone(),
+            body,
+            state: EchoState::Sending,
+        });
+        txn_id
+    }
+    pub fn handle_send_result(
+        &mut self,
+        txn_id: &TransactionId,
+        result: Result<OwnedEventId, String>,
+    ) {
+        if let Some(echo) = self.local_echoes.iter_mut().find(|e| e.txn_id == txn_id) {
+            echo.state = match result {
+                Ok(event_id) => EchoState::Sent(event_id),
+                Err(e) => EchoState::Failed(e),
+            };
+        }
+        self.remove_synced_echoes();
+    }
+    /// The sync may deliver a sent message before or after the send request returns.
+    fn remove_synced_echoes(&mut self) {
+        let messages = &self.messages;
+        self.local_echoes.retain(|e| match &e.state {
+            EchoState::Sent(event_id) => messages.message_from_id(event_id).is_none(),
+            _ => true,
+        });
+    }
+    /// Check whether `user_id` has the power level required for `privilege` so that actions can
+    /// fail with a clear message before the server rejects them. Without cached power levels the
+    /// server has the final say.
+    pub fn check_privilege(&self, user_id: &UserId, privilege: Privilege) -> Result<(), String> {
+        let Some(levels) = &self.power_levels else {
+            return Ok(());
+        };
+        let required = privilege.required_level(levels);
+        let own = levels.for_user(user_id);
+        if own >= required {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} requires PL {}, you have {}",
+                privilege.description(),
+                required,
+                own
+            ))
+        }
+    }
+}
+
+/// A logged in account. Each account has its own client and sync loop.
+pub struct AccountState {
+    client: Client,
+    config: crate::config::Config,
+    user_id: OwnedUserId, // This is a cache for the user_id in non-async contexts. we may be able to remove it at some point.
+    sync_retry_at: Option<std::time::Instant>, // Set while the connection to the server is down
+    session_status: SessionStatus,
+    store_passphrase: Option<String>, // To open the crypto store of the client on demand
+}
+
+impl AccountState {
+    fn new(
+        client: Client,
+        config: crate::config::Config,
+        store_passphrase: Option<String>,
+    ) -> Self {
+        AccountState {
+            user_id: client.user_id().unwrap().to_owned(),
+            client,
+            config,
+            store_passphrase,
+            sync_retry_at: None,
+            session_status: SessionStatus::Valid,
+        }
+    }
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
 }
 
 pub struct State {
-    pub rooms: BTreeMap<OwnedRoomId, RoomState>,
+    pub rooms: BTreeMap<RoomKey, RoomState>,
     tui: tui::TuiState,
     clipboard_context: Option<cli_clipboard::ClipboardContext>,
-    user_id: OwnedUserId, // This is a cache for the user_id in non-async contexts. we may be able to remove it at some point.
+    pub accounts: Vec<AccountState>,
+    last_input: std::time::Instant,
+    away: bool,
+    own_presence: Option<PresenceState>, // Set by the user, overrides the automatic presence
+    do_not_disturb: bool, // Suppresses notifications, but unread counts are still updated
+    snoozed_until: Option<std::time::Instant>, // Like do_not_disturb, but only until then
+    presence: BTreeMap<OwnedUserId, PresenceState>,
+    display_names: display_names::DisplayNames,
+    verification: Option<verification::VerificationFlow>,
+    outgoing_verifications: mpsc::UnboundedSender<verification::OutgoingRequest>,
+    seen_aliases: BTreeSet<OwnedRoomAliasId>, // Mentioned in messages received since startup
+    knocks: BTreeMap<RoomKey, KnockStatus>,   // Known from the store or the sync
+    pending_sends: shutdown::PendingSends,
+    notification_clicks: notification_clicks::NotificationClicks,
+}
+
+/// The state of a request to be let into a room with join rule `knock`.
+#[derive(Copy, Clone, PartialEq)]
+pub enum KnockStatus {
+    Pending,
+    Invited,
+    Joined,
+    Rejected,
+}
+
+impl std::fmt::Display for KnockStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            KnockStatus::Pending => "pending",
+            KnockStatus::Invited => "invited",
+            KnockStatus::Joined => "joined",
+            KnockStatus::Rejected => "rejected",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum SessionStatus {
+    Valid,
+    /// The access token was invalidated, but we can log in again using the same device.
+    SoftLoggedOut,
+    /// The device was logged out and the session cannot be resumed.
+    LoggedOut,
 }
 fn init_clipboard() -> Option<cli_clipboard::ClipboardContext> {
     use cli_clipboard::ClipboardProvider;
@@ -164,24 +633,87 @@ fn init_clipboard() -> Option<cli_clipboard::ClipboardContext> {
 }
 
 impl State {
-    fn new(rooms: BTreeMap<OwnedRoomId, RoomState>, user_id: OwnedUserId) -> Self {
-        let tui = crate::tui_app::tui::TuiState::new(rooms.keys().next().map(|k| &**k));
+    fn new(
+        rooms: BTreeMap<RoomKey, RoomState>,
+        history: Vec<RoomKey>,
+        current_room: Option<RoomKey>,
+        accounts: Vec<AccountState>,
+        display_names: display_names::DisplayNames,
+        outgoing_verifications: mpsc::UnboundedSender<verification::OutgoingRequest>,
+    ) -> Self {
+        let tui = crate::tui_app::tui::TuiState::new(history, current_room.as_ref());
+        let knocks = accounts
+            .iter()
+            .enumerate()
+            .flat_map(|(i, account)| {
+                account
+                    .client
+                    .rooms_filtered(RoomStateFilter::KNOCKED)
+                    .into_iter()
+                    .map(move |room| (RoomKey::new(i, room.room_id().into()), KnockStatus::Pending))
+            })
+            .collect();
         State {
             rooms,
             tui,
             clipboard_context: init_clipboard(),
-            user_id,
+            accounts,
+            last_input: std::time::Instant::now(),
+            away: false,
+            own_presence: None,
+            do_not_disturb: false,
+            snoozed_until: None,
+            presence: BTreeMap::new(),
+            display_names,
+            verification: None,
+            outgoing_verifications,
+            seen_aliases: BTreeSet::new(),
+            knocks,
+            pending_sends: shutdown::PendingSends::default(),
+            notification_clicks: notification_clicks::NotificationClicks::default(),
         }
     }
-    async fn update_room_info(&mut self, room: &Room) {
-        if let Some(r) = self.rooms.get_mut(room.room_id()) {
-            r.name = room.compute_display_name().await.unwrap().to_string();
-            r.user_colors = calculate_user_colors(room).await;
+    /// Refresh the name and user colors after the state of `room` changed. Colors are only
+    /// recalculated from the full member list if more than one membership changed.
+    async fn update_room_info(
+        &mut self,
+        room: &Room,
+        account: usize,
+        member_changes: &[(OwnedUserId, MembershipState)],
+    ) {
+        let key = RoomKey::new(account, room.room_id().to_owned());
+        if let Some(r) = self.rooms.get_mut(&key) {
+            r.name = room_name(room).await;
+            r.direct_target = direct_target(room);
+            r.topic = room.topic();
+            r.aliases = room_aliases(room);
+            r.power_levels = load_power_levels(room).await;
+            match member_changes {
+                [] => {}
+                [(user_id, membership)] => {
+                    update_user_color(&mut r.user_colors, user_id, membership);
+                    r.display_names.update_member(room, user_id).await;
+                }
+                _ => {
+                    r.user_colors = calculate_user_colors(room).await;
+                    r.display_names.load_room(room).await;
+                }
+            }
         } else {
-            self.rooms
-                .insert(room.room_id().to_owned(), RoomState::from_room(room).await);
+            self.rooms.insert(
+                key,
+                RoomState::from_room(room, account, &self.display_names).await,
+            );
         }
     }
+    /// Whether notifications are suppressed by do not disturb or a snooze that has not run out.
+    fn notifications_silenced(&self) -> bool {
+        self.do_not_disturb || self.snooze_remaining().is_some()
+    }
+    pub fn snooze_remaining(&self) -> Option<std::time::Duration> {
+        self.snoozed_until
+            .and_then(|until| until.checked_duration_since(std::time::Instant::now()))
+    }
     fn current_room_state(&self) -> Option<&RoomState> {
         self.tui
             .room_selection
@@ -195,70 +727,195 @@ impl State {
             None
         }
     }
+    /// The account of the current room, or the first account if no room is selected.
+    fn current_account_index(&self) -> usize {
+        self.current_room_state().map(|r| r.account).unwrap_or(0)
+    }
+    fn current_account(&self) -> &AccountState {
+        &self.accounts[self.current_account_index()]
+    }
+    fn current_client(&self) -> Client {
+        self.current_account().client.clone()
+    }
     fn user_id(&self) -> &UserId {
-        &self.user_id
+        self.current_account().user_id()
+    }
+    pub fn presence(&self, user_id: &UserId) -> Option<&PresenceState> {
+        self.presence.get(user_id)
+    }
+    /// The presence that is sent with every sync: Unavailable while idle, unless the user set one.
+    fn sync_presence(&self) -> PresenceState {
+        match &self.own_presence {
+            Some(presence) => presence.clone(),
+            None if self.away => PresenceState::Unavailable,
+            None => PresenceState::Online,
+        }
+    }
+    fn register_input(&mut self) {
+        self.last_input = std::time::Instant::now();
+        if self.away {
+            self.away = false;
+            if self.own_presence.is_none() {
+                for account in &self.accounts {
+                    set_presence(&account.client, PresenceState::Online, None);
+                }
+            }
+        }
+    }
+    fn check_idle(&mut self, timeout: Option<std::time::Duration>) {
+        if let Some(timeout) = timeout {
+            if !self.away && self.last_input.elapsed() > timeout {
+                self.away = true;
+                if self.own_presence.is_none() {
+                    for account in &self.accounts {
+                        set_presence(&account.client, PresenceState::Unavailable, None);
+                    }
+                }
+            }
+        }
+    }
+    fn enforce_timeline_cache_limits(&mut self, config: &crate::config::Config) {
+        for r in self.rooms.values_mut() {
+            r.messages
+                .evict(config.timeline_cache_room_limit, r.tui.selected_event());
+        }
+
+        let mut total = self
+            .rooms
+            .values()
+            .map(|r| r.messages.num_events())
+            .sum::<usize>();
+        if total <= config.timeline_cache_total_limit {
+            return;
+        }
+        // Trim the least recently used rooms first, starting with their oldest events. The current
+        // room is never trimmed.
+        let current = self.tui.room_selection.current().cloned();
+        let mut candidates = self
+            .rooms
+            .keys()
+            .filter(|key| Some(*key) != current.as_ref())
+            .cloned()
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|key| self.tui.room_selection.recency(key));
+        for key in candidates {
+            if total <= config.timeline_cache_total_limit {
+                break;
+            }
+            let m = &mut self.rooms.get_mut(&key).unwrap().messages;
+            let before = m.num_events();
+            let excess = total - config.timeline_cache_total_limit;
+            m.evict(before.saturating_sub(excess), None);
+            total -= before - m.num_events();
+        }
     }
 }
 
 async fn handle_notification(c: &Connection, room: &Room, notification: Notification) {
     let c = c.clone();
     let mut bell = None;
-    let mut notification_handle = None;
-    if notification
-        .actions
-        .iter()
-        .any(|t| matches!(t, matrix_sdk::ruma::push::Action::Notify))
+    let mut pending_notification = None;
+    let (level, do_not_disturb) = {
+        let state = c.state.lock().await;
+        let level = state
+            .rooms
+            .get(&RoomKey::new(c.account, room.room_id().to_owned()))
+            .and_then(|r| r.notification_level)
+            .unwrap_or_else(|| c.config.room_notification_level(room.room_id()));
+        (level, state.notifications_silenced())
+    };
+    let is_highlight = notification.actions.iter().any(|t| {
+        matches!(
+            t,
+            matrix_sdk::ruma::push::Action::SetTweak(matrix_sdk::ruma::push::Tweak::Highlight(
+                true
+            ))
+        )
+    });
+    if is_highlight {
+        if let RawAnySyncOrStrippedTimelineEvent::Sync(raw) = &notification.event {
+            match raw.deserialize() {
+                Ok(e) if Some(e.sender()) != c.client.user_id().as_deref() => {
+                    let room_name = room.compute_display_name().await.unwrap().to_string();
+                    c.state.lock().await.tui.mentions.add(Mention {
+                        room: RoomKey::new(c.account, room.room_id().to_owned()),
+                        room_name,
+                        event_id: e.event_id().to_owned(),
+                        sender: e.sender().to_owned(),
+                        body: event_summary(&e),
+                        ts: e.origin_server_ts(),
+                    });
+                    let _ = c.events.lock().await.send(Event::Highlight).await;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!("can't deserialize event from notification: {:?}", e)
+                }
+            }
+        }
+    }
+    let level_permits = match level {
+        NotificationLevel::All => true,
+        NotificationLevel::MentionsOnly => is_highlight,
+        NotificationLevel::Muted => false,
+    };
+    if level_permits
+        && !do_not_disturb
+        && notification
+            .actions
+            .iter()
+            .any(|t| matches!(t, matrix_sdk::ruma::push::Action::Notify))
     {
         use crate::config::NotificationStyle;
         if let RawAnySyncOrStrippedTimelineEvent::Sync(raw) = notification.event {
             match raw.deserialize() {
                 Ok(e) => {
                     if Some(e.sender()) != c.client.user_id().as_deref() {
-                        let mut notification = notify_rust::Notification::new();
                         let sender = e.sender().to_string();
+                        let room_name = room.compute_display_name().await.unwrap().to_string();
                         let group_string = if room.is_direct().await.unwrap() {
                             format!("{}", sender)
                         } else {
-                            let g = room.compute_display_name().await.unwrap().to_string();
-                            format!("{} in {}", sender, g)
+                            format!("{} in {}", sender, room_name)
                         };
-                        let content = if let AnySyncTimelineEvent::MessageLike(m) = e {
-                            if let Some(AnyMessageLikeEventContent::RoomMessage(m)) =
-                                m.original_content()
-                            {
-                                match m.msgtype {
-                                    MessageType::Text(t) => t.body,
-                                    MessageType::Image(_) => String::from("sent an image"),
-                                    MessageType::Audio(_) => String::from("sent an audio message"),
-                                    MessageType::Video(_) => String::from("sent a video"),
-                                    MessageType::File(_) => String::from("sent a file"),
-                                    _ => String::new(),
-                                }
-                            } else {
-                                String::new()
-                            }
-                        } else {
-                            String::new()
+                        let content = event_summary(&e);
+                        if let Some(cmd) = &c.config.notification_command {
+                            run_notification_command(cmd.clone(), &sender, &room_name, &content);
+                        }
+                        let (summary, body) = match c.config.notification_style {
+                            NotificationStyle::Disabled => (None, None),
+                            NotificationStyle::NameOnly => (Some(sender.clone()), None),
+                            NotificationStyle::NameAndGroup => (Some(group_string), None),
+                            NotificationStyle::Full => (
+                                Some(group_string),
+                                Some(notification_body(
+                                    &c.config.notification_body,
+                                    &sender,
+                                    &room_name,
+                                    &content,
+                                )),
+                            ),
                         };
-                        match c.config.notification_style {
-                            NotificationStyle::Disabled => {}
-                            NotificationStyle::NameOnly => {
-                                notification.summary(&format!("{}", sender));
-                            }
-                            NotificationStyle::NameAndGroup => {
-                                notification.summary(&group_string);
-                            }
-                            NotificationStyle::Full => {
-                                notification.summary(&group_string);
-                                notification.body(&format!("{}", content));
+                        if let Some(summary) = summary {
+                            let notification = DesktopNotification {
+                                room: RoomKey::new(c.account, room.room_id().to_owned()),
+                                room_name,
+                                sender,
+                                message: content,
+                                summary,
+                                body,
+                                urgency: notify_rust::Urgency::Normal,
+                            };
+                            let events = c.events.lock().await.clone();
+                            if c.config.hooks.is_registered(Hook::Notification) {
+                                // The hooks run in the tui, which shows the notification afterwards.
+                                let _ = events.send(Event::Notification(notification)).await;
+                            } else {
+                                pending_notification = Some(notification);
                             }
-                        }
-                        if !matches!(c.config.notification_style, NotificationStyle::Disabled) {
-                            match notification.show() {
-                                Ok(handle) => notification_handle = Some(handle),
-                                Err(e) => tracing::error!("Failed to show notification {}", e),
+                            if c.config.bell.rings_for(is_highlight) {
+                                bell = Some(Event::Bell);
                             }
-                            bell = Some(Event::Bell);
                         }
                     }
                 }
@@ -270,12 +927,13 @@ async fn handle_notification(c: &Connection, room: &Room, notification: Notifica
     }
     {
         let mut state = c.state.lock().await;
-        let m = &mut state.rooms.get_mut(room.room_id()).unwrap();
+        let key = RoomKey::new(c.account, room.room_id().to_owned());
+        let clicks = state.notification_clicks.clone();
+        let m = &mut state.rooms.get_mut(&key).unwrap();
         m.num_unread_notifications = room.unread_notification_counts().notification_count;
-        if let Some(handle) = notification_handle {
-            m.last_notification_handle
-                .replace(handle)
-                .map(|old_handle| old_handle.close());
+        m.num_unread_highlights = room.unread_notification_counts().highlight_count;
+        if let Some(notification) = pending_notification {
+            m.show_notification(notification, &c.config, &clicks);
         }
         if let Some(bell) = bell {
             c.events.lock().await.send(bell).await.unwrap();
@@ -284,9 +942,314 @@ async fn handle_notification(c: &Connection, room: &Room, notification: Notifica
         }
     }
 }
+/// Knocks are answered with an invite (or a join if the room was made public meanwhile) or rejected
+/// by removing our membership. New knocks, including the ones sent by other clients, are taken
+/// from the store, which is up to date with the sync.
+async fn update_knocks(c: &Connection, rooms: &matrix_sdk::sync::RoomUpdates) {
+    let mut state = c.state.lock().await;
+    for (key, status) in state.knocks.iter_mut() {
+        if key.account != c.account {
+            continue;
+        }
+        if rooms.join.contains_key(&key.id) {
+            *status = KnockStatus::Joined;
+        } else if rooms.invite.contains_key(&key.id) {
+            *status = KnockStatus::Invited;
+        } else if rooms.leave.contains_key(&key.id) {
+            *status = KnockStatus::Rejected;
+        }
+    }
+    for room in c.client.rooms_filtered(RoomStateFilter::KNOCKED) {
+        let key = RoomKey::new(c.account, room.room_id().into());
+        state.knocks.insert(key, KnockStatus::Pending);
+    }
+}
+
+/// Show a desktop notification naming the inviter and the room for an invite that arrived with
+/// the latest sync.
+async fn handle_invite(c: &Connection, room_id: &RoomId) {
+    use crate::config::NotificationStyle;
+    let Some(room) = c.client.get_room(room_id) else {
+        return;
+    };
+    if room.state() != matrix_sdk::RoomState::Invited
+        || c.state.lock().await.notifications_silenced()
+    {
+        return;
+    }
+    let inviter = match room.invite_details().await {
+        Ok(invite) => invite.inviter.map(|member| member.name().to_owned()),
+        Err(e) => {
+            tracing::warn!("Failed to load invite details of {}: {}", room_id, e);
+            None
+        }
+    };
+    let inviter = inviter.unwrap_or_else(|| "Someone".to_owned());
+    let room_name = match room.compute_display_name().await {
+        Ok(name) => name.to_string(),
+        Err(_) => room_id.to_string(),
+    };
+    if let Some(cmd) = &c.config.notification_command {
+        run_notification_command(cmd.clone(), &inviter, &room_name, "invited you");
+    }
+    let mut notification = notify_rust::Notification::new();
+    match c.config.notification_style {
+        NotificationStyle::Disabled => return,
+        NotificationStyle::NameOnly => {
+            notification.summary(&format!("{} invited you", inviter));
+        }
+        NotificationStyle::NameAndGroup | NotificationStyle::Full => {
+            notification.summary(&format!("{} invited you to {}", inviter, room_name));
+        }
+    }
+    if let Err(e) = notification.show() {
+        tracing::error!("Failed to show notification {}", e);
+    }
+    if c.config.bell.rings_for(false) {
+        c.events.lock().await.send(Event::Bell).await.unwrap();
+    }
+}
+
+fn event_summary(e: &AnySyncTimelineEvent) -> String {
+    if let AnySyncTimelineEvent::MessageLike(m) = e {
+        match m.original_content() {
+            Some(AnyMessageLikeEventContent::RoomMessage(m)) => {
+                // Edits carry the full new content separately from the fallback body.
+                return match m.relates_to {
+                    Some(Relation::Replacement(r)) => {
+                        format!("edited: {}", message_summary(r.new_content.msgtype))
+                    }
+                    _ => message_summary(m.msgtype),
+                };
+            }
+            Some(AnyMessageLikeEventContent::Reaction(r)) => {
+                return format!("reacted {} to your message", r.relates_to.key);
+            }
+            _ => {}
+        }
+    }
+    String::new()
+}
+
+fn message_summary(msgtype: MessageType) -> String {
+    // Media bodies are captions if a filename is given separately.
+    match msgtype {
+        MessageType::Text(t) => t.body,
+        MessageType::Notice(t) => t.body,
+        MessageType::Emote(t) => format!("* {}", t.body),
+        MessageType::Image(i) => format!("sent an image: {}", i.filename.unwrap_or(i.body)),
+        MessageType::Audio(a) => {
+            format!("sent an audio message: {}", a.filename.unwrap_or(a.body))
+        }
+        MessageType::Video(v) => format!("sent a video: {}", v.filename.unwrap_or(v.body)),
+        MessageType::File(f) => format!("sent a file: {}", f.filename.unwrap_or(f.body)),
+        MessageType::Location(_) => String::from("shared a location"),
+        _ => String::new(),
+    }
+}
+
+/// Fill in the `{sender}`, `{room}` and `{body}` placeholders of the `notification_body` template.
+/// Substitutes the placeholders in a single pass so that a sender or body which itself contains
+/// "{room}" etc. is left as is.
+fn notification_body(template: &str, sender: &str, room: &str, body: &str) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let replacement = [("{sender}", sender), ("{room}", room), ("{body}", body)]
+            .into_iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder));
+        match replacement {
+            Some((placeholder, value)) => {
+                result.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn run_notification_command(cmd: String, sender: &str, room: &str, body: &str) {
+    let child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("SPARSE_SENDER", sender)
+        .env("SPARSE_ROOM", room)
+        .env("SPARSE_BODY", body)
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                if let Err(e) = child.wait().await {
+                    tracing::error!("Failed to wait for notification command: {}", e);
+                }
+            });
+        }
+        Err(e) => tracing::error!("Failed to run notification command: {}", e),
+    }
+}
+
+/// A desktop notification about a message, as configured by the notification style.
+pub struct DesktopNotification {
+    room: RoomKey,
+    room_name: String,
+    sender: String,
+    message: String,
+    pub summary: String,
+    pub body: Option<String>,
+    pub urgency: notify_rust::Urgency,
+}
+
+impl DesktopNotification {
+    pub fn room(&self) -> &RoomKey {
+        &self.room
+    }
+    /// The data passed to the `on_notification` hooks.
+    pub fn hook_data(&self) -> tui::actions::HookData {
+        let urgency = match self.urgency {
+            notify_rust::Urgency::Low => "low",
+            notify_rust::Urgency::Normal => "normal",
+            notify_rust::Urgency::Critical => "critical",
+        };
+        vec![
+            ("room_id", self.room.id.to_string()),
+            ("room", self.room_name.clone()),
+            ("sender", self.sender.clone()),
+            ("body", self.message.clone()),
+            ("summary", self.summary.clone()),
+            ("urgency", urgency.to_owned()),
+        ]
+    }
+}
+
+pub fn parse_urgency(urgency: &str) -> Result<notify_rust::Urgency, String> {
+    match urgency {
+        "low" => Ok(notify_rust::Urgency::Low),
+        "normal" => Ok(notify_rust::Urgency::Normal),
+        "critical" => Ok(notify_rust::Urgency::Critical),
+        _ => Err(format!("'{}' is not a valid urgency", urgency)),
+    }
+}
+
+/// Show `n` and return its handle. Clicks on the notification focus the room if configured, see
+/// `notification_clicks`.
+fn show_notification(
+    n: &DesktopNotification,
+    config: &crate::config::Config,
+    clicks: &notification_clicks::NotificationClicks,
+) -> Option<notify_rust::NotificationHandle> {
+    let mut notification = notify_rust::Notification::new();
+    notification.summary(&n.summary);
+    if let Some(body) = &n.body {
+        notification.body(body);
+    }
+    notification.urgency(n.urgency);
+    if config.notification_click_focus {
+        notification.action("default", "Open");
+    }
+    match notification.show() {
+        Ok(handle) => {
+            if config.notification_click_focus {
+                clicks.register(handle.id(), n.room.clone());
+            }
+            Some(handle)
+        }
+        Err(e) => {
+            tracing::error!("Failed to show notification {}", e);
+            None
+        }
+    }
+}
+
+fn set_presence(client: &Client, presence: PresenceState, status_msg: Option<String>) {
+    let Some(user_id) = client.user_id().map(|u| u.to_owned()) else {
+        return;
+    };
+    let client = client.clone();
+    tokio::spawn(async move {
+        let mut request = set_presence::v3::Request::new(user_id, presence);
+        request.status_msg = status_msg;
+        if let Err(e) = client.send(request, None).await {
+            tracing::error!("Failed to set presence: {:?}", e);
+        }
+    });
+}
+
+async fn populate_room_state(c: Connection, room: Room) {
+    let name = room_name(&room).await;
+    let latest_read_message = load_latest_read_message(&room).await;
+    let user_colors = calculate_user_colors(&room).await;
+    let power_levels = load_power_levels(&room).await;
+    let tags = load_tags(&room).await;
+    let cached_events = load_cached_events(&room).await;
+    let display_names = c.state.lock().await.display_names.clone();
+    display_names.load_room(&room).await;
+
+    let mut state = c.state.lock().await;
+    let key = RoomKey::new(c.account, room.room_id().to_owned());
+    if let Some(r) = state.rooms.get_mut(&key) {
+        r.messages.insert_cached(cached_events);
+        r.name = name;
+        r.user_colors = user_colors;
+        r.power_levels = power_levels;
+        r.tags = tags;
+        // The user may have already read newer messages while we were loading.
+        if r.latest_read_message.is_none() {
+            r.latest_read_message = latest_read_message;
+        }
+    }
+    c.update().await;
+}
+
+/// The latest events of the room that the event cache kept in the store.
+async fn load_cached_events(room: &Room) -> Vec<SyncTimelineEvent> {
+    let res = match room.event_cache().await {
+        Ok((cache, _drop_handles)) => cache.subscribe().await.map(|(events, _)| events),
+        Err(e) => Err(e),
+    };
+    res.unwrap_or_else(|e| {
+        tracing::warn!("Failed to load cached events of {}: {}", room.room_id(), e);
+        Vec::new()
+    })
+}
+
+/// Apply changes of the `m.direct` account data to the rooms of the account.
+async fn update_direct_targets(c: &Connection) {
+    let changed = {
+        let state = c.state.lock().await;
+        c.client
+            .joined_rooms()
+            .into_iter()
+            .filter(|room| {
+                state
+                    .rooms
+                    .get(&RoomKey::new(c.account, room.room_id().to_owned()))
+                    .map_or(false, |r| r.direct_target != direct_target(room))
+            })
+            .collect::<Vec<_>>()
+    };
+    for room in changed {
+        let name = room_name(&room).await;
+        let key = RoomKey::new(c.account, room.room_id().to_owned());
+        if let Some(r) = c.state.lock().await.rooms.get_mut(&key) {
+            r.direct_target = direct_target(&room);
+            r.name = name;
+        }
+    }
+}
+
 async fn try_reset_timeline_cache(c: &Connection, room_id: &RoomId) {
     let mut state = c.state.lock().await;
-    let m = &mut state.rooms.get_mut(room_id).unwrap().messages;
+    let key = RoomKey::new(c.account, room_id.to_owned());
+    let Some(m) = state.rooms.get_mut(&key).map(|r| &mut r.messages) else {
+        return;
+    };
     if m.has_undecrypted_messages() {
         tracing::info!(
             "Reseting cache of room {} with undecrypted messages due to new room key",
@@ -296,76 +1259,383 @@ async fn try_reset_timeline_cache(c: &Connection, room_id: &RoomId) {
     }
 }
 
+/// Try to decrypt the events again. For each missing session, the crypto machine of the sdk queues
+/// an `m.room_key_request` to our own devices, which is sent with the next sync, and downloads the
+/// key from the backup. Once a key arrives, the timeline is reloaded (see
+/// `try_reset_timeline_cache`), which also clears the requests.
+fn request_missing_keys(c: &Connection, state: &mut State) {
+    for (key, room_state) in state.rooms.iter_mut() {
+        if key.account != c.account {
+            continue;
+        }
+        let events = room_state.messages.take_missing_key_events();
+        if events.is_empty() {
+            continue;
+        }
+        let Some(room) = c.client.get_room(&key.id) else {
+            continue;
+        };
+        let c = c.clone();
+        tokio::spawn(async move {
+            let mut decrypted_any = false;
+            for event in events {
+                match room.decrypt_event(&event).await {
+                    Ok(e) if e.encryption_info().is_some() => decrypted_any = true,
+                    Ok(_) => {}
+                    Err(e) => tracing::debug!("Still unable to decrypt event: {}", e),
+                }
+            }
+            if decrypted_any {
+                try_reset_timeline_cache(&c, room.room_id()).await;
+                c.update().await;
+            }
+        });
+    }
+}
+
+/// State events of a sync response that change what `update_room_info` loads about a room.
+#[derive(Default)]
+struct RoomInfoChanges {
+    changed: bool,
+    members: Vec<(OwnedUserId, MembershipState)>,
+}
+
+impl RoomInfoChanges {
+    fn note(&mut self, event: &AnySyncStateEvent) {
+        match event {
+            AnySyncStateEvent::RoomMember(m) => {
+                self.changed = true;
+                self.members
+                    .push((m.state_key().to_owned(), m.membership().clone()));
+            }
+            AnySyncStateEvent::RoomName(_)
+            | AnySyncStateEvent::RoomCanonicalAlias(_)
+            | AnySyncStateEvent::RoomTopic(_)
+            | AnySyncStateEvent::RoomPowerLevels(_) => self.changed = true,
+            _ => {}
+        }
+    }
+}
+
+const SYNC_RETRY_BACKOFF_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+const SYNC_RETRY_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(120);
+const IDLE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
 async fn run_matrix_event_loop(c: Connection) {
     let client = c.client.clone();
 
     let c = &c;
-    loop {
-        let settings = SyncSettings::default();
-        let res = client
-            .sync_with_callback(settings, |response| async move {
-                for (room_id, notifications) in response.notifications {
-                    if let Some(room) = c.client.get_room(&room_id) {
-                        for notification in notifications {
-                            handle_notification(c, &room, notification).await;
-                        }
+    let mut backoff = SYNC_RETRY_BACKOFF_MIN;
+    while !c.quitting() {
+        // The server sets the presence from the sync requests, so the sync is restarted whenever
+        // it changes.
+        let presence = c.state.lock().await.sync_presence();
+        let settings = SyncSettings::default()
+            .filter(c.config.sync_filter().into())
+            .set_presence(presence.clone());
+        let presence = &presence;
+        let processing = &std::sync::atomic::AtomicBool::new(false);
+        let sync = client.sync_with_callback(settings, |response| async move {
+            processing.store(true, std::sync::atomic::Ordering::Relaxed);
+            {
+                let mut state = c.state.lock().await;
+                let account = &mut state.accounts[c.account];
+                account.sync_retry_at = None;
+                account.session_status = SessionStatus::Valid;
+            }
+            for (room_id, notifications) in response.notifications {
+                if let Some(room) = c.client.get_room(&room_id) {
+                    for notification in notifications {
+                        handle_notification(c, &room, notification).await;
                     }
                 }
-                for e in response.to_device {
+            }
+            if !response.presence.is_empty() {
+                let mut state = c.state.lock().await;
+                for e in &response.presence {
                     match e.deserialize() {
-                        Ok(AnyToDeviceEvent::RoomKey(e)) => {
-                            try_reset_timeline_cache(&c, &e.content.room_id).await
-                        }
-                        Ok(AnyToDeviceEvent::ForwardedRoomKey(e)) => {
-                            try_reset_timeline_cache(&c, &e.content.room_id).await
+                        Ok(e) => {
+                            state.presence.insert(e.sender, e.content.presence);
                         }
-                        Ok(_) => {}
                         Err(e) => {
-                            tracing::warn!("Failed to deserialize state event {}", e)
+                            tracing::warn!("Failed to deserialize presence event {}", e)
                         }
                     }
                 }
-                for (room_id, room_info) in response.rooms.join {
-                    let timeline = room_info.timeline;
+            }
+            for e in response.to_device {
+                match e.deserialize() {
+                    Ok(AnyToDeviceEvent::RoomKey(e)) => {
+                        try_reset_timeline_cache(&c, &e.content.room_id).await
+                    }
+                    Ok(AnyToDeviceEvent::ForwardedRoomKey(e)) => {
+                        try_reset_timeline_cache(&c, &e.content.room_id).await
+                    }
+                    Ok(AnyToDeviceEvent::KeyVerificationRequest(e)) => {
+                        let device = Some(e.content.from_device);
+                        let flow_id = e.content.transaction_id.as_str();
+                        verification::handle_request(&c, &e.sender, flow_id, device).await
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::warn!("Failed to deserialize state event {}", e)
+                    }
+                }
+            }
+            let direct_changed = response
+                .account_data
+                .iter()
+                .any(|e| matches!(e.deserialize(), Ok(AnyGlobalAccountDataEvent::Direct(_))));
+            if direct_changed {
+                update_direct_targets(c).await;
+            }
+            for room_id in response.rooms.invite.keys() {
+                handle_invite(c, room_id).await;
+            }
+            update_knocks(c, &response.rooms).await;
+            if !response.rooms.leave.is_empty() {
+                // Left rooms stay in the list, but only to read their history.
+                let mut state = c.state.lock().await;
+                for room_id in response.rooms.leave.keys() {
+                    let key = RoomKey::new(c.account, room_id.clone());
+                    if let Some(room) = state.rooms.get_mut(&key) {
+                        room.read_only = true;
+                    }
+                }
+            }
+            let run_message_hooks = c.config.hooks.is_registered(Hook::Message);
+            let mut message_hooks = Vec::new();
+            for (room_id, room_info) in response.rooms.join {
+                let timeline = room_info.timeline;
 
-                    let mut state = c.state.lock().await;
-                    // Lazily insert new rooms if they just now become known to the client
-                    let room = match state.rooms.entry(room_id.clone()) {
-                        std::collections::btree_map::Entry::Vacant(entry) => {
-                            let room = c.client.get_room(&room_id).unwrap();
-                            entry.insert(RoomState::from_room(&room).await)
+                let mut room_messages = Vec::new();
+                let mut seen_aliases = Vec::new();
+                let mut num_messages = 0;
+                let mut latest_activity = 0;
+                let mut info_changes = RoomInfoChanges::default();
+                for e in &timeline.events {
+                    if let Ok(Some(ts)) = e
+                        .raw()
+                        .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                    {
+                        latest_activity = latest_activity.max(u64::from(ts.0));
+                    }
+                    let event = e.raw().deserialize();
+                    // Live joins, renames etc. arrive in the timeline rather than the state.
+                    if let Ok(AnySyncTimelineEvent::State(s)) = &event {
+                        info_changes.note(s);
+                    }
+                    if let Ok(AnySyncTimelineEvent::MessageLike(
+                        AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(m)),
+                    )) = event
+                    {
+                        num_messages += 1;
+                        seen_aliases.extend(aliases_in(m.content.body()));
+                        if run_message_hooks {
+                            room_messages.push(vec![
+                                ("room_id", room_id.to_string()),
+                                ("event_id", m.event_id.to_string()),
+                                ("sender", m.sender.to_string()),
+                                ("msgtype", m.content.msgtype().to_owned()),
+                                ("body", m.content.body().to_owned()),
+                                ("account", c.client.user_id().unwrap().to_string()),
+                            ]);
                         }
-                        std::collections::btree_map::Entry::Occupied(r) => r.into_mut(),
-                    };
-                    let m = &mut room.messages;
-                    m.handle_sync_batch(timeline, &response.next_batch);
-
-                    use matrix_sdk::ruma::events::AnySyncStateEvent;
-                    let room = c.client.get_room(&room_id).unwrap();
-                    for e in room_info.state {
-                        match e.deserialize() {
-                            Ok(
-                                AnySyncStateEvent::RoomMember(_)
-                                | AnySyncStateEvent::RoomName(_)
-                                | AnySyncStateEvent::RoomCanonicalAlias(_),
-                            ) => {
-                                state.update_room_info(&room).await;
-                            }
-                            Ok(_) => {}
-                            Err(e) => {
-                                tracing::warn!("Failed to deserialize state event {}", e)
-                            }
+                        if let MessageType::VerificationRequest(r) = m.content.msgtype {
+                            let device = Some(r.from_device);
+                            let flow_id = m.event_id.as_str();
+                            verification::handle_request(&c, &m.sender, flow_id, device).await
                         }
                     }
                 }
 
-                c.update().await;
+                let mut state = c.state.lock().await;
+                state.seen_aliases.extend(seen_aliases);
+                let auto_follow = state.tui.auto_follow;
+                // Lazily insert new rooms if they just now become known to the client
+                let key = RoomKey::new(c.account, room_id.clone());
+                let display_names = state.display_names.clone();
+                let room = match state.rooms.entry(key) {
+                    std::collections::btree_map::Entry::Vacant(entry) => {
+                        let room = c.client.get_room(&room_id).unwrap();
+                        entry.insert(RoomState::from_room(&room, c.account, &display_names).await)
+                    }
+                    std::collections::btree_map::Entry::Occupied(r) => r.into_mut(),
+                };
+                message_hooks.extend(room_messages);
+                room.read_only = false;
+                room.last_activity = room.last_activity.max(latest_activity);
+                let m = &mut room.messages;
+                let previous_newest = m
+                    .walk_from_newest()
+                    .message()
+                    .map(|pos| m.message(pos).event_id().to_owned());
+                m.handle_sync_batch(timeline, &response.next_batch);
+                room.remove_synced_echoes();
+                room.tui
+                    .handle_new_messages(previous_newest, num_messages, auto_follow);
+                if let Some(r) = c.client.get_room(&room_id) {
+                    room.successor = r.tombstone().map(|t| t.replacement_room);
+                }
+                for e in &room_info.ephemeral {
+                    if let Ok(AnySyncEphemeralRoomEvent::Receipt(r)) = e.deserialize() {
+                        room.handle_receipts(&r.content, c.client.user_id().unwrap());
+                    }
+                }
+                for e in &room_info.account_data {
+                    if let Ok(AnyRoomAccountDataEvent::Tag(t)) = e.deserialize() {
+                        room.tags = tag_names(&t.content.tags);
+                    }
+                }
+
+                // Collect all changes first so that the room info is only updated once per
+                // sync response, even if many members join at once.
+                let room = c.client.get_room(&room_id).unwrap();
+                for e in room_info.state {
+                    match e.deserialize() {
+                        Ok(s) => info_changes.note(&s),
+                        Err(e) => {
+                            tracing::warn!("Failed to deserialize state event {}", e)
+                        }
+                    }
+                }
+                if info_changes.changed {
+                    state
+                        .update_room_info(&room, c.account, &info_changes.members)
+                        .await;
+                }
+            }
+
+            {
+                let mut state = c.state.lock().await;
+                state.enforce_timeline_cache_limits(&c.config);
+                request_missing_keys(c, &mut state);
+                if let Some(path) = &c.config.status_file {
+                    status_file::write(path, &state);
+                }
+            }
+            let events = c.events.lock().await.clone();
+            for data in message_hooks {
+                let _ = events.send(tui::Event::Hook(Hook::Message, data)).await;
+            }
+            if c.config.hooks.is_registered(Hook::Sync) {
+                let data = vec![
+                    ("account", c.client.user_id().unwrap().to_string()),
+                    ("next_batch", response.next_batch.clone()),
+                ];
+                let _ = events.send(tui::Event::Hook(Hook::Sync, data)).await;
+            }
+            c.update().await;
+            processing.store(false, std::sync::atomic::Ordering::Relaxed);
+            if c.quitting() || c.state.lock().await.sync_presence() != *presence {
+                LoopCtrl::Break
+            } else {
                 LoopCtrl::Continue
-            })
-            .await;
+            }
+        });
+        // Quitting drops a sync request that is still waiting for the server. A response that is
+        // being processed by the callback is finished first, the callback then stops the sync.
+        let res = tokio::select! {
+            res = sync => res,
+            _ = async {
+                c.quit().await;
+                if processing.load(std::sync::atomic::Ordering::Relaxed) {
+                    std::future::pending::<()>().await
+                }
+            } => break,
+        };
 
         if let Err(e) = res {
             tracing::error!("Error in sync loop: {}", e);
+
+            let retry_at = {
+                let mut state = c.state.lock().await;
+                let account = &mut state.accounts[c.account];
+                if account.sync_retry_at.is_none() {
+                    // The previous attempt succeeded at least once, so start over.
+                    backoff = SYNC_RETRY_BACKOFF_MIN;
+                }
+                let retry_at = std::time::Instant::now() + backoff;
+                account.sync_retry_at = Some(retry_at);
+                retry_at
+            };
+            // Wake up regularly so that the countdown in the status bar is updated. A successful
+            // login clears the retry time to resume syncing right away.
+            while std::time::Instant::now() < retry_at
+                && !c.quitting()
+                && c.state.lock().await.accounts[c.account]
+                    .sync_retry_at
+                    .is_some()
+            {
+                c.update().await;
+                tokio::select! {
+                    _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {}
+                    _ = c.quit() => {}
+                }
+            }
+            backoff = (backoff * 2).min(SYNC_RETRY_BACKOFF_MAX);
+        }
+    }
+}
+
+/// Set the presence to unavailable once there was no input for the configured time.
+async fn run_idle_loop(c: Connection, timeout: std::time::Duration) {
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = c.quit() => break,
+        }
+        c.state.lock().await.check_idle(Some(timeout));
+    }
+}
+
+async fn run_session_change_loop(c: Connection) {
+    let mut changes = c.client.subscribe_to_session_changes();
+    loop {
+        let change = tokio::select! {
+            change = changes.recv() => change,
+            _ = c.quit() => break,
+        };
+        match change {
+            Ok(SessionChange::TokensRefreshed) => {
+                if let Some(session) = c.client.matrix_auth().session() {
+                    if let Err(e) = crate::session::try_store_session(&c.config, &session) {
+                        tracing::error!("Failed to store refreshed session: {}", e);
+                    }
+                }
+            }
+            Ok(SessionChange::UnknownToken { soft_logout }) => {
+                tracing::warn!(
+                    "Access token was invalidated (soft logout: {})",
+                    soft_logout
+                );
+                let was_valid = {
+                    let mut state = c.state.lock().await;
+                    let account = &mut state.accounts[c.account];
+                    let was_valid = account.session_status == SessionStatus::Valid;
+                    account.session_status = if soft_logout {
+                        SessionStatus::SoftLoggedOut
+                    } else {
+                        SessionStatus::LoggedOut
+                    };
+                    was_valid
+                };
+                // Every failing request reports the invalid token, but we only ask once.
+                if was_valid && c.config.hooks.is_registered(Hook::SessionExpired) {
+                    let data = vec![
+                        ("account", c.client.user_id().unwrap().to_string()),
+                        ("soft_logout", soft_logout.to_string()),
+                    ];
+                    let events = c.events.lock().await.clone();
+                    let _ = events
+                        .send(tui::Event::Hook(Hook::SessionExpired, data))
+                        .await;
+                }
+                c.update().await;
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
         }
     }
 }
@@ -373,12 +1643,25 @@ async fn run_matrix_event_loop(c: Connection) {
 #[derive(Clone)]
 struct Connection {
     client: Client,
+    account: usize,
     state: Arc<Mutex<State>>,
     events: Arc<Mutex<mpsc::Sender<tui::Event>>>,
     config: crate::config::Config,
+    quit: watch::Receiver<bool>, // Set once sparse is quitting
 }
 
 impl Connection {
+    /// Whether the loops using this connection should stop instead of starting another request.
+    fn quitting(&self) -> bool {
+        *self.quit.borrow()
+    }
+
+    /// Resolves once sparse is quitting.
+    async fn quit(&self) {
+        let mut quit = self.quit.clone();
+        let _ = quit.wait_for(|quitting| *quitting).await;
+    }
+
     pub async fn update(&self) {
         match self.events.lock().await.try_send(tui::Event::Update) {
             Ok(_) => {}
@@ -403,24 +1686,41 @@ async fn run_matrix_message_fetch_loop(
     c: Connection,
     mut tasks: watch::Receiver<Option<tui::MessageQueryRequest>>,
 ) {
-    while tasks.changed().await.is_ok() {
+    loop {
+        let changed = tokio::select! {
+            changed = tasks.changed() => changed,
+            _ = c.quit() => break,
+        };
+        if changed.is_err() {
+            break;
+        }
         let task = { tasks.borrow().clone() };
         if let Some(task) = task {
-            let rid = task.room.as_ref();
-            let room = c.client.get_room(rid).unwrap();
+            let key = &task.room;
 
             let query = {
                 let state = c.state.lock().await;
-                let m = state.rooms.get(rid).unwrap();
+                let m = state.rooms.get(key).unwrap();
+                let room = state.accounts[key.account]
+                    .client
+                    .get_room(&key.id)
+                    .unwrap();
 
                 m.messages.events_query(room, task.kind).await
             };
 
-            let res = query.await.unwrap();
+            let res = match query.await {
+                Ok(res) => res,
+                Err(e) => {
+                    tracing::error!("Failed to fetch messages: {}", e);
+                    continue;
+                }
+            };
 
             let mut state = c.state.lock().await;
-            let m = state.rooms.get_mut(rid).unwrap();
+            let m = state.rooms.get_mut(key).unwrap();
             m.messages.update(res);
+            state.enforce_timeline_cache_limits(&c.config);
             c.update().await;
         }
     }
@@ -470,29 +1770,84 @@ pub fn init() {
 }
 
 pub async fn run(
-    client: Client,
-    config: crate::config::Config,
+    accounts: Vec<(Client, crate::config::Config, Option<String>)>,
     command_environment: tui::actions::CommandEnvironment,
 ) -> Result<(), matrix_sdk::Error> {
+    // The first account provides the settings for the tui itself.
+    let config = accounts[0].1.clone();
+
+    // Only create placeholders here so that the tui is shown immediately. Computing names,
+    // receipts and colors requires store access and may take a while for many rooms, so we do
+    // that in the background below.
+    let display_names = display_names::DisplayNames::default();
     let mut rooms = BTreeMap::new();
-    for room in client.joined_rooms() {
-        let id = room.room_id();
-        if let Some(room) = client.get_room(id) {
-            rooms.insert(id.to_owned(), RoomState::from_room(&room).await);
+    let mut listed_rooms = Vec::new();
+    for (account, (client, _, _)) in accounts.iter().enumerate() {
+        // Left rooms are archived.
+        for room in client.joined_rooms().into_iter().chain(client.left_rooms()) {
+            rooms.insert(
+                RoomKey::new(account, room.room_id().to_owned()),
+                RoomState::placeholder(&room, account, &display_names),
+            );
+            listed_rooms.push((account, room));
         }
     }
-    let user_id = client.user_id().unwrap();
-    let state = Arc::new(Mutex::new(State::new(rooms, user_id.into())));
+    for (client, _, _) in &accounts {
+        // Keep the latest events of each room in the store, so that the timelines can be shown
+        // before the first sync, e.g., while the server cannot be reached.
+        let event_cache = client.event_cache();
+        if let Err(e) = event_cache
+            .subscribe()
+            .and_then(|_| event_cache.enable_storage())
+        {
+            tracing::warn!("Failed to enable the event cache: {}", e);
+        }
+    }
+    let saved_rooms = history::SavedRooms::load(&config);
+    for room in rooms.values_mut() {
+        if let Some(ts) = saved_rooms.activity.get(&room.id) {
+            room.last_activity = *ts;
+        }
+    }
+    let current_room = saved_rooms.initial_room(&config, &rooms);
+    let room_history = if config.restore_room_history {
+        saved_rooms.history(&rooms)
+    } else {
+        Vec::new()
+    };
+
+    let (verification_sender, verification_receiver) = mpsc::unbounded_channel();
+    let state = Arc::new(Mutex::new(State::new(
+        rooms,
+        room_history,
+        current_room,
+        accounts
+            .iter()
+            .map(|(client, config, store_passphrase)| {
+                AccountState::new(client.clone(), config.clone(), store_passphrase.clone())
+            })
+            .collect(),
+        display_names,
+        verification_sender,
+    )));
 
     let (event_sender, event_receiver) = mpsc::channel(1);
     let (message_query_sender, message_query_receiver) = watch::channel(None);
 
-    let connection = Connection {
-        client: client.clone(),
-        state: state.clone(),
-        events: Arc::new(Mutex::new(event_sender.clone())),
-        config: config.clone(),
-    };
+    let events = Arc::new(Mutex::new(event_sender.clone()));
+    let (quit_sender, quit) = watch::channel(false);
+    let connections = accounts
+        .into_iter()
+        .enumerate()
+        .map(|(account, (client, config, _))| Connection {
+            client,
+            account,
+            state: state.clone(),
+            events: events.clone(),
+            config,
+            quit: quit.clone(),
+        })
+        .collect::<Vec<_>>();
 
     let orig_attr = std::sync::Mutex::new(
         nix::sys::termios::tcgetattr(STDOUT).expect("Failed to get terminal attributes"),
@@ -534,27 +1889,78 @@ pub async fn run(
         .unwrap();
     }));
 
-    let tui_client = connection.client.clone();
-    let connection_events = connection.clone();
-    let connection_queries = connection;
-    let _event_loop = tokio::spawn(async { run_matrix_event_loop(connection_events).await });
-    let _message_query_loop = tokio::spawn(async {
+    for (account, room) in listed_rooms {
+        tokio::spawn(populate_room_state(connections[account].clone(), room));
+    }
+
+    // Loops that use the clients, which are stopped when quitting.
+    let mut loops = Vec::new();
+    for connection in &connections {
+        loops.push(tokio::spawn(run_session_change_loop(connection.clone())));
+        loops.push(tokio::spawn(run_matrix_event_loop(connection.clone())));
+    }
+    // These only need a client that is specific to the room or request, so one loop suffices.
+    loops.push(tokio::spawn(verification::run_outgoing_loop(
+        connections[0].clone(),
+        verification_receiver,
+    )));
+    if config.notification_click_focus {
+        let clicks = state.lock().await.notification_clicks.clone();
+        loops.push(tokio::spawn(notification_clicks::run(
+            connections[0].clone(),
+            clicks,
+        )));
+    }
+    if let Some(timeout) = config.auto_away_timeout {
+        loops.push(tokio::spawn(run_idle_loop(connections[0].clone(), timeout)));
+    }
+    let connection_queries = connections[0].clone();
+    loops.push(tokio::spawn(async {
         run_matrix_message_fetch_loop(connection_queries, message_query_receiver).await
-    });
+    }));
     //tokio::spawn(async { tui::run_keyboard_loop(sender) });
 
+    let control_socket_path = config.control_socket_path();
+    let control_socket = control::bind_control_socket(&control_socket_path).await;
+    let control_socket_bound = control_socket.is_some();
+    if let Some(listener) = control_socket {
+        loops.push(tokio::spawn(control::run_control_socket(
+            connections[0].clone(),
+            listener,
+        )));
+    }
+
+    #[cfg(feature = "dbus")]
+    let dbus_service = dbus::start_service(connections[0].clone()).await;
+
+    let pending_sends = state.lock().await.pending_sends.clone();
+    let tui_events = event_sender.clone();
     start_signal_thread(event_sender.clone());
     start_keyboard_thread(event_sender);
 
     tui::run_tui(
         event_receiver,
+        tui_events,
         message_query_sender,
-        state,
-        tui_client,
+        state.clone(),
         command_environment,
-        config,
+        config.clone(),
+        shutdown::run(quit_sender, loops, pending_sends),
     )
     .await;
 
+    history::SavedRooms::from_state(&*state.lock().await).save(&config);
+
+    // Close the stores: Their database connections are closed once the last client is dropped,
+    // which the stopped loops no longer hold.
+    #[cfg(feature = "dbus")]
+    drop(dbus_service);
+    drop(connections);
+    drop(state);
+
+    if control_socket_bound {
+        let _ = std::fs::remove_file(control_socket_path);
+    }
+
     Ok(())
 }
@@ -0,0 +1,74 @@
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::{Connection, Event, RoomKey};
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+/// The rooms of the notifications that are still open, by notification id.
+#[derive(Clone, Default)]
+pub struct NotificationClicks(Arc<Mutex<HashMap<u32, RoomKey>>>);
+
+impl NotificationClicks {
+    /// Focus `room` once the notification with `id` is clicked.
+    pub fn register(&self, id: u32, room: RoomKey) {
+        self.0.lock().unwrap().insert(id, room);
+    }
+
+    fn take(&self, id: u32) -> Option<RoomKey> {
+        self.0.lock().unwrap().remove(&id)
+    }
+}
+
+/// Listen for clicks on our notifications on the session bus and focus their rooms. A single loop
+/// serves all notifications, which are matched to the rooms by id.
+pub async fn run(c: Connection, clicks: NotificationClicks) {
+    if let Err(e) = listen(&c, &clicks).await {
+        tracing::error!("Failed to listen for notification clicks: {}", e);
+    }
+}
+
+async fn listen(c: &Connection, clicks: &NotificationClicks) -> zbus::Result<()> {
+    let connection = zbus::Connection::session().await?;
+    let proxy = zbus::Proxy::new(
+        &connection,
+        NOTIFICATIONS_NAME,
+        NOTIFICATIONS_PATH,
+        NOTIFICATIONS_NAME,
+    )
+    .await?;
+    let mut invoked = proxy.receive_signal("ActionInvoked").await?;
+    let mut closed = proxy.receive_signal("NotificationClosed").await?;
+    loop {
+        tokio::select! {
+            Some(msg) = invoked.next() => {
+                let (id, action) = msg.body().deserialize::<(u32, String)>()?;
+                if action != "default" {
+                    continue;
+                }
+                if let Some(room) = clicks.take(id) {
+                    focus(c, room).await;
+                }
+            }
+            Some(msg) = closed.next() => {
+                let (id, _reason) = msg.body().deserialize::<(u32, u32)>()?;
+                clicks.take(id);
+            }
+            _ = c.quit() => break,
+            else => break,
+        }
+    }
+    Ok(())
+}
+
+async fn focus(c: &Connection, room: RoomKey) {
+    if let Some(cmd) = &c.config.notification_raise_command {
+        if let Err(e) = std::process::Command::new("sh").arg("-c").arg(cmd).spawn() {
+            tracing::error!("Failed to run notification raise command: {}", e);
+        }
+    }
+    let events = c.events.lock().await.clone();
+    let _ = events.send(Event::FocusRoom(room)).await;
+}
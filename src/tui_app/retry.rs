@@ -0,0 +1,68 @@
+use matrix_sdk::ruma::api::client::error::{ErrorKind, RetryAfter};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+const MAX_ATTEMPTS: usize = 5;
+// Used if the server does not tell us how long to wait
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+static NUM_RATE_LIMITED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of requests that are currently waiting for a rate limit to expire.
+pub fn num_rate_limited() -> usize {
+    NUM_RATE_LIMITED.load(Ordering::Relaxed)
+}
+
+pub trait ApiError: std::fmt::Debug {
+    fn error_kind(&self) -> Option<&ErrorKind>;
+}
+
+impl ApiError for matrix_sdk::Error {
+    fn error_kind(&self) -> Option<&ErrorKind> {
+        self.client_api_error_kind()
+    }
+}
+
+impl ApiError for matrix_sdk::HttpError {
+    fn error_kind(&self) -> Option<&ErrorKind> {
+        self.client_api_error_kind()
+    }
+}
+
+fn rate_limit_delay(e: &impl ApiError) -> Option<Duration> {
+    match e.error_kind()? {
+        ErrorKind::LimitExceeded { retry_after } => Some(match retry_after {
+            Some(RetryAfter::Delay(d)) => *d,
+            Some(RetryAfter::DateTime(t)) => {
+                t.duration_since(SystemTime::now()).unwrap_or_default()
+            }
+            None => DEFAULT_RETRY_AFTER,
+        }),
+        _ => None,
+    }
+}
+
+/// Run the request produced by `request` until it is not rejected due to rate limiting anymore,
+/// waiting as long as the server asks us to in between.
+pub async fn with_retry<T, E: ApiError, F: Future<Output = Result<T, E>>>(
+    mut request: impl FnMut() -> F,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                if let Some(delay) = rate_limit_delay(&e) {
+                    tracing::warn!("Rate limited, retrying in {:?}", delay);
+                    NUM_RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay).await;
+                    NUM_RATE_LIMITED.fetch_sub(1, Ordering::Relaxed);
+                    attempt += 1;
+                } else {
+                    return Err(e);
+                }
+            }
+            res => return res,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+// How long quitting waits for the loops and sends that are still running.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends of messages, reactions, redactions, files and receipts that may still be running.
+#[derive(Clone, Default)]
+pub struct PendingSends(Arc<Mutex<Vec<JoinHandle<()>>>>);
+
+impl PendingSends {
+    /// Spawn a task that sends something to the server, which should not be cut off by quitting.
+    pub fn spawn(&self, f: impl Future<Output = ()> + Send + 'static) {
+        let handle = tokio::spawn(f);
+        let mut pending = self.0.lock().unwrap();
+        pending.retain(|h| !h.is_finished());
+        pending.push(handle);
+    }
+
+    fn take(&self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Wait for `tasks` until `deadline`. Returns how many did not finish in time.
+async fn wait_until(deadline: Instant, tasks: Vec<JoinHandle<()>>) -> usize {
+    let mut unfinished = 0;
+    for handle in tasks {
+        if tokio::time::timeout_at(deadline, handle).await.is_err() {
+            unfinished += 1;
+        }
+    }
+    unfinished
+}
+
+/// Ask the loops to stop and give them and the pending sends some time to finish before the tui
+/// restores the terminal and exits. The sync loops stop right away while they wait for the server,
+/// but finish processing a response that already arrived.
+pub async fn run(quit: watch::Sender<bool>, loops: Vec<JoinHandle<()>>, sends: PendingSends) {
+    let deadline = Instant::now() + FLUSH_TIMEOUT;
+    // Nothing received from now on would be shown anyway.
+    let _ = quit.send(true);
+    let unfinished_loops = wait_until(deadline, loops).await;
+    if unfinished_loops > 0 {
+        // Most likely a sync that is stuck processing a response.
+        tracing::info!("Quitting with {} loop(s) still running", unfinished_loops);
+    }
+    let unfinished_sends = wait_until(deadline, sends.take()).await;
+    if unfinished_sends > 0 {
+        tracing::warn!("Quitting with {} unfinished send(s)", unfinished_sends);
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use super::State;
+
+/// The unread counts in the format of the status file: The totals and the counts of all rooms with
+/// unread notifications, once per account that is in the room, e.g.
+/// `{"unread":3,"highlights":1,"rooms":[{"id":"!a:b.c","name":"#rust","account":"@me:b.c",
+/// "unread":3,"highlights":1}]}`
+fn status(state: &State) -> serde_json::Value {
+    let rooms = state
+        .rooms
+        .values()
+        .filter(|r| r.num_unread_notifications > 0 || r.num_unread_highlights > 0)
+        .map(|r| {
+            serde_json::json!({
+                "id": r.id.as_str(),
+                "name": r.name(),
+                "account": state.accounts[r.account()].user_id().as_str(),
+                "unread": r.num_unread_notifications,
+                "highlights": r.num_unread_highlights,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "unread": state.rooms.values().map(|r| r.num_unread_notifications).sum::<u64>(),
+        "highlights": state.rooms.values().map(|r| r.num_unread_highlights).sum::<u64>(),
+        "rooms": rooms,
+    })
+}
+
+/// Replace the status file at `path` with the current unread counts, for external status bars.
+pub fn write(path: &Path, state: &State) {
+    // Status bars may read the file at any time, so it is replaced at once instead of rewritten.
+    let tmp = path.with_extension("tmp");
+    let res =
+        std::fs::write(&tmp, status(state).to_string()).and_then(|()| std::fs::rename(&tmp, path));
+    if let Err(e) = res {
+        tracing::error!("Failed to write status file {}: {}", path.display(), e);
+    }
+}
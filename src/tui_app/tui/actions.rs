@@ -1,8 +1,9 @@
+use std::future::IntoFuture;
 use std::ops::Bound;
 use std::str::FromStr;
 
 use matrix_sdk::ruma::events::{
-    room::message::{Relation, RoomMessageEventContent},
+    room::message::{MessageFormat, Relation, RoomMessageEventContent},
     AnySyncMessageLikeEvent, SyncMessageLikeEvent,
 };
 use rlua::{Lua, RegistryKey, UserData, UserDataMethods, Value};
@@ -11,18 +12,49 @@ use matrix_sdk::Client;
 use unsegen::input::{Editable, Navigatable, OperationResult, Scrollable, Writable};
 use unsegen::widget::builtin::{TextEdit, TextElement, TextTarget};
 
+use matrix_sdk::ruma::events::key::verification::VerificationMethod;
 use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::presence::PresenceState;
+use matrix_sdk::ruma::{
+    MatrixId, MatrixToUri, MatrixUri, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName,
+    RoomAliasId, RoomId, RoomOrAliasId, UInt,
+};
 
 use cli_clipboard::ClipboardProvider;
+use tokio::sync::mpsc;
 
 use super::{super::State, Mode};
 use super::{BuiltinMode, EventDetail, SendMessageType, Tasks};
-use crate::config::Config;
+use crate::config::{Config, Hook};
 use crate::search::Filter;
 use crate::timeline::Event;
+use crate::tui_app::retry::with_retry;
+use crate::tui_app::{DesktopNotification, Privilege, SessionStatus};
 
 pub struct Action<'a>(pub &'a RegistryKey);
 
+/// Fields of the table that is passed to hook functions
+pub type HookData = Vec<(&'static str, String)>;
+
+/// A Lua function to be called once a process started by `spawn` has finished.
+pub struct Callback(RegistryKey);
+
+impl std::fmt::Debug for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// Command, arguments and optional callback
+type SpawnArgs<'lua> = (String, Vec<String>, Option<rlua::Function<'lua>>);
+
+#[derive(Debug)]
+pub struct ProcessOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: Option<i32>, // None if the process could not be started or was killed
+}
+
 pub struct CommandContext<'a> {
     pub client: &'a Client,
     pub state: &'a mut State,
@@ -30,6 +62,7 @@ pub struct CommandContext<'a> {
     pub tasks: Tasks<'a>,
     pub continue_running: &'a mut bool,
     pub command_environment: &'a CommandEnvironment,
+    pub events: &'a mpsc::Sender<super::Event>,
 }
 
 impl<'a> CommandContext<'a> {
@@ -52,6 +85,77 @@ impl<'a> CommandContext<'a> {
             })
         })
     }
+    pub fn run_hook(&mut self, action: Action, data: &HookData) -> rlua::Result<ActionResult> {
+        self.command_environment.lua.context(|lua_ctx| {
+            lua_ctx.scope(|scope| {
+                let c = scope.create_nonstatic_userdata(self)?;
+                let table = lua_ctx.create_table()?;
+                for (k, v) in data {
+                    table.set(*k, v.as_str())?;
+                }
+                let action: rlua::Function = lua_ctx.registry_value(action.0).unwrap();
+                // Hooks do not have to return a result, unlike actions bound to keys.
+                let res = action.call::<_, Option<ActionResult>>((c, table))?;
+                Ok(res.unwrap_or(ActionResult::Noop))
+            })
+        })
+    }
+    /// Returns false if the hook suppresses the notification. Hooks can also return a table with
+    /// a new summary, body or urgency.
+    pub fn run_notification_hook(
+        &mut self,
+        action: Action,
+        notification: &mut DesktopNotification,
+    ) -> rlua::Result<bool> {
+        self.command_environment.lua.context(|lua_ctx| {
+            lua_ctx.scope(|scope| {
+                let c = scope.create_nonstatic_userdata(self)?;
+                let table = lua_ctx.create_table()?;
+                for (k, v) in notification.hook_data() {
+                    table.set(k, v)?;
+                }
+                let action: rlua::Function = lua_ctx.registry_value(action.0).unwrap();
+                match action.call::<_, rlua::Value>((c, table))? {
+                    rlua::Value::Boolean(false) => Ok(false),
+                    rlua::Value::Table(t) => {
+                        if let Some(summary) = t.get::<_, Option<String>>("summary")? {
+                            notification.summary = summary;
+                        }
+                        if let Some(body) = t.get::<_, Option<String>>("body")? {
+                            notification.body = Some(body);
+                        }
+                        if let Some(urgency) = t.get::<_, Option<String>>("urgency")? {
+                            notification.urgency = crate::tui_app::parse_urgency(&urgency)
+                                .map_err(rlua::Error::RuntimeError)?;
+                        }
+                        Ok(true)
+                    }
+                    _ => Ok(true),
+                }
+            })
+        })
+    }
+    pub fn run_callback(
+        &mut self,
+        callback: Callback,
+        output: ProcessOutput,
+    ) -> rlua::Result<ActionResult> {
+        self.command_environment.lua.context(|lua_ctx| {
+            let res = lua_ctx.scope(|scope| {
+                let c = scope.create_nonstatic_userdata(self)?;
+                let f: rlua::Function = lua_ctx.registry_value(&callback.0)?;
+                let res = f.call::<_, Option<ActionResult>>((
+                    c,
+                    output.stdout,
+                    output.status,
+                    output.stderr,
+                ))?;
+                Ok(res.unwrap_or(ActionResult::Noop))
+            });
+            lua_ctx.remove_registry_value(callback.0)?;
+            res
+        })
+    }
     pub fn run_action(&mut self, action: Action) -> rlua::Result<ActionResult> {
         self.command_environment.lua.context(|lua_ctx| {
             lua_ctx.scope(|scope| {
@@ -116,10 +220,203 @@ impl UserData for &mut CommandContext<'_> {
             }
         });
 
+        methods.add_method_mut("yank_code_block", move |_, this, n: Option<usize>| {
+            Ok(yank_code_block(this, n.unwrap_or(1)))
+        });
+
+        methods.add_method_mut("can_redact", move |_, this, _: ()| {
+            let our_id = this.state.user_id().to_owned();
+            let room = this
+                .state
+                .current_room_state()
+                .ok_or_else(|| rlua::Error::RuntimeError("No current room".to_owned()))?;
+            match &room.tui.selection {
+                super::MessageSelection::Newest => {
+                    Err(rlua::Error::RuntimeError("No message selected".to_owned()))
+                }
+                super::MessageSelection::Specific(eid) => {
+                    let privilege = redact_privilege(room, &our_id, eid);
+                    Ok(room.check_privilege(&our_id, privilege).is_ok())
+                }
+            }
+        });
+
+        methods.add_method_mut("can_invite", move |_, this, _: ()| {
+            let room = this
+                .state
+                .current_room_state()
+                .ok_or_else(|| rlua::Error::RuntimeError("No current room".to_owned()))?;
+            Ok(room
+                .check_privilege(this.state.user_id(), Privilege::Invite)
+                .is_ok())
+        });
+
+        methods.add_method_mut("list_rooms", move |_, this, _: ()| {
+            // Rooms shared by multiple accounts are listed once.
+            let ids = this
+                .state
+                .rooms
+                .keys()
+                .map(|key| key.id.to_string())
+                .collect::<std::collections::BTreeSet<_>>();
+            Ok(ids.into_iter().collect::<Vec<_>>())
+        });
+
+        methods.add_method_mut("room_info", move |lua, this, id: String| {
+            let room = find_room(this.state, &id)?;
+            let info = lua.create_table()?;
+            info.set("id", room.id.as_str())?;
+            info.set("name", room.name())?;
+            info.set("unread", room.num_unread_notifications())?;
+            info.set("direct_target", room.direct_target().map(|u| u.to_string()))?;
+            info.set(
+                "account",
+                this.state.accounts[room.account()].user_id.to_string(),
+            )?;
+            info.set(
+                "current",
+                this.state.tui.room_selection.current() == Some(&room.key()),
+            )?;
+            Ok(info)
+        });
+
+        methods.add_method_mut("send_to", move |_, this, (id, text): (String, String)| {
+            let room = find_room(this.state, &id)?;
+            let client = &this.state.accounts[room.account()].client;
+            if room.is_read_only() {
+                return Ok(ActionResult::Noop);
+            }
+            if let Some(m_room) = client.get_room(&room.id) {
+                let content = RoomMessageEventContent::text_plain(text);
+                this.state.pending_sends.spawn(async move {
+                    let res = with_retry(|| m_room.send(content.clone()).into_future()).await;
+                    if let Err(e) = res {
+                        tracing::error!("Cannot send message: {:?}", e);
+                    }
+                });
+                Ok(ActionResult::Ok)
+            } else {
+                Ok(ActionResult::Error(
+                    "can't send message, no joined room".to_owned(),
+                ))
+            }
+        });
+
+        methods.add_method_mut("switch_to", move |_, this, id: String| {
+            let key = find_room(this.state, &id)?.key();
+            this.state.tui.set_current_room(Some(&key));
+            Ok(ActionResult::Ok)
+        });
+
+        methods.add_method_mut(
+            "spawn",
+            move |lua, this, (cmd, args, callback): SpawnArgs<'lua>| {
+                let callback = callback
+                    .map(|f| lua.create_registry_value(f).map(Callback))
+                    .transpose()?;
+                let events = this.events.clone();
+                tokio::spawn(async move {
+                    let output = tokio::process::Command::new(&cmd)
+                        .args(&args)
+                        .stdin(std::process::Stdio::null())
+                        .output()
+                        .await;
+                    let output = match output {
+                        Ok(o) => ProcessOutput {
+                            stdout: String::from_utf8_lossy(&o.stdout).into_owned(),
+                            stderr: String::from_utf8_lossy(&o.stderr).into_owned(),
+                            status: o.status.code(),
+                        },
+                        Err(e) => ProcessOutput {
+                            stdout: String::new(),
+                            stderr: format!("Failed to run '{}': {}", cmd, e),
+                            status: None,
+                        },
+                    };
+                    if let Some(callback) = callback {
+                        let _ = events
+                            .send(super::Event::ProcessFinished(callback, output))
+                            .await;
+                    } else if output.status != Some(0) {
+                        tracing::warn!("'{}' failed: {}", cmd, output.stderr);
+                    }
+                });
+                Ok(())
+            },
+        );
+
+        methods.add_method_mut(
+            "get_selected_device",
+            move |_, this, _: ()| match selected_device(this) {
+                Ok(id) => Ok(id.to_string()),
+                Err(_) => Err(rlua::Error::RuntimeError("No device selected".to_owned())),
+            },
+        );
+
         methods.add_method_mut("get_auxline_content", move |_, this, _: ()| {
             Ok(this.state.tui.aux_line_state.current().get().to_owned())
         });
 
+        // The emoji selected in the picker for the content of the aux line, or nil.
+        methods.add_method_mut("get_picked_emoji", move |_, this, _: ()| {
+            let query = this.state.tui.aux_line_state.current().get();
+            Ok(this
+                .state
+                .tui
+                .emoji_picker
+                .as_ref()
+                .and_then(|p| p.picked(query))
+                .map(|e| e.to_string()))
+        });
+
+        // The emoji matching the name or shortcode `query` as tables with emoji, name and, if it
+        // has one, shortcode, for completion.
+        methods.add_method_mut("find_emoji", move |lua, _, query: String| {
+            super::emoji::search(&query)
+                .into_iter()
+                .map(|e| {
+                    let t = lua.create_table()?;
+                    t.set("emoji", e.as_str())?;
+                    t.set("name", e.name())?;
+                    if let Some(shortcode) = e.shortcode() {
+                        t.set("shortcode", shortcode)?;
+                    }
+                    Ok(t)
+                })
+                .collect::<rlua::Result<Vec<_>>>()
+        });
+
+        // Aliases of the joined rooms and the ones mentioned in messages, for completion.
+        methods.add_method_mut("get_room_aliases", move |_, this, _: ()| {
+            let mut aliases = this.state.seen_aliases.clone();
+            aliases.extend(
+                this.state
+                    .rooms
+                    .values()
+                    .flat_map(|r| r.aliases().iter().cloned()),
+            );
+            Ok(aliases
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>())
+        });
+
+        // The members of the current room as tables with user_id and, if set, name.
+        methods.add_method_mut("get_room_members", move |lua, this, _: ()| {
+            let room = this
+                .state
+                .current_room_state()
+                .ok_or_else(|| rlua::Error::RuntimeError("No current room".to_owned()))?;
+            let members = lua.create_table()?;
+            for (i, user_id) in room.user_colors.keys().enumerate() {
+                let member = lua.create_table()?;
+                member.set("user_id", user_id.as_str())?;
+                member.set("name", room.display_name(user_id))?;
+                members.set(i + 1, member)?;
+            }
+            Ok(members)
+        });
+
         methods.add_method_mut(
             "cursor_move_forward",
             move |_, this, element: LuaTextElement| {
@@ -163,6 +460,134 @@ impl UserData for &mut CommandContext<'_> {
     }
 }
 
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The content of a message written in the composer. Room aliases are linked in the formatted body,
+/// which other clients show as pills.
+fn message_content(msg: String) -> RoomMessageEventContent {
+    if crate::tui_app::aliases_in(&msg).next().is_none() {
+        return RoomMessageEventContent::text_plain(msg);
+    }
+    let mut html = String::new();
+    let mut rest = msg.as_str();
+    while !rest.is_empty() {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (word, tail) = rest.split_at(end);
+        match crate::tui_app::alias_prefix(word) {
+            Some((alias, suffix)) => {
+                html.push_str(&format!(
+                    "<a href=\"{}\">{}</a>",
+                    escape_html(&alias.matrix_to_uri().to_string()),
+                    escape_html(alias.as_str())
+                ));
+                html.push_str(&escape_html(suffix));
+            }
+            None => html.push_str(&escape_html(word)),
+        }
+        let separator = tail.chars().next();
+        match separator {
+            Some('\n') => html.push_str("<br>"),
+            Some(c) => html.push(c),
+            None => {}
+        }
+        rest = &tail[separator.map_or(0, char::len_utf8)..];
+    }
+    RoomMessageEventContent::text_html(msg, html)
+}
+
+/// All links in a message body in the order in which they appear.
+fn message_links(body: &str) -> Vec<String> {
+    use linkify::{LinkFinder, LinkKind};
+
+    let mut finder = LinkFinder::new();
+    let mut links = finder
+        .kinds(&[LinkKind::Url])
+        .links(body)
+        .map(|link| (link.start(), link.as_str().to_owned()))
+        .collect::<Vec<_>>();
+    // matrix: uris are not found by linkify because they lack the "//"
+    for (start, _) in body.match_indices("matrix:") {
+        let at_word_start = body[..start]
+            .chars()
+            .next_back()
+            .map_or(true, char::is_whitespace);
+        let word = body[start..].split(char::is_whitespace).next().unwrap();
+        if at_word_start && !links.iter().any(|(_, url)| url == word) {
+            links.push((start, word.to_owned()));
+        }
+    }
+    links.sort_by_key(|(start, _)| *start);
+    links.into_iter().map(|(_, url)| url).collect()
+}
+
+/// Put the `n`th (starting at 1) code block of the selected message into the clipboard.
+fn yank_code_block(c: &mut CommandContext, n: usize) -> ActionResult {
+    let Some(room) = c.state.current_room_state() else {
+        return ActionResult::Error("No current room".to_owned());
+    };
+    let super::MessageSelection::Specific(eid) = &room.tui.selection else {
+        return ActionResult::Error("No message selected".to_owned());
+    };
+    let Some(Event::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(msg),
+    ))) = room.messages.message_from_id(eid).and_then(|m| m.latest())
+    else {
+        return ActionResult::Error("Can only get code blocks from message events".to_owned());
+    };
+    // Edits carry the full new content separately from the fallback body.
+    let msgtype = match &msg.content.relates_to {
+        Some(Relation::Replacement(r)) => &r.new_content.msgtype,
+        _ => &msg.content.msgtype,
+    };
+    let formatted = match msgtype {
+        MessageType::Text(t) => t.formatted.as_ref(),
+        MessageType::Notice(t) => t.formatted.as_ref(),
+        MessageType::Emote(t) => t.formatted.as_ref(),
+        _ => None,
+    }
+    .filter(|f| f.format == MessageFormat::Html)
+    .map(|f| f.body.as_str());
+    let blocks = crate::code_blocks::code_blocks(msgtype.body(), formatted);
+    let Some(block) = n.checked_sub(1).and_then(|i| blocks.into_iter().nth(i)) else {
+        return ActionResult::Error(format!("The message has no code block {}", n));
+    };
+    let Some(clipboard) = &mut c.state.clipboard_context else {
+        return ActionResult::Error("No clipboard available".to_owned());
+    };
+    match clipboard.set_contents(block) {
+        Ok(()) => ActionResult::Ok,
+        Err(e) => ActionResult::Error(format!("Failed to set clipboard content: {}", e)),
+    }
+}
+
+/// Deleting our own messages may require a lower power level than deleting those of others.
+fn redact_privilege(
+    room: &super::super::RoomState,
+    our_id: &matrix_sdk::ruma::UserId,
+    event_id: &matrix_sdk::ruma::EventId,
+) -> Privilege {
+    match room.messages.message_from_id(event_id) {
+        Some(entry) if entry.original().sender() != our_id => Privilege::RedactOther,
+        _ => Privilege::RedactOwn,
+    }
+}
+
+/// The room with `id`. Rooms shared by multiple accounts are looked up for the first account that
+/// is still a member.
+fn find_room<'s>(state: &'s State, id: &str) -> rlua::Result<&'s super::super::RoomState> {
+    state
+        .rooms
+        .values()
+        .filter(|r| r.id.as_str() == id)
+        .min_by_key(|r| r.is_read_only())
+        .ok_or_else(|| rlua::Error::RuntimeError(format!("No room with id '{}'", id)))
+}
+
 fn build_target_range(
     range: (LuaTextElement, LuaTextElement),
 ) -> (Bound<TextTarget>, Bound<TextTarget>) {
@@ -219,7 +644,7 @@ impl rlua::FromLua<'_> for LuaTextElement {
 }
 
 pub struct CommandEnvironment {
-    lua: Lua,
+    pub(super) lua: Lua,
 }
 
 impl CommandEnvironment {
@@ -264,19 +689,22 @@ pub type ActionArgsString = fn(&mut CommandContext, String) -> ActionResult;
 
 pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
     ("send_message", |c| {
+        let sends = c.state.pending_sends.clone();
         if let Some(room) = c.state.current_room_state_mut() {
+            if room.is_read_only() {
+                return ActionResult::Noop;
+            }
             let msg = room.tui.msg_edit.get(..).to_owned();
             if !msg.is_empty() {
-                room.tui.msg_edit.clear().unwrap();
-                let mut tmp_type = SendMessageType::Simple;
-                std::mem::swap(&mut tmp_type, &mut room.tui.msg_edit_type);
                 if let Some(m_room) = c.client.get_room(&room.id) {
-                    let content = match tmp_type {
-                        SendMessageType::Simple => RoomMessageEventContent::text_plain(msg),
+                    let content = match &room.tui.msg_edit_type {
+                        SendMessageType::Simple => message_content(msg.clone()),
                         SendMessageType::Reply(prev_id, original_message) => {
-                            let repl = RoomMessageEventContent::text_plain(msg);
+                            let repl = message_content(msg.clone());
                             let mut repl = repl.make_reply_to(
-                                &original_message.into_full_event(m_room.room_id().into()),
+                                &original_message
+                                    .clone()
+                                    .into_full_event(m_room.room_id().into()),
                                 matrix_sdk::ruma::events::room::message::ForwardThread::No,
                                 matrix_sdk::ruma::events::room::message::AddMentions::No,
                             );
@@ -286,25 +714,59 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
                             // display the relation correctly...
                             repl.relates_to = Some(Relation::Reply {
                                 in_reply_to: matrix_sdk::ruma::events::relation::InReplyTo::new(
-                                    prev_id.into(),
+                                    prev_id.clone(),
                                 ),
                             });
                             repl
                         }
                         SendMessageType::Edit(prev_id, prev_msg) => {
-                            let m = RoomMessageEventContent::text_plain(msg);
+                            let m = message_content(msg.clone());
                             let m = m.make_replacement(
                                 matrix_sdk::ruma::events::room::message::ReplacementMetadata::new(
-                                    prev_id.into(),
+                                    prev_id.clone(),
                                     None,
                                 ),
-                                Some(&prev_msg.into_full_event(room.id.clone())),
+                                Some(&prev_msg.clone().into_full_event(room.id.clone())),
                             );
                             m
                         }
                     };
-                    tokio::spawn(async move {
-                        m_room.send(content).await.unwrap();
+                    // Replies and edits repeat (parts of) the message, so measure what is sent.
+                    let size = content_size(&content);
+                    if size > MAX_MESSAGE_SIZE {
+                        return ActionResult::Error(format!(
+                            "Message is too large ({} bytes, at most {}), send it in parts with :send_message_split",
+                            size,
+                            MAX_MESSAGE_SIZE
+                        ));
+                    }
+                    room.tui.msg_edit.clear().unwrap();
+                    let msg_type =
+                        std::mem::replace(&mut room.tui.msg_edit_type, SendMessageType::Simple);
+                    // Edits change a message that is already shown instead of adding a new one.
+                    let txn_id = match msg_type {
+                        SendMessageType::Edit(..) => None,
+                        _ => Some(room.add_local_echo(msg)),
+                    };
+                    let key = room.key();
+                    let events = c.events.clone();
+                    sends.spawn(async move {
+                        let res = with_retry(|| {
+                            let send = m_room.send(content.clone());
+                            let send = match &txn_id {
+                                Some(txn_id) => send.with_transaction_id(txn_id.clone()),
+                                None => send,
+                            };
+                            send.into_future()
+                        })
+                        .await;
+                        if let Err(e) = &res {
+                            tracing::error!("Cannot send message: {:?}", e);
+                        }
+                        if let Some(txn_id) = txn_id {
+                            let result = res.map(|r| r.event_id).map_err(|e| e.to_string());
+                            let _ = events.send(super::Event::Sent(key, txn_id, result)).await;
+                        }
                     });
                     ActionResult::Ok
                 } else {
@@ -317,13 +779,75 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
             ActionResult::Error("No current room".to_owned())
         }
     }),
+    ("send_message_split", |c| {
+        let sends = c.state.pending_sends.clone();
+        let Some(room) = c.state.current_room_state_mut() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        if room.is_read_only() {
+            return ActionResult::Noop;
+        }
+        if !matches!(room.tui.msg_edit_type, SendMessageType::Simple) {
+            return ActionResult::Error("Only new messages can be sent in parts".to_owned());
+        }
+        let msg = room.tui.msg_edit.get(..).to_owned();
+        if msg.is_empty() {
+            return ActionResult::Noop;
+        }
+        let Some(m_room) = c.client.get_room(&room.id) else {
+            return ActionResult::Error("can't send message, no joined room".to_owned());
+        };
+        room.tui.msg_edit.clear().unwrap();
+        let parts = split_message(&msg, MAX_MESSAGE_SIZE)
+            .into_iter()
+            .map(|part| (room.add_local_echo(part.clone()), part))
+            .collect::<Vec<_>>();
+        let key = room.key();
+        let events = c.events.clone();
+        sends.spawn(async move {
+            // Sequentially, so that the parts appear in order
+            let mut failed = None;
+            for (txn_id, part) in parts {
+                let result = if let Some(e) = &failed {
+                    Err(format!("An earlier part failed: {}", e))
+                } else {
+                    let content = message_content(part);
+                    let res = with_retry(|| {
+                        m_room
+                            .send(content.clone())
+                            .with_transaction_id(txn_id.clone())
+                            .into_future()
+                    })
+                    .await;
+                    res.map(|r| r.event_id).map_err(|e| {
+                        tracing::error!("Cannot send message: {:?}", e);
+                        failed = Some(e.to_string());
+                        e.to_string()
+                    })
+                };
+                let _ = events
+                    .send(super::Event::Sent(key.clone(), txn_id, result))
+                    .await;
+            }
+        });
+        ActionResult::Ok
+    }),
     ("delete_message", |c| {
+        let sends = c.state.pending_sends.clone();
+        let our_id = c.state.user_id().to_owned();
         if let Some(room) = c.state.current_room_state_mut() {
+            if room.is_read_only() {
+                return ActionResult::Noop;
+            }
             if let super::MessageSelection::Specific(selected_id) = &room.tui.selection {
+                let privilege = redact_privilege(room, &our_id, selected_id);
+                if let Err(e) = room.check_privilege(&our_id, privilege) {
+                    return ActionResult::Error(e);
+                }
                 if let Some(joined_room) = c.client.get_room(&room.id) {
                     let id = selected_id.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = joined_room.redact(&id, None, None).await {
+                    sends.spawn(async move {
+                        if let Err(e) = with_retry(|| joined_room.redact(&id, None, None)).await {
                             tracing::error!("Cannot delete event: {:?}", e);
                         }
                     });
@@ -339,10 +863,17 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
         }
     }),
     ("delete_reactions", |c| {
+        let sends = c.state.pending_sends.clone();
         let our_id = c.state.user_id().to_owned();
         if let Some(room) = c.state.current_room_state_mut() {
+            if room.is_read_only() {
+                return ActionResult::Noop;
+            }
             if let super::MessageSelection::Specific(selected_id) = &room.tui.selection {
                 if let Some(reactions) = room.messages.reactions(selected_id) {
+                    if let Err(e) = room.check_privilege(&our_id, Privilege::RedactOwn) {
+                        return ActionResult::Error(e);
+                    }
                     let to_redact = reactions
                         .values()
                         .flat_map(|v| v.iter())
@@ -351,10 +882,12 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
                         .collect::<Vec<_>>();
 
                     if let Some(joined_room) = c.client.get_room(&room.id) {
-                        tokio::spawn(async move {
+                        sends.spawn(async move {
                             for eid in to_redact {
                                 tracing::info!("redacting reaction event: {:?}", eid);
-                                if let Err(e) = joined_room.redact(&eid, None, None).await {
+                                if let Err(e) =
+                                    with_retry(|| joined_room.redact(&eid, None, None)).await
+                                {
                                     tracing::error!("Cannot delete event: {:?}", e);
                                 }
                             }
@@ -392,11 +925,18 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
             .deselect_message()
             .into()
     }),
+    ("scroll_to_end", |c| {
+        super::messages::MessagesMut(c.state).scroll_to_end().into()
+    }),
     ("select_next_room", |c| {
-        super::rooms::RoomsMut(c.state).scroll_forwards().into()
+        super::rooms::RoomsMut(c.state, c.tasks.room_list)
+            .scroll_forwards()
+            .into()
     }),
     ("select_prev_room", |c| {
-        super::rooms::RoomsMut(c.state).scroll_backwards().into()
+        super::rooms::RoomsMut(c.state, c.tasks.room_list)
+            .scroll_backwards()
+            .into()
     }),
     ("select_room_history_next", |c| {
         c.state.tui.room_selection.scroll_forwards().into()
@@ -435,7 +975,7 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
         }
     }),
     ("force_room_selection", |c| {
-        let mut r = super::rooms::RoomsMut(&mut c.state);
+        let mut r = super::rooms::RoomsMut(&mut c.state, c.tasks.room_list);
         if !r.as_rooms().active_contains_current() {
             let _ = r.scroll_forwards(); // Implicitly select first
             ActionResult::Ok
@@ -506,6 +1046,37 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
             ActionResult::Error("No current room".to_owned())
         }
     }),
+    ("quote_reply", |c| {
+        let Some(room) = c.state.current_room_state_mut() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        let super::MessageSelection::Specific(eid) = &room.tui.selection else {
+            return ActionResult::Error("No message selected".to_owned());
+        };
+        let Some(m) = room.messages.message_from_id(&eid) else {
+            return ActionResult::Error(format!("Cannot find message with id {:?}", eid));
+        };
+        let Some(Event::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(message),
+        ))) = m.latest()
+        else {
+            return ActionResult::Error("Only simple message events can be quoted".to_owned());
+        };
+        let sender = room
+            .display_name(&message.sender)
+            .unwrap_or_else(|| message.sender.to_string());
+        let body = super::messages::strip_body(message.content.body(), eid, &room.messages);
+        let mut quote = format!("{} wrote:\n", sender);
+        for line in body.lines() {
+            quote.push_str("> ");
+            quote.push_str(line);
+            quote.push('\n');
+        }
+        quote.push('\n');
+        quote.push_str(&room.tui.msg_edit.get(..));
+        room.tui.msg_edit.set(&quote);
+        ActionResult::Ok
+    }),
     ("cancel_special_message", |c| {
         if let Some(room) = c.state.current_room_state_mut() {
             if !matches!(room.tui.msg_edit_type, super::SendMessageType::Simple) {
@@ -546,13 +1117,13 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
                     {
                         match &msg.content.msgtype {
                             MessageType::Text(t) => {
-                                use linkify::{LinkFinder, LinkKind};
-
-                                let mut finder = LinkFinder::new();
-                                let links = finder.kinds(&[LinkKind::Url]).links(&t.body);
+                                let mut urls = message_links(&t.body);
+                                if !c.config.open_all_links {
+                                    urls.truncate(1);
+                                }
                                 let mut res = ActionResult::Noop;
-                                for link in links {
-                                    open_url(&c.config, link.as_str().to_owned());
+                                for url in urls {
+                                    open_link(c.config, c.client, r.account(), c.events, url);
                                     res = ActionResult::Ok;
                                 }
                                 res
@@ -584,8 +1155,37 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
             ActionResult::Error("No current room".to_owned())
         }
     }),
+    ("preview_selected_message", |c| {
+        if let Some(r) = c.state.current_room_state_mut() {
+            if let super::MessageSelection::Specific(eid) = &r.tui.selection {
+                if let Some(Event::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                    SyncMessageLikeEvent::Original(msg),
+                ))) = r.messages.message_from_id(&eid).and_then(|m| m.latest())
+                {
+                    match &msg.content.msgtype {
+                        MessageType::Image(f) => {
+                            preview_file(c.client.clone(), &c.config, c.events, f.clone());
+                            ActionResult::Ok
+                        }
+                        MessageType::Video(f) => {
+                            preview_file(c.client.clone(), &c.config, c.events, f.clone());
+                            ActionResult::Ok
+                        }
+                        o => ActionResult::Error(format!("No preview for message {:?}", o)),
+                    }
+                } else {
+                    ActionResult::Error("No message selected".to_owned())
+                }
+            } else {
+                ActionResult::Error("No message selected".to_owned())
+            }
+        } else {
+            ActionResult::Error("No current room".to_owned())
+        }
+    }),
     ("cursor_move_down", |c| with_msg_edit(c, |e| e.move_down())),
     ("cursor_move_up", |c| with_msg_edit(c, |e| e.move_up())),
+    ("insert_newline", |c| with_msg_edit(c, |e| e.write('\n'))),
     ("cursor_delete_left", |c| {
         with_msg_edit(c, |e| e.delete_backwards())
     }),
@@ -612,6 +1212,22 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
             ActionResult::Error("No current room".to_owned())
         }
     }),
+    ("accept_verification", |c| match &mut c.state.verification {
+        Some(flow) => match flow.accept() {
+            Ok(()) => ActionResult::Ok,
+            Err(e) => ActionResult::Error(e),
+        },
+        None => ActionResult::Error("No verification in progress".to_owned()),
+    }),
+    ("cancel_verification", |c| {
+        match c.state.verification.take() {
+            Some(flow) => {
+                flow.cancel();
+                ActionResult::Ok
+            }
+            None => ActionResult::Noop,
+        }
+    }),
     ("accept_auxline", |c| {
         c.state.tui.aux_line_state.current_mut().finish_line();
         ActionResult::Ok
@@ -619,83 +1235,311 @@ pub const ACTIONS_ARGS_NONE: &[(&'static str, ActionArgsNone)] = &[
     ("clear_auxline", |c| {
         c.state.tui.aux_line_state.current_mut().clear().into()
     }),
-    ("list_room_invitations", |c| {
-        let rooms = c.client.invited_rooms();
-        let mut s = "Invited (name: id): ".to_owned();
-        for room in rooms {
-            s.push_str(&format!(
-                "\n{}: {}",
-                room.name().as_deref().unwrap_or(""),
-                room.room_id(),
-            ));
-        }
-        // TODO: semantically not that nice that we use the error message field. Maybe rename it to
-        // status or something?
-        c.state.tui.last_error_message = Some(s);
+    ("hide_auxline_content", |c| {
+        c.state.tui.aux_line_state.hide_current();
         ActionResult::Ok
     }),
-    ("leave_room", |c| {
-        if let Some(room) = c.state.current_room_state_mut() {
-            if let Some(joined_room) = c.client.get_room(&room.id) {
-                tokio::spawn(async move {
-                    if let Err(e) = joined_room.leave().await {
-                        tracing::error!("Failed to leave room: {:?}", e);
-                    }
-                });
-                ActionResult::Ok
-            } else {
-                ActionResult::Error("Room not joined".to_owned())
+    ("toggle_do_not_disturb", |c| {
+        c.state.do_not_disturb = !c.state.do_not_disturb;
+        ActionResult::Ok
+    }),
+    ("follow_upgrade", |c| {
+        let Some(room) = c.state.current_room_state() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        let Some(successor) = room.successor().map(|s| s.to_owned()) else {
+            return ActionResult::Error("Room has not been upgraded".to_owned());
+        };
+        let account = room.account();
+        let client = c.client.clone();
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            let via = successor.server_name().map(|s| s.to_owned());
+            if let Err(e) = join_room(&client, (&*successor).into(), via.as_slice()).await {
+                return tracing::error!("Cannot join upgraded room: {}", e);
             }
+            let _ = events
+                .send(super::Event::FocusJoinedRoom(account, successor, None))
+                .await;
+        });
+        ActionResult::Ok
+    }),
+    ("toggle_auto_follow", |c| {
+        c.state.tui.auto_follow = !c.state.tui.auto_follow;
+        ActionResult::Ok
+    }),
+    ("toggle_image_placeholders", |c| {
+        c.state.tui.image_placeholders = !c.state.tui.image_placeholders;
+        ActionResult::Ok
+    }),
+    ("grow_room_pane", |c| resize_room_pane(c, ROOM_PANE_STEP)),
+    ("shrink_room_pane", |c| resize_room_pane(c, -ROOM_PANE_STEP)),
+    ("open_device_manager", |c| {
+        c.state.tui.device_manager = Some(super::devices::DeviceManager::loading());
+        reload_devices(c);
+        ActionResult::Ok
+    }),
+    ("close_device_manager", |c| {
+        match c.state.tui.device_manager.take() {
+            Some(_) => ActionResult::Ok,
+            None => ActionResult::Noop,
+        }
+    }),
+    ("select_next_device", |c| {
+        match &mut c.state.tui.device_manager {
+            Some(manager) => manager.scroll_forwards().into(),
+            None => ActionResult::Error("The device manager is not open".to_owned()),
+        }
+    }),
+    ("select_prev_device", |c| {
+        match &mut c.state.tui.device_manager {
+            Some(manager) => manager.scroll_backwards().into(),
+            None => ActionResult::Error("The device manager is not open".to_owned()),
+        }
+    }),
+    ("open_emoji_picker", |c| {
+        c.state.tui.emoji_picker = Some(Default::default());
+        ActionResult::Ok
+    }),
+    ("close_emoji_picker", |c| {
+        if c.state.tui.emoji_picker.take().is_some() {
+            ActionResult::Ok
         } else {
-            ActionResult::Error("No current room".to_owned())
+            ActionResult::Noop
         }
     }),
-];
-
-pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
-    ("type", |c, s| {
-        match c.state.tui.current_mode().builtin_mode() {
-            BuiltinMode::Normal | BuiltinMode::Insert => {
-                if let Some(room) = c.state.current_room_state_mut() {
-                    for ch in s.chars() {
-                        room.tui.msg_edit.write(ch).unwrap();
-                    }
-                    ActionResult::Ok
-                } else {
-                    ActionResult::Error("No current room".to_owned())
-                }
-            }
-            BuiltinMode::Command => {
-                for ch in s.chars() {
-                    c.state.tui.aux_line_state.current_mut().write(ch).unwrap();
-                }
-                ActionResult::Ok
-            }
-            BuiltinMode::RoomFilter | BuiltinMode::RoomFilterUnread => {
-                for ch in s.chars() {
-                    c.state.tui.room_filter_line.write(ch).unwrap();
-                }
+    ("select_next_emoji", |c| {
+        let query = c.state.tui.aux_line_state.current().get().to_owned();
+        match &mut c.state.tui.emoji_picker {
+            Some(picker) => {
+                picker.select_next(&query);
                 ActionResult::Ok
             }
+            None => ActionResult::Error("The emoji picker is not open".to_owned()),
         }
     }),
-    ("switch_mode", |c, s| match c.config.modes.get(&s) {
-        None => ActionResult::Error(format!("'{}' is not a mode", s)),
-        Some(new) => {
-            let current = c.state.tui.current_mode().clone();
-            let change = current != new;
-            if change {
-                run_on_mode_leave(current, c);
-            }
-            let res = c.state.tui.switch_mode(new.clone()).into();
-            if change {
-                run_on_mode_enter(new, c);
+    ("select_prev_emoji", |c| {
+        let query = c.state.tui.aux_line_state.current().get().to_owned();
+        match &mut c.state.tui.emoji_picker {
+            Some(picker) => {
+                picker.select_prev(&query);
+                ActionResult::Ok
             }
-            res
+            None => ActionResult::Error("The emoji picker is not open".to_owned()),
         }
     }),
-    ("push_mode", |c, s| match c.config.modes.get(&s) {
-        None => ActionResult::Error(format!("'{}' is not a mode", s)),
+    ("toggle_members", |c| {
+        c.state.tui.show_members = !c.state.tui.show_members;
+        ActionResult::Ok
+    }),
+    ("open_mentions", |c| {
+        c.state.tui.show_mentions = true;
+        ActionResult::Ok
+    }),
+    ("close_mentions", |c| {
+        if std::mem::take(&mut c.state.tui.show_mentions) {
+            ActionResult::Ok
+        } else {
+            ActionResult::Noop
+        }
+    }),
+    ("select_next_mention", |c| {
+        if c.state.tui.show_mentions {
+            c.state.tui.mentions.scroll_forwards().into()
+        } else {
+            ActionResult::Error("The mentions view is not open".to_owned())
+        }
+    }),
+    ("select_prev_mention", |c| {
+        if c.state.tui.show_mentions {
+            c.state.tui.mentions.scroll_backwards().into()
+        } else {
+            ActionResult::Error("The mentions view is not open".to_owned())
+        }
+    }),
+    ("jump_to_mention", |c| {
+        let Some(mention) = c.state.tui.mentions.selected() else {
+            return ActionResult::Error("No mention selected".to_owned());
+        };
+        let (key, event_id) = (mention.room.clone(), mention.event_id.clone());
+        let Some(room) = c.state.rooms.get_mut(&key) else {
+            return ActionResult::Error(format!("Unknown room {}", key.id));
+        };
+        room.tui.selection = super::MessageSelection::Specific(event_id);
+        c.state.tui.set_current_room(Some(&key));
+        ActionResult::Ok
+    }),
+    ("mark_mention_handled", |c| {
+        match c.state.tui.mentions.remove_selected() {
+            Some(_) => ActionResult::Ok,
+            None => ActionResult::Noop,
+        }
+    }),
+    ("clear_mentions", |c| {
+        c.state.tui.mentions.clear();
+        ActionResult::Ok
+    }),
+    ("close_popup", |c| {
+        let popup = c.state.tui.popup.take();
+        let reaction_details = c.state.tui.reaction_details.take();
+        if popup.is_some() || reaction_details.is_some() {
+            ActionResult::Ok
+        } else {
+            ActionResult::Noop
+        }
+    }),
+    ("show_reactions", |c| {
+        let Some(room) = c.state.current_room_state() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        let super::MessageSelection::Specific(eid) = &room.tui.selection else {
+            return ActionResult::Error("No message selected".to_owned());
+        };
+        if room.messages.reactions(eid).map_or(true, |r| r.is_empty()) {
+            return ActionResult::Error("The message has no reactions".to_owned());
+        }
+        c.state.tui.reaction_details = Some((room.key(), eid.clone()));
+        ActionResult::Ok
+    }),
+    ("show_encryption_info", |c| {
+        let Some(room) = c.state.current_room_state() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        let account = &c.state.accounts[room.account()];
+        let config = account.config.clone();
+        let store_passphrase = account.store_passphrase.clone();
+        let Some(room) = c.client.get_room(&room.id) else {
+            return ActionResult::Error("Room not joined".to_owned());
+        };
+        let client = c.client.clone();
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            let crypto_store =
+                crate::session::open_crypto_store(&config, store_passphrase.as_deref()).await;
+            match encryption_info(&client, crypto_store.as_ref(), &room).await {
+                Ok(info) => {
+                    let _ = events.send(super::Event::ShowPopup(info)).await;
+                }
+                Err(e) => tracing::error!("Failed to collect encryption info: {}", e),
+            }
+        });
+        ActionResult::Ok
+    }),
+    ("list_room_invitations", |c| {
+        let multiple_accounts = c.state.accounts.len() > 1;
+        let mut s = "Invited (name: id): ".to_owned();
+        for account in &c.state.accounts {
+            for room in account.client.invited_rooms() {
+                s.push_str(&format!(
+                    "\n{}: {}",
+                    room.name().as_deref().unwrap_or(""),
+                    room.room_id(),
+                ));
+                if multiple_accounts {
+                    s.push_str(&format!(" (for {})", account.user_id()));
+                }
+            }
+        }
+        // TODO: semantically not that nice that we use the error message field. Maybe rename it to
+        // status or something?
+        c.state.tui.last_error_message = Some(s);
+        ActionResult::Ok
+    }),
+    ("list_knocks", |c| {
+        let multiple_accounts = c.state.accounts.len() > 1;
+        let mut s = "Knocked (room: status): ".to_owned();
+        for (key, status) in &c.state.knocks {
+            let account = &c.state.accounts[key.account];
+            match account.client.get_room(&key.id).and_then(|r| r.name()) {
+                Some(name) => s.push_str(&format!("\n{}: {} ({})", name, status, key.id)),
+                None => s.push_str(&format!("\n{}: {}", key.id, status)),
+            }
+            if multiple_accounts {
+                s.push_str(&format!(" (for {})", account.user_id()));
+            }
+        }
+        c.state.tui.last_error_message = Some(s);
+        ActionResult::Ok
+    }),
+    ("rejoin_room", |c| {
+        let Some(room) = c.state.current_room_state() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        if !room.is_read_only() {
+            return ActionResult::Noop;
+        }
+        let room_id = room.id.clone();
+        let client = c.client.clone();
+        tokio::spawn(async move {
+            // The room is no longer archived once it shows up as joined in the sync.
+            if let Err(e) = client.join_room_by_id(&room_id).await {
+                tracing::error!("Failed to rejoin {}: {:?}", room_id, e);
+            }
+        });
+        ActionResult::Ok
+    }),
+    ("leave_room", |c| {
+        if let Some(room) = c.state.current_room_state_mut() {
+            if let Some(joined_room) = c.client.get_room(&room.id) {
+                tokio::spawn(async move {
+                    if let Err(e) = joined_room.leave().await {
+                        tracing::error!("Failed to leave room: {:?}", e);
+                    }
+                });
+                ActionResult::Ok
+            } else {
+                ActionResult::Error("Room not joined".to_owned())
+            }
+        } else {
+            ActionResult::Error("No current room".to_owned())
+        }
+    }),
+];
+
+pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
+    ("type", |c, s| {
+        match c.state.tui.current_mode().builtin_mode() {
+            BuiltinMode::Normal | BuiltinMode::Insert => {
+                if let Some(room) = c.state.current_room_state_mut() {
+                    for ch in s.chars() {
+                        room.tui.msg_edit.write(ch).unwrap();
+                    }
+                    ActionResult::Ok
+                } else {
+                    ActionResult::Error("No current room".to_owned())
+                }
+            }
+            BuiltinMode::Command => {
+                for ch in s.chars() {
+                    c.state.tui.aux_line_state.current_mut().write(ch).unwrap();
+                }
+                ActionResult::Ok
+            }
+            BuiltinMode::RoomFilter | BuiltinMode::RoomFilterUnread => {
+                for ch in s.chars() {
+                    c.state.tui.room_filter_line.write(ch).unwrap();
+                }
+                ActionResult::Ok
+            }
+        }
+    }),
+    ("switch_mode", |c, s| match c.config.modes.get(&s) {
+        None => ActionResult::Error(format!("'{}' is not a mode", s)),
+        Some(new) => {
+            let current = c.state.tui.current_mode().clone();
+            let change = current != new;
+            if change {
+                run_on_mode_leave(current, c);
+            }
+            let res = c.state.tui.switch_mode(new.clone()).into();
+            if change {
+                run_on_mode_enter(new, c);
+            }
+            res
+        }
+    }),
+    ("push_mode", |c, s| match c.config.modes.get(&s) {
+        None => ActionResult::Error(format!("'{}' is not a mode", s)),
         Some(new) => {
             run_on_mode_leave(c.state.tui.current_mode().clone(), c);
             c.state.tui.push_mode(new.clone());
@@ -717,14 +1561,19 @@ pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
         }
     }),
     ("react", |c, s| {
+        let sends = c.state.pending_sends.clone();
         if let Some(room) = c.state.current_room_state_mut() {
+            if room.is_read_only() {
+                return ActionResult::Noop;
+            }
             if let super::MessageSelection::Specific(eid) = &room.tui.selection {
                 let reaction = matrix_sdk::ruma::events::reaction::ReactionEventContent::new(
                     matrix_sdk::ruma::events::relation::Annotation::new(eid.clone(), s),
                 );
                 if let Some(joined_room) = c.client.get_room(&room.id) {
-                    tokio::spawn(async move {
-                        if let Err(e) = joined_room.send(reaction).await {
+                    sends.spawn(async move {
+                        let res = with_retry(|| joined_room.send(reaction.clone()).into_future());
+                        if let Err(e) = res.await {
                             tracing::error!("Cannot react to event: {:?}", e);
                         }
                     });
@@ -752,52 +1601,8 @@ pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
             ActionResult::Error(format!("Invalid value for event detail: {}", s))
         }
     }),
-    ("send_file", |c, path| {
-        if let Some(room) = c.state.current_room_state_mut() {
-            if let Some(joined_room) = c.client.get_room(&room.id) {
-                let path = match shellexpand::full(&path) {
-                    Ok(p) => std::path::PathBuf::from(p.as_ref()),
-                    Err(e) => {
-                        return ActionResult::Error(format!(
-                            "Failed to expand path {}",
-                            e.to_string()
-                        ))
-                    }
-                };
-                match std::fs::File::open(&path) {
-                    Ok(mut file) => {
-                        use std::io::Read;
-
-                        let mime_type = mime_guess::from_path(&path).first_or_octet_stream();
-                        let description: String =
-                            path.file_name().unwrap().to_string_lossy().to_string();
-                        let mut buf = Vec::new();
-                        match file.read_to_end(&mut buf) {
-                            Ok(_) => {
-                                let config = matrix_sdk::attachment::AttachmentConfig::new();
-                                //TODO: we could provide more info based on the mime_type
-                                tokio::spawn(async move {
-                                    if let Err(e) = joined_room
-                                        .send_attachment(&description, &mime_type, buf, config)
-                                        .await
-                                    {
-                                        tracing::error!("Cannot send file: {:?}", e);
-                                    }
-                                });
-                                ActionResult::Ok
-                            }
-                            Err(e) => ActionResult::Error(format!("Failed to read file: {:?}", e)),
-                        }
-                    }
-                    Err(e) => ActionResult::Error(format!("Cannot open file for sending: {:?}", e)),
-                }
-            } else {
-                ActionResult::Error("Room not joined".to_owned())
-            }
-        } else {
-            ActionResult::Error("No current room".to_owned())
-        }
-    }),
+    ("send_file", |c, path| send_file(c, path, true)),
+    ("send_file_original", |c, path| send_file(c, path, false)),
     ("save_file", |c, path| {
         if let Some(r) = c.state.current_room_state() {
             match &r.tui.selection {
@@ -812,16 +1617,16 @@ pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
                         let fname = msg.content.body();
                         match &msg.content.msgtype {
                             MessageType::Image(f) => {
-                                save_file(c.client.clone(), f.clone(), &path, fname)
+                                save_file(c.client.clone(), c.config, f.clone(), &path, fname)
                             }
                             MessageType::Video(f) => {
-                                save_file(c.client.clone(), f.clone(), &path, fname)
+                                save_file(c.client.clone(), c.config, f.clone(), &path, fname)
                             }
                             MessageType::Audio(f) => {
-                                save_file(c.client.clone(), f.clone(), &path, fname)
+                                save_file(c.client.clone(), c.config, f.clone(), &path, fname)
                             }
                             MessageType::File(f) => {
-                                save_file(c.client.clone(), f.clone(), &path, fname)
+                                save_file(c.client.clone(), c.config, f.clone(), &path, fname)
                             }
                             o => ActionResult::Error(format!("No file to save in message {:?}", o)),
                         }
@@ -834,12 +1639,189 @@ pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
             ActionResult::Error("No current room".to_owned())
         }
     }),
+    ("set_presence", |c, s| {
+        let (presence, status_msg) = match s.split_once(' ') {
+            Some((p, msg)) => (p, Some(msg.trim().to_owned())),
+            None => (s.as_str(), None),
+        };
+        let presence = match presence {
+            "online" => PresenceState::Online,
+            "unavailable" => PresenceState::Unavailable,
+            "offline" => PresenceState::Offline,
+            o => return ActionResult::Error(format!("Invalid presence state: {}", o)),
+        };
+        // Online returns to the automatic presence, which is unavailable while idle.
+        c.state.own_presence = if presence == PresenceState::Online {
+            None
+        } else {
+            Some(presence.clone())
+        };
+        super::super::set_presence(c.client, presence, status_msg);
+        ActionResult::Ok
+    }),
+    (
+        "set_room_notify",
+        |c, level| match crate::config::NotificationLevel::from_str(&level) {
+            Ok(level) => {
+                if let Some(room) = c.state.current_room_state_mut() {
+                    room.notification_level = Some(level);
+                    ActionResult::Ok
+                } else {
+                    ActionResult::Error("No current room".to_owned())
+                }
+            }
+            Err(()) => ActionResult::Error(format!("Invalid notification level: {}", level)),
+        },
+    ),
     ("switch_auxline", |c, identifier| {
         c.state.tui.aux_line_state.select(identifier);
         ActionResult::Ok
     }),
+    ("rename_device", |c, name| {
+        let device_id = match selected_device(c) {
+            Ok(id) => id,
+            Err(e) => return e,
+        };
+        let client = c.client.clone();
+        change_devices(c, async move {
+            client
+                .rename_device(&device_id, &name)
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("Failed to rename device: {}", e))
+        })
+    }),
+    ("delete_device", |c, password| {
+        let device_id = match selected_device(c) {
+            Ok(id) => id,
+            Err(e) => return e,
+        };
+        let client = c.client.clone();
+        change_devices(c, async move {
+            super::devices::delete_device(&client, device_id, password).await
+        })
+    }),
+    ("set_auxline_content", |c, content| {
+        let line = c.state.tui.aux_line_state.current_mut();
+        let _ = line.clear();
+        for ch in content.chars() {
+            let _ = line.write(ch);
+        }
+        ActionResult::Ok
+    }),
     ("set_auxline_prompt", |c, prompt| {
-        c.state.tui.aux_line_state.current_mut().set_prompt(prompt);
+        c.state.tui.aux_line_state.set_prompt(prompt);
+        ActionResult::Ok
+    }),
+    ("login_with_password", |c, password| {
+        // Log in the account whose session expired, which is not necessarily the current one.
+        let account = c
+            .state
+            .accounts
+            .iter()
+            .position(|a| a.session_status == SessionStatus::SoftLoggedOut)
+            .unwrap_or_else(|| c.state.current_account_index());
+        let client = c.state.accounts[account].client.clone();
+        let device_id = match client.device_id() {
+            Some(id) => id.to_owned(),
+            None => return ActionResult::Error("Not logged in".to_owned()),
+        };
+        // The session has to be stored for the account of the client, not the tui
+        let config = c.state.accounts[account].config.clone();
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            // Reusing the device id keeps our encryption keys valid after a soft logout.
+            let login = client
+                .matrix_auth()
+                .login_username(&config.user, &password)
+                .device_id(device_id.as_str())
+                .request_refresh_token();
+            let result = match login.send().await {
+                Ok(response) => {
+                    let session = matrix_sdk::matrix_auth::MatrixSession::from(&response);
+                    if let Err(e) = crate::session::try_store_session(&config, &session) {
+                        tracing::error!("Failed to store session: {}", e);
+                    }
+                    Ok(())
+                }
+                Err(e) => {
+                    tracing::error!("Failed to log in: {}", e);
+                    Err(format!("Failed to log in: {}", e))
+                }
+            };
+            let _ = events.send(super::Event::Relogin(account, result)).await;
+        });
+        ActionResult::Ok
+    }),
+    ("verify_device", |c, device_id| {
+        let client = c.client.clone();
+        let user_id = c.state.user_id().to_owned();
+        let sink = c.state.outgoing_verifications.clone();
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            let device_id = matrix_sdk::ruma::OwnedDeviceId::from(device_id);
+            let request = match client.encryption().get_device(&user_id, &device_id).await {
+                Ok(Some(device)) => device
+                    .request_verification_with_methods(vec![VerificationMethod::SasV1])
+                    .await
+                    .map_err(|e| format!("Failed to request verification: {}", e)),
+                Ok(None) => Err(format!("Unknown device {}", device_id)),
+                Err(e) => Err(format!("Failed to get device {}: {}", device_id, e)),
+            };
+            match request {
+                Ok(request) => {
+                    let _ = sink.send((request, Some(device_id)));
+                }
+                Err(msg) => {
+                    tracing::error!("{}", msg);
+                    let _ = events.send(super::Event::ShowPopup(msg)).await;
+                }
+            }
+        });
+        ActionResult::Ok
+    }),
+    ("verify_user", |c, user_id| {
+        let user_id = match matrix_sdk::ruma::UserId::parse(user_id) {
+            Ok(id) => id,
+            Err(e) => return ActionResult::Error(format!("Invalid user id: {}", e)),
+        };
+        let client = c.client.clone();
+        let sink = c.state.outgoing_verifications.clone();
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            let request = match client.encryption().get_user_identity(&user_id).await {
+                Ok(Some(identity)) => identity
+                    .request_verification_with_methods(vec![VerificationMethod::SasV1])
+                    .await
+                    .map_err(|e| format!("Failed to request verification: {}", e)),
+                Ok(None) => Err(format!("No cross-signing identity for {}", user_id)),
+                Err(e) => Err(format!("Failed to get identity of {}: {}", user_id, e)),
+            };
+            match request {
+                Ok(request) => {
+                    let _ = sink.send((request, None));
+                }
+                Err(msg) => {
+                    tracing::error!("{}", msg);
+                    let _ = events.send(super::Event::ShowPopup(msg)).await;
+                }
+            }
+        });
+        ActionResult::Ok
+    }),
+    ("recover_secrets", |c, key| {
+        let client = c.client.clone();
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            let msg = match client.encryption().recovery().recover(key.trim()).await {
+                Ok(()) => "Unlocked secret storage".to_owned(),
+                Err(e) => {
+                    tracing::error!("Failed to unlock secret storage: {}", e);
+                    format!("Failed to unlock secret storage: {}", e)
+                }
+            };
+            let _ = events.send(super::Event::ShowPopup(msg)).await;
+        });
         ActionResult::Ok
     }),
     ("run", |c, cmd| match c.run_command(&cmd) {
@@ -857,6 +1839,32 @@ pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
         });
         ActionResult::Ok
     }),
+    ("create_dm", |c, s| {
+        match matrix_sdk::ruma::UserId::parse(s) {
+            Ok(uid) => {
+                let client = c.client.clone();
+                let account = c.state.current_account_index();
+                let events = c.events.clone();
+                tokio::spawn(async move {
+                    // Also adds the room to the m.direct account data.
+                    match client.create_dm(&uid).await {
+                        Ok(room) => {
+                            let _ = events
+                                .send(super::Event::FocusJoinedRoom(
+                                    account,
+                                    room.room_id().to_owned(),
+                                    None,
+                                ))
+                                .await;
+                        }
+                        Err(e) => tracing::error!("Cannot create direct chat: {:?}", e),
+                    }
+                });
+                ActionResult::Ok
+            }
+            Err(e) => ActionResult::Error(format!("Invalid user id: {}", e)),
+        }
+    }),
     ("join_by_id", |c, s| {
         match matrix_sdk::ruma::RoomId::parse(s) {
             Ok(rid) => {
@@ -871,9 +1879,41 @@ pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
             Err(e) => ActionResult::Error(format!("{}", e)),
         }
     }),
+    ("open_link", |c, s| {
+        let Ok(n) = s.trim().parse::<usize>() else {
+            return ActionResult::Error(format!("'{}' is not a link number", s));
+        };
+        let Some(r) = c.state.current_room_state() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        let super::MessageSelection::Specific(eid) = &r.tui.selection else {
+            return ActionResult::Error("No message selected".to_owned());
+        };
+        let Some(Event::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(msg),
+        ))) = r.messages.message_from_id(eid).and_then(|m| m.latest())
+        else {
+            return ActionResult::Error("Can only open links of message events".to_owned());
+        };
+        let links = message_links(msg.content.body());
+        match n.checked_sub(1).and_then(|i| links.into_iter().nth(i)) {
+            Some(url) => {
+                open_link(c.config, c.client, r.account(), c.events, url);
+                ActionResult::Ok
+            }
+            None => ActionResult::Error(format!("The message has no link {}", n)),
+        }
+    }),
     ("invite", |c, s| match matrix_sdk::ruma::UserId::parse(s) {
         Ok(uid) => {
+            let our_id = c.state.user_id().to_owned();
             if let Some(room) = c.state.current_room_state_mut() {
+                if room.is_read_only() {
+                    return ActionResult::Noop;
+                }
+                if let Err(e) = room.check_privilege(&our_id, Privilege::Invite) {
+                    return ActionResult::Error(e);
+                }
                 if let Some(joined_room) = c.client.get_room(&room.id) {
                     tokio::spawn(async move {
                         if let Err(e) = joined_room.invite_user_by_id(&uid).await {
@@ -890,8 +1930,360 @@ pub const ACTIONS_ARGS_STRING: &[(&'static str, ActionArgsString)] = &[
         }
         Err(e) => ActionResult::Error(format!("{}", e)),
     }),
+    ("knock", |c, s| {
+        // The room may be followed by a reason.
+        let (room, reason) = match s.trim().split_once(char::is_whitespace) {
+            Some((room, reason)) => (room.to_owned(), Some(reason.trim().to_owned())),
+            None => (s.trim().to_owned(), None),
+        };
+        let id = match RoomOrAliasId::parse(&room) {
+            Ok(id) => id,
+            Err(e) => {
+                return ActionResult::Error(format!("'{}' is not a room id or alias: {}", room, e))
+            }
+        };
+        let client = c.client.clone();
+        let events = c.events.clone();
+        // The knocked room shows up in the next sync, so only failures are reported.
+        tokio::spawn(async move {
+            if let Err(e) = crate::manage::send_knock(&client, &id, reason).await {
+                tracing::error!("Failed to knock on {}: {:?}", room, e);
+                let msg = format!("Failed to knock on {}: {}", room, e);
+                let _ = events.send(super::Event::ShowPopup(msg)).await;
+            }
+        });
+        ActionResult::Ok
+    }),
+    ("snooze_notifications", |c, s| {
+        let Ok(minutes) = s.trim().parse::<u64>() else {
+            return ActionResult::Error(format!("'{}' is not a number of minutes", s));
+        };
+        // Snoozing for 0 minutes resumes notifications right away.
+        let Some(duration) = minutes.checked_mul(60).map(std::time::Duration::from_secs) else {
+            return ActionResult::Error(format!("Can't snooze for {} minutes", minutes));
+        };
+        let Some(until) = std::time::Instant::now().checked_add(duration) else {
+            return ActionResult::Error(format!("Can't snooze for {} minutes", minutes));
+        };
+        c.state.snoozed_until = Some(until);
+        // Redraw once the snooze is over to remove it from the status bar.
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            let _ = events.send(super::Event::Update).await;
+        });
+        ActionResult::Ok
+    }),
+    ("send_state_event", |c, s| {
+        let (event_type, rest) = split_word(&s);
+        let (state_key, json) = split_word(rest);
+        if event_type.is_empty() {
+            return ActionResult::Error(
+                "Usage: send_state_event <type> <state_key> <json>".to_owned(),
+            );
+        }
+        let content = match serde_json::from_str::<serde_json::Value>(json) {
+            Ok(content @ serde_json::Value::Object(_)) => content,
+            Ok(_) => {
+                return ActionResult::Error(
+                    "The content of a state event must be a JSON object".to_owned(),
+                )
+            }
+            Err(e) => return ActionResult::Error(format!("Invalid JSON: {}", e)),
+        };
+        let Some(room) = c.state.current_room_state() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        let Some(joined_room) = c.client.get_room(&room.id) else {
+            return ActionResult::Error("Room not joined".to_owned());
+        };
+        let (event_type, state_key) = (event_type.to_owned(), state_key.to_owned());
+        tokio::spawn(async move {
+            let res = joined_room
+                .send_state_event_raw(&event_type, &state_key, content)
+                .await;
+            if let Err(e) = res {
+                tracing::error!("Failed to send {} state event: {:?}", event_type, e);
+            }
+        });
+        ActionResult::Ok
+    }),
+    ("show_state_event", |c, s| {
+        let (event_type, rest) = split_word(&s);
+        let (state_key, _) = split_word(rest);
+        if event_type.is_empty() {
+            return ActionResult::Error("Usage: show_state_event <type> [<state_key>]".to_owned());
+        }
+        let Some(room) = c.state.current_room_state() else {
+            return ActionResult::Error("No current room".to_owned());
+        };
+        let Some(joined_room) = c.client.get_room(&room.id) else {
+            return ActionResult::Error("Room not joined".to_owned());
+        };
+        let (event_type, state_key) = (event_type.to_owned(), state_key.to_owned());
+        let events = c.events.clone();
+        tokio::spawn(async move {
+            match state_event_content(&joined_room, &event_type, &state_key).await {
+                Ok(text) => {
+                    let _ = events.send(super::Event::ShowPopup(text)).await;
+                }
+                Err(e) => tracing::error!("Failed to load {} state event: {}", event_type, e),
+            }
+        });
+        ActionResult::Ok
+    }),
+    ("set_join_rule", |c, s| {
+        use matrix_sdk::ruma::events::room::join_rules::{JoinRule, RoomJoinRulesEventContent};
+        let rule = match s.as_str() {
+            "public" => JoinRule::Public,
+            "invite" => JoinRule::Invite,
+            "knock" => JoinRule::Knock,
+            o => {
+                return ActionResult::Error(format!(
+                    "Invalid join rule '{}', use public, invite or knock",
+                    o
+                ))
+            }
+        };
+        let content = RoomJoinRulesEventContent::new(rule);
+        send_room_state(c, Privilege::ChangeJoinRule, content)
+    }),
+    ("set_history_visibility", |c, s| {
+        use matrix_sdk::ruma::events::room::history_visibility::{
+            HistoryVisibility, RoomHistoryVisibilityEventContent,
+        };
+        let visibility = match s.as_str() {
+            "world_readable" => HistoryVisibility::WorldReadable,
+            "shared" => HistoryVisibility::Shared,
+            "invited" => HistoryVisibility::Invited,
+            "joined" => HistoryVisibility::Joined,
+            o => {
+                return ActionResult::Error(format!(
+                "Invalid history visibility '{}', use world_readable, shared, invited or joined",
+                o
+            ))
+            }
+        };
+        let content = RoomHistoryVisibilityEventContent::new(visibility);
+        send_room_state(c, Privilege::ChangeHistoryVisibility, content)
+    }),
+    ("add_alias", |c, s| change_alias(c, s, AliasChange::Add)),
+    ("remove_alias", |c, s| {
+        change_alias(c, s, AliasChange::Remove)
+    }),
+    ("set_canonical_alias", |c, s| {
+        change_alias(c, s, AliasChange::SetCanonical)
+    }),
 ];
 
+/// Replace a state event (with an empty state key) of the current room after checking that we are
+/// allowed to.
+fn send_room_state<C>(c: &mut CommandContext, privilege: Privilege, content: C) -> ActionResult
+where
+    C: matrix_sdk::ruma::events::StateEventContent<
+            StateKey = matrix_sdk::ruma::events::EmptyStateKey,
+        > + Send
+        + 'static,
+{
+    let our_id = c.state.user_id().to_owned();
+    let Some(room) = c.state.current_room_state() else {
+        return ActionResult::Error("No current room".to_owned());
+    };
+    if let Err(e) = room.check_privilege(&our_id, privilege) {
+        return ActionResult::Error(e);
+    }
+    let Some(joined_room) = c.client.get_room(&room.id) else {
+        return ActionResult::Error("Room not joined".to_owned());
+    };
+    tokio::spawn(async move {
+        let event_type = content.event_type();
+        if let Err(e) = joined_room.send_state_event(content).await {
+            tracing::error!("Failed to send {} state event: {:?}", event_type, e);
+        }
+    });
+    ActionResult::Ok
+}
+
+/// Split off the first word of `s`. `""` stands for an empty word, like the state key of most state
+/// events.
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    let (word, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    let word = if word == "\"\"" { "" } else { word };
+    (word, rest.trim())
+}
+
+/// The content of a state event of `room` as known to the store, pretty printed.
+async fn state_event_content(
+    room: &matrix_sdk::Room,
+    event_type: &str,
+    state_key: &str,
+) -> Result<String, String> {
+    use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+    use matrix_sdk::ruma::events::StateEventType;
+
+    let event = room
+        .get_state_event(StateEventType::from(event_type), state_key)
+        .await
+        .map_err(|e| e.to_string())?;
+    let json = match &event {
+        Some(RawAnySyncOrStrippedState::Sync(raw)) => raw.json().get(),
+        Some(RawAnySyncOrStrippedState::Stripped(raw)) => raw.json().get(),
+        None => {
+            return Ok(format!(
+                "No {} state event with state key \"{}\"",
+                event_type, state_key
+            ))
+        }
+    };
+    let event: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let content = serde_json::to_string_pretty(&event["content"]).map_err(|e| e.to_string())?;
+    Ok(format!(
+        "{} \"{}\" in {}:\n{}",
+        event_type,
+        state_key,
+        room.room_id(),
+        content
+    ))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum AliasChange {
+    Add,
+    Remove,
+    SetCanonical,
+}
+
+/// Add or remove an alias of the current room in the room directory and publish the change in the
+/// canonical alias event of the room if we are allowed to.
+fn change_alias(c: &mut CommandContext, alias: String, change: AliasChange) -> ActionResult {
+    use matrix_sdk::ruma::api::client::alias::{create_alias, delete_alias};
+    use matrix_sdk::ruma::events::room::canonical_alias::RoomCanonicalAliasEventContent;
+
+    let alias = match RoomAliasId::parse(alias) {
+        Ok(alias) => alias,
+        Err(e) => return ActionResult::Error(format!("Invalid room alias: {}", e)),
+    };
+    let our_id = c.state.user_id().to_owned();
+    let Some(room) = c.state.current_room_state() else {
+        return ActionResult::Error("No current room".to_owned());
+    };
+    let may_publish = room.check_privilege(&our_id, Privilege::ChangeAliases);
+    if change == AliasChange::SetCanonical {
+        if let Err(e) = may_publish {
+            return ActionResult::Error(e);
+        }
+    }
+    let Some(joined_room) = c.client.get_room(&room.id) else {
+        return ActionResult::Error("Room not joined".to_owned());
+    };
+    let client = c.client.clone();
+    tokio::spawn(async move {
+        let room_id = joined_room.room_id().to_owned();
+        let directory = match change {
+            AliasChange::Add => client
+                .send(create_alias::v3::Request::new(alias.clone(), room_id), None)
+                .await
+                .map(|_| ()),
+            AliasChange::Remove => client
+                .send(delete_alias::v3::Request::new(alias.clone()), None)
+                .await
+                .map(|_| ()),
+            AliasChange::SetCanonical => Ok(()),
+        };
+        if let Err(e) = directory {
+            return tracing::error!("Failed to update the room directory: {:?}", e);
+        }
+        if may_publish.is_err() {
+            return;
+        }
+        let mut content = RoomCanonicalAliasEventContent::new();
+        content.alias = joined_room.canonical_alias();
+        content.alt_aliases = joined_room.alt_aliases();
+        let is_canonical = content.alias.as_ref() == Some(&alias);
+        let is_alt = content.alt_aliases.contains(&alias);
+        match change {
+            AliasChange::Add if is_canonical || is_alt => return,
+            AliasChange::Add => content.alt_aliases.push(alias),
+            AliasChange::Remove if !is_canonical && !is_alt => return,
+            AliasChange::Remove => {
+                content.alt_aliases.retain(|a| *a != alias);
+                if is_canonical {
+                    content.alias = None;
+                }
+            }
+            AliasChange::SetCanonical if is_canonical => return,
+            AliasChange::SetCanonical => {
+                content.alt_aliases.retain(|a| *a != alias);
+                // The previous canonical alias stays published.
+                if let Some(previous) = content.alias.replace(alias) {
+                    content.alt_aliases.push(previous);
+                }
+            }
+        }
+        if let Err(e) = joined_room.send_state_event(content).await {
+            tracing::error!("Failed to publish room aliases: {:?}", e);
+        }
+    });
+    ActionResult::Ok
+}
+
+const ROOM_PANE_STEP: f64 = 0.05;
+
+fn resize_room_pane(c: &mut CommandContext, delta: f64) -> ActionResult {
+    let fraction = &mut c.state.tui.room_pane_fraction;
+    let resized = (*fraction + delta).clamp(ROOM_PANE_STEP, 1.0 - ROOM_PANE_STEP);
+    if resized == *fraction {
+        ActionResult::Noop
+    } else {
+        *fraction = resized;
+        ActionResult::Ok
+    }
+}
+
+fn reload_devices(c: &mut CommandContext) {
+    let client = c.client.clone();
+    let events = c.events.clone();
+    tokio::spawn(async move {
+        let devices = super::devices::fetch_devices(&client).await;
+        let _ = events.send(super::Event::Devices(devices)).await;
+    });
+}
+
+fn selected_device(
+    c: &mut CommandContext,
+) -> Result<matrix_sdk::ruma::OwnedDeviceId, ActionResult> {
+    match &c.state.tui.device_manager {
+        Some(manager) => match manager.selected() {
+            Some(device) => Ok(device.id.clone()),
+            None => Err(ActionResult::Error("No device selected".to_owned())),
+        },
+        None => Err(ActionResult::Error(
+            "The device manager is not open".to_owned(),
+        )),
+    }
+}
+
+/// Run `change` in the background and show the updated device list afterwards.
+fn change_devices(
+    c: &mut CommandContext,
+    change: impl std::future::Future<Output = Result<(), String>> + Send + 'static,
+) -> ActionResult {
+    if let Some(manager) = &mut c.state.tui.device_manager {
+        manager.set_loading();
+    }
+    let client = c.client.clone();
+    let events = c.events.clone();
+    tokio::spawn(async move {
+        let devices = match change.await {
+            Ok(()) => super::devices::fetch_devices(&client).await,
+            Err(e) => Err(e),
+        };
+        let _ = events.send(super::Event::Devices(devices)).await;
+    });
+    ActionResult::Ok
+}
+
 fn with_msg_edit(
     c: &mut CommandContext,
     mut f: impl FnMut(&mut TextEdit) -> OperationResult,
@@ -904,6 +2296,295 @@ fn with_msg_edit(
     }
 }
 
+/// Upload the file at `path` to the current room. Large images are downscaled according to the
+/// config if `downscale` is set and metadata is removed if configured for the room.
+fn send_file(c: &mut CommandContext, path: String, downscale: bool) -> ActionResult {
+    let sends = c.state.pending_sends.clone();
+    if let Some(room) = c.state.current_room_state_mut() {
+        if room.is_read_only() {
+            return ActionResult::Noop;
+        }
+        if let Some(joined_room) = c.client.get_room(&room.id) {
+            let path = match shellexpand::full(&path) {
+                Ok(p) => std::path::PathBuf::from(p.as_ref()),
+                Err(e) => {
+                    return ActionResult::Error(format!("Failed to expand path {}", e.to_string()))
+                }
+            };
+            match std::fs::File::open(&path) {
+                Ok(mut file) => {
+                    use std::io::Read;
+
+                    let mime_type = mime_guess::from_path(&path).first_or_octet_stream();
+                    let description: String =
+                        path.file_name().unwrap().to_string_lossy().to_string();
+                    let mut buf = Vec::new();
+                    let is_image = mime_type.type_() == mime_guess::mime::IMAGE;
+                    let processing = is_image.then(|| ImageProcessing {
+                        max_dimension: c.config.upload_image_max_dimension.filter(|_| downscale),
+                        strip_exif: c.config.strip_exif_for(&room.id),
+                        quality: c.config.upload_image_quality,
+                    });
+                    match file.read_to_end(&mut buf) {
+                        Ok(_) => {
+                            sends.spawn(async move {
+                                let (description, mime_type, buf, image_info) = match processing {
+                                    Some(p) => match p.run(description, mime_type, buf).await {
+                                        Ok(r) => r,
+                                        Err(e) => {
+                                            return tracing::error!("Cannot send file: {}", e)
+                                        }
+                                    },
+                                    None => (description, mime_type, buf, None),
+                                };
+                                let res = with_retry(|| {
+                                    let mut config =
+                                        matrix_sdk::attachment::AttachmentConfig::new();
+                                    if let Some((width, height, blurhash)) = &image_info {
+                                        config = config.info(
+                                            matrix_sdk::attachment::AttachmentInfo::Image(
+                                                matrix_sdk::attachment::BaseImageInfo {
+                                                    width: Some(UInt::from(*width)),
+                                                    height: Some(UInt::from(*height)),
+                                                    size: UInt::new(buf.len() as u64),
+                                                    blurhash: Some(blurhash.clone()),
+                                                    ..Default::default()
+                                                },
+                                            ),
+                                        );
+                                    }
+                                    joined_room
+                                        .send_attachment(
+                                            &description,
+                                            &mime_type,
+                                            buf.clone(),
+                                            config,
+                                        )
+                                        .into_future()
+                                });
+                                if let Err(e) = res.await {
+                                    tracing::error!("Cannot send file: {:?}", e);
+                                }
+                            });
+                            ActionResult::Ok
+                        }
+                        Err(e) => ActionResult::Error(format!("Failed to read file: {:?}", e)),
+                    }
+                }
+                Err(e) => ActionResult::Error(format!("Cannot open file for sending: {:?}", e)),
+            }
+        } else {
+            ActionResult::Error("Room not joined".to_owned())
+        }
+    } else {
+        ActionResult::Error("No current room".to_owned())
+    }
+}
+
+struct ImageProcessing {
+    max_dimension: Option<u32>,
+    strip_exif: bool,
+    quality: u8,
+}
+
+impl ImageProcessing {
+    /// Falls back to the original file if the image cannot be downscaled, but fails if the
+    /// metadata cannot be removed. Also returns the width, height and blurhash of the image to
+    /// send, if available.
+    async fn run(
+        self,
+        description: String,
+        mime_type: mime_guess::Mime,
+        buf: Vec<u8>,
+    ) -> Result<
+        (
+            String,
+            mime_guess::Mime,
+            Vec<u8>,
+            Option<(u32, u32, String)>,
+        ),
+        String,
+    > {
+        let name = description.clone();
+        let (res, buf) = tokio::task::spawn_blocking(move || {
+            let res = self.process(&name, &buf);
+            (res, buf)
+        })
+        .await
+        .map_err(|e| format!("processing {} failed: {}", description, e))?;
+        match res {
+            Ok((Some((processed, processed_type)), info)) => {
+                let description = if processed_type == mime_type {
+                    description
+                } else if processed_type == mime_guess::mime::IMAGE_PNG {
+                    with_extension(&description, "png")
+                } else {
+                    with_extension(&description, "jpg")
+                };
+                Ok((description, processed_type, processed, info))
+            }
+            Ok((None, info)) => Ok((description, mime_type, buf, info)),
+            Err(e) => Err(format!("can't remove metadata from {}: {}", description, e)),
+        }
+    }
+
+    fn process(
+        &self,
+        name: &str,
+        buf: &[u8],
+    ) -> Result<
+        (
+            Option<(Vec<u8>, mime_guess::Mime)>,
+            Option<(u32, u32, String)>,
+        ),
+        String,
+    > {
+        let mut processed = None;
+        if let Some(max) = self.max_dimension {
+            match crate::media::downscale_image(buf, max, self.quality) {
+                // Metadata is not carried over when re-encoding
+                Ok(scaled) => processed = scaled,
+                Err(e) => tracing::warn!("Sending {} without downscaling: {}", name, e),
+            }
+        }
+        if processed.is_none() && self.strip_exif {
+            processed = crate::media::strip_exif(buf, self.quality)?;
+        }
+        let data = processed.as_ref().map(|(data, _)| &data[..]).unwrap_or(buf);
+        let info = crate::media::blurhash(data).unwrap_or_else(|e| {
+            tracing::warn!("Sending {} without blurhash: {}", name, e);
+            None
+        });
+        Ok((processed, info))
+    }
+}
+
+/// Events may be at most 64KiB large, including the fields added by the server. The limit is fixed
+/// by the specification rather than advertised in the server capabilities, so leave room for those
+/// fields and the base64 encoding of encrypted messages. Applies to the serialized content.
+pub const MAX_MESSAGE_SIZE: usize = (65536 - 4096) * 3 / 4;
+
+/// The size of `content` as sent to the server, including the formatted body.
+fn content_size(content: &RoomMessageEventContent) -> usize {
+    serde_json::to_vec(content).map_or(0, |json| json.len())
+}
+
+/// The size of the content of a message written in the composer.
+pub fn message_size(msg: &str) -> usize {
+    content_size(&message_content(msg.to_owned()))
+}
+
+/// Split `msg` into parts whose content is at most `max_size` bytes, preferably at line breaks.
+fn split_message(msg: &str, max_size: usize) -> Vec<String> {
+    let fits = |s: &str| message_size(s) <= max_size;
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    for line in msg.split_inclusive('\n') {
+        if !current.is_empty() && !fits(&format!("{}{}", current, line)) {
+            parts.push(std::mem::take(&mut current));
+        }
+        let mut line = line;
+        while !fits(line) {
+            let end = longest_fitting_prefix(line, &fits);
+            parts.push(line[..end].to_owned());
+            line = &line[end..];
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    // Trailing line breaks would be stripped by most clients anyway
+    parts
+        .into_iter()
+        .map(|p| p.trim_end_matches('\n').to_owned())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// The length of the longest prefix of `line` that `fits`, but at least one character. Escaping
+/// makes the serialized size grow faster than the text, so search instead of calculating it.
+fn longest_fitting_prefix(line: &str, fits: impl Fn(&str) -> bool) -> usize {
+    let boundaries = line
+        .char_indices()
+        .map(|(i, _)| i)
+        .skip(1)
+        .chain(std::iter::once(line.len()))
+        .collect::<Vec<_>>();
+    let n = boundaries.partition_point(|&end| fits(&line[..end]));
+    boundaries[n.saturating_sub(1)]
+}
+
+fn with_extension(file_name: &str, ext: &str) -> String {
+    std::path::Path::new(file_name)
+        .with_extension(ext)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Join `room` unless we are already a member of it and return its id.
+async fn join_room(
+    client: &Client,
+    room: &RoomOrAliasId,
+    via: &[OwnedServerName],
+) -> Result<OwnedRoomId, String> {
+    let room_id = if room.is_room_id() {
+        RoomId::parse(room.as_str()).ok()
+    } else if let Ok(alias) = RoomAliasId::parse(room.as_str()) {
+        client
+            .resolve_room_alias(&alias)
+            .await
+            .ok()
+            .map(|r| r.room_id)
+    } else {
+        None
+    };
+    if let Some(room_id) = room_id {
+        if client
+            .get_room(&room_id)
+            .map_or(false, |r| r.state() == matrix_sdk::RoomState::Joined)
+        {
+            return Ok(room_id);
+        }
+    }
+    client
+        .join_room_by_id_or_alias(room, via)
+        .await
+        .map(|r| r.room_id().to_owned())
+        .map_err(|e| format!("Cannot join {}: {:?}", room, e))
+}
+
+/// Show rooms and events referenced by matrix.to and matrix: links in sparse (joining the room if
+/// necessary) and open all other links with the configured program.
+fn open_link(
+    config: &Config,
+    client: &Client,
+    account: usize,
+    events: &mpsc::Sender<super::Event>,
+    url: String,
+) {
+    let target = MatrixToUri::parse(&url)
+        .map(|uri| (uri.id().clone(), uri.via().to_vec()))
+        .or_else(|_| MatrixUri::parse(&url).map(|uri| (uri.id().clone(), uri.via().to_vec())));
+    let (room, event, via): (OwnedRoomOrAliasId, _, _) = match target {
+        Ok((MatrixId::Room(room), via)) => (room.into(), None, via),
+        Ok((MatrixId::RoomAlias(alias), via)) => (alias.into(), None, via),
+        Ok((MatrixId::Event(room, event), via)) => (room, Some(event), via),
+        _ => return open_url(config, url),
+    };
+    let client = client.clone();
+    let events = events.clone();
+    tokio::spawn(async move {
+        let room_id = match join_room(&client, &room, &via).await {
+            Ok(room_id) => room_id,
+            Err(e) => return tracing::error!("{}", e),
+        };
+        let _ = events
+            .send(super::Event::FocusJoinedRoom(account, room_id, event))
+            .await;
+    });
+}
+
 fn open_url(config: &Config, url: String) {
     let open_prog = config.url_open_program.clone();
     tokio::spawn(async move {
@@ -915,61 +2596,73 @@ fn open_url(config: &Config, url: String) {
     });
 }
 
-async fn write_file(
-    c: Client,
-    content: impl matrix_sdk::media::MediaEventContent + Send,
-    target: &mut (dyn std::io::Write + Send),
-) -> Result<(), String> {
-    if let Some(media_type) = content.source() {
-        match c
-            .media()
-            .get_media_content(
-                &matrix_sdk::media::MediaRequestParameters {
-                    source: media_type,
-                    format: matrix_sdk::media::MediaFormat::File,
-                },
-                true,
-            )
-            .await
-        {
-            Ok(bytes) => {
-                target.write_all(&bytes[..]).unwrap();
-                target.flush().unwrap();
-                Ok(())
-            }
-            Err(e) => Err(format!("can't open file: {:?}", e)),
-        }
-    } else {
-        Err("can't open file: No content".to_owned())
-    }
-}
-
 fn open_file(
     c: Client,
     config: &Config,
     content: impl matrix_sdk::media::MediaEventContent + Send + Sync + 'static,
 ) {
     let open_prog = config.file_open_program.clone();
+    let cache = crate::media::MediaCache::new(config);
     tokio::spawn(async move {
-        let path = {
-            let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
-            if let Err(e) = write_file(c, content, &mut tmpfile).await {
-                tracing::error!("{}", e);
-                return;
-            }
-            tmpfile.into_temp_path()
+        let path = match cache.get_file(&c, content).await {
+            Ok(path) => path,
+            Err(e) => return tracing::error!("{}", e),
         };
         let mut join_handle = tokio::process::Command::new(open_prog)
-            .arg(&path)
+            .arg(&*path)
             .spawn()
             .unwrap();
         join_handle.wait().await.unwrap();
-        path.keep().unwrap(); //We don't know if the file was opened when xdg-open finished...
+        // We don't know if the file was opened when xdg-open finished...
+        if let Err(e) = path.keep() {
+            tracing::error!("{}", e);
+        }
+    });
+}
+
+const PREVIEW_WIDTH: u32 = 800;
+const PREVIEW_HEIGHT: u32 = 600;
+
+/// Open a thumbnail of the media in `content`. Failures are shown in a popup, since the thumbnail
+/// is fetched in the background.
+fn preview_file(
+    c: Client,
+    config: &Config,
+    events: &mpsc::Sender<super::Event>,
+    content: impl matrix_sdk::media::MediaEventContent + Send + Sync + 'static,
+) {
+    let open_prog = config.file_open_program.clone();
+    let cache = crate::media::MediaCache::new(config);
+    let events = events.clone();
+    tokio::spawn(async move {
+        let res = async {
+            let path = cache
+                .get_thumbnail(&c, content, PREVIEW_WIDTH, PREVIEW_HEIGHT)
+                .await
+                .map_err(|e| e.to_string())?;
+            tokio::process::Command::new(&open_prog)
+                .arg(&*path)
+                .spawn()
+                .map_err(|e| format!("Failed to run {}: {}", open_prog, e))?
+                .wait()
+                .await
+                .map_err(|e| format!("Failed to wait for {}: {}", open_prog, e))?;
+            // We don't know if the file was opened when xdg-open finished...
+            path.keep().map_err(|e| e.to_string())?;
+            Ok::<_, String>(())
+        }
+        .await;
+        if let Err(e) = res {
+            tracing::error!("Failed to preview file: {}", e);
+            let msg = format!("Failed to preview file: {}", e);
+            let _ = events.send(super::Event::ShowPopup(msg)).await;
+        }
     });
 }
 
 fn save_file(
     c: Client,
+    config: &Config,
     content: impl matrix_sdk::media::MediaEventContent + Send + Sync + 'static,
     path: &str,
     possible_name: &str,
@@ -983,8 +2676,16 @@ fn save_file(
     }
     match std::fs::File::create(&path) {
         Ok(mut file) => {
+            let cache = crate::media::MediaCache::new(config);
             tokio::spawn(async move {
-                if let Err(e) = write_file(c, content, &mut file).await {
+                let res = match cache.get_file(&c, content).await {
+                    Ok(cached) => std::fs::File::open(&*cached)
+                        .and_then(|mut cached| std::io::copy(&mut cached, &mut file))
+                        .map(|_| ())
+                        .map_err(|e| format!("can't write file: {}", e)),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
                     tracing::error!("{}", e);
                 }
             });
@@ -994,6 +2695,122 @@ fn save_file(
     }
 }
 
+async fn encryption_info(
+    client: &Client,
+    crypto_store: Option<&matrix_sdk_sqlite::SqliteCryptoStore>,
+    room: &matrix_sdk::Room,
+) -> Result<String, matrix_sdk::Error> {
+    use matrix_sdk::crypto::store::CryptoStore;
+    use std::fmt::Write;
+
+    let mut s = String::new();
+    match room.encryption_settings() {
+        Some(settings) => {
+            let _ = write!(s, "Encryption: {}", settings.algorithm);
+            if let Some(ms) = settings.rotation_period_ms {
+                let _ = write!(s, ", session rotation after {}s", u64::from(ms) / 1000);
+            }
+            if let Some(msgs) = settings.rotation_period_msgs {
+                let _ = write!(s, ", session rotation after {} messages", msgs);
+            }
+            // The session our messages are currently encrypted with, as last saved to the store.
+            let outbound = match crypto_store {
+                Some(store) => Some(store.get_outbound_group_session(room.room_id()).await),
+                None => None,
+            };
+            match outbound {
+                Some(Ok(Some(session))) => {
+                    let pickle = session.pickle().await;
+                    let created =
+                        chrono::DateTime::from_timestamp(pickle.creation_time.get().into(), 0)
+                            .unwrap();
+                    let created: chrono::DateTime<chrono::Local> = created.into();
+                    let _ = write!(
+                        s,
+                        "\nOutbound session: created {}, used for {} messages",
+                        created.format("%Y-%m-%d %H:%M"),
+                        pickle.message_count
+                    );
+                }
+                Some(Ok(None)) => s.push_str("\nOutbound session: none yet"),
+                Some(Err(e)) => {
+                    let _ = write!(s, "\nOutbound session: can't read the store: {}", e);
+                }
+                None => {}
+            }
+        }
+        None => s.push_str("Encryption: disabled"),
+    }
+
+    let members = room.members(matrix_sdk::RoomMemberships::JOIN).await?;
+    for member in members {
+        let devices = client
+            .encryption()
+            .get_user_devices(member.user_id())
+            .await?;
+        let num_devices = devices.devices().count();
+        let num_verified = devices.devices().filter(|d| d.is_verified()).count();
+        let _ = write!(
+            s,
+            "\n{}: {} devices ({} verified)",
+            member.user_id(),
+            num_devices,
+            num_verified
+        );
+    }
+    Ok(s)
+}
+
+fn show_error(c: &mut CommandContext, res: rlua::Result<ActionResult>) {
+    match res {
+        Ok(ActionResult::Ok | ActionResult::Noop) => {}
+        Ok(ActionResult::Error(e)) => {
+            c.state.tui.last_error_message = Some(e);
+        }
+        Err(e) => {
+            c.state.tui.last_error_message = Some(format!("{}", e));
+        }
+    }
+}
+
+pub fn run_hooks(hook: Hook, data: &HookData, c: &mut CommandContext) {
+    let hooks = c.config.hooks.clone();
+    for action in hooks.get(hook) {
+        let res = c.run_hook(action, data);
+        show_error(c, res);
+    }
+}
+
+/// Show `notification` unless one of the on_notification hooks suppresses it.
+pub fn show_notification(mut notification: DesktopNotification, c: &mut CommandContext) {
+    let hooks = c.config.hooks.clone();
+    for action in hooks.get(Hook::Notification) {
+        match c.run_notification_hook(action, &mut notification) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => show_error(c, Err(e)),
+        }
+    }
+    let clicks = c.state.notification_clicks.clone();
+    if let Some(room) = c.state.rooms.get_mut(notification.room()) {
+        room.show_notification(notification, c.config, &clicks);
+    }
+}
+
+pub fn run_callback(callback: Callback, output: ProcessOutput, c: &mut CommandContext) {
+    let res = c.run_callback(callback, output);
+    show_error(c, res);
+}
+
+pub fn enter_start_mode(c: &mut CommandContext) {
+    // The mode is checked to exist in ConfigBuilder::finalize.
+    let mode = c.config.modes.get(&c.config.start_mode).unwrap();
+    if mode != *c.state.tui.current_mode() {
+        let _ = c.state.tui.switch_mode(mode.clone());
+        run_on_mode_enter(mode, c);
+    }
+}
+
 fn run_on_mode_enter(mode: Mode, c: &mut CommandContext) {
     if let Some(action) = c.config.modes.get_on_enter(&mode) {
         match c.run_action(action) {
@@ -1021,3 +2838,75 @@ fn run_on_mode_leave(mode: Mode, c: &mut CommandContext) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The line breaks at which the message was split are dropped, all other text must be kept.
+    fn check_split(msg: &str, max_size: usize) -> Vec<String> {
+        let parts = split_message(msg, max_size);
+        for part in &parts {
+            assert!(!part.is_empty());
+            assert!(
+                message_size(part) <= max_size,
+                "{} > {}: {:?}",
+                message_size(part),
+                max_size,
+                part
+            );
+        }
+        assert_eq!(parts.concat().replace('\n', ""), msg.replace('\n', ""));
+        parts
+    }
+
+    #[test]
+    fn test_split_message_short() {
+        assert_eq!(check_split("hello\nworld", 200), vec!["hello\nworld"]);
+        assert!(check_split("", 200).is_empty());
+    }
+
+    #[test]
+    fn test_split_message_at_line_breaks() {
+        let msg = (0..100)
+            .map(|i| format!("line {}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let parts = check_split(&msg, 200);
+        assert!(parts.len() > 1);
+        assert_eq!(parts.join("\n"), msg);
+    }
+
+    #[test]
+    fn test_split_message_multibyte() {
+        let msg = "äöü€😀".repeat(100);
+        let parts = check_split(&msg, 100);
+        assert!(parts.len() > 1);
+        assert_eq!(parts.concat(), msg);
+    }
+
+    #[test]
+    fn test_split_message_long_lines() {
+        let msg = format!("short\n{}\nshort", "x".repeat(1000));
+        let parts = check_split(&msg, 200);
+        assert_eq!(parts.first().map(String::as_str), Some("short"));
+        assert!(parts.len() > 2);
+    }
+
+    #[test]
+    fn test_split_message_escaped() {
+        // Quotes and line breaks take twice the space once serialized.
+        let msg = format!("{}{}", "\"\n".repeat(200), "\"\\".repeat(200));
+        let parts = check_split(&msg, 100);
+        assert!(parts.len() > 1);
+        check_split("\u{1}".repeat(100).as_str(), 100);
+    }
+
+    #[test]
+    fn test_longest_fitting_prefix() {
+        assert_eq!(longest_fitting_prefix("abcdef", |s| s.len() <= 3), 3);
+        assert_eq!(longest_fitting_prefix("abc", |s| s.len() <= 3), 3);
+        // At least one character, even if it does not fit
+        assert_eq!(longest_fitting_prefix("😀a", |_| false), "😀".len());
+    }
+}
@@ -0,0 +1,205 @@
+use matrix_sdk::ruma::api::client::uiaa;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedDeviceId};
+use matrix_sdk::Client;
+use unsegen::base::*;
+use unsegen::input::{OperationResult, Scrollable};
+use unsegen::widget::*;
+
+pub struct DeviceInfo {
+    pub id: OwnedDeviceId,
+    pub name: String,
+    pub verified: Option<bool>, // None if the device has no (known) keys
+    pub last_seen_ip: Option<String>,
+    pub last_seen_ts: Option<MilliSecondsSinceUnixEpoch>,
+    pub current: bool,
+}
+
+/// State of the device manager panel. The list is reloaded after every change.
+pub struct DeviceManager {
+    devices: Vec<DeviceInfo>,
+    selected: usize,
+    loading: bool,
+}
+
+impl DeviceManager {
+    pub fn loading() -> Self {
+        DeviceManager {
+            devices: Vec::new(),
+            selected: 0,
+            loading: true,
+        }
+    }
+
+    pub fn set_loading(&mut self) {
+        self.loading = true;
+    }
+
+    pub fn set_devices(&mut self, devices: Vec<DeviceInfo>) {
+        // Keep the selection on the same device if it still exists.
+        let previous = self.selected().map(|d| d.id.clone());
+        self.devices = devices;
+        self.loading = false;
+        self.selected = previous
+            .and_then(|id| self.devices.iter().position(|d| d.id == id))
+            .unwrap_or(0);
+    }
+
+    pub fn loading_failed(&mut self) {
+        self.loading = false;
+    }
+
+    pub fn selected(&self) -> Option<&DeviceInfo> {
+        self.devices.get(self.selected)
+    }
+}
+
+impl Scrollable for DeviceManager {
+    fn scroll_backwards(&mut self) -> OperationResult {
+        self.selected = self.selected.checked_sub(1).ok_or(())?;
+        Ok(())
+    }
+
+    fn scroll_forwards(&mut self) -> OperationResult {
+        if self.selected + 1 < self.devices.len() {
+            self.selected += 1;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+fn format_last_seen(ts: Option<MilliSecondsSinceUnixEpoch>) -> String {
+    ts.and_then(|ts| chrono::DateTime::from_timestamp_millis(i64::from(ts.0)))
+        .map(|t| {
+            let t: chrono::DateTime<chrono::Local> = t.into();
+            t.format("%Y-%m-%d %H:%M").to_string()
+        })
+        .unwrap_or_else(|| "never".to_owned())
+}
+
+pub async fn fetch_devices(client: &Client) -> Result<Vec<DeviceInfo>, String> {
+    let response = client
+        .devices()
+        .await
+        .map_err(|e| format!("Failed to get devices: {}", e))?;
+    let user_id = client.user_id().unwrap();
+    let current_device = client.device_id();
+    let mut devices = Vec::new();
+    for device in response.devices {
+        let verified = match client
+            .encryption()
+            .get_device(user_id, &device.device_id)
+            .await
+        {
+            Ok(d) => d.map(|d| d.is_verified()),
+            Err(e) => {
+                tracing::warn!("Failed to get keys of device {}: {}", device.device_id, e);
+                None
+            }
+        };
+        devices.push(DeviceInfo {
+            current: Some(&*device.device_id) == current_device,
+            name: device.display_name.unwrap_or_default(),
+            verified,
+            last_seen_ip: device.last_seen_ip,
+            last_seen_ts: device.last_seen_ts,
+            id: device.device_id,
+        });
+    }
+    // Most recently used devices first
+    devices.sort_by(|l, r| r.last_seen_ts.cmp(&l.last_seen_ts));
+    Ok(devices)
+}
+
+/// Delete a device, authenticating with the password if the homeserver requires it.
+pub async fn delete_device(
+    client: &Client,
+    device_id: OwnedDeviceId,
+    password: String,
+) -> Result<(), String> {
+    let device_ids = [device_id];
+    let e = match client.delete_devices(&device_ids, None).await {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    let info = e
+        .as_uiaa_response()
+        .ok_or_else(|| format!("Failed to delete device: {}", e))?;
+    if password.is_empty() {
+        return Err("Deleting the device requires a password".to_owned());
+    }
+    let user_id = client.user_id().unwrap();
+    let mut auth_data = uiaa::Password::new(
+        uiaa::UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+        password,
+    );
+    auth_data.session = info.session.clone();
+    client
+        .delete_devices(&device_ids, Some(uiaa::AuthData::Password(auth_data)))
+        .await
+        .map_err(|e| format!("Failed to delete device: {}", e))?;
+    Ok(())
+}
+
+struct DeviceLine<'a> {
+    device: &'a DeviceInfo,
+    selected: bool,
+}
+
+impl DeviceLine<'_> {
+    fn text(&self) -> String {
+        let verified = match self.device.verified {
+            Some(true) => "verified",
+            Some(false) => "unverified",
+            None => "no keys",
+        };
+        format!(
+            "{}{} \"{}\" ({}) last seen {} {}",
+            if self.device.current { "* " } else { "  " },
+            self.device.id,
+            self.device.name,
+            verified,
+            format_last_seen(self.device.last_seen_ts),
+            self.device.last_seen_ip.as_deref().unwrap_or(""),
+        )
+    }
+}
+
+impl Widget for DeviceLine<'_> {
+    fn space_demand(&self) -> Demand2D {
+        Demand2D {
+            width: ColDemand::exact(text_width(&self.text())),
+            height: RowDemand::exact(1),
+        }
+    }
+
+    fn draw(&self, mut window: Window, _hints: RenderingHints) {
+        let mut c = Cursor::new(&mut window);
+        let mut style = StyleModifier::new();
+        if self.selected {
+            style = style.invert(true);
+        }
+        if self.device.verified == Some(false) {
+            style = style.fg_color(Color::Yellow);
+        }
+        c.set_style_modifier(style);
+        c.write(&self.text());
+    }
+}
+
+pub fn device_manager_panel<'a>(manager: &'a DeviceManager) -> impl Widget + 'a {
+    let mut layout = VLayout::new();
+    if manager.loading {
+        layout = layout.widget("Devices (loading...)");
+    } else {
+        layout = layout.widget(format!("Devices ({})", manager.devices.len()));
+    }
+    for (i, device) in manager.devices.iter().enumerate() {
+        layout = layout.widget(DeviceLine {
+            device,
+            selected: i == manager.selected,
+        });
+    }
+    layout.widget("Rename with r, delete with d, verify with v, close with <Esc>")
+}
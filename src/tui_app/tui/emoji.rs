@@ -0,0 +1,118 @@
+use unsegen::base::*;
+use unsegen::widget::*;
+
+// The picker shows this many matches around the selected one.
+const SHOWN_MATCHES: usize = 8;
+
+/// The emoji whose shortcode starts with `query` (with or without the leading ':'), followed by the
+/// ones whose shortcode or name contains it.
+pub fn search(query: &str) -> Vec<&'static emojis::Emoji> {
+    let query = query.trim_matches(':').to_lowercase();
+    if query.is_empty() {
+        return Vec::new();
+    }
+    let mut matches = Vec::new();
+    let mut partial_matches = Vec::new();
+    for emoji in emojis::iter() {
+        if emoji.shortcodes().any(|s| s.starts_with(&query)) {
+            matches.push(emoji);
+        } else if emoji.shortcodes().any(|s| s.contains(&query)) || emoji.name().contains(&query) {
+            partial_matches.push(emoji);
+        }
+    }
+    matches.extend(partial_matches);
+    matches
+}
+
+/// Searching for an emoji with the content of the aux line. The selection starts over whenever the
+/// content changes.
+#[derive(Default)]
+pub struct EmojiPicker {
+    query: String,
+    selected: usize,
+}
+
+impl EmojiPicker {
+    fn selected(&self, query: &str) -> usize {
+        if self.query == query {
+            self.selected
+        } else {
+            0
+        }
+    }
+
+    /// The selected match for `query`, if there is any.
+    pub fn picked(&self, query: &str) -> Option<&'static emojis::Emoji> {
+        search(query).get(self.selected(query)).copied()
+    }
+
+    pub fn select_next(&mut self, query: &str) {
+        let num_matches = search(query).len();
+        if num_matches > 0 {
+            self.selected = (self.selected(query) + 1) % num_matches;
+            self.query = query.to_owned();
+        }
+    }
+
+    pub fn select_prev(&mut self, query: &str) {
+        let num_matches = search(query).len();
+        if num_matches > 0 {
+            self.selected = (self.selected(query) + num_matches - 1) % num_matches;
+            self.query = query.to_owned();
+        }
+    }
+}
+
+struct EmojiLine {
+    emoji: &'static emojis::Emoji,
+    selected: bool,
+}
+
+impl EmojiLine {
+    fn text(&self) -> String {
+        match self.emoji.shortcode() {
+            Some(shortcode) => format!("{} :{}: {}", self.emoji, shortcode, self.emoji.name()),
+            None => format!("{} {}", self.emoji, self.emoji.name()),
+        }
+    }
+}
+
+impl Widget for EmojiLine {
+    fn space_demand(&self) -> Demand2D {
+        Demand2D {
+            width: ColDemand::exact(text_width(&self.text())),
+            height: RowDemand::exact(1),
+        }
+    }
+
+    fn draw(&self, mut window: Window, _hints: RenderingHints) {
+        let mut c = Cursor::new(&mut window);
+        if self.selected {
+            c.set_style_modifier(StyleModifier::new().invert(true));
+        }
+        c.write(&self.text());
+    }
+}
+
+pub fn emoji_picker_panel(picker: &EmojiPicker, query: &str) -> impl Widget {
+    let matches = search(query);
+    let selected = picker.selected(query);
+    let mut layout = if query.trim_matches(':').is_empty() {
+        VLayout::new().widget("Type to search for an emoji by its name or shortcode")
+    } else {
+        VLayout::new().widget(format!("Emoji matching '{}' ({})", query, matches.len()))
+    };
+    let first = selected.saturating_sub(SHOWN_MATCHES - 1);
+    for (i, emoji) in matches
+        .into_iter()
+        .enumerate()
+        .skip(first)
+        .take(SHOWN_MATCHES)
+    {
+        layout = layout.widget(EmojiLine {
+            emoji,
+            selected: i == selected,
+        });
+    }
+    layout.widget("Select with <C-n>/<C-p>, pick with <Return> (or type as is with <A-Return>), close with <Esc>")
+}
@@ -0,0 +1,230 @@
+use matrix_sdk::ruma::events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent};
+use matrix_sdk::ruma::OwnedEventId;
+use rlua::RegistryKey;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use unsegen::base::{Color, StyleModifier};
+
+use crate::tui_app::tui::actions::CommandEnvironment;
+use crate::tui_app::RoomState;
+
+/// A part of a formatted message that is drawn in its own style.
+pub struct Segment {
+    pub text: String,
+    pub style: StyleModifier,
+}
+
+// Results are forgotten once this many events have been formatted.
+const MAX_CACHED_RESULTS: usize = 10000;
+
+/// The results of the formatter by event id (and whether the event has been redacted since), so
+/// that it runs once per event rather than on every redraw.
+#[derive(Default)]
+pub struct FormatterCache {
+    results: RefCell<HashMap<(OwnedEventId, bool), Option<Rc<Vec<Segment>>>>>,
+    disabled: Cell<bool>, // After the formatter failed once
+}
+
+/// A Lua function registered with `message_formatter` that may replace how the content of events
+/// is drawn in the timeline.
+#[derive(Copy, Clone)]
+pub struct MessageFormatter<'a> {
+    environment: &'a CommandEnvironment,
+    function: &'a RegistryKey,
+    cache: &'a FormatterCache,
+}
+
+impl<'a> MessageFormatter<'a> {
+    pub fn new(
+        environment: &'a CommandEnvironment,
+        function: &'a RegistryKey,
+        cache: &'a FormatterCache,
+    ) -> Self {
+        MessageFormatter {
+            environment,
+            function,
+            cache,
+        }
+    }
+
+    /// The segments to draw instead of the content of `event`, or none if the formatter leaves it
+    /// to the default rendering. A formatter that fails is disabled, so that the error is logged
+    /// once instead of on every redraw.
+    pub fn format(
+        &self,
+        event: &AnySyncTimelineEvent,
+        room: &RoomState,
+    ) -> Option<Rc<Vec<Segment>>> {
+        if self.cache.disabled.get() {
+            return None;
+        }
+        let redacted = match event {
+            AnySyncTimelineEvent::MessageLike(m) => m.original_content().is_none(),
+            AnySyncTimelineEvent::State(s) => s.original_content().is_none(),
+        };
+        let key = (event.event_id().to_owned(), redacted);
+        if let Some(result) = self.cache.results.borrow().get(&key) {
+            return result.clone();
+        }
+        let res = self.environment.lua.context(|lua_ctx| {
+            let table = event_table(&lua_ctx, event, room)?;
+            let f: rlua::Function = lua_ctx.registry_value(self.function)?;
+            match f.call::<_, rlua::Value>(table)? {
+                rlua::Value::Nil => Ok(None),
+                rlua::Value::Table(segments) => segments
+                    .sequence_values::<rlua::Value>()
+                    .map(|s| segment(s?))
+                    .collect::<rlua::Result<Vec<_>>>()
+                    .map(Some),
+                s => segment(s).map(|s| Some(vec![s])),
+            }
+        });
+        let result = match res {
+            Ok(segments) => segments.map(Rc::new),
+            Err(e) => {
+                tracing::error!(
+                    "Message formatter failed for {}, disabling it: {}",
+                    event.event_id(),
+                    e
+                );
+                self.cache.disabled.set(true);
+                return None;
+            }
+        };
+        let mut results = self.cache.results.borrow_mut();
+        if results.len() >= MAX_CACHED_RESULTS {
+            results.clear();
+        }
+        results.insert(key, result.clone());
+        result
+    }
+}
+
+/// The event as passed to the formatter: Its id, sender, sender_name, type, msgtype and body (for
+/// messages) and content.
+fn event_table<'lua>(
+    lua_ctx: &rlua::Context<'lua>,
+    event: &AnySyncTimelineEvent,
+    room: &RoomState,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    table.set("event_id", event.event_id().as_str())?;
+    table.set("sender", event.sender().as_str())?;
+    let sender_name = room
+        .display_name(event.sender())
+        .unwrap_or_else(|| event.sender().to_string());
+    table.set("sender_name", sender_name)?;
+    table.set("type", event.event_type().to_string())?;
+    let content = match event {
+        AnySyncTimelineEvent::MessageLike(m) => {
+            if let AnySyncMessageLikeEvent::RoomMessage(msg) = m {
+                if let Some(msg) = msg.as_original() {
+                    table.set("msgtype", msg.content.msgtype.msgtype())?;
+                    table.set("body", msg.content.msgtype.body())?;
+                }
+            }
+            m.original_content().map(serde_json::to_value)
+        }
+        AnySyncTimelineEvent::State(s) => s.original_content().map(serde_json::to_value),
+    };
+    if let Some(content) = content {
+        let content = content.map_err(|e| rlua::Error::RuntimeError(e.to_string()))?;
+        table.set("content", json_to_lua(lua_ctx, &content)?)?;
+    }
+    Ok(table)
+}
+
+fn json_to_lua<'lua>(
+    lua_ctx: &rlua::Context<'lua>,
+    value: &serde_json::Value,
+) -> rlua::Result<rlua::Value<'lua>> {
+    use serde_json::Value;
+    Ok(match value {
+        Value::Null => rlua::Value::Nil,
+        Value::Bool(b) => rlua::Value::Boolean(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => rlua::Value::Integer(i),
+            None => rlua::Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => rlua::Value::String(lua_ctx.create_string(s)?),
+        Value::Array(values) => {
+            let table = lua_ctx.create_table()?;
+            for (i, v) in values.iter().enumerate() {
+                table.set(i + 1, json_to_lua(lua_ctx, v)?)?;
+            }
+            rlua::Value::Table(table)
+        }
+        Value::Object(entries) => {
+            let table = lua_ctx.create_table()?;
+            for (k, v) in entries {
+                table.set(k.as_str(), json_to_lua(lua_ctx, v)?)?;
+            }
+            rlua::Value::Table(table)
+        }
+    })
+}
+
+/// A segment is either a string or a table with the text and optionally fg, bg, bold, italic and
+/// underline.
+fn segment(value: rlua::Value) -> rlua::Result<Segment> {
+    match value {
+        rlua::Value::String(s) => Ok(Segment {
+            text: s.to_str()?.to_owned(),
+            style: StyleModifier::new(),
+        }),
+        rlua::Value::Table(t) => {
+            let mut style = StyleModifier::new();
+            if let Some(color) = t.get::<_, Option<String>>("fg")? {
+                style = style.fg_color(parse_color(&color)?);
+            }
+            if let Some(color) = t.get::<_, Option<String>>("bg")? {
+                style = style.bg_color(parse_color(&color)?);
+            }
+            if let Some(bold) = t.get::<_, Option<bool>>("bold")? {
+                style = style.bold(bold);
+            }
+            if let Some(italic) = t.get::<_, Option<bool>>("italic")? {
+                style = style.italic(italic);
+            }
+            if let Some(underline) = t.get::<_, Option<bool>>("underline")? {
+                style = style.underline(underline);
+            }
+            Ok(Segment {
+                text: t.get("text")?,
+                style,
+            })
+        }
+        _ => Err(rlua::Error::RuntimeError(
+            "Message segments must be strings or tables".to_owned(),
+        )),
+    }
+}
+
+/// One of the basic terminal colors or a hex color like #ff8800.
+fn parse_color(color: &str) -> rlua::Result<Color> {
+    let invalid = || rlua::Error::RuntimeError(format!("'{}' is not a valid color", color));
+    Ok(match color {
+        "default" => Color::Default,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        _ => {
+            let hex = color
+                .strip_prefix('#')
+                .filter(|h| h.len() == 6)
+                .ok_or_else(invalid)?;
+            let rgb = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+            Color::Rgb {
+                r: (rgb >> 16) as u8,
+                g: (rgb >> 8) as u8,
+                b: rgb as u8,
+            }
+        }
+    })
+}
@@ -0,0 +1,68 @@
+use matrix_sdk::ruma::presence::PresenceState;
+use unsegen::base::*;
+use unsegen::widget::*;
+
+use super::rooms::{presence_color, PRESENCE_SYMBOL};
+use crate::tui_app::{RoomState, State};
+
+/// Share of the room pane's width that the member pane takes up while it is shown.
+pub const MEMBER_PANE_FRACTION: f64 = 0.25;
+
+struct MemberLine<'a> {
+    name: String,
+    presence: Option<&'a PresenceState>,
+}
+
+impl Widget for MemberLine<'_> {
+    fn space_demand(&self) -> Demand2D {
+        Demand2D {
+            width: ColDemand::exact(text_width(PRESENCE_SYMBOL) + text_width(&self.name)),
+            height: RowDemand::exact(1),
+        }
+    }
+
+    fn draw(&self, mut window: Window, _hints: RenderingHints) {
+        let mut c = Cursor::new(&mut window);
+        {
+            let color = self.presence.map_or(Color::Default, presence_color);
+            let mut c = c.save().style_modifier();
+            c.apply_style_modifier(StyleModifier::new().fg_color(color));
+            c.write(PRESENCE_SYMBOL);
+        }
+        c.write(&self.name);
+    }
+}
+
+// Online members first, then idle ones, then everyone else.
+fn presence_rank(presence: Option<&PresenceState>) -> u8 {
+    match presence {
+        Some(PresenceState::Online) => 0,
+        Some(PresenceState::Unavailable) => 1,
+        _ => 2,
+    }
+}
+
+/// The joined members of `room` with their presence. Members that don't fit are cut off at the
+/// bottom, which are the offline ones in larger rooms.
+pub fn member_pane<'a>(state: &'a State, room: &'a RoomState) -> impl Widget + 'a {
+    let mut members = room
+        .user_colors
+        .keys()
+        .map(|user_id| MemberLine {
+            name: room
+                .display_name(user_id)
+                .unwrap_or_else(|| user_id.to_string()),
+            presence: state.presence(user_id),
+        })
+        .collect::<Vec<_>>();
+    members.sort_by(|a, b| {
+        presence_rank(a.presence)
+            .cmp(&presence_rank(b.presence))
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+    let mut layout = VLayout::new().widget(format!("Members ({})", members.len()));
+    for member in members {
+        layout = layout.widget(member);
+    }
+    layout
+}
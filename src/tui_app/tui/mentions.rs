@@ -0,0 +1,124 @@
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId};
+use unsegen::base::*;
+use unsegen::input::{OperationResult, Scrollable};
+use unsegen::widget::*;
+
+use crate::tui_app::RoomKey;
+
+pub struct Mention {
+    pub room: RoomKey,
+    pub room_name: String,
+    pub event_id: OwnedEventId,
+    pub sender: OwnedUserId,
+    pub body: String,
+    pub ts: MilliSecondsSinceUnixEpoch,
+}
+
+/// Events that highlighted us (according to their push actions) across all rooms, in the order
+/// they arrived. Mentions stay in the list until they are marked as handled.
+#[derive(Default)]
+pub struct Mentions {
+    mentions: Vec<Mention>,
+    selected: usize,
+}
+
+impl Mentions {
+    pub fn add(&mut self, mention: Mention) {
+        if !self.mentions.iter().any(|m| m.event_id == mention.event_id) {
+            self.mentions.push(mention);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mentions.len()
+    }
+
+    pub fn selected(&self) -> Option<&Mention> {
+        self.mentions.get(self.selected)
+    }
+
+    pub fn remove_selected(&mut self) -> Option<Mention> {
+        if self.selected >= self.mentions.len() {
+            return None;
+        }
+        let removed = self.mentions.remove(self.selected);
+        self.selected = self.selected.min(self.mentions.len().saturating_sub(1));
+        Some(removed)
+    }
+
+    pub fn clear(&mut self) {
+        self.mentions.clear();
+        self.selected = 0;
+    }
+}
+
+impl Scrollable for Mentions {
+    fn scroll_backwards(&mut self) -> OperationResult {
+        self.selected = self.selected.checked_sub(1).ok_or(())?;
+        Ok(())
+    }
+
+    fn scroll_forwards(&mut self) -> OperationResult {
+        if self.selected + 1 < self.mentions.len() {
+            self.selected += 1;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+fn format_time(ts: MilliSecondsSinceUnixEpoch) -> impl std::fmt::Display {
+    let time = chrono::DateTime::from_timestamp(ts.as_secs().into(), 0).unwrap();
+    let time: chrono::DateTime<chrono::Local> = time.into();
+    time.format("%m-%d %H:%M")
+}
+
+struct MentionLine<'a> {
+    mention: &'a Mention,
+    selected: bool,
+}
+
+impl MentionLine<'_> {
+    fn text(&self) -> String {
+        // Only the first line of multi-line messages
+        let body = self.mention.body.lines().next().unwrap_or("");
+        format!(
+            "{} {} in {}: {}",
+            format_time(self.mention.ts),
+            self.mention.sender,
+            self.mention.room_name,
+            body,
+        )
+    }
+}
+
+impl Widget for MentionLine<'_> {
+    fn space_demand(&self) -> Demand2D {
+        Demand2D {
+            width: ColDemand::exact(text_width(&self.text())),
+            height: RowDemand::exact(1),
+        }
+    }
+
+    fn draw(&self, mut window: Window, _hints: RenderingHints) {
+        let mut c = Cursor::new(&mut window);
+        if self.selected {
+            c.set_style_modifier(StyleModifier::new().invert(true));
+        }
+        c.write(&self.text());
+    }
+}
+
+pub fn mentions_panel<'a>(mentions: &'a Mentions) -> impl Widget + 'a {
+    let mut layout = VLayout::new().widget(format!("Mentions ({})", mentions.len()));
+    for (i, mention) in mentions.mentions.iter().enumerate() {
+        layout = layout.widget(MentionLine {
+            mention,
+            selected: i == mentions.selected,
+        });
+    }
+    layout.widget(
+        "Jump to a mention with <Return>, mark as handled with d (all with D), close with <Esc>",
+    )
+}
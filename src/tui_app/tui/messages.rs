@@ -1,13 +1,21 @@
 use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
 use matrix_sdk::ruma::events::{OriginalSyncMessageLikeEvent, SyncMessageLikeEvent};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::rc::Rc;
 use unsegen::base::*;
 use unsegen::input::{OperationResult, Scrollable};
 use unsegen::widget::*;
 
-use crate::timeline::{EventWalkResult, EventWalkResultNewest, MessageQuery, TimelineEntry};
-use crate::tui_app::State;
+use crate::config::HiddenEvents;
+use crate::timeline::{
+    membership_change, EventWalkResult, EventWalkResultNewest, MessageQuery, RoomTimelineCache,
+    RoomTimelineIndex, TimelineEntry,
+};
+use crate::tui_app::{EchoState, State};
 
+use crate::tui_app::tui::formatter::Segment;
 use crate::tui_app::tui::{MessageSelection, Tasks};
 
 use matrix_sdk::{
@@ -16,7 +24,7 @@ use matrix_sdk::{
         room::message::{MessageType, Relation},
         AnySyncMessageLikeEvent, AnySyncStateEvent,
     },
-    ruma::{EventId, RoomId, UserId},
+    ruma::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, UserId},
 };
 
 use super::EventDetail;
@@ -28,26 +36,34 @@ macro_rules! message_fetch_symbol {
 }
 pub const REPLY_PREFIX: &str = "╭➤ ";
 pub const EDIT_PREFIX: &str = "Editing: ";
+const SENT_GLYPH: &str = "✓";
+const READ_GLYPH: &str = "✓✓";
+const SENDING_GLYPH: &str = "…";
+const FAILED_GLYPH: &str = "✗ failed";
 
 pub struct MessagesMut<'a>(pub &'a mut State);
 
 impl MessagesMut<'_> {
     pub fn deselect_message(&mut self) -> OperationResult {
         let current = self.0.current_room_state_mut().ok_or(())?;
-        let sel = &mut current.tui.selection;
-        if let MessageSelection::Newest = *sel {
+        let tui = &mut current.tui;
+        if let MessageSelection::Newest = tui.selection {
             Err(())
         } else {
-            *sel = MessageSelection::Newest;
+            tui.selection = MessageSelection::Newest;
+            if tui.anchor.is_none() {
+                tui.num_unseen = 0;
+            }
             Ok(())
         }
     }
 }
 impl Scrollable for MessagesMut<'_> {
     fn scroll_backwards(&mut self) -> OperationResult {
+        let hidden = self.0.tui.hidden_events.clone();
         let current = self.0.current_room_state_mut().ok_or(())?;
         let messages = &current.messages;
-        let pos = match &current.tui.selection {
+        let mut pos = match &current.tui.selection {
             MessageSelection::Newest => messages.walk_from_newest().message(),
             MessageSelection::Specific(id) => {
                 let pos = messages.walk_from_known(&id).message().ok_or(())?;
@@ -55,34 +71,48 @@ impl Scrollable for MessagesMut<'_> {
             }
         }
         .ok_or(())?;
+        while hidden.hides(messages.message(pos).original()) {
+            pos = messages.previous(pos).message().ok_or(())?;
+        }
         current.tui.selection =
             MessageSelection::Specific(messages.message(pos).event_id().to_owned());
         Ok(())
     }
 
     fn scroll_forwards(&mut self) -> OperationResult {
+        let hidden = self.0.tui.hidden_events.clone();
         let current = self.0.current_room_state_mut().ok_or(())?;
         let messages = &current.messages;
-        let pos = match &current.tui.selection {
+        let mut pos = match &current.tui.selection {
             MessageSelection::Newest => return Err(()),
             MessageSelection::Specific(id) => messages.walk_from_known(&id).message(),
         }
         .ok_or(())?;
-        current.tui.selection = match messages.next(pos) {
-            EventWalkResult::Message(pos) => {
-                MessageSelection::Specific(messages.message(pos).event_id().to_owned())
+        current.tui.selection = loop {
+            match messages.next(pos) {
+                EventWalkResult::Message(next)
+                    if hidden.hides(messages.message(next).original()) =>
+                {
+                    pos = next;
+                }
+                EventWalkResult::Message(next) => {
+                    break MessageSelection::Specific(messages.message(next).event_id().to_owned())
+                }
+                EventWalkResult::RequiresFetch | EventWalkResult::End => return Err(()),
             }
-            EventWalkResult::RequiresFetch | EventWalkResult::End => return Err(()),
         };
         Ok(())
     }
 
     fn scroll_to_end(&mut self) -> OperationResult {
         let current = self.0.current_room_state_mut().ok_or(())?;
-        current.tui.selection = match &current.tui.selection {
-            MessageSelection::Newest => return Err(()),
-            MessageSelection::Specific(_id) => MessageSelection::Newest,
-        };
+        let tui = &mut current.tui;
+        if let (MessageSelection::Newest, None) = (&tui.selection, &tui.anchor) {
+            return Err(());
+        }
+        tui.selection = MessageSelection::Newest;
+        tui.anchor = None;
+        tui.num_unseen = 0;
         Ok(())
     }
 }
@@ -100,37 +130,86 @@ fn show_mode(state: &State, selected: bool) -> EventShowMode {
 pub struct Messages<'a>(pub &'a State, pub Tasks<'a>);
 
 impl Messages<'_> {
+    /// What to draw for the event at `pos`, and the oldest and newest position that it covers.
+    /// Events hidden by the configuration are skipped unless they are selected. Runs of
+    /// membership events are collapsed into a single line unless one of them is selected.
+    fn item_at<'b>(
+        &'b self,
+        pos: RoomTimelineIndex<'b>,
+        state: &'b crate::tui_app::RoomState,
+        width: Width,
+    ) -> (
+        Option<TimelineItem<'b>>,
+        RoomTimelineIndex<'b>,
+        RoomTimelineIndex<'b>,
+    ) {
+        let selected = match &state.tui.selection {
+            MessageSelection::Specific(id) => Some(&**id),
+            MessageSelection::Newest => None,
+        };
+        let hidden = &*self.0.tui.hidden_events;
+        let messages = &state.messages;
+        let entry = messages.message(pos);
+        let is_selected = selected == Some(entry.event_id());
+        if hidden.hides(entry.original()) && !is_selected {
+            return (None, pos, pos);
+        }
+        if self.0.tui.collapse_membership_events && membership_change(entry.original()).is_some() {
+            let runs = self.1.membership_runs;
+            let run = runs.run_at(pos, messages, hidden);
+            let contains_selected = selected.map_or(false, |id| runs.is_in(id, &run));
+            if !contains_selected && run.num_shown > 1 {
+                if let (Some(first), Some(last), Some(first_shown)) = (
+                    messages.index_of(&run.first),
+                    messages.index_of(&run.last),
+                    messages.message_from_id(&run.first_shown),
+                ) {
+                    let item = TimelineItem::Collapsed(CollapsedMembership {
+                        first: first_shown.original(),
+                        run,
+                        width,
+                    });
+                    return (Some(item), first, last);
+                }
+            }
+        }
+        let item = TimelineItem::Event(TuiEvent {
+            event: entry,
+            width,
+            room_state: state,
+            own_user_id: self.0.accounts[state.account()].user_id(),
+            show_mode: show_mode(self.0, is_selected),
+            image_placeholders: self.0.tui.image_placeholders,
+            tasks: self.1,
+        });
+        (Some(item), pos, pos)
+    }
+
     fn draw_up_from<'b>(
         &self,
         mut window: Window,
         hints: RenderingHints,
         mut msg: EventWalkResult<'b>,
-        room: &RoomId,
         state: &'b crate::tui_app::RoomState,
     ) {
         loop {
             msg = match msg {
                 EventWalkResult::Message(id) => {
-                    let e = state.messages.message(id);
-                    let evt = TuiEvent {
-                        event: e,
-                        width: window.get_width(),
-                        room_state: state,
-                        show_mode: show_mode(&self.0, false),
-                        tasks: self.1,
-                    };
-                    let h = evt.space_demand().height.min;
-                    let window_height = window.get_height();
-                    let (above, below) = match window.split((window_height - h).from_origin()) {
-                        Ok(pair) => pair,
-                        Err(_) => {
-                            break;
-                        }
-                    };
+                    let (item, first, _) = self.item_at(id, state, window.get_width());
+                    if let Some(evt) = item {
+                        let h = evt.space_demand().height.min;
+                        let window_height = window.get_height();
+                        let (above, below) = match window.split((window_height - h).from_origin()) {
+                            Ok(pair) => pair,
+                            Err(_) => {
+                                break;
+                            }
+                        };
 
-                    evt.draw(below, hints);
-                    window = above;
-                    state.messages.previous(id)
+                        evt.draw(below, hints);
+                        window = above;
+                    }
+                    state.messages.previous(first)
                 }
                 EventWalkResult::End => {
                     break;
@@ -139,19 +218,50 @@ impl Messages<'_> {
                     let mut c = Cursor::new(&mut window);
                     write!(&mut c, message_fetch_symbol!()).unwrap();
                     self.1
-                        .set_message_query(room.to_owned(), MessageQuery::BeforeCache);
+                        .set_message_query(state.key(), MessageQuery::BeforeCache);
                     break;
                 }
             };
         }
     }
+    /// Draw the messages that have not arrived in a sync yet at the bottom of `window`, one line
+    /// each, and return the space above them.
+    fn draw_local_echoes<'w>(
+        &self,
+        window: Window<'w>,
+        state: &crate::tui_app::RoomState,
+    ) -> Option<Window<'w>> {
+        let echoes = state.local_echoes();
+        if echoes.is_empty() {
+            return Some(window);
+        }
+        let height = Height::new(echoes.len() as i32).unwrap();
+        let window_height = window.get_height();
+        let (above, mut below) = match window.split((window_height - height).from_origin()) {
+            Ok((above, below)) => (Some(above), below),
+            Err(below) => (None, below),
+        };
+        let mut c = Cursor::new(&mut below);
+        c.set_style_modifier(StyleModifier::new().italic(true));
+        for echo in echoes {
+            let marker = match &echo.state {
+                EchoState::Sending | EchoState::Sent(_) => format!("{} ", SENDING_GLYPH),
+                EchoState::Failed(e) => format!("{} ({}) ", FAILED_GLYPH, e),
+            };
+            let _ = write!(c, "{}{}", marker, echo.body.replace('\n', " "));
+            c.wrap_line();
+        }
+        above
+    }
     fn draw_newest(
         &self,
-        mut window: Window,
+        window: Window,
         hints: RenderingHints,
-        room: &RoomId,
         state: &crate::tui_app::RoomState,
     ) {
+        let Some(mut window) = self.draw_local_echoes(window, state) else {
+            return;
+        };
         let mut query_for_newest = None;
         let newest_id = match state.messages.walk_from_newest() {
             EventWalkResultNewest::Message(m) => Some(m),
@@ -182,10 +292,10 @@ impl Messages<'_> {
             }
         };
         if let Some(msg_id) = newest_id {
-            self.draw_up_from(window, hints, EventWalkResult::Message(msg_id), room, state);
+            self.draw_up_from(window, hints, EventWalkResult::Message(msg_id), state);
         }
         if let Some(query) = query_for_newest {
-            self.1.set_message_query(room.to_owned(), query);
+            self.1.set_message_query(state.key(), query);
         }
     }
     fn draw_specific(
@@ -193,7 +303,6 @@ impl Messages<'_> {
         window: Window,
         hints: RenderingHints,
         selected_msg: &EventId,
-        room: &RoomId,
         state: &crate::tui_app::RoomState,
     ) {
         let start_msg = state.messages.walk_from_known(selected_msg);
@@ -203,19 +312,11 @@ impl Messages<'_> {
         loop {
             match msg {
                 EventWalkResult::Message(id) => {
-                    let event = state.messages.message(id);
-                    let selected = event.event_id() == selected_msg;
-                    collected_height += TuiEvent {
-                        event,
-                        width: window.get_width(),
-                        room_state: state,
-                        show_mode: show_mode(self.0, selected),
-                        tasks: self.1,
+                    let (item, _, last) = self.item_at(id, state, window.get_width());
+                    if let Some(item) = item {
+                        collected_height += item.space_demand().height.min;
                     }
-                    .space_demand()
-                    .height
-                    .min;
-                    msg = state.messages.next(id);
+                    msg = state.messages.next(last);
                 }
                 EventWalkResult::End => {
                     break;
@@ -238,38 +339,33 @@ impl Messages<'_> {
             above_selected,
             start_msg.message().map(|id| state.messages.previous(id)),
         ) {
-            self.draw_up_from(above, hints, evt, room, state);
+            self.draw_up_from(above, hints, evt, state);
         }
         let mut window = below_selected;
         let mut msg = start_msg;
         loop {
             msg = match msg {
                 EventWalkResult::Message(id) => {
-                    let event = state.messages.message(id);
-                    let selected = event.event_id() == selected_msg;
-                    let evt = TuiEvent {
-                        event,
-                        width: window.get_width(),
-                        room_state: state,
-                        show_mode: show_mode(self.0, selected),
-                        tasks: self.1,
-                    };
-                    let h = evt.space_demand().height.min;
-                    let (mut current, below) = match window.split(h.from_origin()) {
-                        Ok(pair) => pair,
-                        Err(_) => {
-                            break;
-                        }
-                    };
+                    let selected = state.messages.message(id).event_id() == selected_msg;
+                    let (item, _, last) = self.item_at(id, state, window.get_width());
+                    if let Some(evt) = item {
+                        let h = evt.space_demand().height.min;
+                        let (mut current, below) = match window.split(h.from_origin()) {
+                            Ok(pair) => pair,
+                            Err(_) => {
+                                break;
+                            }
+                        };
 
-                    if selected {
-                        current.set_default_style(
-                            StyleModifier::new().invert(true).apply_to_default(),
-                        );
+                        if selected {
+                            current.set_default_style(
+                                StyleModifier::new().invert(true).apply_to_default(),
+                            );
+                        }
+                        evt.draw(current, hints);
+                        window = below;
                     }
-                    evt.draw(current, hints);
-                    window = below;
-                    state.messages.next(id)
+                    state.messages.next(last)
                 }
                 EventWalkResult::End => {
                     break;
@@ -286,7 +382,7 @@ impl Messages<'_> {
                     } else {
                         MessageQuery::AfterCache
                     };
-                    self.1.set_message_query(room.to_owned(), query);
+                    self.1.set_message_query(state.key(), query);
                     break;
                 }
             };
@@ -305,10 +401,14 @@ impl Widget for Messages<'_> {
     fn draw(&self, window: Window, hints: RenderingHints) {
         if let Some(current) = self.0.current_room_state().as_ref() {
             match &current.tui.selection {
-                MessageSelection::Newest => self.draw_newest(window, hints, &current.id, current),
-                MessageSelection::Specific(id) => {
-                    self.draw_specific(window, hints, id, &current.id, current)
-                }
+                MessageSelection::Newest => match &current.tui.anchor {
+                    Some(anchor) => {
+                        let msg = current.messages.walk_from_known(anchor);
+                        self.draw_up_from(window, hints, msg, current)
+                    }
+                    None => self.draw_newest(window, hints, current),
+                },
+                MessageSelection::Specific(id) => self.draw_specific(window, hints, id, current),
             }
         }
     }
@@ -365,7 +465,9 @@ struct TuiEvent<'a> {
     event: crate::timeline::TimelineEntry<'a>,
     width: Width,
     room_state: &'a crate::tui_app::RoomState,
+    own_user_id: &'a UserId,
     show_mode: EventShowMode,
+    image_placeholders: bool,
     tasks: Tasks<'a>,
 }
 
@@ -420,7 +522,30 @@ fn write_user<T: unsegen::base::CursorTarget>(
     let color = state.user_colors.get(user_id).unwrap_or(&Color::Default);
     let mut c = c.save().style_modifier();
     c.set_style_modifier(StyleModifier::new().fg_color(*color).bold(true));
-    let _ = write!(c, "{}", user_id.as_str());
+    if let Some(name) = state.display_name(user_id) {
+        let _ = write!(c, "{}", name);
+    } else {
+        let _ = write!(c, "{}", user_id.as_str());
+    }
+}
+
+/// Draw the content of a message as returned by the message formatter of the config.
+fn write_segments<T: unsegen::base::CursorTarget>(
+    c: &mut Cursor<T>,
+    sender: &UserId,
+    segments: &[Segment],
+    room_state: &crate::tui_app::RoomState,
+) {
+    write_user(c, sender, room_state);
+    c.set_wrapping_mode(WrappingMode::Wrap);
+    let _ = write!(c, ": ");
+    let start = c.get_col();
+    c.set_line_start_column(start);
+    for segment in segments {
+        let mut c = c.save().style_modifier();
+        c.set_style_modifier(segment.style);
+        c.write(&segment.text);
+    }
 }
 
 pub fn draw_event_preview<T: unsegen::base::CursorTarget, D: DrawEvent>(
@@ -475,7 +600,7 @@ impl DrawEvent for OriginalSyncMessageLikeEvent<RoomMessageEventContent> {
                         let mut c = Cursor::new(&mut l);
                         c.write(REPLY_PREFIX);
                         c.write(message_fetch_symbol!());
-                        tasks.set_message_query(room_state.id.clone(), MessageQuery::BeforeCache);
+                        tasks.set_message_query(room_state.key(), MessageQuery::BeforeCache);
                     }
                     c.write_preformatted(l.content.as_slice());
                     c.wrap_line();
@@ -565,6 +690,9 @@ impl DrawEvent for AnySyncMessageLikeEvent {
                     c.set_style_modifier(StyleModifier::new().italic(true));
                     c.write("*Unable to decrypt message from ");
                     write_user(c, &msg.sender(), room_state);
+                    if room_state.messages.is_key_requested(msg.event_id()) {
+                        c.write(" (key requested)");
+                    }
                     c.write("*");
                 }
                 AnySyncMessageLikeEvent::CallAnswer(msg) => {
@@ -651,7 +779,6 @@ impl DrawEvent for AnySyncStateEvent {
             //AnySyncStateEvent::PolicyRuleRoom(_) => todo!(),
             //AnySyncStateEvent::PolicyRuleServer(_) => todo!(),
             //AnySyncStateEvent::PolicyRuleUser(_) => todo!(),
-            //AnySyncStateEvent::RoomTombstone(_) => todo!(),
             //AnySyncStateEvent::RoomPowerLevels(_) => todo!(),
             //AnySyncStateEvent::RoomServerAcl(_) => todo!(),
             //AnySyncStateEvent::SpaceChild(_) => todo!(),
@@ -666,6 +793,15 @@ impl DrawEvent for AnySyncStateEvent {
                     let _ = write!(c, " has removed the room alias.",);
                 }
             }
+            AnySyncStateEvent::RoomTombstone(e) => {
+                let e = e.as_original().unwrap();
+                write_user(c, &e.sender, room_state);
+                let _ = write!(
+                    c,
+                    " upgraded the room, it continues in {}: {}",
+                    e.content.replacement_room, e.content.body
+                );
+            }
             AnySyncStateEvent::RoomCreate(e) => {
                 let e = e.as_original().unwrap();
                 write_user(c, &e.sender, room_state);
@@ -895,6 +1031,18 @@ impl DrawEvent for Detailed<'_> {
                     let mut c = c.save().style_modifier().line_start_column();
                     original.draw(room_state, &mut c, simplified, tasks);
                 }
+                {
+                    let mut c = c.save().style_modifier();
+                    c.set_style_modifier(StyleModifier::new().italic(true));
+                    let n = versions.len();
+                    let _ = write!(
+                        c,
+                        " (edited {} {}, last at {})",
+                        n,
+                        if n == 1 { "time" } else { "times" },
+                        format_time(versions.last().unwrap())
+                    );
+                }
                 let mut prev = original;
                 for this in versions {
                     let mut c = c.save().style_modifier().line_start_column();
@@ -941,15 +1089,87 @@ impl DrawEvent for TimelineEntry<'_> {
     }
 }
 
-fn write_time<T: unsegen::base::CursorTarget>(c: &mut Cursor<T>, event: &crate::timeline::Event) {
-    let send_time_secs_unix = event.origin_server_ts().as_secs();
+fn format_time(event: &crate::timeline::Event) -> impl std::fmt::Display {
+    format_ts(event.origin_server_ts())
+}
+
+fn format_ts(ts: MilliSecondsSinceUnixEpoch) -> impl std::fmt::Display {
+    let send_time_secs_unix = ts.as_secs();
     let send_time = chrono::DateTime::from_timestamp(send_time_secs_unix.into(), 0).unwrap();
     let send_time: chrono::DateTime<chrono::Local> = send_time.into();
-    let time_str = send_time.format("%m-%d %H:%M");
-    let _ = write!(c, "{} ", time_str);
+    send_time.format("%m-%d %H:%M")
+}
+
+fn write_time<T: unsegen::base::CursorTarget>(c: &mut Cursor<T>, event: &crate::timeline::Event) {
+    let _ = write!(c, "{} ", format_time(event));
+}
+
+const PLACEHOLDER_WIDTH: u32 = 24;
+const PLACEHOLDER_MAX_HEIGHT: u32 = 12;
+
+/// Draw a blurred version of an image from its blurhash. Every cell shows two pixels on top of
+/// each other using the upper half block, which makes the pixels roughly square.
+fn write_blurhash<T: unsegen::base::CursorTarget>(
+    c: &mut Cursor<T>,
+    blurhash: &str,
+    width: Option<matrix_sdk::ruma::UInt>,
+    height: Option<matrix_sdk::ruma::UInt>,
+    max_width: Width,
+) {
+    let cols = PLACEHOLDER_WIDTH
+        .min(max_width.raw_value() as u32 / 2)
+        .max(1);
+    let aspect_ratio = match (width, height) {
+        (Some(w), Some(h)) if u64::from(w) > 0 => u64::from(h) as f32 / u64::from(w) as f32,
+        _ => 0.75,
+    };
+    let rows = ((cols as f32 * aspect_ratio / 2.0).round() as u32).clamp(1, PLACEHOLDER_MAX_HEIGHT);
+    let pixels = match blurhash::decode(blurhash, cols, 2 * rows, 1.0) {
+        Ok(pixels) => pixels,
+        Err(_) => return,
+    };
+    let color = |x: u32, y: u32| {
+        let i = ((y * cols + x) * 4) as usize;
+        Color::Rgb {
+            r: pixels[i],
+            g: pixels[i + 1],
+            b: pixels[i + 2],
+        }
+    };
+    for row in 0..rows {
+        let _ = write!(c, "\n");
+        for x in 0..cols {
+            let mut c = c.save().style_modifier();
+            c.set_style_modifier(
+                StyleModifier::new()
+                    .fg_color(color(x, 2 * row))
+                    .bg_color(color(x, 2 * row + 1)),
+            );
+            c.write("\u{2580}");
+        }
+    }
 }
 
 impl TuiEvent<'_> {
+    /// Our own messages are marked once they came back from the server, and again once another
+    /// member has read them.
+    fn delivery_state(&self) -> Option<&'static str> {
+        let event = self.event.original();
+        if event.sender() != self.own_user_id
+            || !matches!(
+                event,
+                crate::timeline::Event::MessageLike(AnySyncMessageLikeEvent::RoomMessage(_))
+            )
+        {
+            return None;
+        }
+        Some(if self.room_state.is_read_by_others(event.event_id()) {
+            READ_GLYPH
+        } else {
+            SENT_GLYPH
+        })
+    }
+
     fn draw_with_cursor<T: unsegen::base::CursorTarget>(&self, c: &mut Cursor<T>) {
         write_time(c, self.event.original());
 
@@ -959,7 +1179,20 @@ impl TuiEvent<'_> {
         let detailed;
         match self.show_mode {
             EventShowMode::Simple => {
-                self.event.draw(self.room_state, c, false, self.tasks);
+                let formatted = self.event.latest().and_then(|event| {
+                    let f = self.tasks.formatter?;
+                    Some((event, f.format(event, self.room_state)?))
+                });
+                if let Some((event, segments)) = formatted {
+                    write_segments(c, event.sender(), &segments, self.room_state);
+                    if let TimelineEntry::Edited { .. } = self.event {
+                        let mut c = c.save().style_modifier();
+                        c.set_style_modifier(StyleModifier::new().italic(true));
+                        c.write(" (edited)");
+                    }
+                } else {
+                    self.event.draw(self.room_state, c, false, self.tasks);
+                }
                 detailed = false;
             }
             EventShowMode::Detailed => {
@@ -972,6 +1205,27 @@ impl TuiEvent<'_> {
             }
         }
 
+        if let Some(glyph) = self.delivery_state() {
+            let mut c = c.save().style_modifier();
+            c.set_style_modifier(StyleModifier::new().italic(true));
+            let _ = write!(c, " {}", glyph);
+        }
+
+        if self.image_placeholders && !matches!(self.show_mode, EventShowMode::Debug) {
+            if let Some(crate::timeline::Event::MessageLike(
+                AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(msg)),
+            )) = self.event.latest()
+            {
+                if let MessageType::Image(img) = &msg.content.msgtype {
+                    if let Some(info) = &img.info {
+                        if let Some(blurhash) = &info.blurhash {
+                            write_blurhash(c, blurhash, info.width, info.height, self.width);
+                        }
+                    }
+                }
+            }
+        }
+
         if let Some(reactions) = self.room_state.messages.reactions(self.event.event_id()) {
             {
                 let mut c = c.save().style_modifier();
@@ -1001,9 +1255,180 @@ impl TuiEvent<'_> {
     }
 }
 
-impl Widget for TuiEvent<'_> {
+/// Description of a membership change (see `crate::timeline::MEMBERSHIP_CHANGES`) following
+/// the number of affected users.
+fn membership_change_description(change: &str) -> &'static str {
+    match change {
+        "join" => "joined",
+        "leave" => "left",
+        "invite" => "were invited",
+        "reject" => "rejected an invitation",
+        "revoke" => "had their invitation revoked",
+        "kick" => "were kicked",
+        "ban" => "were banned",
+        "unban" => "were unbanned",
+        "displayname" => "changed their name",
+        "avatar" => "changed their avatar",
+        _ => "changed their membership",
+    }
+}
+
+const MAX_CACHED_RUN_EVENTS: usize = 10000;
+
+/// A run of consecutive membership events (and hidden events in between).
+struct MembershipRun {
+    generation: u64, // Of the timeline that the run was found in
+    first: OwnedEventId,
+    last: OwnedEventId,
+    first_shown: OwnedEventId,
+    num_shown: usize,
+    summary: String,
+}
+
+/// The runs of membership events by the id of each of their events, so that a run is searched for
+/// once after the timeline changed rather than for each of its events on every redraw.
+#[derive(Default)]
+pub struct MembershipRunCache {
+    runs: RefCell<HashMap<OwnedEventId, Rc<MembershipRun>>>,
+}
+
+impl MembershipRunCache {
+    /// The run that the membership event at `pos` is part of.
+    fn run_at(
+        &self,
+        pos: RoomTimelineIndex,
+        messages: &RoomTimelineCache,
+        hidden: &HiddenEvents,
+    ) -> Rc<MembershipRun> {
+        let event_id = messages.message(pos).event_id();
+        if let Some(run) = self.runs.borrow().get(event_id) {
+            if run.generation == messages.generation() {
+                return run.clone();
+            }
+        }
+        // Hidden events do not interrupt a run.
+        let part_of_run = |p: &RoomTimelineIndex| {
+            let e = messages.message(*p).original();
+            membership_change(e).is_some() || hidden.hides(e)
+        };
+        let mut run = vec![pos];
+        while let Some(p) = messages
+            .previous(*run.last().unwrap())
+            .message()
+            .filter(part_of_run)
+        {
+            run.push(p);
+        }
+        run.reverse();
+        while let Some(p) = messages
+            .next(*run.last().unwrap())
+            .message()
+            .filter(part_of_run)
+        {
+            run.push(p);
+        }
+        let shown = run
+            .iter()
+            .map(|p| messages.message(*p).original())
+            .filter(|e| !hidden.hides(e))
+            .collect::<Vec<_>>();
+        let event_id_at = |p: &RoomTimelineIndex| messages.message(*p).event_id().to_owned();
+        let result = Rc::new(MembershipRun {
+            generation: messages.generation(),
+            first: event_id_at(&run[0]),
+            last: event_id_at(run.last().unwrap()),
+            // Only a selected event is drawn even though it is hidden, which is not collapsed.
+            first_shown: shown
+                .first()
+                .map_or(event_id.to_owned(), |e| e.event_id().to_owned()),
+            num_shown: shown.len(),
+            summary: membership_summary(&shown),
+        });
+        let mut runs = self.runs.borrow_mut();
+        if runs.len() + run.len() > MAX_CACHED_RUN_EVENTS {
+            runs.clear();
+        }
+        for p in &run {
+            runs.insert(event_id_at(p), result.clone());
+        }
+        result
+    }
+
+    /// Whether the event `id` is part of `run`.
+    fn is_in(&self, id: &EventId, run: &Rc<MembershipRun>) -> bool {
+        self.runs
+            .borrow()
+            .get(id)
+            .map_or(false, |r| Rc::ptr_eq(r, run))
+    }
+}
+
+/// Summarizes membership events as e.g. "5 people joined, 2 left".
+fn membership_summary(events: &[&crate::timeline::Event]) -> String {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for change in events.iter().filter_map(|e| membership_change(e)) {
+        match counts.iter_mut().find(|(c, _)| *c == change) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((change, 1)),
+        }
+    }
+    counts
+        .iter()
+        .enumerate()
+        .map(|(i, (change, n))| {
+            let people = match (i, n) {
+                (0, 1) => " person",
+                (0, _) => " people",
+                _ => "",
+            };
+            format!("{}{} {}", n, people, membership_change_description(change))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A run of membership events drawn as a single line with its summary.
+struct CollapsedMembership<'a> {
+    first: &'a crate::timeline::Event,
+    run: Rc<MembershipRun>,
+    width: Width,
+}
+
+impl CollapsedMembership<'_> {
+    fn draw_with_cursor<T: unsegen::base::CursorTarget>(&self, c: &mut Cursor<T>) {
+        write_time(c, self.first);
+        let start = c.get_col();
+        c.set_line_start_column(start);
+        c.set_wrapping_mode(WrappingMode::Wrap);
+        c.set_style_modifier(StyleModifier::new().italic(true));
+        let _ = write!(c, "{}", self.run.summary);
+    }
+}
+
+enum TimelineItem<'a> {
+    Event(TuiEvent<'a>),
+    Collapsed(CollapsedMembership<'a>),
+}
+
+impl TimelineItem<'_> {
+    fn draw_with_cursor<T: unsegen::base::CursorTarget>(&self, c: &mut Cursor<T>) {
+        match self {
+            TimelineItem::Event(e) => e.draw_with_cursor(c),
+            TimelineItem::Collapsed(m) => m.draw_with_cursor(c),
+        }
+    }
+
+    fn width(&self) -> Width {
+        match self {
+            TimelineItem::Event(e) => e.width,
+            TimelineItem::Collapsed(m) => m.width,
+        }
+    }
+}
+
+impl Widget for TimelineItem<'_> {
     fn space_demand(&self) -> unsegen::widget::Demand2D {
-        let mut est = unsegen::base::window::ExtentEstimationWindow::with_width(self.width);
+        let mut est = unsegen::base::window::ExtentEstimationWindow::with_width(self.width());
         let mut c = Cursor::new(&mut est);
         self.draw_with_cursor(&mut c);
         Demand2D {
@@ -1020,3 +1445,55 @@ impl Widget for TuiEvent<'_> {
         self.draw_with_cursor(&mut c);
     }
 }
+
+/// Every user that reacted to an event, grouped by reaction, the most frequent first.
+pub struct ReactionDetails<'a> {
+    pub room_state: &'a crate::tui_app::RoomState,
+    pub event_id: &'a EventId,
+}
+
+impl ReactionDetails<'_> {
+    fn sorted(&self) -> Vec<(&String, &Vec<crate::timeline::Reaction>)> {
+        let mut reactions = self
+            .room_state
+            .messages
+            .reactions(self.event_id)
+            .map(|r| r.iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        reactions.sort_by(|(k1, v1), (k2, v2)| v2.len().cmp(&v1.len()).then(k1.cmp(k2)));
+        reactions
+    }
+}
+
+impl Widget for ReactionDetails<'_> {
+    fn space_demand(&self) -> unsegen::widget::Demand2D {
+        let lines = self
+            .sorted()
+            .iter()
+            .map(|(_, events)| 1 + events.len())
+            .sum::<usize>();
+        unsegen::widget::Demand2D {
+            width: ColDemand::at_least(1),
+            height: RowDemand::exact(lines.max(1)),
+        }
+    }
+
+    fn draw(&self, mut window: Window, _hints: RenderingHints) {
+        let mut c = Cursor::new(&mut window);
+        let reactions = self.sorted();
+        if reactions.is_empty() {
+            c.write("No reactions");
+        }
+        for (i, (emoji, events)) in reactions.into_iter().enumerate() {
+            if i > 0 {
+                let _ = write!(c, "\n");
+            }
+            let _ = write!(c, "{} {}", emoji, events.len());
+            for e in events {
+                let _ = write!(c, "\n  ");
+                write_user(&mut c, &e.sender, self.room_state);
+                let _ = write!(c, " at {}", format_ts(e.origin_server_ts));
+            }
+        }
+    }
+}
@@ -1,10 +1,8 @@
-use matrix_sdk::ruma::api::client::receipt::create_receipt::v3::ReceiptType;
-use matrix_sdk::ruma::events::receipt::ReceiptThread;
 use matrix_sdk::ruma::events::OriginalSyncMessageLikeEvent;
-use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId};
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedTransactionId};
 use matrix_sdk::Client;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::stdout;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -18,26 +16,46 @@ use unsegen::widget::*;
 
 use matrix_sdk::ruma::{events::room::message::RoomMessageEventContent, RoomId};
 
-use crate::config::{Config, KeyMapFunctionResult, Keys};
+use crate::config::{Config, Hook, KeyMapFunctionResult, Keys};
 use crate::timeline::MessageQuery;
-use crate::tui_app::tui::actions::CommandEnvironment;
-use crate::tui_app::State;
+use crate::tui_app::retry::{self, with_retry};
+use crate::tui_app::shutdown;
+use crate::tui_app::tui::actions::{Callback, CommandEnvironment, HookData, ProcessOutput};
+use crate::tui_app::verification::{Stage, VerificationFlow};
+use crate::tui_app::{DesktopNotification, KnockStatus, RoomKey, SessionStatus, State};
 
 use nix::sys::signal;
 
 pub mod actions;
+pub mod devices;
+pub mod emoji;
+mod formatter;
+pub mod members;
+pub mod mentions;
 pub mod messages;
 pub mod rooms;
 
 const DRAW_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(16);
+// Terminals report focus changes with these sequences once focus reporting is enabled.
+const ENABLE_FOCUS_REPORTING: &str = "\x1b[?1004h";
+const DISABLE_FOCUS_REPORTING: &str = "\x1b[?1004l";
+const FOCUS_IN: &[u8] = b"\x1b[I";
+const FOCUS_OUT: &[u8] = b"\x1b[O";
+// A bell (which most terminals can be configured to turn into an urgency hint) followed by the
+// iTerm2 request for attention, which other terminals ignore.
+const URGENCY_HINT: &str = "\x07\x1b]1337;RequestAttention=yes\x07";
+const VISUAL_BELL_DURATION: std::time::Duration = std::time::Duration::from_millis(200);
 
 #[derive(Copy, Clone)]
 pub struct Tasks<'a> {
     message_query: &'a RefCell<Option<MessageQueryRequest>>,
+    formatter: Option<formatter::MessageFormatter<'a>>,
+    room_list: rooms::RoomListHooks<'a>,
+    membership_runs: &'a messages::MembershipRunCache,
 }
 
 impl Tasks<'_> {
-    fn set_message_query(&self, room: OwnedRoomId, query: MessageQuery) {
+    fn set_message_query(&self, room: RoomKey, query: MessageQuery) {
         let mut q = self.message_query.borrow_mut();
         *q = Some(MessageQueryRequest { room, kind: query });
     }
@@ -52,6 +70,8 @@ pub struct RoomTuiState {
     pub msg_edit: TextEdit,
     msg_edit_type: SendMessageType,
     selection: MessageSelection,
+    anchor: Option<OwnedEventId>, // Shown instead of the newest message if not following
+    num_unseen: usize,            // Messages received while scrolled back or not following
 }
 
 impl RoomTuiState {
@@ -60,15 +80,122 @@ impl RoomTuiState {
             msg_edit: TextEdit::new(),
             msg_edit_type: SendMessageType::Simple,
             selection: MessageSelection::Newest,
+            anchor: None,
+            num_unseen: 0,
         }
     }
+
+    /// Keep track of messages that arrived below the visible part of the timeline. Without auto
+    /// follow, the view stays at `previous_newest` instead of moving to the new messages.
+    pub fn handle_new_messages(
+        &mut self,
+        previous_newest: Option<OwnedEventId>,
+        num_new: usize,
+        auto_follow: bool,
+    ) {
+        if num_new == 0 {
+            return;
+        }
+        if let (MessageSelection::Newest, None) = (&self.selection, &self.anchor) {
+            if auto_follow || previous_newest.is_none() {
+                return;
+            }
+            self.anchor = previous_newest;
+        }
+        self.num_unseen += num_new;
+    }
+
+    pub fn num_unseen(&self) -> usize {
+        self.num_unseen
+    }
+
+    pub fn selected_event(&self) -> Option<&EventId> {
+        match &self.selection {
+            MessageSelection::Newest => None,
+            MessageSelection::Specific(id) => Some(id),
+        }
+    }
+}
+/// Tracks since when the current room is shown, so that rooms that are only passed while cycling
+/// through the room list are not marked as read.
+struct ReadDelay {
+    room: Option<RoomKey>,
+    since: std::time::Instant,
+}
+
+impl ReadDelay {
+    fn new() -> Self {
+        ReadDelay {
+            room: None,
+            since: std::time::Instant::now(),
+        }
+    }
+
+    /// Whether `room` has been shown for at least `delay`. If the room just changed, an event is
+    /// sent once the delay has passed to check again.
+    fn elapsed(
+        &mut self,
+        room: RoomKey,
+        delay: std::time::Duration,
+        events: &mpsc::Sender<Event>,
+    ) -> bool {
+        if self.room.as_ref() != Some(&room) {
+            self.room = Some(room);
+            self.since = std::time::Instant::now();
+            if !delay.is_zero() {
+                let events = events.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = events.send(Event::ReadDelayElapsed).await;
+                });
+            }
+        }
+        self.since.elapsed() >= delay
+    }
 }
-fn send_read_receipt(c: &Client, rid: &RoomId, eid: OwnedEventId) {
+
+fn mark_current_room_as_read(
+    state: &mut State,
+    config: &Config,
+    read_delay: &mut ReadDelay,
+    events: &mpsc::Sender<Event>,
+) {
+    let client = state.current_client();
+    let sends = state.pending_sends.clone();
+    if let Some(room) = state.current_room_state_mut() {
+        if read_delay.elapsed(room.key(), config.mark_read_delay, events) {
+            let read_only = room.is_read_only();
+            if let Some(read_event_id) = room.mark_newest_event_as_read() {
+                if !read_only {
+                    send_read_receipt(&client, &sends, config, &room.id, read_event_id);
+                }
+            }
+        }
+    }
+}
+
+fn send_read_receipt(
+    c: &Client,
+    sends: &shutdown::PendingSends,
+    config: &Config,
+    rid: &RoomId,
+    eid: OwnedEventId,
+) {
+    let receipt_types = config.read_receipts.receipt_types();
+    if receipt_types.is_empty() {
+        return;
+    }
+    let thread = config.read_receipt_thread.receipt_thread();
     if let Some(room) = c.get_room(rid) {
-        tokio::spawn(async move {
-            room.send_single_receipt(ReceiptType::ReadPrivate, ReceiptThread::Main, eid)
-                .await
-                .unwrap();
+        sends.spawn(async move {
+            for receipt_type in receipt_types {
+                let res = with_retry(|| {
+                    room.send_single_receipt(receipt_type.clone(), thread.clone(), eid.clone())
+                });
+                if let Err(e) = res.await {
+                    tracing::error!("Cannot send read receipt: {:?}", e);
+                }
+            }
         });
     } else {
         tracing::error!("can't send read receipt, no joined room");
@@ -77,19 +204,28 @@ fn send_read_receipt(c: &Client, rid: &RoomId, eid: OwnedEventId) {
 
 #[derive(Default)]
 pub struct RoomSelectionHistory {
-    selections: Vec<OwnedRoomId>, // Ordered from least to most recent access via `select`
+    selections: Vec<RoomKey>, // Ordered from least to most recent access via `select`
     current: usize,
 }
 
 impl RoomSelectionHistory {
-    pub fn current(&self) -> Option<&RoomId> {
-        self.selections.get(self.current).map(|c| &**c)
+    pub fn current(&self) -> Option<&RoomKey> {
+        self.selections.get(self.current)
+    }
+
+    pub fn history(&self) -> &[RoomKey] {
+        &self.selections
+    }
+
+    /// Position of the room in the access history. Higher values mean more recent access.
+    pub fn recency(&self, key: &RoomKey) -> Option<usize> {
+        self.selections.iter().position(|c| c == key)
     }
 
     fn deselect(&mut self) {
         self.current = self.selections.len();
     }
-    fn select(&mut self, id: &RoomId) {
+    fn select(&mut self, id: &RoomKey) {
         let index = self
             .selections
             .iter()
@@ -127,6 +263,8 @@ impl Scrollable for RoomSelectionHistory {
 pub struct AuxLineState {
     states: HashMap<String, PromptLine>,
     current: String,
+    prompts: HashMap<String, String>,
+    hidden: HashSet<String>, // Aux lines whose content (e.g., passwords) should not be displayed
 }
 
 impl AuxLineState {
@@ -134,6 +272,8 @@ impl AuxLineState {
         let s = AuxLineState {
             states: HashMap::new(),
             current: "".to_string(),
+            prompts: HashMap::new(),
+            hidden: HashSet::new(),
         };
 
         s
@@ -153,6 +293,25 @@ impl AuxLineState {
     fn current_mut(&mut self) -> &mut PromptLine {
         self.states.get_mut(&self.current).unwrap()
     }
+
+    fn set_prompt(&mut self, prompt: String) {
+        self.current_mut().set_prompt(prompt.clone());
+        self.prompts.insert(self.current.clone(), prompt);
+    }
+
+    fn hide_current(&mut self) {
+        self.hidden.insert(self.current.clone());
+    }
+
+    fn masked_current(&self) -> Option<String> {
+        if self.hidden.contains(&self.current) {
+            let prompt = self.prompts.get(&self.current).map(|s| s.as_str());
+            let masked = "*".repeat(self.current().get().chars().count());
+            Some(format!("{}{}", prompt.unwrap_or(""), masked))
+        } else {
+            None
+        }
+    }
 }
 
 pub struct TuiState {
@@ -163,6 +322,22 @@ pub struct TuiState {
     aux_line_state: AuxLineState,
     previous_keys: Keys,
     last_error_message: Option<String>,
+    popup: Option<String>,
+    reaction_details: Option<(RoomKey, OwnedEventId)>,
+    device_manager: Option<devices::DeviceManager>,
+    emoji_picker: Option<emoji::EmojiPicker>,
+    pub mentions: mentions::Mentions,
+    show_mentions: bool,
+    show_members: bool,
+    bell_flash_until: Option<std::time::Instant>,
+    pub image_placeholders: bool,
+    pub auto_follow: bool,
+    pub composer_max_rows: usize,
+    pub room_filter_topic: bool,
+    pub room_filter_members: crate::config::RoomFilterMembers,
+    pub hidden_events: std::sync::Arc<crate::config::HiddenEvents>,
+    pub collapse_membership_events: bool,
+    pub room_pane_fraction: f64,
 }
 
 fn key_action_behavior<'a>(
@@ -204,15 +379,34 @@ fn key_action_behavior<'a>(
 }
 
 impl TuiState {
-    pub fn new(current_room: Option<&RoomId>) -> Self {
+    pub fn new(history: Vec<RoomKey>, current_room: Option<&RoomKey>) -> Self {
         let mut s = TuiState {
-            room_selection: RoomSelectionHistory::default(),
+            room_selection: RoomSelectionHistory {
+                selections: history,
+                current: 0,
+            },
             event_detail: EventDetail::default(),
             mode_stack: vec![Mode::default()],
             room_filter_line: LineEdit::new(),
             aux_line_state: AuxLineState::new(),
             previous_keys: Keys(Vec::new()),
             last_error_message: None,
+            popup: None,
+            reaction_details: None,
+            device_manager: None,
+            emoji_picker: None,
+            mentions: mentions::Mentions::default(),
+            show_mentions: false,
+            show_members: false,
+            bell_flash_until: None,
+            image_placeholders: false,
+            auto_follow: true,
+            composer_max_rows: 1,
+            room_filter_topic: false,
+            room_filter_members: crate::config::RoomFilterMembers::default(),
+            hidden_events: Default::default(),
+            collapse_membership_events: false,
+            room_pane_fraction: 0.25,
         };
         s.set_current_room(current_room);
         s
@@ -264,7 +458,7 @@ impl TuiState {
         );
         Ok(())
     }
-    fn set_current_room(&mut self, id: Option<&RoomId>) {
+    fn set_current_room(&mut self, id: Option<&RoomKey>) {
         if let Some(id) = id {
             self.room_selection.select(id);
         } else {
@@ -291,8 +485,24 @@ impl<F: Fn(Window, RenderingHints)> Widget for Foo<F> {
 fn msg_edit<'a>(
     room_state: &'a crate::tui_app::RoomState,
     potentially_active: bool,
+    max_rows: usize,
+    width: usize,
     tasks: Tasks<'a>,
 ) -> impl Widget + 'a {
+    // Long lines wrap, so they take up more than one row next to the "> " prompt.
+    let line_width = width.saturating_sub(2).max(1);
+    let rows = room_state
+        .tui
+        .msg_edit
+        .get(..)
+        .split('\n')
+        .map(|line| {
+            (text_width(line).raw_value() as usize)
+                .div_ceil(line_width)
+                .max(1)
+        })
+        .sum::<usize>()
+        .clamp(1, max_rows);
     let mut layout = VLayout::new();
     match &room_state.tui.msg_edit_type {
         SendMessageType::Reply(_, display_message) => {
@@ -327,61 +537,318 @@ fn msg_edit<'a>(
         }
         &SendMessageType::Simple => {}
     }
-    layout.widget(
-        HLayout::new().widget("> ").widget(
-            room_state
-                .tui
-                .msg_edit
-                .as_widget()
-                .cursor_blink_on(StyleModifier::new().underline(true))
-                .cursor_inactive(StyleModifier::new().invert(BoolModifyMode::Toggle))
-                .with_hints(move |h| h.active(h.active && potentially_active)),
-        ),
-    )
+    layout = layout.widget(
+        HLayout::new()
+            .widget("> ")
+            .widget(
+                room_state
+                    .tui
+                    .msg_edit
+                    .as_widget()
+                    .cursor_blink_on(StyleModifier::new().underline(true))
+                    .cursor_inactive(StyleModifier::new().invert(BoolModifyMode::Toggle))
+                    .with_hints(move |h| h.active(h.active && potentially_active)),
+            )
+            // Grow with the number of lines in the draft
+            .with_demand(move |d| Demand2D {
+                width: d.width,
+                height: RowDemand::exact(rows),
+            }),
+    );
+    let draft = room_state.tui.msg_edit.get(..);
+    if potentially_active && !draft.is_empty() {
+        let bytes = actions::message_size(draft);
+        let mut size = format!(
+            "{} characters, {}/{} bytes",
+            draft.chars().count(),
+            bytes,
+            actions::MAX_MESSAGE_SIZE
+        );
+        if bytes > actions::MAX_MESSAGE_SIZE {
+            size.push_str(" ⚠ too large, send it in parts with :send_message_split");
+        }
+        layout = layout.widget(size);
+    }
+    layout
 }
 
-fn bottom_bar<'a>(tui_state: &'a TuiState) -> impl Widget + 'a {
+/// Draws the wrapped widget in inverted colors while `active`.
+struct Flash<W>(W, bool);
+
+impl<W: Widget> Widget for Flash<W> {
+    fn space_demand(&self) -> Demand2D {
+        self.0.space_demand()
+    }
+
+    fn draw(&self, mut window: Window, hints: RenderingHints) {
+        if self.1 {
+            window.set_default_style(StyleModifier::new().invert(true).apply_to_default());
+            window.clear();
+        }
+        self.0.draw(window, hints)
+    }
+}
+
+fn bottom_bar<'a>(state: &'a State) -> impl Widget + 'a {
+    let tui_state = &state.tui;
     let spacer = " ".with_demand(|_| Demand2D {
         width: ColDemand::at_least(0),
         height: RowDemand::exact(1),
     });
     let mut hlayout = HLayout::new().separator(GraphemeCluster::try_from(' ').unwrap());
 
+    let multiple_accounts = state.accounts.len() > 1;
+    if multiple_accounts {
+        hlayout = hlayout.widget(rooms::AccountIndicator(state.current_account_index()));
+        hlayout = hlayout.widget(state.current_account().user_id().as_str());
+    }
+
+    for account in &state.accounts {
+        // Only name the account if it is not clear which one is meant.
+        let prefix = if multiple_accounts {
+            format!("{}: ", account.user_id())
+        } else {
+            String::new()
+        };
+        if let Some(retry_at) = account.sync_retry_at {
+            let remaining = retry_at.saturating_duration_since(std::time::Instant::now());
+            hlayout = hlayout.widget(format!(
+                "⚠ {}offline (showing cached data), retrying in {}s",
+                prefix,
+                remaining.as_secs()
+            ));
+        }
+
+        match account.session_status {
+            SessionStatus::Valid => {}
+            SessionStatus::SoftLoggedOut => {
+                hlayout = hlayout.widget(format!(
+                    "⚠ {}session expired, log in again with :relogin",
+                    prefix
+                ))
+            }
+            SessionStatus::LoggedOut => {
+                hlayout = hlayout.widget(format!(
+                    "⚠ {}logged out by the server, restart to log in again",
+                    prefix
+                ))
+            }
+        }
+    }
+
+    if state.do_not_disturb {
+        hlayout = hlayout.widget("🔕 do not disturb");
+    } else if let Some(remaining) = state.snooze_remaining() {
+        hlayout = hlayout.widget(format!(
+            "💤 notifications snoozed for {}m",
+            (remaining.as_secs() + 59) / 60
+        ));
+    }
+
+    let num_mentions = tui_state.mentions.len();
+    if num_mentions > 0 && !tui_state.show_mentions {
+        hlayout = hlayout.widget(format!(
+            "@ {} mention(s), show them with :show_mentions",
+            num_mentions
+        ));
+    }
+
+    let num_invites: usize = state
+        .accounts
+        .iter()
+        .map(|account| account.client.invited_rooms().len())
+        .sum();
+    if num_invites > 0 {
+        hlayout = hlayout.widget(format!(
+            "✉ {} invite(s), show them with :list_room_invitations",
+            num_invites
+        ));
+    }
+
+    let num_knocks = state
+        .knocks
+        .values()
+        .filter(|status| **status == KnockStatus::Pending)
+        .count();
+    if num_knocks > 0 {
+        hlayout = hlayout.widget(format!(
+            "✋ {} pending knock(s), show them with :list_knocks",
+            num_knocks
+        ));
+    }
+
+    let num_rate_limited = retry::num_rate_limited();
+    if num_rate_limited > 0 {
+        hlayout = hlayout.widget(format!("⏳ {} request(s) rate limited", num_rate_limited));
+    }
+
     if let Some(msg) = &tui_state.last_error_message {
         hlayout = hlayout.widget(msg)
     } else if matches!(
         tui_state.current_mode().builtin_mode(),
         BuiltinMode::Command
     ) {
-        hlayout = hlayout.widget(tui_state.aux_line_state.current().as_widget())
+        if let Some(masked) = tui_state.aux_line_state.masked_current() {
+            hlayout = hlayout.widget(masked)
+        } else {
+            hlayout = hlayout.widget(tui_state.aux_line_state.current().as_widget())
+        }
     }
 
     hlayout = hlayout
         .widget(spacer)
         .widget(tui_state.current_mode().to_string())
         .widget(format!("{}", tui_state.previous_keys));
-    hlayout
+    let flash = tui_state
+        .bell_flash_until
+        .map(|until| std::time::Instant::now() < until)
+        .unwrap_or(false);
+    Flash(hlayout, flash)
+}
+
+fn verification_panel<'a>(flow: &'a VerificationFlow) -> impl Widget + 'a {
+    let other_user = flow.request.other_user_id();
+    let other = match &flow.other_device {
+        Some(device) => format!("{} ({})", other_user, device),
+        None => other_user.to_string(),
+    };
+    let mut layout = VLayout::new();
+    match &flow.stage {
+        Stage::Requested => {
+            layout = layout
+                .widget(format!("Verification request from {}", other))
+                .widget("Accept with <C-y>, decline with <C-x>");
+        }
+        Stage::Waiting => {
+            layout = layout
+                .widget(format!(
+                    "Verifying {}: Waiting for the other side...",
+                    other
+                ))
+                .widget("Cancel with <C-x>");
+        }
+        Stage::Comparing => {
+            let sas = flow.sas.as_ref().unwrap();
+            layout = layout.widget(format!(
+                "Verifying {}: Do the following match the other device?",
+                other
+            ));
+            if let Some(emoji) = sas.emoji() {
+                let mut line = String::new();
+                for e in emoji.iter() {
+                    line.push_str(&format!("{} ({}) ", e.symbol, e.description));
+                }
+                layout = layout.widget(line);
+            }
+            if let Some((n1, n2, n3)) = sas.decimals() {
+                layout = layout.widget(format!("{}-{}-{}", n1, n2, n3));
+            }
+            layout = layout.widget("Confirm with <C-y>, reject with <C-x>");
+        }
+        Stage::Finished(msg) => {
+            layout = layout.widget(msg.as_str()).widget("Dismiss with <C-x>");
+        }
+    }
+    layout
 }
 
-fn tui<'a>(state: &'a State, tasks: Tasks<'a>) -> impl Widget + 'a {
+fn tui<'a>(state: &'a State, tasks: Tasks<'a>, width: Width) -> impl Widget + 'a {
     let mut hlayout = HLayout::new()
         .separator(GraphemeCluster::try_from('│').unwrap())
-        .widget_weighted(rooms::Rooms(state).as_widget(), 0.25);
+        .widget_weighted(
+            rooms::Rooms(state, tasks.room_list).as_widget(),
+            state.tui.room_pane_fraction,
+        );
     if let Some(room) = state.current_room_state() {
+        let mut room_layout = VLayout::new();
+        if let Some(successor) = room.successor() {
+            let name = state
+                .rooms
+                .get(&RoomKey::new(room.account(), successor.to_owned()))
+                .map(|r| r.name().to_owned())
+                .unwrap_or_else(|| successor.to_string());
+            room_layout = room_layout.widget(format!(
+                "⚠ This room has been replaced by {}, switch to it with :follow_upgrade",
+                name
+            ));
+        }
+        if room.is_read_only() {
+            room_layout = room_layout.widget(
+                "🔒 You are not a member of this room anymore, rejoin it with :rejoin_room",
+            );
+        }
+        room_layout = room_layout.widget(messages::Messages(state, tasks));
+        let num_unseen = room.tui.num_unseen();
+        if num_unseen > 0 {
+            room_layout = room_layout.widget(format!(
+                "↓ {} new message(s), jump to present with :scroll_to_end",
+                num_unseen
+            ));
+        }
+        let mut room_fraction = 1.0 - state.tui.room_pane_fraction;
+        let mut separators = 1;
+        if state.tui.show_members {
+            room_fraction *= 1.0 - members::MEMBER_PANE_FRACTION;
+            separators += 1;
+        }
         hlayout = hlayout.widget_weighted(
-            VLayout::new()
-                .widget(messages::Messages(state, tasks))
-                .widget(msg_edit(
-                    room,
-                    matches!(state.tui.current_mode().builtin_mode(), BuiltinMode::Insert),
-                    tasks,
-                )),
-            0.75,
-        )
+            room_layout.widget(msg_edit(
+                room,
+                matches!(state.tui.current_mode().builtin_mode(), BuiltinMode::Insert),
+                state.tui.composer_max_rows,
+                // The room pane gets its share of the width that is left next to the separators.
+                ((width.raw_value() - separators).max(0) as f64 * room_fraction) as usize,
+                tasks,
+            )),
+            room_fraction,
+        );
+        if state.tui.show_members {
+            hlayout = hlayout.widget_weighted(
+                members::member_pane(state, room),
+                1.0 - state.tui.room_pane_fraction - room_fraction,
+            );
+        }
+    }
+    let mut vlayout = VLayout::new().widget(hlayout);
+    if let Some(popup) = &state.tui.popup {
+        vlayout = vlayout.separator(GraphemeCluster::try_from('─').unwrap());
+        let mut lines = VLayout::new();
+        for line in popup.lines() {
+            lines = lines.widget(line);
+        }
+        vlayout = vlayout.widget(lines.widget("Dismiss with <Esc>"));
+    }
+    if let Some((room, event_id)) = &state.tui.reaction_details {
+        if let Some(room_state) = state.rooms.get(room) {
+            vlayout = vlayout.separator(GraphemeCluster::try_from('─').unwrap());
+            vlayout = vlayout.widget(
+                VLayout::new()
+                    .widget(messages::ReactionDetails {
+                        room_state,
+                        event_id,
+                    })
+                    .widget("Dismiss with <Esc>"),
+            );
+        }
     }
-    VLayout::new()
-        .widget(hlayout)
-        .widget(bottom_bar(&state.tui))
+    if let Some(manager) = &state.tui.device_manager {
+        vlayout = vlayout.separator(GraphemeCluster::try_from('─').unwrap());
+        vlayout = vlayout.widget(devices::device_manager_panel(manager));
+    }
+    if let Some(picker) = &state.tui.emoji_picker {
+        let query = state.tui.aux_line_state.current().get();
+        vlayout = vlayout.separator(GraphemeCluster::try_from('─').unwrap());
+        vlayout = vlayout.widget(emoji::emoji_picker_panel(picker, query));
+    }
+    if state.tui.show_mentions {
+        vlayout = vlayout.separator(GraphemeCluster::try_from('─').unwrap());
+        vlayout = vlayout.widget(mentions::mentions_panel(&state.tui.mentions));
+    }
+    if let Some(flow) = &state.verification {
+        vlayout = vlayout.separator(GraphemeCluster::try_from('─').unwrap());
+        vlayout = vlayout.widget(verification_panel(flow));
+    }
+    vlayout.widget(bottom_bar(state))
 }
 
 #[derive(Debug)]
@@ -390,6 +857,21 @@ pub enum Event {
     Input(Input),
     Signal(signal::Signal),
     Bell,
+    FocusRoom(RoomKey),
+    // Account index, room that may not be synced yet and the event to select in it
+    FocusJoinedRoom(usize, OwnedRoomId, Option<OwnedEventId>),
+    Highlight,
+    ReadDelayElapsed,
+    ShowPopup(String),
+    Hook(Hook, HookData),
+    ProcessFinished(Callback, ProcessOutput),
+    Devices(Result<Vec<devices::DeviceInfo>, String>),
+    // Account index and result of logging in again after the session expired
+    Relogin(usize, Result<(), String>),
+    // To be shown after running the on_notification hooks
+    Notification(DesktopNotification),
+    // Room and transaction id of a local echo and the id of the sent event
+    Sent(RoomKey, OwnedTransactionId, Result<OwnedEventId, String>),
 }
 
 #[derive(Debug)]
@@ -407,33 +889,113 @@ pub enum SendMessageType {
 
 #[derive(Clone)]
 pub struct MessageQueryRequest {
-    pub room: OwnedRoomId,
+    pub room: RoomKey,
     pub kind: MessageQuery,
 }
 
 pub async fn run_tui(
     mut events: mpsc::Receiver<Event>,
+    event_sink: mpsc::Sender<Event>,
     message_query_sink: watch::Sender<Option<MessageQueryRequest>>,
     state: Arc<Mutex<State>>,
-    client: Client,
     command_environment: CommandEnvironment,
     config: Config,
+    shutdown: impl std::future::Future<Output = ()>,
 ) {
     let stdout = stdout();
     let mut term = Terminal::new(stdout.lock()).unwrap();
 
+    let track_focus = config.urgency_hint || config.urgency_hint_command.is_some();
+    if track_focus {
+        write_to_terminal(ENABLE_FOCUS_REPORTING);
+    }
+    let mut focused = true;
+    let mut read_delay = ReadDelay::new();
+
     let mut run = true;
 
     let message_query = RefCell::new(None);
+    let formatter_cache = formatter::FormatterCache::default();
+    let room_list_cache = rooms::RoomListCache::default();
+    let membership_runs = messages::MembershipRunCache::default();
 
     let tasks = Tasks {
         message_query: &message_query,
+        formatter: config
+            .message_formatter
+            .as_deref()
+            .map(|f| formatter::MessageFormatter::new(&command_environment, f, &formatter_cache)),
+        room_list: rooms::RoomListHooks::new(&command_environment, &config, &room_list_cache),
+        membership_runs: &membership_runs,
     };
+    let mut pending_hooks = vec![(Hook::Startup, HookData::new())];
+    {
+        let mut state = state.lock().await;
+        state.tui.image_placeholders = config.image_placeholders;
+        state.tui.auto_follow = config.auto_follow;
+        state.tui.composer_max_rows = config.composer_max_rows;
+        state.tui.room_filter_topic = config.room_filter_topic;
+        state.tui.room_filter_members = config.room_filter_members;
+        state.tui.hidden_events = config.hidden_events.clone();
+        state.tui.collapse_membership_events = config.collapse_membership_events;
+        state.tui.room_pane_fraction = config.room_pane_fraction;
+        let client = state.current_client();
+        let mut c = actions::CommandContext {
+            state: &mut state,
+            client: &client,
+            tasks,
+            continue_running: &mut run,
+            config: &config,
+            command_environment: &command_environment,
+            events: &event_sink,
+        };
+        actions::enter_start_mode(&mut c);
+    }
+    let mut pending_callbacks = Vec::new();
+    let mut pending_notifications = Vec::new();
+    let mut last_room = None;
     while run {
         {
-            let state = state.lock().await;
+            let mut state = state.lock().await;
+            if let Some(room) = state.current_room_state() {
+                if last_room.as_ref() != Some(&room.id) {
+                    last_room = Some(room.id.clone());
+                    pending_hooks.push((
+                        Hook::RoomChange,
+                        vec![
+                            ("room_id", room.id.to_string()),
+                            ("name", room.name().to_owned()),
+                        ],
+                    ));
+                }
+            }
+            if !pending_hooks.is_empty()
+                || !pending_callbacks.is_empty()
+                || !pending_notifications.is_empty()
+            {
+                let client = state.current_client();
+                let mut c = actions::CommandContext {
+                    state: &mut state,
+                    client: &client,
+                    tasks,
+                    continue_running: &mut run,
+                    config: &config,
+                    command_environment: &command_environment,
+                    events: &event_sink,
+                };
+                for (hook, data) in pending_hooks.drain(..) {
+                    actions::run_hooks(hook, &data, &mut c);
+                }
+                for (callback, output) in pending_callbacks.drain(..) {
+                    actions::run_callback(callback, output, &mut c);
+                }
+                for notification in pending_notifications.drain(..) {
+                    actions::show_notification(notification, &mut c);
+                }
+            }
             let win = term.create_root_window();
-            tui(&state, tasks).draw(win, RenderingHints::new().active(true));
+            let width = win.get_width();
+            tui(&state, tasks, width).draw(win, RenderingHints::new().active(true));
         }
         term.present();
 
@@ -462,7 +1024,105 @@ pub async fn run_tui(
             };
             match event.unwrap() {
                 Event::Update => {}
+                Event::Bell if config.visual_bell => {
+                    state.lock().await.tui.bell_flash_until =
+                        Some(std::time::Instant::now() + VISUAL_BELL_DURATION);
+                    // Redraw once the flash is over
+                    let sink = event_sink.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(VISUAL_BELL_DURATION).await;
+                        let _ = sink.send(Event::Update).await;
+                    });
+                }
                 Event::Bell => term.emit_bell(),
+                Event::Highlight if !focused => {
+                    if config.urgency_hint {
+                        write_to_terminal(URGENCY_HINT);
+                    }
+                    if let Some(cmd) = &config.urgency_hint_command {
+                        run_urgency_hint_command(cmd);
+                    }
+                }
+                Event::Highlight => {}
+                Event::ReadDelayElapsed => {
+                    let mut state = state.lock().await;
+                    mark_current_room_as_read(&mut state, &config, &mut read_delay, &event_sink);
+                }
+                Event::FocusRoom(key) => {
+                    let mut state = state.lock().await;
+                    if state.rooms.contains_key(&key) {
+                        state.tui.set_current_room(Some(&key));
+                    }
+                }
+                Event::FocusJoinedRoom(account, id, event) => {
+                    let mut state = state.lock().await;
+                    let key = RoomKey::new(account, id);
+                    if !state.rooms.contains_key(&key) {
+                        let client = state.accounts[account].client.clone();
+                        if let Some(room) = client.get_room(&key.id) {
+                            let room_state = crate::tui_app::RoomState::from_room(
+                                &room,
+                                account,
+                                &state.display_names,
+                            )
+                            .await;
+                            state.rooms.insert(key.clone(), room_state);
+                        }
+                    }
+                    let state = &mut *state;
+                    if let Some(room) = state.rooms.get_mut(&key) {
+                        if let Some(event) = event {
+                            room.tui.selection = MessageSelection::Specific(event);
+                        }
+                        state.tui.set_current_room(Some(&key));
+                    }
+                }
+                Event::ShowPopup(text) => {
+                    state.lock().await.tui.popup = Some(text);
+                }
+                Event::Hook(hook, data) => pending_hooks.push((hook, data)),
+                Event::Notification(n) => pending_notifications.push(n),
+                Event::Devices(result) => {
+                    let mut state = state.lock().await;
+                    let tui = &mut state.tui;
+                    if let Some(manager) = &mut tui.device_manager {
+                        match result {
+                            Ok(devices) => manager.set_devices(devices),
+                            Err(e) => {
+                                manager.loading_failed();
+                                tui.last_error_message = Some(e);
+                            }
+                        }
+                    }
+                }
+                Event::Sent(room, txn_id, result) => {
+                    if let Some(room) = state.lock().await.rooms.get_mut(&room) {
+                        room.handle_send_result(&txn_id, result);
+                    }
+                }
+                Event::Relogin(account, result) => {
+                    let mut state = state.lock().await;
+                    match result {
+                        Ok(()) => {
+                            let account = &mut state.accounts[account];
+                            account.session_status = SessionStatus::Valid;
+                            // Wakes up the sync loop if it is waiting to retry.
+                            account.sync_retry_at = None;
+                        }
+                        Err(e) => {
+                            // Asks for the password again, the prompt shows the error.
+                            let data = vec![
+                                ("account", state.accounts[account].user_id().to_string()),
+                                ("soft_logout", "true".to_owned()),
+                                ("error", e),
+                            ];
+                            pending_hooks.push((Hook::SessionExpired, data));
+                        }
+                    }
+                }
+                Event::ProcessFinished(callback, output) => {
+                    pending_callbacks.push((callback, output))
+                }
                 Event::Signal(signal::Signal::SIGWINCH) => { /* Just redraw the window */ }
                 Event::Signal(signal::Signal::SIGTSTP) => {
                     if let Err(e) = term.handle_sigtstp() {
@@ -474,11 +1134,19 @@ pub async fn run_tui(
                     tracing::warn!("Unhandled signal {}", s);
                 }
                 Event::Input(input) => {
+                    if let unsegen::input::Event::Unsupported(seq) = &input.event {
+                        if seq.as_slice() == FOCUS_IN || seq.as_slice() == FOCUS_OUT {
+                            focused = seq.as_slice() == FOCUS_IN;
+                            continue;
+                        }
+                    }
                     let sig_behavior = unsegen_signals::SignalBehavior::new()
                         .on_default::<unsegen_signals::SIGTSTP>();
                     let input = input.chain(sig_behavior);
 
                     let mut state = state.lock().await;
+                    state.register_input();
+                    let client = state.current_client();
 
                     let mut c = actions::CommandContext {
                         state: &mut state,
@@ -487,6 +1155,7 @@ pub async fn run_tui(
                         continue_running: &mut run,
                         config: &config,
                         command_environment: &command_environment,
+                        events: &event_sink,
                     };
                     let input = input.chain(key_action_behavior(&mut c));
                     match state.tui.current_mode().builtin_mode() {
@@ -520,15 +1189,44 @@ pub async fn run_tui(
                         }
                     };
 
-                    if let Some(room) = state.current_room_state_mut() {
-                        if let Some(read_event_id) = room.mark_newest_event_as_read() {
-                            send_read_receipt(&client, &room.id, read_event_id);
-                        }
-                    }
+                    mark_current_room_as_read(&mut state, &config, &mut read_delay, &event_sink);
                 }
             }
         }
     }
+    // The terminal is restored only afterwards, when it is dropped.
+    shutdown.await;
+    if track_focus {
+        write_to_terminal(DISABLE_FOCUS_REPORTING);
+    }
+}
+
+fn write_to_terminal(sequence: &str) {
+    use std::io::Write;
+    let mut stdout = stdout();
+    if let Err(e) = stdout
+        .write_all(sequence.as_bytes())
+        .and_then(|_| stdout.flush())
+    {
+        tracing::warn!("Unable to write to terminal: {}", e);
+    }
+}
+
+fn run_urgency_hint_command(cmd: &str) {
+    match tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .spawn()
+    {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                if let Err(e) = child.wait().await {
+                    tracing::error!("Failed to wait for urgency hint command: {}", e);
+                }
+            });
+        }
+        Err(e) => tracing::error!("Failed to run urgency hint command: {}", e),
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
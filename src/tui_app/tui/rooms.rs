@@ -1,49 +1,238 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::fmt::Write;
+use std::hash::{Hash, Hasher};
 use unsegen::base::*;
 use unsegen::input::{OperationResult, Scrollable};
 use unsegen::widget::*;
 
-use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::presence::PresenceState;
 
+use crate::config::{Config, RoomFilterMembers};
+use crate::tui_app::tui::actions::CommandEnvironment;
 use crate::tui_app::tui::BuiltinMode;
-use crate::tui_app::State;
+use crate::tui_app::{RoomKey, RoomState, State};
 
+/// The last result of the room list functions together with a hash of what they were given, so
+/// that they only run again once the list changes, and the last error, which is logged once.
+#[derive(Default)]
+pub struct RoomListCache {
+    result: RefCell<Option<(u64, Vec<RoomKey>)>>,
+    last_error: RefCell<Option<String>>,
+}
+
+/// The room list functions of the config, which decide which rooms are shown and in which order.
 #[derive(Copy, Clone)]
-pub struct Rooms<'a>(pub &'a State);
+pub struct RoomListHooks<'a> {
+    environment: &'a CommandEnvironment,
+    config: &'a Config,
+    cache: &'a RoomListCache,
+}
+
+impl<'a> RoomListHooks<'a> {
+    pub fn new(
+        environment: &'a CommandEnvironment,
+        config: &'a Config,
+        cache: &'a RoomListCache,
+    ) -> Self {
+        RoomListHooks {
+            environment,
+            config,
+            cache,
+        }
+    }
+
+    /// Filter and sort `rooms` using `room_list_filter` and `room_list_sort`. The rooms are left as
+    /// they are if one of the functions fails.
+    fn apply<'r>(
+        self,
+        state: &State,
+        rooms: Vec<(&'r RoomKey, &'r RoomState)>,
+    ) -> Vec<(&'r RoomKey, &'r RoomState)> {
+        let filter = self.config.room_list_filter.as_deref();
+        let sort = self.config.room_list_sort.as_deref();
+        if filter.is_none() && sort.is_none() {
+            return rooms;
+        }
+        let input = hash_room_tables(state, &rooms, self.config);
+        if let Some((hash, keys)) = &*self.cache.result.borrow() {
+            if *hash == input {
+                let by_key = rooms.iter().copied().collect::<BTreeMap<_, _>>();
+                return keys
+                    .iter()
+                    .filter_map(|k| by_key.get_key_value(k).map(|(k, r)| (*k, *r)))
+                    .collect();
+            }
+        }
+        let rooms = self.run(state, filter, sort, rooms);
+        let keys = rooms.iter().map(|(k, _)| (*k).clone()).collect();
+        *self.cache.result.borrow_mut() = Some((input, keys));
+        rooms
+    }
+
+    fn run<'r>(
+        self,
+        state: &State,
+        filter: Option<&rlua::RegistryKey>,
+        sort: Option<&rlua::RegistryKey>,
+        rooms: Vec<(&'r RoomKey, &'r RoomState)>,
+    ) -> Vec<(&'r RoomKey, &'r RoomState)> {
+        let res = self.environment.lua.context(|lua_ctx| {
+            let filter = filter
+                .map(|f| lua_ctx.registry_value::<rlua::Function>(f))
+                .transpose()?;
+            // Rooms are sorted in Lua, so we have to map the tables back to the rooms afterwards.
+            let list = lua_ctx.create_table()?;
+            let indices = lua_ctx.create_table()?;
+            for (i, (_, r)) in rooms.iter().enumerate() {
+                let table = room_table(&lua_ctx, state, r, self.config)?;
+                if let Some(f) = &filter {
+                    if !f.call::<_, bool>(table.clone())? {
+                        continue;
+                    }
+                }
+                indices.set(table.clone(), i)?;
+                list.set(list.raw_len() + 1, table)?;
+            }
+            if let Some(sort) = sort {
+                let f: rlua::Function = lua_ctx.registry_value(sort)?;
+                let table_sort: rlua::Function = lua_ctx
+                    .globals()
+                    .get::<_, rlua::Table>("table")?
+                    .get("sort")?;
+                table_sort.call::<_, ()>((list.clone(), f))?;
+            }
+            list.sequence_values::<rlua::Table>()
+                .map(|table| Ok(rooms[indices.get::<_, usize>(table?)?]))
+                .collect::<rlua::Result<Vec<_>>>()
+        });
+        let mut last_error = self.cache.last_error.borrow_mut();
+        match res {
+            Ok(sorted) => {
+                *last_error = None;
+                sorted
+            }
+            Err(e) => {
+                let e = e.to_string();
+                if last_error.as_ref() != Some(&e) {
+                    tracing::error!("Failed to apply the room list functions: {}", e);
+                    *last_error = Some(e);
+                }
+                rooms
+            }
+        }
+    }
+}
+
+/// A hash of what `room_table` passes to the room list functions for each of `rooms`.
+fn hash_room_tables(state: &State, rooms: &[(&RoomKey, &RoomState)], config: &Config) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (key, room) in rooms {
+        key.account.hash(&mut hasher);
+        key.id.hash(&mut hasher);
+        room.name().hash(&mut hasher);
+        room.topic().hash(&mut hasher);
+        room.num_unread_notifications().hash(&mut hasher);
+        room.last_activity().hash(&mut hasher);
+        room.tags().hash(&mut hasher);
+        room.direct_target().hash(&mut hasher);
+        room.notification_level(config).name().hash(&mut hasher);
+        state
+            .accounts
+            .get(room.account())
+            .map(|a| a.user_id())
+            .hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// The room as passed to the room list functions: Its id, name, topic, unread, last_activity (ms),
+/// tags, direct_target, notification_level and account.
+fn room_table<'lua>(
+    lua_ctx: &rlua::Context<'lua>,
+    state: &State,
+    room: &RoomState,
+    config: &Config,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    table.set("id", room.id.as_str())?;
+    table.set("name", room.name())?;
+    table.set("topic", room.topic())?;
+    table.set("unread", room.num_unread_notifications())?;
+    table.set("last_activity", room.last_activity())?;
+    table.set(
+        "tags",
+        lua_ctx.create_sequence_from(room.tags().iter().cloned())?,
+    )?;
+    table.set("direct_target", room.direct_target().map(|u| u.as_str()))?;
+    table.set("notification_level", room.notification_level(config).name())?;
+    if let Some(account) = state.accounts.get(room.account()) {
+        table.set("account", account.user_id().as_str())?;
+    }
+    Ok(table)
+}
+
+#[derive(Copy, Clone)]
+pub struct Rooms<'a>(pub &'a State, pub RoomListHooks<'a>);
 
 impl<'a> Rooms<'a> {
     fn all_rooms<'r>(
         self,
-    ) -> impl DoubleEndedIterator<Item = (&'a OwnedRoomId, &'a crate::tui_app::RoomState)> + 'a
-    {
+    ) -> impl DoubleEndedIterator<Item = (&'a RoomKey, &'a crate::tui_app::RoomState)> + 'a {
         self.0.rooms.iter()
     }
     fn active_rooms(
         self,
-    ) -> impl DoubleEndedIterator<Item = (&'a OwnedRoomId, &'a crate::tui_app::RoomState)> {
+    ) -> impl DoubleEndedIterator<Item = (&'a RoomKey, &'a crate::tui_app::RoomState)> {
         let s = self.0.tui.room_filter_line.get();
         let s_lower = s.to_lowercase();
         let mixed = s != s_lower;
+        let matches_filter = move |text: &str| {
+            if mixed {
+                text.contains(s)
+            } else {
+                text.to_lowercase().contains(&s_lower)
+            }
+        };
         let rooms = self.all_rooms();
         let only_with_unread = matches!(
             self.0.tui.current_mode().builtin_mode(),
             BuiltinMode::RoomFilterUnread
         );
-        rooms.filter(move |(_i, r)| {
-            let passes_filter_string = if mixed {
-                r.name().contains(s)
-            } else {
-                r.name().to_lowercase().contains(&s_lower)
-            };
-            let passes_unread_filter = !(only_with_unread && !r.has_unread());
-            passes_filter_string && passes_unread_filter
-        })
+        let search_topic = self.0.tui.room_filter_topic && !s.is_empty();
+        let member_filter = self.0.tui.room_filter_members;
+        let search_members = member_filter != RoomFilterMembers::Disabled && !s.is_empty();
+        let accounts = &self.0.accounts;
+        // Our own name would match in every room.
+        let has_matching_member = |r: &RoomState| {
+            r.user_colors.keys().any(|user_id| {
+                !accounts.iter().any(|a| a.user_id() == user_id)
+                    && r.display_name(user_id)
+                        .map_or(false, |n| matches_filter(&n))
+            })
+        };
+        let rooms = rooms
+            .filter(|(_, r)| {
+                let passes_filter_string = matches_filter(r.name())
+                    || (search_topic && r.topic().map_or(false, |t| matches_filter(t)))
+                    || (search_members
+                        && member_filter.applies_to(r.direct_target().is_some())
+                        && has_matching_member(*r));
+                let passes_unread_filter = !(only_with_unread && !r.has_unread());
+                passes_filter_string && passes_unread_filter
+            })
+            .collect();
+        let mut rooms = self.1.apply(self.0, rooms);
+        // Archived rooms come last, in a section of their own.
+        rooms.sort_by_key(|(_, r)| r.is_read_only());
+        rooms.into_iter()
     }
     pub fn active_contains_current(&self) -> bool {
         if let Some(current) = &self.0.tui.room_selection.current() {
             self.active_rooms()
                 .into_iter()
-                .find(|(id, _)| **id == *current)
+                .find(|(key, _)| *key == *current)
                 .is_some()
         } else {
             false
@@ -61,21 +250,33 @@ impl<'a> Rooms<'a> {
                     .widget(self.0.tui.room_filter_line.as_widget()),
             );
         };
+        let multiple_accounts = self.0.accounts.len() > 1;
+        let mut archived = false;
         for (id, r) in self.active_rooms().into_iter() {
+            if r.is_read_only() && !archived {
+                archived = true;
+                layout = layout.widget("── Archived ──");
+            }
             layout = layout.widget(RoomSummary {
                 state: r,
                 current: self.0.tui.room_selection.current() == Some(id),
+                presence: r.direct_target().and_then(|u| self.0.presence(u)),
+                account: if multiple_accounts {
+                    Some(r.account())
+                } else {
+                    None
+                },
             });
         }
         layout
     }
 }
 
-pub struct RoomsMut<'a>(pub &'a mut State);
+pub struct RoomsMut<'a>(pub &'a mut State, pub RoomListHooks<'a>);
 
 impl RoomsMut<'_> {
     pub fn as_rooms<'b>(&'b self) -> Rooms<'b> {
-        Rooms(self.0)
+        Rooms(self.0, self.1)
     }
 }
 impl Scrollable for RoomsMut<'_> {
@@ -86,19 +287,19 @@ impl Scrollable for RoomsMut<'_> {
                 .active_rooms()
                 .into_iter()
                 .rev()
-                .skip_while(|(id, _)| **id != current);
+                .skip_while(|(key, _)| *key != current);
             it.next();
             Some(
                 it.next()
                     .or(self.as_rooms().active_rooms().into_iter().rev().next())
-                    .map(|(k, _)| &**k)
+                    .map(|(k, _)| k)
                     .unwrap_or(current),
             )
         } else {
-            self.0.rooms.keys().rev().next().map(|k| &**k)
+            self.0.rooms.keys().rev().next()
         }
-        .map(|r| r.to_owned());
-        self.0.tui.set_current_room(new_current_room.as_deref());
+        .cloned();
+        self.0.tui.set_current_room(new_current_room.as_ref());
         Ok(())
     }
 
@@ -108,19 +309,19 @@ impl Scrollable for RoomsMut<'_> {
             let mut it = rooms
                 .active_rooms()
                 .into_iter()
-                .skip_while(|(id, _)| **id != current);
+                .skip_while(|(key, _)| *key != current);
             it.next();
             Some(
                 it.next()
                     .or(self.as_rooms().active_rooms().into_iter().next())
-                    .map(|(k, _)| &**k)
+                    .map(|(k, _)| k)
                     .unwrap_or(current),
             )
         } else {
-            self.0.rooms.keys().next().map(|k| &**k)
+            self.0.rooms.keys().next()
         }
-        .map(|r| r.to_owned());
-        self.0.tui.set_current_room(new_current_room.as_deref());
+        .cloned();
+        self.0.tui.set_current_room(new_current_room.as_ref());
         Ok(())
     }
 }
@@ -128,11 +329,69 @@ impl Scrollable for RoomsMut<'_> {
 struct RoomSummary<'a> {
     state: &'a crate::tui_app::RoomState,
     current: bool,
+    presence: Option<&'a PresenceState>, // Of the other user in a direct chat
+    account: Option<usize>,              // Only shown if there are multiple accounts
+}
+
+pub const PRESENCE_SYMBOL: &str = "● ";
+
+const ACCOUNT_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Green,
+    Color::Blue,
+    Color::Yellow,
+    Color::Red,
+];
+
+fn account_label(account: usize) -> String {
+    format!("[{}] ", account + 1)
+}
+
+/// Marks rooms (and the status bar) with the account they belong to.
+pub struct AccountIndicator(pub usize);
+
+impl AccountIndicator {
+    fn write(&self, c: &mut Cursor) {
+        let mut c = c.save().style_modifier();
+        c.apply_style_modifier(
+            StyleModifier::new().fg_color(ACCOUNT_COLORS[self.0 % ACCOUNT_COLORS.len()]),
+        );
+        c.write(&account_label(self.0));
+    }
+}
+
+impl Widget for AccountIndicator {
+    fn space_demand(&self) -> Demand2D {
+        Demand2D {
+            width: ColDemand::exact(text_width(&account_label(self.0))),
+            height: RowDemand::exact(1),
+        }
+    }
+
+    fn draw(&self, mut window: Window, _hints: RenderingHints) {
+        let mut c = Cursor::new(&mut window);
+        self.write(&mut c);
+    }
+}
+
+pub fn presence_color(presence: &PresenceState) -> Color {
+    match presence {
+        PresenceState::Online => Color::Green,
+        PresenceState::Unavailable => Color::Yellow,
+        _ => Color::Default,
+    }
 }
 
 impl Widget for RoomSummary<'_> {
     fn space_demand(&self) -> Demand2D {
         let mut w = text_width(self.state.name());
+        if self.state.direct_target().is_some() {
+            w += text_width(PRESENCE_SYMBOL);
+        }
+        if let Some(account) = self.account {
+            w += text_width(&account_label(account));
+        }
         let h = Height::new(1).unwrap();
         if self.state.has_unread() {
             w += text_width(&format!(" {}", self.state.num_unread_notifications()));
@@ -155,6 +414,16 @@ impl Widget for RoomSummary<'_> {
         }
         c.set_style_modifier(style);
 
+        if let Some(account) = self.account {
+            AccountIndicator(account).write(&mut c);
+        }
+        // Direct chats are always marked, even if the presence of the other user is unknown.
+        if self.state.direct_target().is_some() {
+            let color = self.presence.map_or(Color::Default, presence_color);
+            let mut c = c.save().style_modifier();
+            c.apply_style_modifier(StyleModifier::new().fg_color(color));
+            c.write(PRESENCE_SYMBOL);
+        }
         c.write(self.state.name());
 
         if self.state.has_unread() {
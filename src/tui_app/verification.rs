@@ -0,0 +1,266 @@
+use futures_util::{stream, StreamExt};
+use matrix_sdk::encryption::verification::{
+    SasState, SasVerification, VerificationRequest, VerificationRequestState,
+};
+use matrix_sdk::ruma::events::key::verification::VerificationMethod;
+use matrix_sdk::ruma::{DeviceId, OwnedDeviceId, UserId};
+
+use tokio::sync::mpsc;
+
+use super::Connection;
+
+/// A verification request initiated by us, together with the device that we want to verify.
+pub type OutgoingRequest = (VerificationRequest, Option<OwnedDeviceId>);
+
+pub enum Stage {
+    Requested, // Incoming request that the user has not accepted (yet)
+    Waiting,   // Waiting for the other side
+    Comparing, // Emoji (or numbers) are shown and have to be compared by the user
+    Finished(String),
+}
+
+pub struct VerificationFlow {
+    pub request: VerificationRequest,
+    pub other_device: Option<OwnedDeviceId>,
+    pub sas: Option<SasVerification>,
+    pub stage: Stage,
+}
+
+impl VerificationFlow {
+    pub fn is_active(&self) -> bool {
+        !matches!(self.stage, Stage::Finished(_))
+    }
+
+    /// Accept the incoming request or confirm that the emoji match, depending on the stage.
+    pub fn accept(&mut self) -> Result<(), String> {
+        match self.stage {
+            Stage::Requested => {
+                let request = self.request.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = request
+                        .accept_with_methods(vec![VerificationMethod::SasV1])
+                        .await
+                    {
+                        tracing::error!("Failed to accept verification request: {}", e);
+                    }
+                });
+            }
+            Stage::Comparing => {
+                let sas = self.sas.clone().unwrap();
+                tokio::spawn(async move {
+                    if let Err(e) = sas.confirm().await {
+                        tracing::error!("Failed to confirm verification: {}", e);
+                    }
+                });
+            }
+            _ => return Err("Nothing to accept".to_owned()),
+        }
+        self.stage = Stage::Waiting;
+        Ok(())
+    }
+
+    /// Decline the request or abort the running verification.
+    pub fn cancel(&self) {
+        if !self.is_active() {
+            return;
+        }
+        let request = self.request.clone();
+        let sas = self.sas.clone();
+        tokio::spawn(async move {
+            let res = if let Some(sas) = sas {
+                sas.cancel().await
+            } else {
+                request.cancel().await
+            };
+            if let Err(e) = res {
+                tracing::error!("Failed to cancel verification: {}", e);
+            }
+        });
+    }
+}
+
+async fn update_flow(c: &Connection, flow_id: &str, f: impl FnOnce(&mut VerificationFlow)) {
+    {
+        let mut state = c.state.lock().await;
+        match &mut state.verification {
+            Some(flow) if flow.request.flow_id() == flow_id => f(flow),
+            _ => return, // The flow was dismissed by the user in the meantime
+        }
+    }
+    c.update().await;
+}
+
+async fn finish(c: &Connection, flow_id: &str, msg: String) {
+    update_flow(c, flow_id, |flow| flow.stage = Stage::Finished(msg)).await
+}
+
+/// Start tracking the verification request `flow_id` of `sender` if it is still pending.
+pub async fn handle_request(
+    c: &Connection,
+    sender: &UserId,
+    flow_id: &str,
+    other_device: Option<OwnedDeviceId>,
+) {
+    if let Some(request) = c
+        .client
+        .encryption()
+        .get_verification_request(sender, flow_id)
+        .await
+    {
+        if !request.we_started() && !request.is_done() && !request.is_cancelled() {
+            tokio::spawn(drive(c.clone(), request, other_device));
+        }
+    }
+}
+
+/// Whether `request` comes from another one of our devices that is already cross-signed.
+async fn from_own_signed_device(
+    c: &Connection,
+    request: &VerificationRequest,
+    device: Option<&DeviceId>,
+) -> bool {
+    let Some(device) = device else {
+        return false;
+    };
+    if !request.is_self_verification() {
+        return false;
+    }
+    match c
+        .client
+        .encryption()
+        .get_device(request.other_user_id(), device)
+        .await
+    {
+        Ok(Some(device)) => device.is_cross_signed_by_owner(),
+        Ok(None) => false,
+        Err(e) => {
+            tracing::error!("Failed to look up device {}: {}", device, e);
+            false
+        }
+    }
+}
+
+/// Drive the verification flow of `request` until it is done or cancelled, reflecting its
+/// progress in the state so that the user can interact with it.
+pub async fn drive(
+    c: Connection,
+    request: VerificationRequest,
+    other_device: Option<OwnedDeviceId>,
+) {
+    let flow_id = request.flow_id().to_owned();
+    // The emoji still have to be compared, only the request itself is accepted.
+    let auto_accept = !request.we_started()
+        && c.config.auto_accept_own_verifications
+        && from_own_signed_device(&c, &request, other_device.as_deref()).await;
+    {
+        let mut state = c.state.lock().await;
+        if let Some(flow) = &state.verification {
+            if flow.is_active() && flow.request.flow_id() != flow_id {
+                drop(state);
+                // Decline it explicitly so that the other side doesn't wait until it times out.
+                tracing::warn!(
+                    "Declining verification request from {} while another one is active",
+                    request.other_user_id()
+                );
+                if let Err(e) = request.cancel().await {
+                    tracing::error!("Failed to decline verification request: {}", e);
+                }
+                return;
+            }
+        }
+        state.verification = Some(VerificationFlow {
+            stage: if request.we_started() {
+                Stage::Waiting
+            } else {
+                Stage::Requested
+            },
+            request: request.clone(),
+            other_device,
+            sas: None,
+        });
+        if auto_accept {
+            let _ = state.verification.as_mut().unwrap().accept();
+        }
+    }
+    c.update().await;
+
+    let changes = request.changes();
+    let mut changes = Box::pin(stream::iter([request.state()]).chain(changes));
+    while let Some(s) = changes.next().await {
+        match s {
+            VerificationRequestState::Ready { .. } => match request.start_sas().await {
+                Ok(Some(sas)) => return drive_sas(&c, &flow_id, sas).await,
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!("Failed to start verification: {}", e);
+                    return finish(&c, &flow_id, format!("Failed to start verification: {}", e))
+                        .await;
+                }
+            },
+            VerificationRequestState::Transitioned { verification } => {
+                return match verification.sas() {
+                    Some(sas) => drive_sas(&c, &flow_id, sas).await,
+                    None => {
+                        finish(&c, &flow_id, "Unsupported verification method".to_owned()).await
+                    }
+                };
+            }
+            VerificationRequestState::Done => {
+                return finish(&c, &flow_id, "Verification done".to_owned()).await;
+            }
+            VerificationRequestState::Cancelled(info) => {
+                let msg = format!("Verification cancelled: {}", info.reason());
+                return finish(&c, &flow_id, msg).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn drive_sas(c: &Connection, flow_id: &str, sas: SasVerification) {
+    update_flow(c, flow_id, |flow| flow.sas = Some(sas.clone())).await;
+
+    let changes = sas.changes();
+    let mut changes = Box::pin(stream::iter([sas.state()]).chain(changes));
+    while let Some(s) = changes.next().await {
+        match s {
+            SasState::Started { .. } if !sas.we_started() => {
+                if let Err(e) = sas.accept().await {
+                    tracing::error!("Failed to accept verification: {}", e);
+                }
+            }
+            SasState::KeysExchanged { .. } => {
+                update_flow(c, flow_id, |flow| flow.stage = Stage::Comparing).await;
+            }
+            SasState::Done { .. } => {
+                let device = sas.other_device();
+                let msg = format!(
+                    "Successfully verified {} ({})",
+                    device.user_id(),
+                    device.device_id()
+                );
+                return finish(c, flow_id, msg).await;
+            }
+            SasState::Cancelled(info) => {
+                let msg = format!("Verification cancelled: {}", info.reason());
+                return finish(c, flow_id, msg).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Drive all verifications that are initiated by the user from within the tui.
+pub async fn run_outgoing_loop(
+    c: Connection,
+    mut requests: mpsc::UnboundedReceiver<OutgoingRequest>,
+) {
+    loop {
+        let (request, other_device) = tokio::select! {
+            Some(request) = requests.recv() => request,
+            _ = c.quit() => break,
+            else => break,
+        };
+        tokio::spawn(drive(c.clone(), request, other_device));
+    }
+}
@@ -0,0 +1,10 @@
+use matrix_sdk::Client;
+use std::path::PathBuf;
+
+pub async fn run(client: Client, file: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let data = std::fs::read(&file)?;
+    let mime_type = mime_guess::from_path(&file).first_or_octet_stream();
+    let response = client.media().upload(&mime_type, data, None).await?;
+    println!("{}", response.content_uri);
+    Ok(())
+}
@@ -1,10 +1,36 @@
-use matrix_sdk::encryption::verification::{SasVerification, Verification};
+use matrix_sdk::encryption::verification::{SasVerification, Verification, VerificationRequest};
 use matrix_sdk::ruma::events::{key::verification::VerificationMethod, AnyToDeviceEvent};
+use matrix_sdk::ruma::{OwnedUserId, UserId};
 
-use matrix_sdk::{self, config::SyncSettings, Client, LoopCtrl};
+use matrix_sdk::{self, config::SyncSettings, sync::SyncResponse, Client, LoopCtrl};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-async fn wait_for_confirmation(sas: SasVerification) {
+/// How long we wait for the other side to take the next step of a verification.
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(120);
+/// Long polling timeout of the syncs, which also determines how quickly a timeout is noticed.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn read_confirmation() -> Result<bool, String> {
+    loop {
+        let mut input = String::new();
+        let read = std::io::stdin()
+            .read_line(&mut input)
+            .map_err(|e| format!("Unable to read user input: {}", e))?;
+        if read == 0 {
+            return Err("Input closed before the verification was confirmed".to_owned());
+        }
+        match input.trim().to_lowercase().as_ref() {
+            "yes" => return Ok(true),
+            "no" => return Ok(false),
+            _ => println!("Please type 'yes' or 'no'"),
+        }
+    }
+}
+
+/// Ask the user to compare the emoji or numbers. Returns whether they match.
+async fn wait_for_confirmation(sas: &SasVerification) -> Result<bool, String> {
     println!("Type 'yes' if the emoji or the numbers match or 'no' if they don't:");
     if let Some(emoji) = sas.emoji() {
         print!("Emoji:");
@@ -16,32 +42,10 @@ async fn wait_for_confirmation(sas: SasVerification) {
     if let Some((n1, n2, n3)) = sas.decimals() {
         println!("Numbers: {}-{}-{}", n1, n2, n3);
     }
-
-    loop {
-        let mut input = String::new();
-        std::io::stdin()
-            .read_line(&mut input)
-            .expect("error: unable to read user input");
-
-        match input.trim().to_lowercase().as_ref() {
-            "yes" => {
-                sas.confirm().await.unwrap();
-
-                if sas.is_done() {
-                    print_result(&sas);
-                }
-                break;
-            }
-            "no" => {
-                println!("Canceled verification.");
-                sas.cancel().await.unwrap();
-                break;
-            }
-            _ => {
-                println!("Please type 'yes' or 'no'");
-            }
-        }
-    }
+    // Reading stdin blocks, which must not stall the runtime.
+    tokio::task::spawn_blocking(read_confirmation)
+        .await
+        .map_err(|e| format!("Unable to read user input: {}", e))?
 }
 
 fn print_result(sas: &SasVerification) {
@@ -55,152 +59,265 @@ fn print_result(sas: &SasVerification) {
     );
 }
 
-pub async fn run_verify_loop(client: &Client) -> Result<(), matrix_sdk::Error> {
-    let user_id = client.user_id().unwrap();
-    let device_id = client.device_id().unwrap();
-    let user_id = &user_id;
-    let device_id = &device_id;
-    let we_started = AtomicBool::new(false);
-    let we_started = &we_started;
-    client
-        .sync_with_callback(SyncSettings::new(), |response| async move {
-            for event in response
-                .to_device
-                .iter()
-                .filter_map(|e| e.deserialize().ok())
-            {
-                match event {
-                    AnyToDeviceEvent::KeyVerificationRequest(e) => {
-                        tracing::debug!("== Request {:?}", e);
-                        let request = client
-                            .encryption()
-                            .get_verification_request(&e.sender, &e.content.transaction_id)
-                            .await;
-                        if let Some(request) = request {
-                            request
-                                .accept_with_methods(vec![VerificationMethod::SasV1])
-                                .await
-                                .unwrap();
+/// The verification we are waiting for the other side to continue.
+struct Pending {
+    user_id: OwnedUserId,
+    flow_id: String,
+    deadline: Instant,
+}
 
-                            let _device = client
-                                .encryption()
-                                .get_device(&e.sender, &e.content.from_device)
-                                .await
-                                .unwrap();
-                            let _sas = request.start_sas().await.unwrap();
-                            we_started.store(true, Ordering::SeqCst);
-                        }
-                    }
-                    AnyToDeviceEvent::KeyVerificationReady(e) => {
-                        tracing::debug!("== Ready {:?}", e);
-                        let request = client
-                            .encryption()
-                            .get_verification_request(&e.sender, &e.content.transaction_id)
-                            .await;
-                        if let Some(request) = request {
-                            let _sas = request.start_sas().await.unwrap();
-                            we_started.store(true, Ordering::SeqCst);
-                        }
-                    }
+struct VerifyLoop<'a> {
+    client: &'a Client,
+    we_started: AtomicBool,
+    pending: Mutex<Option<Pending>>,
+}
 
-                    AnyToDeviceEvent::KeyVerificationStart(e) => {
-                        tracing::debug!("== Start: {:?}", e);
-                        if let Some(Verification::SasV1(sas)) = client
-                            .encryption()
-                            .get_verification(&e.sender, e.content.transaction_id.as_ref())
-                            .await
-                        {
-                            let accept = if we_started.load(Ordering::SeqCst) {
-                                use std::cmp::Ordering;
-                                println!("blub");
-                                match (
-                                    sas.other_user_id().cmp(user_id),
-                                    sas.other_device().device_id().cmp(device_id),
-                                ) {
-                                    (Ordering::Greater, _)
-                                    | (Ordering::Equal, Ordering::Greater) => {
-                                        println!(
-                                            "Letting other user/device begin verification process"
-                                        );
-                                        false
-                                    }
-                                    _ => true,
-                                }
-                            } else {
-                                true
-                            };
-                            if accept {
-                                println!(
-                                    "Starting verification with {} {}",
-                                    &sas.other_device().user_id(),
-                                    &sas.other_device().device_id()
-                                );
-                                sas.accept().await.unwrap();
-                            }
-                        }
-                    }
+impl VerifyLoop<'_> {
+    /// Restart the timeout after progress in the verification `flow_id` with `user_id`.
+    fn progress(&self, user_id: &UserId, flow_id: &str) {
+        *self.pending.lock().unwrap() = Some(Pending {
+            user_id: user_id.to_owned(),
+            flow_id: flow_id.to_owned(),
+            deadline: Instant::now() + VERIFICATION_TIMEOUT,
+        });
+    }
 
-                    AnyToDeviceEvent::KeyVerificationKey(e) => {
-                        tracing::debug!("== Key: {:?}", e);
-                        if let Some(Verification::SasV1(sas)) = client
-                            .encryption()
-                            .get_verification(&e.sender, e.content.transaction_id.as_str())
-                            .await
-                        {
-                            tokio::spawn(wait_for_confirmation(sas));
-                        }
-                    }
+    async fn sas(&self, user_id: &UserId, flow_id: &str) -> Option<SasVerification> {
+        match self
+            .client
+            .encryption()
+            .get_verification(user_id, flow_id)
+            .await
+        {
+            Some(Verification::SasV1(sas)) => Some(sas),
+            _ => None,
+        }
+    }
 
-                    AnyToDeviceEvent::KeyVerificationMac(e) => {
-                        tracing::debug!("== Mac: {:?}", e);
-                        if let Some(Verification::SasV1(sas)) = client
-                            .encryption()
-                            .get_verification(&e.sender, e.content.transaction_id.as_str())
-                            .await
-                        {
-                            if sas.is_done() {
-                                print_result(&sas);
-                                return LoopCtrl::Break;
-                            }
-                        }
-                    }
+    /// Cancel the pending verification if the other side did not respond in time.
+    async fn check_timeout(&self) -> Result<(), String> {
+        let timed_out = {
+            let mut pending = self.pending.lock().unwrap();
+            match &*pending {
+                Some(p) if Instant::now() >= p.deadline => pending.take(),
+                _ => None,
+            }
+        };
+        let Some(pending) = timed_out else {
+            return Ok(());
+        };
+        // Let the other side know, but time out regardless of whether that works.
+        let canceled = match self.sas(&pending.user_id, &pending.flow_id).await {
+            Some(sas) => sas.cancel().await,
+            None => match self
+                .client
+                .encryption()
+                .get_verification_request(&pending.user_id, &pending.flow_id)
+                .await
+            {
+                Some(request) => request.cancel().await,
+                None => Ok(()),
+            },
+        };
+        if let Err(e) = canceled {
+            tracing::warn!("Failed to cancel verification: {}", e);
+        }
+        Err(format!(
+            "Verification timed out: {} did not respond within {}s",
+            pending.user_id,
+            VERIFICATION_TIMEOUT.as_secs()
+        ))
+    }
 
-                    AnyToDeviceEvent::KeyVerificationAccept(e) => {
-                        tracing::debug!("== Accept: {:?}", e);
-                        if let Some(Verification::SasV1(sas)) = client
-                            .encryption()
-                            .get_verification(&e.sender, e.content.transaction_id.as_str())
-                            .await
-                        {
-                            if sas.is_done() {
-                                print_result(&sas);
-                                return LoopCtrl::Break;
+    /// Returns whether the verification finished successfully.
+    async fn handle_event(&self, event: AnyToDeviceEvent) -> Result<bool, String> {
+        let user_id = self.client.user_id().unwrap();
+        let device_id = self.client.device_id().unwrap();
+        match event {
+            AnyToDeviceEvent::KeyVerificationRequest(e) => {
+                tracing::debug!("== Request {:?}", e);
+                let flow_id = e.content.transaction_id.as_str();
+                let request = self
+                    .client
+                    .encryption()
+                    .get_verification_request(&e.sender, flow_id)
+                    .await;
+                if let Some(request) = request {
+                    request
+                        .accept_with_methods(vec![VerificationMethod::SasV1])
+                        .await
+                        .map_err(|e| format!("Failed to accept verification request: {}", e))?;
+                    request
+                        .start_sas()
+                        .await
+                        .map_err(|e| format!("Failed to start verification: {}", e))?;
+                    self.we_started.store(true, Ordering::SeqCst);
+                    self.progress(&e.sender, flow_id);
+                }
+            }
+            AnyToDeviceEvent::KeyVerificationReady(e) => {
+                tracing::debug!("== Ready {:?}", e);
+                let flow_id = e.content.transaction_id.as_str();
+                let request = self
+                    .client
+                    .encryption()
+                    .get_verification_request(&e.sender, flow_id)
+                    .await;
+                if let Some(request) = request {
+                    request
+                        .start_sas()
+                        .await
+                        .map_err(|e| format!("Failed to start verification: {}", e))?;
+                    self.we_started.store(true, Ordering::SeqCst);
+                    self.progress(&e.sender, flow_id);
+                }
+            }
+            AnyToDeviceEvent::KeyVerificationStart(e) => {
+                tracing::debug!("== Start: {:?}", e);
+                let flow_id = e.content.transaction_id.as_str();
+                if let Some(sas) = self.sas(&e.sender, flow_id).await {
+                    let accept = if self.we_started.load(Ordering::SeqCst) {
+                        use std::cmp::Ordering;
+                        match (
+                            sas.other_user_id().cmp(user_id),
+                            sas.other_device().device_id().cmp(device_id),
+                        ) {
+                            (Ordering::Greater, _) | (Ordering::Equal, Ordering::Greater) => {
+                                println!("Letting other user/device begin verification process");
+                                false
                             }
+                            _ => true,
                         }
+                    } else {
+                        true
+                    };
+                    if accept {
+                        println!(
+                            "Starting verification with {} {}",
+                            &sas.other_device().user_id(),
+                            &sas.other_device().device_id()
+                        );
+                        sas.accept()
+                            .await
+                            .map_err(|e| format!("Failed to accept verification: {}", e))?;
                     }
-                    AnyToDeviceEvent::KeyVerificationCancel(e) => {
-                        println!("Verification has been canceled {}", e.content.reason);
-                        return LoopCtrl::Break;
-                    }
-                    AnyToDeviceEvent::KeyVerificationDone(e) => {
-                        tracing::debug!("== Done: {:?}", e);
-                        if let Some(Verification::SasV1(sas)) = client
-                            .encryption()
-                            .get_verification(&e.sender, e.content.transaction_id.as_str())
+                    self.progress(&e.sender, flow_id);
+                }
+            }
+            AnyToDeviceEvent::KeyVerificationKey(e) => {
+                tracing::debug!("== Key: {:?}", e);
+                let flow_id = e.content.transaction_id.as_str();
+                if let Some(sas) = self.sas(&e.sender, flow_id).await {
+                    // Only the other side can time out, so waiting for the user does not count.
+                    self.pending.lock().unwrap().take();
+                    if !wait_for_confirmation(&sas).await? {
+                        sas.cancel()
                             .await
-                        {
-                            if sas.is_done() {
-                                print_result(&sas);
-                                return LoopCtrl::Break;
-                            }
-                        }
+                            .map_err(|e| format!("Failed to cancel verification: {}", e))?;
+                        return Err("Canceled verification.".to_owned());
                     }
-                    o => {
-                        tracing::debug!("other event {:?}", o);
+                    sas.confirm()
+                        .await
+                        .map_err(|e| format!("Failed to confirm verification: {}", e))?;
+                    if sas.is_done() {
+                        print_result(&sas);
+                        return Ok(true);
                     }
+                    self.progress(&e.sender, flow_id);
                 }
             }
-            LoopCtrl::Continue
-        })
-        .await
+            AnyToDeviceEvent::KeyVerificationMac(e) => {
+                tracing::debug!("== Mac: {:?}", e);
+                return Ok(self
+                    .check_done(&e.sender, e.content.transaction_id.as_str())
+                    .await);
+            }
+            AnyToDeviceEvent::KeyVerificationAccept(e) => {
+                tracing::debug!("== Accept: {:?}", e);
+                return Ok(self
+                    .check_done(&e.sender, e.content.transaction_id.as_str())
+                    .await);
+            }
+            AnyToDeviceEvent::KeyVerificationCancel(e) => {
+                return Err(format!(
+                    "Verification has been canceled: {}",
+                    e.content.reason
+                ));
+            }
+            AnyToDeviceEvent::KeyVerificationDone(e) => {
+                tracing::debug!("== Done: {:?}", e);
+                return Ok(self
+                    .check_done(&e.sender, e.content.transaction_id.as_str())
+                    .await);
+            }
+            o => {
+                tracing::debug!("other event {:?}", o);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn check_done(&self, user_id: &UserId, flow_id: &str) -> bool {
+        if let Some(sas) = self.sas(user_id, flow_id).await {
+            if sas.is_done() {
+                print_result(&sas);
+                return true;
+            }
+            self.progress(user_id, flow_id);
+        }
+        false
+    }
+
+    /// Returns whether the verification finished successfully.
+    async fn handle_response(&self, response: SyncResponse) -> Result<bool, String> {
+        for event in response
+            .to_device
+            .iter()
+            .filter_map(|e| e.deserialize().ok())
+        {
+            if self.handle_event(event).await? {
+                return Ok(true);
+            }
+        }
+        self.check_timeout().await?;
+        Ok(false)
+    }
+}
+
+/// Handle incoming verification events until a verification succeeds, fails or times out.
+/// `initiated` is a request we sent, which times out if the other side does not answer.
+pub async fn run_verify_loop(
+    client: &Client,
+    initiated: Option<&VerificationRequest>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let verify_loop = VerifyLoop {
+        client,
+        we_started: AtomicBool::new(false),
+        pending: Mutex::new(None),
+    };
+    if let Some(request) = initiated {
+        verify_loop.progress(request.other_user_id(), request.flow_id());
+    }
+    let verify_loop = &verify_loop;
+    let failure = Mutex::new(None);
+    let failure = &failure;
+    client
+        .sync_with_callback(
+            SyncSettings::new().timeout(SYNC_TIMEOUT),
+            |response| async move {
+                match verify_loop.handle_response(response).await {
+                    Ok(false) => LoopCtrl::Continue,
+                    Ok(true) => LoopCtrl::Break,
+                    Err(e) => {
+                        *failure.lock().unwrap() = Some(e);
+                        LoopCtrl::Break
+                    }
+                }
+            },
+        )
+        .await?;
+    match failure.lock().unwrap().take() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
 }
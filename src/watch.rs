@@ -0,0 +1,46 @@
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+};
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::{config::SyncSettings, Client, LoopCtrl};
+use std::collections::HashSet;
+
+pub async fn run(client: Client, rooms: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.sync_once(SyncSettings::new()).await?;
+
+    let mut filter = HashSet::<OwnedRoomId>::new();
+    for room in &rooms {
+        let room = crate::resolve::find_room(&client, room).await?;
+        filter.insert(room.room_id().to_owned());
+    }
+    let filter = &filter;
+
+    // Only print messages that arrive from now on.
+    let settings = SyncSettings::new().token(response.next_batch);
+    client
+        .sync_with_callback(settings, |response| async move {
+            for (room_id, room_info) in response.rooms.join {
+                if !filter.is_empty() && !filter.contains(&room_id) {
+                    continue;
+                }
+                for e in room_info.timeline.events {
+                    if let Ok(AnySyncTimelineEvent::MessageLike(
+                        AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(m)),
+                    )) = e.raw().deserialize()
+                    {
+                        let line = serde_json::json!({
+                            "room": room_id,
+                            "sender": m.sender,
+                            "type": m.content.msgtype(),
+                            "body": m.content.body(),
+                            "timestamp": m.origin_server_ts,
+                        });
+                        println!("{}", line);
+                    }
+                }
+            }
+            LoopCtrl::Continue
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use matrix_sdk::{config::SyncSettings, Client};
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+pub async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let _res = client.sync_once(SyncSettings::new()).await?;
+
+    let user_id = client.user_id().ok_or("Not logged in")?;
+    let device_id = client.device_id().ok_or("Not logged in")?;
+    println!("User:\t\t{}", user_id);
+    println!("Device:\t\t{}", device_id);
+    println!("Homeserver:\t{}", client.homeserver());
+
+    let encryption = client.encryption();
+    match encryption.cross_signing_status().await {
+        Some(status) => println!(
+            "Cross-signing:\t{}",
+            if status.is_complete() {
+                "set up"
+            } else if status.has_master {
+                "incomplete (private keys missing)"
+            } else {
+                "not set up"
+            }
+        ),
+        None => println!("Cross-signing:\tunknown"),
+    }
+    if let Some(device) = encryption.get_own_device().await? {
+        println!(
+            "Device signed:\t{}",
+            yes_no(device.is_cross_signed_by_owner())
+        );
+        println!("Device verified:\t{}", yes_no(device.is_verified()));
+    }
+    Ok(())
+}